@@ -53,56 +53,84 @@ def ohlcvv_to_df(array):
     return df
 
 
+def ohlcvv_merged_to_df(array):
+    df = pd.DataFrame(
+        array, columns=["timestamp", "open", "high", "low", "close", "vol", "buy_vol", "sell_vol",
+                        "buy_count", "sell_count", "count", "vwap", "delta", "start_time", "end_time"])
+    df['timestamp'] = pd.to_datetime(
+        (df["timestamp"]), utc=True, unit='us')
+    df = df.set_index('timestamp')
 
-def result_to_df(result_list):
-    update_time = []
-    order_id = []
-    order_sub_id = []
-    order_type = []
-    post_only = []
-    create_time = []
-    status = []
-    open_price = []
-    close_price = []
-    price = []
-    size = []
-    volume = []
-    profit = []
-    fee = []
-    total_profit = []
-    position_change = []
-    message = []
+    df['start_time'] = pd.to_datetime(
+        (df["start_time"]), utc=True, unit='us')
 
-    for item in result_list:
-        update_time.append(item.update_time)
-        order_id.append(item.order_id)
-        order_sub_id.append(item.order_sub_id)
-        order_type.append(item.order_type)
-        post_only.append(item.post_only)
-        create_time.append(item.create_time)
-        status.append(item.status)
-        open_price.append(item.open_price)
-        close_price.append(item.close_price)
-        price.append(item.price)
-        size.append(item.size)
-        volume.append(item.volume)
-        profit.append(item.profit)
-        fee.append(item.fee)
-        total_profit.append(item.total_profit)
-        position_change.append(item.position_change)
-        message.append(item.message)
+    df['end_time'] = pd.to_datetime(
+        (df["end_time"]), utc=True, unit='us')
+
+    return df
 
+
+def audit_to_df(issues):
+    df = pd.DataFrame(
+        issues, columns=["kind", "start_time", "end_time", "id", "value"])
+    df['start_time'] = pd.to_datetime(
+        (df["start_time"]), utc=True, unit='us')
+    df['end_time'] = pd.to_datetime(
+        (df["end_time"]), utc=True, unit='us')
+
+    return df
+
+
+def equity_to_df(array):
     df = pd.DataFrame(
-    data={"update_time": update_time, "order_id": order_id, "sub_id": order_sub_id,
-          "order_type": order_type, "post_only": post_only, "create_time": create_time,
-          "status":  status, "open_price": open_price, "close_price": close_price,
-          "price": price, "size": size, "volume": volume, "profit": profit, "fee": fee,
-          "total_profit": total_profit, "pos_change": position_change, "message": message},
-    columns=["update_time", "order_id", "sub_id", "order_type", "post_only",
-             "create_time", "status", "open_price", "close_price", "price", "size", "volume",
-             "profit", "fee", "total_profit", "pos_change", "message"])
-    df["update_time"] = pd.to_datetime((df["update_time"]), utc=True, unit="ms")
-    df["create_time"] = pd.to_datetime((df["create_time"]), utc=True, unit="ms")
+        array, columns=["timestamp", "wallet_balance", "realized_pnl", "unrealized_pnl",
+                        "long_position_size", "short_position_size", "long_order_size", "short_order_size"])
+    df['timestamp'] = pd.to_datetime(
+        (df["timestamp"]), utc=True, unit='us')
+    df = df.set_index('timestamp')
+
+    # wallet_balanceは入金額に確定損益（手数料・Funding込み）を加えたもの。
+    # equityは口座の評価額（wallet_balance + 含み損益）、pnlは損益のみ。
+    df['equity'] = df['wallet_balance'] + df['unrealized_pnl']
+    df['pnl'] = df['realized_pnl'] + df['unrealized_pnl']
+
+    return df
+
+
+def position_change(item):
+    status = str(item.status)
+    side = str(item.order_side)
+
+    if status.endswith("OpenPosition"):
+        size = item.open_home_size
+    elif status.endswith("ClosePosition") or status.endswith("Liquidation"):
+        size = item.close_home_size
+    else:
+        return 0.0
+
+    if side.endswith("Buy"):
+        return size
+    else:
+        return -size
+
+
+def result_to_df(result_list):
+    columns = ["update_time", "order_id", "sub_id", "order_side", "post_only",
+               "create_time", "status", "open_price", "close_price", "order_price",
+               "order_home_size", "order_foreign_size", "is_maker",
+               "profit", "fee", "total_profit", "pos_change", "message"]
+
+    data = []
+    for item in result_list:
+        data.append([
+            item.update_time, item.order_id, item.order_sub_id, str(item.order_side), item.post_only,
+            item.create_time, str(item.status), item.open_price, item.close_price, item.order_price,
+            item.order_home_size, item.order_foreign_size, item.is_maker,
+            item.profit, item.fee, item.total_profit, position_change(item), item.message])
+
+    df = pd.DataFrame(data, columns=columns)
+    df["update_time"] = pd.to_datetime((df["update_time"]), utc=True, unit="us")
+    df["create_time"] = pd.to_datetime((df["create_time"]), utc=True, unit="us")
     df["sum_profit"] = df["total_profit"].cumsum()
     df["sum_pos"] = df["pos_change"].cumsum()
     df = df.set_index("create_time", drop=True)
@@ -120,14 +148,48 @@ class BaseAgent:
     def clock_interval(self):
         return 60
 
-    def _on_tick(self, time, session, price, side, size):
-        self.on_tick(time, Session(session), price, side, size)
+    # 複数マーケットのBackTesterでは、最後の引数にマーケット("BN/BTCBUSD")が渡される。
+    def _on_tick(self, time, session, price, side, size, market=None):
+        if market is None:
+            self.on_tick(time, Session(session), price, side, size)
+        else:
+            self.on_tick(time, Session(session), price, side, size, market)
+
+    def _on_clock(self, time, session, market=None):
+        if market is None:
+            self.on_clock(time, Session(session))
+        else:
+            self.on_clock(time, Session(session), market)
+
+    def _on_update(self, time, session, result, market=None):
+        if market is None:
+            self.on_update(time, Session(session), result)
+        else:
+            self.on_update(time, Session(session), result, market)
+
+
+class PaperSession:
+    # WebSocketの約定で動かすペーパートレード。手数料などは.sessionに設定する。
+    def __init__(self, exchange_name, market_name):
+        self.paper = _PaperSession(exchange_name, market_name)
+
+    def run(self, agent, duration_sec=0):
+        return self.paper.run(agent, duration_sec)
 
-    def _on_clock(self, time, session):
-        self.on_clock(time, Session(session))
+    def __getattr__(self, func):
+        return getattr(self.paper, func)
+
+
+class LiveSession:
+    # 取引所へオーダーを送るセッション。エンドポイントは.live.rest_baseで変更できる。
+    def __init__(self, exchange_name, market_name, api_key, api_secret):
+        self.live = _LiveSession(exchange_name, market_name, api_key, api_secret)
 
-    def _on_update(self, time, session, result):
-        self.on_update(time, Session(session), result)
+    def run(self, agent, duration_sec=0):
+        return self.live.run(agent, duration_sec)
+
+    def __getattr__(self, func):
+        return getattr(self.live, func)
 
 
 class Session:
@@ -137,8 +199,11 @@ class Session:
     def __getattr__(self, func):
         return getattr(self.session, func)
         
-    def ohlcv(self, time_window, num_of_bars, exchange_name=None, market_name=None):
-        if not exchange_name:
+    # market: 複数マーケットのセッションでは"BN/BTCBUSD"の形式で指定できる。
+    def ohlcv(self, time_window, num_of_bars, exchange_name=None, market_name=None, split_side=True, market=None):
+        if market:
+            exchange_name, market_name = market.split("/", 1)
+        elif not exchange_name:
             exchange_name = self.session.exchange_name
             market_name = self.session.market_name
         
@@ -146,7 +211,7 @@ class Session:
 
         now = self.session.current_timestamp
 
-        return market.ohlcvv(now - time_window * num_of_bars * 1_000_000, now, time_window)
+        return market.ohlcvv(now - time_window * num_of_bars * 1_000_000, now, time_window, split_side)
 
 
 
@@ -172,6 +237,36 @@ class Market:
             key = Market.key(exchange, market)
             cls.MARKET[key] = m
             return m
+        elif exchange == "BB":
+            m = BybitMarket(market, cls.DUMMY_MODE)
+            key = Market.key(exchange, market)
+            cls.MARKET[key] = m
+            return m
+        elif exchange == "BN-UM":
+            m = BinanceMarket(market, cls.DUMMY_MODE, market_type="um")
+            key = Market.key(exchange, market)
+            cls.MARKET[key] = m
+            return m
+        elif exchange == "BN-CM":
+            m = BinanceMarket(market, cls.DUMMY_MODE, market_type="cm")
+            key = Market.key(exchange, market)
+            cls.MARKET[key] = m
+            return m
+        elif exchange == "BN-AGG":
+            m = BinanceMarket(market, cls.DUMMY_MODE, data_type="aggTrades")
+            key = Market.key(exchange, market)
+            cls.MARKET[key] = m
+            return m
+        elif exchange == "BN-UM-AGG":
+            m = BinanceMarket(market, cls.DUMMY_MODE, market_type="um", data_type="aggTrades")
+            key = Market.key(exchange, market)
+            cls.MARKET[key] = m
+            return m
+        elif exchange == "BN-CM-AGG":
+            m = BinanceMarket(market, cls.DUMMY_MODE, market_type="cm", data_type="aggTrades")
+            key = Market.key(exchange, market)
+            cls.MARKET[key] = m
+            return m
 
     @classmethod
     def download(cls, ndays):
@@ -214,21 +309,69 @@ class FtxMarket:
 
 
 class BinanceMarket:
-    def __init__(self, name, dummy=True):
+    # market_type: "spot" | "um" | "cm",  data_type: "trades" | "aggTrades"
+    def __init__(self, name, dummy=True, market_type="spot", data_type="trades"):
         self.dummy = dummy
-        self.market = _BinanceMarket(name, dummy)
-        self.exchange_name = "BN"
+        self.market = _BinanceMarket(name, dummy, market_type, data_type)
+        self.exchange_name = BinanceMarket.exchange_code(market_type, data_type)
         self.market_name = name
 
+    # 取引所コード（"BN", "BN-UM", "BN-CM"。aggTradesの場合は"-AGG"がつく）
+    @staticmethod
+    def exchange_code(market_type="spot", data_type="trades"):
+        code = "BN" if market_type == "spot" else "BN-" + market_type.upper()
+        if data_type == "aggTrades":
+            code += "-AGG"
+        return code
+
     def select_trades(self, from_time, to_time):
         return trades_to_df(self.market.select_trades(from_time, to_time))
 
-    def ohlcvv(self, from_time, to_time, window_sec):
-        return ohlcvv_to_df(self.market.ohlcvv(from_time, to_time, window_sec))
+    def ohlcvv(self, from_time, to_time, window_sec, split_side=True):
+        if split_side:
+            return ohlcvv_to_df(self.market.ohlcvv(from_time, to_time, window_sec, True))
+
+        return ohlcvv_merged_to_df(self.market.ohlcvv(from_time, to_time, window_sec, False))
+
+    # kind: "tick" | "volume" | "dollar" | "tick_imbalance" | "volume_imbalance"
+    def bars(self, from_time, to_time, kind, threshold):
+        return ohlcvv_to_df(self.market.bars(from_time, to_time, kind, threshold))
 
     def download(self, ndays, force=False):
         return self.market.download(ndays, force)
 
+    def audit(self, from_time=0, to_time=0, **kwargs):
+        return audit_to_df(self.market.audit(from_time, to_time, **kwargs))
+
     def __getattr__(self, func):
-        return getattr(self.ftx, func)
+        return getattr(self.market, func)
+
+
+class BybitMarket:
+    def __init__(self, name, dummy=True):
+        self.dummy = dummy
+        self.market = _BybitMarket(name, dummy)
+        self.exchange_name = "BB"
+        self.market_name = name
 
+    def select_trades(self, from_time, to_time):
+        return trades_to_df(self.market.select_trades(from_time, to_time))
+
+    def ohlcvv(self, from_time, to_time, window_sec, split_side=True):
+        if split_side:
+            return ohlcvv_to_df(self.market.ohlcvv(from_time, to_time, window_sec, True))
+
+        return ohlcvv_merged_to_df(self.market.ohlcvv(from_time, to_time, window_sec, False))
+
+    # kind: "tick" | "volume" | "dollar" | "tick_imbalance" | "volume_imbalance"
+    def bars(self, from_time, to_time, kind, threshold):
+        return ohlcvv_to_df(self.market.bars(from_time, to_time, kind, threshold))
+
+    def download(self, ndays, force=False):
+        return self.market.download(ndays, force)
+
+    def audit(self, from_time=0, to_time=0, **kwargs):
+        return audit_to_df(self.market.audit(from_time, to_time, **kwargs))
+
+    def __getattr__(self, func):
+        return getattr(self.market, func)
@@ -98,6 +98,9 @@ pub struct Order {
     pub size: f64,             // in foreign
     pub message: String,
     pub remain_size: f64, // ログから想定した未約定数。０になったら全部約定。
+    pub queue_ahead: f64, // 前に並んでいると推定されるサイズ（約定モデルが利用）
+    pub arrival_time: MicroSec, // サーバへ届く時刻（この時刻以降の約定ログで約定する）
+    pub fill_count: i32, // 一部約定の回数（一部約定の結果のorder_sub_idに使う）
 }
 
 #[pymethods]
@@ -124,11 +127,14 @@ impl Order {
             size,
             message,
             remain_size: size,
+            queue_ahead: 0.0,
+            arrival_time: create_time,
+            fill_count: 0,
         };
     }
 
     pub fn __str__(&self) -> String {
-        return format!("{{order_index:{}, create_time:{}, order_id:{}, order_side:{:?}, post_only:{}, valid_until:{}, price:{}, size:{}, message:{}, remain_size:{}}}",
+        return format!("{{order_index:{}, create_time:{}, order_id:{}, order_side:{:?}, post_only:{}, valid_until:{}, price:{}, size:{}, message:{}, remain_size:{}, queue_ahead:{}, arrival_time:{}, fill_count:{}}}",
         self._order_index,
         self.create_time,
         self.order_id,
@@ -139,6 +145,9 @@ impl Order {
         self.size,
         self.message,
         self.remain_size,
+        self.queue_ahead,
+        self.arrival_time,
+        self.fill_count,
     );
     }
 
@@ -162,6 +171,7 @@ pub enum OrderStatus {
     PostOnlyError, // 指値不成立。
     NoMoney,       //　証拠金不足（オーダできず）
     Cancel,        //  ユーザによるオーダーキャンセル
+    Funding,       //  FundingRateの支払い・受け取り
     Error,         // その他エラー（基本的には発生させない）
 }
 
@@ -184,26 +194,50 @@ impl OrderStatus {
 #[pyclass]
 #[derive(Debug, Clone)]
 pub struct OrderResult {
+    #[pyo3(get)]
     pub update_time: MicroSec,
+    #[pyo3(get)]
     pub order_id: String,
+    #[pyo3(get)]
     pub order_sub_id: i32, // 分割された場合に利用
+    #[pyo3(get)]
     pub order_side: OrderSide,
+    #[pyo3(get)]
     pub post_only: bool,
+    #[pyo3(get)]
     pub create_time: MicroSec,
+    #[pyo3(get)]
     pub status: OrderStatus,
+    #[pyo3(get)]
     pub open_price: f64,
+    #[pyo3(get)]
     pub open_home_size: f64,
+    #[pyo3(get)]
     pub open_foreign_size: f64,
+    #[pyo3(get)]
     pub close_price: f64,
+    #[pyo3(get)]
     pub close_home_size: f64,
+    #[pyo3(get)]
     pub close_foreign_size: f64,
+    #[pyo3(get)]
     pub order_price: f64,
+    #[pyo3(get)]
     pub order_home_size: f64,
+    #[pyo3(get)]
     pub order_foreign_size: f64,
+    #[pyo3(get)]
+    pub is_maker: bool, // Takerで約定した場合はfalse（手数料計算に利用）
+    #[pyo3(get)]
     pub profit: f64,
+    #[pyo3(get)]
     pub fee: f64,
+    #[pyo3(get)]
     pub total_profit: f64,
+    #[pyo3(get)]
     pub message: String,
+    #[pyo3(get)]
+    pub market: String, // 約定したマーケット（"BN/BTCBUSD"）
 }
 
 impl OrderResult {
@@ -229,10 +263,12 @@ impl OrderResult {
             order_price: order.price,
             order_home_size: order.size,
             order_foreign_size: OrderResult::calc_foreign_size(order.price, order.size),
+            is_maker: true,
             profit: 0.0,
             fee: 0.0,
             total_profit: 0.0,
             message: order.message.clone(),
+            market: "".to_string(),
         };
 
         return result;
@@ -246,6 +282,12 @@ impl OrderResult {
         return home_size / price;
     }
 
+    /// 約定したサイズ（一部約定の場合）にオーダーサイズを変更する。
+    pub fn set_exec_size(&mut self, home_size: f64) {
+        self.order_home_size = home_size;
+        self.order_foreign_size = OrderResult::calc_foreign_size(self.order_price, home_size);
+    }
+
     /// オーダーを指定された大きさで２つに分ける。
     /// 一つはSelf, もう一つはCloneされたChild Order
     /// 子供のオーダについては、sub_idが１インクリメントする。
@@ -280,7 +322,7 @@ impl OrderResult {
 #[pymethods]
 impl OrderResult {
     pub fn __str__(&self) -> String {
-        return format!("update_time: {:?}, order_id: {:?}, order_sub_id: {:?}, order_side: {:?}, post_only: {:?}, create_time: {:?}, status: {:?}, open_price: {:?}, open_home_size: {:?}, open_foreign_size: {:?}, close_price: {:?}, close_home_size: {:?}, close_foreign_size: {:?}, order_price: {:?}, order_home_size: {:?}, order_foreign_size: {:?}, profit: {:?}, fee: {:?}, total_profit: {:?}, message: {:?}",
+        return format!("update_time: {:?}, order_id: {:?}, order_sub_id: {:?}, order_side: {:?}, post_only: {:?}, create_time: {:?}, status: {:?}, open_price: {:?}, open_home_size: {:?}, open_foreign_size: {:?}, close_price: {:?}, close_home_size: {:?}, close_foreign_size: {:?}, order_price: {:?}, order_home_size: {:?}, order_foreign_size: {:?}, is_maker: {:?}, profit: {:?}, fee: {:?}, total_profit: {:?}, message: {:?}, market: {:?}",
                        self.update_time,
                        self.order_id,
                        self.order_sub_id,
@@ -297,10 +339,12 @@ impl OrderResult {
                        self.order_price,
                        self.order_home_size,
                        self.order_foreign_size,
+                       self.is_maker,
                        self.profit,
                        self.fee,
                        self.total_profit,
-                       self.message);
+                       self.message,
+                       self.market);
     }
 
     pub fn __repr__(&self) -> String {
@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::common::order::{OrderSide, Trade};
+use crate::common::time::{MicroSec, DAYS, FLOOR_DAY, SEC};
+
+/// audit の閾値
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub gap: MicroSec,         // これより長い約定の空白を gap とする
+    pub outlier_window: usize, // 中央値を計算する直近の約定数
+    pub outlier_rate: f64,     // 中央値からの乖離率がこれを超えたら price_outlier
+    pub min_day_rate: f64,     // 日毎の件数が中央値のこの比率未満なら short_day
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        return AuditConfig {
+            gap: SEC(60),
+            outlier_window: 100,
+            outlier_rate: 0.05,
+            min_day_rate: 0.5,
+        };
+    }
+}
+
+/// audit で見つかった問題（１件１行）
+///   kind: 問題の種類(AuditIssue::GAPなど)
+///   start_time, end_time: 問題の時刻（gapと日付の場合は範囲）
+///   id: 問題のある約定のid
+///   value: gapの長さ(sec)、価格、サイズ、件数など種類ごとの値
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditIssue {
+    pub kind: String,
+    pub start_time: MicroSec,
+    pub end_time: MicroSec,
+    pub id: String,
+    pub value: f64,
+}
+
+impl AuditIssue {
+    pub const GAP: &'static str = "gap";
+    pub const DUPLICATE_TRADE: &'static str = "duplicate_trade";
+    pub const NON_MONOTONIC_ID: &'static str = "non_monotonic_id";
+    pub const BAD_PRICE: &'static str = "bad_price";
+    pub const BAD_SIZE: &'static str = "bad_size";
+    pub const UNKNOWN_SIDE: &'static str = "unknown_side";
+    pub const PRICE_OUTLIER: &'static str = "price_outlier";
+    pub const MISSING_DAY: &'static str = "missing_day";
+    pub const SHORT_DAY: &'static str = "short_day";
+
+    fn new(kind: &str, start_time: MicroSec, end_time: MicroSec, id: &str, value: f64) -> Self {
+        return AuditIssue {
+            kind: kind.to_string(),
+            start_time,
+            end_time,
+            id: id.to_string(),
+            value,
+        };
+    }
+
+    fn from_trade(kind: &str, trade: &Trade, value: f64) -> Self {
+        return AuditIssue::new(kind, trade.time, trade.time, &trade.id, value);
+    }
+
+    /// Pythonのテーブル用 (kind, start_time, end_time, id, value)
+    pub fn to_tuple(&self) -> (String, MicroSec, MicroSec, String, f64) {
+        return (
+            self.kind.clone(),
+            self.start_time,
+            self.end_time,
+            self.id.clone(),
+            self.value,
+        );
+    }
+}
+
+/// 時刻順の約定を１件ずつ受け取り、問題を記録する。
+#[derive(Debug)]
+pub struct TradeAuditor {
+    config: AuditConfig,
+    issues: Vec<AuditIssue>,
+    count: i64,
+    first_time: MicroSec,
+    last_time: MicroSec,
+    last_id_num: Option<i64>,
+    same_time: Vec<(OrderSide, u64, u64, bool)>, // last_timeの約定の(方向, 価格, サイズ, idが数値か)
+    prices: VecDeque<f64>,
+    day_counts: BTreeMap<MicroSec, i64>,
+}
+
+impl TradeAuditor {
+    pub fn new(config: AuditConfig) -> Self {
+        return TradeAuditor {
+            config,
+            issues: vec![],
+            count: 0,
+            first_time: 0,
+            last_time: 0,
+            last_id_num: None,
+            same_time: vec![],
+            prices: VecDeque::new(),
+            day_counts: BTreeMap::new(),
+        };
+    }
+
+    pub fn push(&mut self, trade: &Trade) {
+        if self.count == 0 {
+            self.first_time = trade.time;
+        } else if self.config.gap < trade.time - self.last_time {
+            self.issues.push(AuditIssue::new(
+                AuditIssue::GAP,
+                self.last_time,
+                trade.time,
+                &trade.id,
+                (trade.time - self.last_time) as f64 / SEC(1) as f64,
+            ));
+        }
+
+        self.check_id(trade);
+        self.check_duplicate(trade);
+
+        if trade.price <= 0.0 {
+            self.issues.push(AuditIssue::from_trade(AuditIssue::BAD_PRICE, trade, trade.price));
+        } else {
+            self.check_outlier(trade);
+        }
+
+        if trade.size <= 0.0 {
+            self.issues.push(AuditIssue::from_trade(AuditIssue::BAD_SIZE, trade, trade.size));
+        }
+
+        if trade.order_side == OrderSide::Unknown {
+            self.issues.push(AuditIssue::from_trade(AuditIssue::UNKNOWN_SIDE, trade, 0.0));
+        }
+
+        *self.day_counts.entry(FLOOR_DAY(trade.time)).or_insert(0) += 1;
+
+        self.count += 1;
+        self.last_time = trade.time;
+    }
+
+    /// 数値のidは時刻順に増加することを確認する（同時刻の約定は順序を問わない）
+    fn check_id(&mut self, trade: &Trade) {
+        let current = match trade.id.parse::<i64>() {
+            Ok(id) => id,
+            Err(_) => return,
+        };
+
+        if let Some(last) = self.last_id_num {
+            if current < last && self.last_time < trade.time {
+                self.issues.push(AuditIssue::from_trade(AuditIssue::NON_MONOTONIC_ID, trade, current as f64));
+            }
+        }
+
+        if self.last_id_num.map_or(true, |last| last < current) {
+            self.last_id_num = Some(current);
+        }
+    }
+
+    /// idはDBの主キーなので重複しない。同時刻・同方向・同価格・同サイズの約定が、id体系の異なる別のidで
+    /// 登録されているものを重複とする（WebSocketとアーカイブなど、id体系の異なるログを混ぜた場合に起きる）。
+    /// 一つのTakerが同じサイズの複数のMakerと約定した場合も同じ内容の約定が並ぶので、同じid体系同士は重複としない。
+    fn check_duplicate(&mut self, trade: &Trade) {
+        if trade.time != self.last_time {
+            self.same_time.clear();
+        }
+
+        let numeric_id = trade.id.parse::<i64>().is_ok();
+        let key = (trade.order_side, trade.price.to_bits(), trade.size.to_bits(), numeric_id);
+
+        let duplicate = self.same_time.iter().any(|(side, price, size, numeric)| {
+            (*side, *price, *size) == (key.0, key.1, key.2) && *numeric != numeric_id
+        });
+
+        if duplicate {
+            self.issues.push(AuditIssue::from_trade(AuditIssue::DUPLICATE_TRADE, trade, trade.size));
+        } else if !self.same_time.contains(&key) {
+            self.same_time.push(key);
+        }
+    }
+
+    fn check_outlier(&mut self, trade: &Trade) {
+        let window = self.config.outlier_window;
+
+        if window != 0 && self.prices.len() == window {
+            let median = median(&self.prices);
+
+            if self.config.outlier_rate < ((trade.price - median) / median).abs() {
+                self.issues.push(AuditIssue::from_trade(AuditIssue::PRICE_OUTLIER, trade, trade.price));
+            }
+        }
+
+        self.prices.push_back(trade.price);
+        while window < self.prices.len() {
+            self.prices.pop_front();
+        }
+    }
+
+    /// 範囲の前後の空白と、日毎の件数を確認して結果を返す。
+    /// from_time/to_timeが0の場合は最初/最後の約定までを範囲とする。
+    pub fn finish(mut self, from_time: MicroSec, to_time: MicroSec) -> Vec<AuditIssue> {
+        if self.count == 0 {
+            if from_time != 0 && to_time != 0 {
+                self.issues.push(AuditIssue::new(
+                    AuditIssue::GAP,
+                    from_time,
+                    to_time,
+                    "",
+                    (to_time - from_time) as f64 / SEC(1) as f64,
+                ));
+            }
+            return self.issues;
+        }
+
+        if from_time != 0 && self.config.gap < self.first_time - from_time {
+            self.issues.insert(
+                0,
+                AuditIssue::new(
+                    AuditIssue::GAP,
+                    from_time,
+                    self.first_time,
+                    "",
+                    (self.first_time - from_time) as f64 / SEC(1) as f64,
+                ),
+            );
+        }
+
+        if to_time != 0 && self.config.gap < to_time - self.last_time {
+            self.issues.push(AuditIssue::new(
+                AuditIssue::GAP,
+                self.last_time,
+                to_time,
+                "",
+                (to_time - self.last_time) as f64 / SEC(1) as f64,
+            ));
+        }
+
+        let range_start = if from_time != 0 { from_time } else { self.first_time };
+        let range_end = if to_time != 0 { to_time } else { self.last_time + 1 };
+
+        // 範囲に全て含まれる日のみを対象とする
+        let mut days: Vec<(MicroSec, i64)> = vec![];
+        let mut day = FLOOR_DAY(range_start);
+        while day < range_end {
+            if range_start <= day && day + DAYS(1) <= range_end {
+                days.push((day, *self.day_counts.get(&day).unwrap_or(&0)));
+            }
+            day += DAYS(1);
+        }
+
+        let mut counts: Vec<f64> = days
+            .iter()
+            .filter(|(_day, count)| *count != 0)
+            .map(|(_day, count)| *count as f64)
+            .collect();
+        let median_count = if counts.is_empty() {
+            0.0
+        } else {
+            counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            counts[counts.len() / 2]
+        };
+
+        for (day, count) in days {
+            if count == 0 {
+                self.issues.push(AuditIssue::new(AuditIssue::MISSING_DAY, day, day + DAYS(1), "", 0.0));
+            } else if (count as f64) < median_count * self.config.min_day_rate {
+                self.issues.push(AuditIssue::new(AuditIssue::SHORT_DAY, day, day + DAYS(1), "", count as f64));
+            }
+        }
+
+        return self.issues;
+    }
+}
+
+fn median(prices: &VecDeque<f64>) -> f64 {
+    let mut v: Vec<f64> = prices.iter().copied().collect();
+    let mid = v.len() / 2;
+    let (_, m, _) = v.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap());
+
+    return *m;
+}
+
+#[cfg(test)]
+mod test_audit {
+    use super::*;
+
+    fn make_trade(time: MicroSec, price: f64, size: f64, side: OrderSide, id: &str) -> Trade {
+        Trade::new(time, side, price, size, id.to_string())
+    }
+
+    #[test]
+    fn test_audit_trades() {
+        let config = AuditConfig {
+            gap: SEC(10),
+            outlier_window: 3,
+            outlier_rate: 0.1,
+            min_day_rate: 0.5,
+        };
+        let mut auditor = TradeAuditor::new(config);
+
+        auditor.push(&make_trade(SEC(1), 100.0, 1.0, OrderSide::Buy, "1"));
+        auditor.push(&make_trade(SEC(2), 100.0, 1.0, OrderSide::Sell, "2"));
+        auditor.push(&make_trade(SEC(2), 100.0, 1.0, OrderSide::Sell, "ws-2")); // dupe
+        auditor.push(&make_trade(SEC(2), 100.0, 2.0, OrderSide::Sell, "ws-3"));
+        auditor.push(&make_trade(SEC(3), 101.0, 1.0, OrderSide::Buy, "3"));
+        auditor.push(&make_trade(SEC(30), 100.0, 1.0, OrderSide::Buy, "4")); // gap
+        auditor.push(&make_trade(SEC(31), 150.0, 1.0, OrderSide::Buy, "5")); // outlier
+        auditor.push(&make_trade(SEC(32), 100.0, 0.0, OrderSide::Unknown, "6")); // size, side
+        auditor.push(&make_trade(SEC(33), 0.0, 1.0, OrderSide::Sell, "3")); // non monotonic, price
+
+        let issues = auditor.finish(0, 0);
+        let kinds: Vec<&str> = issues.iter().map(|i| i.kind.as_str()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AuditIssue::DUPLICATE_TRADE,
+                AuditIssue::GAP,
+                AuditIssue::PRICE_OUTLIER,
+                AuditIssue::BAD_SIZE,
+                AuditIssue::UNKNOWN_SIDE,
+                AuditIssue::NON_MONOTONIC_ID,
+                AuditIssue::BAD_PRICE,
+            ]
+        );
+        assert_eq!(issues[0].id, "ws-2");
+        assert_eq!(issues[1].start_time, SEC(3));
+        assert_eq!(issues[1].end_time, SEC(30));
+        assert_eq!(issues[1].value, 27.0);
+        assert_eq!(issues[2].id, "5");
+    }
+
+    #[test]
+    fn test_audit_multi_maker_fill() {
+        let mut auditor = TradeAuditor::new(AuditConfig::default());
+
+        // 一つのTakerが同じサイズの３つのMakerと約定した（アーカイブでは連番のidになる）
+        auditor.push(&make_trade(SEC(1), 100.0, 0.5, OrderSide::Buy, "10"));
+        auditor.push(&make_trade(SEC(1), 100.0, 0.5, OrderSide::Buy, "11"));
+        auditor.push(&make_trade(SEC(1), 100.0, 0.5, OrderSide::Buy, "12"));
+        assert!(auditor.issues.is_empty());
+
+        // 別のid体系で同じ約定が登録されている場合は重複
+        auditor.push(&make_trade(SEC(1), 100.0, 0.5, OrderSide::Buy, "ws-10"));
+        let issues = auditor.finish(0, 0);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, AuditIssue::DUPLICATE_TRADE);
+        assert_eq!(issues[0].id, "ws-10");
+    }
+
+    #[test]
+    fn test_audit_days() {
+        let mut auditor = TradeAuditor::new(AuditConfig::default());
+
+        // day1: 10件, day2: なし, day3: 2件, day4: 10件
+        for day in [1, 3, 4] {
+            let count = if day == 3 { 2 } else { 10 };
+            for i in 0..count {
+                let time = DAYS(day) + SEC(i * 60);
+                auditor.push(&make_trade(time, 100.0, 1.0, OrderSide::Buy, &(day * 100 + i).to_string()));
+            }
+        }
+
+        let issues = auditor.finish(DAYS(1), DAYS(5));
+        let days: Vec<(&str, MicroSec, f64)> = issues
+            .iter()
+            .filter(|i| i.kind != AuditIssue::GAP)
+            .map(|i| (i.kind.as_str(), i.start_time, i.value))
+            .collect();
+        assert_eq!(
+            days,
+            vec![(AuditIssue::MISSING_DAY, DAYS(2), 0.0), (AuditIssue::SHORT_DAY, DAYS(3), 2.0)]
+        );
+    }
+}
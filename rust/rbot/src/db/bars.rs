@@ -0,0 +1,258 @@
+use polars::prelude::DataFrame;
+use polars::prelude::NamedFrom;
+use polars::prelude::Series;
+
+use crate::common::order::{OrderSide, Trade};
+use crate::common::time::MicroSec;
+use crate::db::df::KEY;
+
+/// 約定の流れから作る足の種類（時間足以外）
+///   Tick: N約定ごと
+///   Volume: 出来高(base通貨)がNごと
+///   Dollar: 売買代金(quote通貨 = price * size)がNごと
+///   TickImbalance: 売買の約定数の差(買い+1, 売り-1)の絶対値がNに達したら
+///   VolumeImbalance: 売買の出来高の差の絶対値がNに達したら
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarType {
+    Tick(f64),
+    Volume(f64),
+    Dollar(f64),
+    TickImbalance(f64),
+    VolumeImbalance(f64),
+}
+
+impl BarType {
+    pub fn from_str(kind: &str, threshold: f64) -> Result<Self, String> {
+        if threshold <= 0.0 {
+            return Err(format!("threshold must be positive {}", threshold));
+        }
+
+        match kind.to_lowercase().as_str() {
+            "tick" => Ok(BarType::Tick(threshold)),
+            "volume" => Ok(BarType::Volume(threshold)),
+            "dollar" => Ok(BarType::Dollar(threshold)),
+            "tick_imbalance" => Ok(BarType::TickImbalance(threshold)),
+            "volume_imbalance" => Ok(BarType::VolumeImbalance(threshold)),
+            _ => Err(format!(
+                "Unknown bar type {} / use tick, volume, dollar, tick_imbalance or volume_imbalance",
+                kind
+            )),
+        }
+    }
+
+    /// 約定ごとに積み上げる値（Unknownの約定は不均衡に数えない）
+    fn measure(&self, trade: &Trade) -> f64 {
+        let sign = match trade.order_side {
+            OrderSide::Buy => 1.0,
+            OrderSide::Sell => -1.0,
+            _ => 0.0,
+        };
+
+        match self {
+            BarType::Tick(_) => 1.0,
+            BarType::Volume(_) => trade.size,
+            BarType::Dollar(_) => trade.price * trade.size,
+            BarType::TickImbalance(_) => sign,
+            BarType::VolumeImbalance(_) => sign * trade.size,
+        }
+    }
+
+    fn threshold(&self) -> f64 {
+        match self {
+            BarType::Tick(t)
+            | BarType::Volume(t)
+            | BarType::Dollar(t)
+            | BarType::TickImbalance(t)
+            | BarType::VolumeImbalance(t) => *t,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoBar {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub vol: f64,
+    pub buy_vol: f64,
+    pub sell_vol: f64,
+    pub count: i64,
+    pub start_time: MicroSec,
+    pub end_time: MicroSec,
+}
+
+impl InfoBar {
+    fn from_trade(trade: &Trade) -> Self {
+        let mut bar = InfoBar {
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            vol: 0.0,
+            buy_vol: 0.0,
+            sell_vol: 0.0,
+            count: 0,
+            start_time: trade.time,
+            end_time: trade.time,
+        };
+        bar.append(trade);
+
+        return bar;
+    }
+
+    fn append(&mut self, trade: &Trade) {
+        if self.high < trade.price {
+            self.high = trade.price;
+        }
+        if trade.price < self.low {
+            self.low = trade.price;
+        }
+        self.close = trade.price;
+        self.vol += trade.size;
+        match trade.order_side {
+            OrderSide::Buy => self.buy_vol += trade.size,
+            OrderSide::Sell => self.sell_vol += trade.size,
+            _ => {}
+        }
+        self.count += 1;
+        self.end_time = trade.time;
+    }
+}
+
+/// 時刻順の約定を受け取り、BarTypeの条件で足を区切る。
+/// 閾値に達した約定までを１本の足とする（閾値を超えた分は次の足に繰り越さない）
+#[derive(Debug)]
+pub struct BarBuilder {
+    bar_type: BarType,
+    current: Option<InfoBar>,
+    accumulated: f64,
+    bars: Vec<InfoBar>,
+}
+
+impl BarBuilder {
+    pub fn new(bar_type: BarType) -> Self {
+        return BarBuilder {
+            bar_type,
+            current: None,
+            accumulated: 0.0,
+            bars: vec![],
+        };
+    }
+
+    pub fn push(&mut self, trade: &Trade) {
+        match self.current.as_mut() {
+            Some(bar) => bar.append(trade),
+            None => self.current = Some(InfoBar::from_trade(trade)),
+        }
+
+        self.accumulated += self.bar_type.measure(trade);
+
+        if self.bar_type.threshold() <= self.accumulated.abs() {
+            self.bars.push(self.current.take().unwrap());
+            self.accumulated = 0.0;
+        }
+    }
+
+    /// 未確定の足も含めて返す
+    pub fn finish(mut self) -> Vec<InfoBar> {
+        if let Some(bar) = self.current.take() {
+            self.bars.push(bar);
+        }
+
+        return self.bars;
+    }
+}
+
+/// ohlcv_dfと同じカラムのDataFrameへ変換する
+///   time_stamp: 足の開始時刻
+///   order_side: 買いの出来高が売り以上ならtrue(Buy)
+pub fn info_bars_to_df(bars: &[InfoBar]) -> DataFrame {
+    let time_stamp = Series::new(KEY::time_stamp, bars.iter().map(|b| b.start_time).collect::<Vec<MicroSec>>());
+    let order_side = Series::new(
+        KEY::order_side,
+        bars.iter().map(|b| b.sell_vol <= b.buy_vol).collect::<Vec<bool>>(),
+    );
+    let open = Series::new(KEY::open, bars.iter().map(|b| b.open).collect::<Vec<f64>>());
+    let high = Series::new(KEY::high, bars.iter().map(|b| b.high).collect::<Vec<f64>>());
+    let low = Series::new(KEY::low, bars.iter().map(|b| b.low).collect::<Vec<f64>>());
+    let close = Series::new(KEY::close, bars.iter().map(|b| b.close).collect::<Vec<f64>>());
+    let vol = Series::new(KEY::vol, bars.iter().map(|b| b.vol).collect::<Vec<f64>>());
+    let count = Series::new(KEY::count, bars.iter().map(|b| b.count).collect::<Vec<i64>>());
+    let start_time = Series::new(KEY::start_time, bars.iter().map(|b| b.start_time).collect::<Vec<MicroSec>>());
+    let end_time = Series::new(KEY::end_time, bars.iter().map(|b| b.end_time).collect::<Vec<MicroSec>>());
+
+    return DataFrame::new(vec![
+        time_stamp, order_side, open, high, low, close, vol, count, start_time, end_time,
+    ])
+    .unwrap();
+}
+
+#[cfg(test)]
+mod test_bars {
+    use super::*;
+
+    fn make_trades() -> Vec<Trade> {
+        vec![
+            Trade::new(1, OrderSide::Buy, 100.0, 1.0, "1".to_string()),
+            Trade::new(2, OrderSide::Buy, 101.0, 2.0, "2".to_string()),
+            Trade::new(3, OrderSide::Sell, 99.0, 1.0, "3".to_string()),
+            Trade::new(4, OrderSide::Buy, 102.0, 3.0, "4".to_string()),
+            Trade::new(5, OrderSide::Buy, 103.0, 1.0, "5".to_string()),
+        ]
+    }
+
+    fn build(bar_type: BarType) -> Vec<InfoBar> {
+        let mut builder = BarBuilder::new(bar_type);
+        for trade in make_trades() {
+            builder.push(&trade);
+        }
+
+        builder.finish()
+    }
+
+    #[test]
+    fn test_bar_type_from_str() {
+        assert_eq!(BarType::from_str("tick", 10.0), Ok(BarType::Tick(10.0)));
+        assert_eq!(BarType::from_str("VOLUME_IMBALANCE", 5.0), Ok(BarType::VolumeImbalance(5.0)));
+        assert!(BarType::from_str("tick", 0.0).is_err());
+        assert!(BarType::from_str("time", 1.0).is_err());
+    }
+
+    #[test]
+    fn test_tick_bars() {
+        let bars = build(BarType::Tick(2.0));
+        assert_eq!(bars.len(), 3);
+        assert_eq!((bars[0].open, bars[0].close, bars[0].count), (100.0, 101.0, 2));
+        assert_eq!((bars[1].high, bars[1].low, bars[1].vol), (102.0, 99.0, 4.0));
+        assert_eq!(bars[2].count, 1); // 未確定
+    }
+
+    #[test]
+    fn test_volume_and_dollar_bars() {
+        let bars = build(BarType::Volume(3.0));
+        assert_eq!(bars.iter().map(|b| b.count).collect::<Vec<i64>>(), vec![2, 2, 1]);
+
+        // 100 + 202 = 302, 99 + 306 = 405, 103
+        let bars = build(BarType::Dollar(300.0));
+        assert_eq!(bars.iter().map(|b| b.count).collect::<Vec<i64>>(), vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn test_imbalance_bars() {
+        // +1 +1 | -1 +1 +1
+        let bars = build(BarType::TickImbalance(2.0));
+        assert_eq!(bars.iter().map(|b| b.count).collect::<Vec<i64>>(), vec![2, 3]);
+
+        // +1 +2 | -1 +3 | +1
+        let bars = build(BarType::VolumeImbalance(2.0));
+        assert_eq!(bars.iter().map(|b| b.count).collect::<Vec<i64>>(), vec![2, 2, 1]);
+        assert_eq!((bars[1].buy_vol, bars[1].sell_vol), (3.0, 1.0));
+    }
+
+    #[test]
+    fn test_info_bars_to_df() {
+        let df = info_bars_to_df(&build(BarType::Tick(2.0)));
+        assert_eq!(df.shape(), (3, 10));
+    }
+}
@@ -0,0 +1,568 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use serde_derive::Deserialize;
+use zip::ZipArchive;
+
+use crate::common::order::OrderSide;
+use crate::common::time::MicroSec;
+
+/// 板の更新（１価格１レコード）
+///   snapshot: trueの場合、同じ時刻のレコードで板全体を置き換える。falseは差分更新。
+///   side: Buyは買い板(bid)、Sellは売り板(ask)
+///   size: 0の場合はその価格の板を削除する。
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookUpdate {
+    pub time: MicroSec,
+    pub snapshot: bool,
+    pub side: OrderSide,
+    pub price: f64,
+    pub size: f64,
+}
+
+impl BookUpdate {
+    pub fn new(time: MicroSec, snapshot: bool, side: OrderSide, price: f64, size: f64) -> Self {
+        return BookUpdate {
+            time,
+            snapshot,
+            side,
+            price,
+            size,
+        };
+    }
+}
+
+/// オンメモリの板（L2）。BookUpdateを時刻順に適用して再生する。
+#[derive(Debug, Clone)]
+pub struct OrderBook {
+    bids: Vec<(f64, f64)>, // (価格, サイズ) 価格の高い順
+    asks: Vec<(f64, f64)>, // (価格, サイズ) 価格の安い順
+    snapshot_time: MicroSec,
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        return OrderBook {
+            bids: vec![],
+            asks: vec![],
+            snapshot_time: 0,
+        };
+    }
+
+    pub fn apply(&mut self, update: &BookUpdate) {
+        // 新しいスナップショットの最初のレコードで板をクリアする
+        if update.snapshot && update.time != self.snapshot_time {
+            self.bids.clear();
+            self.asks.clear();
+            self.snapshot_time = update.time;
+        }
+
+        let (levels, descending) = match update.side {
+            OrderSide::Buy => (&mut self.bids, true),
+            OrderSide::Sell => (&mut self.asks, false),
+            _ => {
+                log::warn!("unknown side {:?}", update);
+                return;
+            }
+        };
+
+        let position = levels.binary_search_by(|(price, _size)| {
+            let order = if descending {
+                update.price.partial_cmp(price)
+            } else {
+                price.partial_cmp(&update.price)
+            };
+
+            order.unwrap_or(Ordering::Equal)
+        });
+
+        match position {
+            Ok(i) => {
+                if update.size == 0.0 {
+                    levels.remove(i);
+                } else {
+                    levels[i].1 = update.size;
+                }
+            }
+            Err(i) => {
+                if update.size != 0.0 {
+                    levels.insert(i, (update.price, update.size));
+                }
+            }
+        }
+    }
+
+    /// 両側の板があるか（DummySessionは板がある場合のみ板の価格を使う）
+    pub fn is_ready(&self) -> bool {
+        return self.bids.is_empty() == false && self.asks.is_empty() == false;
+    }
+
+    pub fn best_bid(&self) -> Option<f64> {
+        return self.bids.first().map(|(price, _size)| *price);
+    }
+
+    pub fn best_ask(&self) -> Option<f64> {
+        return self.asks.first().map(|(price, _size)| *price);
+    }
+
+    /// sideの板の先頭からdepth件（depth=0は全件）
+    pub fn depth(&self, side: OrderSide, depth: usize) -> Vec<(f64, f64)> {
+        let levels = match side {
+            OrderSide::Buy => &self.bids,
+            _ => &self.asks,
+        };
+
+        let depth = if depth == 0 { levels.len() } else { depth.min(levels.len()) };
+
+        return levels[..depth].to_vec();
+    }
+
+    /// sideの板の価格priceに並んでいるサイズ
+    pub fn size_at(&self, side: OrderSide, price: f64) -> f64 {
+        let levels = match side {
+            OrderSide::Buy => &self.bids,
+            _ => &self.asks,
+        };
+
+        return levels
+            .iter()
+            .find(|(p, _size)| *p == price)
+            .map_or(0.0, |(_p, size)| *size);
+    }
+
+    /// Takerとしてsizeを約定させた場合の平均価格（買いは売り板、売りは買い板を先頭から消化する）
+    /// 板が足りない場合、残りは最後の価格で約定したとする。板がない場合はNone
+    pub fn sweep_price(&self, taker_side: OrderSide, size: f64) -> Option<f64> {
+        let levels = match taker_side {
+            OrderSide::Buy => &self.asks,
+            OrderSide::Sell => &self.bids,
+            _ => return None,
+        };
+
+        let (last_price, _size) = levels.last()?;
+
+        if size <= 0.0 {
+            return levels.first().map(|(price, _size)| *price);
+        }
+
+        let mut remain = size;
+        let mut value = 0.0;
+
+        for (price, level_size) in levels {
+            let exec_size = remain.min(*level_size);
+            value += price * exec_size;
+            remain -= exec_size;
+
+            if remain <= 0.0 {
+                break;
+            }
+        }
+
+        if 0.0 < remain {
+            value += last_price * remain;
+        }
+
+        return Some(value / size);
+    }
+
+    /// Takerとしてhome通貨単位のvalue（価格×数量）を約定させた場合の平均価格（DummySessionのオーダーサイズ用）
+    /// 板の数量はforeign通貨単位なので、各価格の板は価格×数量の分だけ消化する。
+    /// 板が足りない場合、残りは最後の価格で約定したとする。板がない場合はNone
+    pub fn sweep_value_price(&self, taker_side: OrderSide, value: f64) -> Option<f64> {
+        let levels = match taker_side {
+            OrderSide::Buy => &self.asks,
+            OrderSide::Sell => &self.bids,
+            _ => return None,
+        };
+
+        let (last_price, _size) = levels.last()?;
+
+        if value <= 0.0 {
+            return levels.first().map(|(price, _size)| *price);
+        }
+
+        let mut remain = value;
+        let mut size = 0.0;
+
+        for (price, level_size) in levels {
+            let exec_value = remain.min(price * level_size);
+            size += exec_value / price;
+            remain -= exec_value;
+
+            if remain <= 0.0 {
+                break;
+            }
+        }
+
+        if 0.0 < remain {
+            size += remain / last_price;
+        }
+
+        return Some(value / size);
+    }
+}
+
+/// Bybitの板アーカイブ(ob500.data)・WebSocketのメッセージ
+/// {"topic":"orderbook.500.BTCUSDT","type":"snapshot","ts":1672304486868,"data":{"s":"BTCUSDT","b":[["16493.50","0.006"]],"a":[["16611.00","0.029"]],"u":18521288,"seq":7961638724}}
+#[derive(Debug, Deserialize)]
+struct BybitBookMessage {
+    topic: String,
+    #[serde(rename = "type")]
+    message_type: String,
+    ts: i64,
+    data: BybitBookData,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopicMessage {
+    topic: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BybitBookData {
+    #[serde(default)]
+    b: Vec<(String, String)>,
+    #[serde(default)]
+    a: Vec<(String, String)>,
+    u: Option<i64>,
+}
+
+/// Binanceの差分(depthUpdate)と、時刻付きのスナップショット(futuresの/depth)
+/// {"e":"depthUpdate","E":123456789,"s":"BNBBTC","U":157,"u":160,"b":[["0.0024","10"]],"a":[["0.0026","100"]]}
+/// {"lastUpdateId":1027024,"E":1589436922972,"T":1589436922959,"bids":[["4.00000000","431.00000000"]],"asks":[["4.00000200","12.00000000"]]}
+/// futuresの差分には前の差分の最終ID(pu)がある。
+#[derive(Debug, Deserialize)]
+struct BinanceDepthMessage {
+    e: Option<String>,
+    #[serde(rename = "E")]
+    event_time: Option<i64>,
+    #[serde(rename = "U")]
+    first_update_id: Option<i64>,
+    #[serde(rename = "u")]
+    final_update_id: Option<i64>,
+    pu: Option<i64>,
+    #[serde(rename = "lastUpdateId")]
+    last_update_id: Option<i64>,
+    #[serde(default)]
+    b: Vec<(String, String)>,
+    #[serde(default)]
+    a: Vec<(String, String)>,
+    #[serde(default)]
+    bids: Vec<(String, String)>,
+    #[serde(default)]
+    asks: Vec<(String, String)>,
+}
+
+fn levels_to_updates(
+    time: MicroSec,
+    snapshot: bool,
+    bids: &[(String, String)],
+    asks: &[(String, String)],
+) -> Vec<BookUpdate> {
+    let bid_updates = bids.iter().map(|level| (OrderSide::Buy, level));
+    let ask_updates = asks.iter().map(|level| (OrderSide::Sell, level));
+
+    return bid_updates
+        .chain(ask_updates)
+        .filter_map(|(side, (price, size))| {
+            let price = price.parse::<f64>().ok()?;
+            let size = size.parse::<f64>().ok()?;
+
+            Some(BookUpdate::new(time, snapshot, side, price, size))
+        })
+        .collect();
+}
+
+/// Binanceのdata.binance.visionにあるbookDepth(CSV)のヘッダー
+/// 価格帯(percentage)ごとに集計した数量で、価格ごとの板ではないので読み込めない。
+const BINANCE_BOOK_DEPTH_HEADER: &str = "timestamp,percentage,depth,notional";
+
+/// 板のメッセージ（JSON１行）をBookUpdateへ変換する。
+/// 差分の更新ID（BinanceはU/u/pu、Bybitはu）が連続しない場合は、欠けた期間としてgapsへ記録する
+/// （次のスナップショットまで板が正しくない）。
+#[derive(Debug, Default)]
+pub struct BookParser {
+    last_update_id: Option<i64>,
+    pub gaps: Vec<(MicroSec, i64, i64)>, // (時刻, 前の最終ID, 次の最初のID)
+}
+
+impl BookParser {
+    pub fn new() -> Self {
+        return BookParser::default();
+    }
+
+    /// 板以外のメッセージ（約定など）は空。読めない行はエラー。
+    pub fn parse_line(&mut self, line: &str) -> Result<Vec<BookUpdate>, String> {
+        let line = line.trim();
+
+        if line.is_empty() {
+            return Ok(vec![]);
+        }
+
+        if line.starts_with(BINANCE_BOOK_DEPTH_HEADER) {
+            return Err(format!(
+                "Binance bookDepth CSV ({}) is aggregated by percentage and cannot be replayed as an order book / use depth updates",
+                BINANCE_BOOK_DEPTH_HEADER
+            ));
+        }
+
+        // Bybitの板以外のトピック（約定など）
+        if let Ok(message) = serde_json::from_str::<TopicMessage>(line) {
+            if message.topic.starts_with("orderbook") == false {
+                return Ok(vec![]);
+            }
+        }
+
+        if let Ok(message) = serde_json::from_str::<BybitBookMessage>(line) {
+            let time = message.ts * 1_000;
+            let snapshot = message.message_type == "snapshot";
+
+            if let Some(u) = message.data.u {
+                if snapshot {
+                    self.last_update_id = Some(u);
+                } else {
+                    self.check_sequence(time, u, None);
+                }
+            }
+
+            return Ok(levels_to_updates(time, snapshot, &message.data.b, &message.data.a));
+        }
+
+        if let Some(message) = serde_json::from_str::<BinanceDepthMessage>(line)
+            .ok()
+            .filter(|m| m.e.is_some() || m.last_update_id.is_some())
+        {
+            let time = match message.event_time {
+                Some(t) => t * 1_000,
+                None => return Err(format!("book snapshot without event time (E) / {}", line)),
+            };
+
+            return match message.e.as_deref() {
+                Some("depthUpdate") => {
+                    if let (Some(first), Some(last)) = (message.first_update_id, message.final_update_id) {
+                        self.check_sequence(time, first, message.pu);
+                        self.last_update_id = Some(last);
+                    }
+
+                    Ok(levels_to_updates(time, false, &message.b, &message.a))
+                }
+                None => {
+                    self.last_update_id = message.last_update_id;
+
+                    Ok(levels_to_updates(time, true, &message.bids, &message.asks))
+                }
+                _ => Ok(vec![]),
+            };
+        }
+
+        return Err(format!("unknown book line / {}", line));
+    }
+
+    /// previous(futuresのpu)がある場合は前の最終IDと一致すること、ない場合はfirstが前の最終ID+1以下であることを確認する。
+    fn check_sequence(&mut self, time: MicroSec, first: i64, previous: Option<i64>) {
+        if let Some(last) = self.last_update_id {
+            let continuous = match previous {
+                Some(pu) => pu == last,
+                None => first <= last + 1,
+            };
+
+            if continuous == false {
+                log::warn!("book update gap at {} / last update id {} next {}", time, last, first);
+                self.gaps.push((time, last, first));
+            }
+        }
+
+        self.last_update_id = Some(first);
+    }
+}
+
+/// 板のファイル（JSON Lines。.zipの場合は最初のファイル、.gzは展開して読む）を読み、
+/// chunk_rows件ごとにfへ渡す。読めない行がある場合はエラー（行番号を含む）。
+/// 更新IDが連続しない箇所はログへ出力する。
+pub fn read_book_file<F>(path: &Path, chunk_rows: usize, mut f: F) -> Result<i64, String>
+where
+    F: FnMut(Vec<BookUpdate>) -> Result<(), String>,
+{
+    let file = File::open(path).map_err(|e| format!("{:?} {}", path, e))?;
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+
+    let reader: Box<dyn Read> = match extension.as_str() {
+        "zip" => {
+            let mut archive = ZipArchive::new(file).map_err(|e| e.to_string())?;
+            let mut data: Vec<u8> = vec![];
+            archive
+                .by_index(0)
+                .map_err(|e| e.to_string())?
+                .read_to_end(&mut data)
+                .map_err(|e| e.to_string())?;
+
+            Box::new(std::io::Cursor::new(data))
+        }
+        "gz" => Box::new(GzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+
+    let mut count = 0;
+    let mut buffer: Vec<BookUpdate> = vec![];
+    let mut parser = BookParser::new();
+
+    for (line_no, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+
+        let mut updates = parser
+            .parse_line(&line)
+            .map_err(|e| format!("{:?} line {}: {}", path, line_no + 1, e))?;
+        buffer.append(&mut updates);
+
+        if chunk_rows <= buffer.len() {
+            count += buffer.len() as i64;
+            f(std::mem::take(&mut buffer))?;
+        }
+    }
+
+    if buffer.is_empty() == false {
+        count += buffer.len() as i64;
+        f(buffer)?;
+    }
+
+    if parser.gaps.is_empty() == false {
+        log::warn!("{:?} has {} book update gaps", path, parser.gaps.len());
+    }
+
+    return Ok(count);
+}
+
+#[cfg(test)]
+mod test_book {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_order_book() {
+        let mut book = OrderBook::new();
+        assert!(book.is_ready() == false);
+
+        book.apply(&BookUpdate::new(1, true, OrderSide::Buy, 99.0, 1.0));
+        book.apply(&BookUpdate::new(1, true, OrderSide::Buy, 100.0, 2.0));
+        book.apply(&BookUpdate::new(1, true, OrderSide::Sell, 102.0, 3.0));
+        book.apply(&BookUpdate::new(1, true, OrderSide::Sell, 101.0, 1.0));
+        assert!(book.is_ready());
+        assert_eq!(book.best_bid(), Some(100.0));
+        assert_eq!(book.best_ask(), Some(101.0));
+        assert_eq!(book.depth(OrderSide::Sell, 0), vec![(101.0, 1.0), (102.0, 3.0)]);
+
+        // 差分: 101.0を削除、99.5を追加、100.0を変更
+        book.apply(&BookUpdate::new(2, false, OrderSide::Sell, 101.0, 0.0));
+        book.apply(&BookUpdate::new(2, false, OrderSide::Buy, 99.5, 4.0));
+        book.apply(&BookUpdate::new(2, false, OrderSide::Buy, 100.0, 0.5));
+        assert_eq!(book.best_ask(), Some(102.0));
+        assert_eq!(book.depth(OrderSide::Buy, 2), vec![(100.0, 0.5), (99.5, 4.0)]);
+        assert_eq!(book.size_at(OrderSide::Buy, 99.5), 4.0);
+        assert_eq!(book.size_at(OrderSide::Buy, 98.0), 0.0);
+
+        // 売り板を消化する買い: 0.5@100.0 + 1.5@99.5
+        assert_eq!(book.sweep_price(OrderSide::Sell, 2.0), Some((0.5 * 100.0 + 1.5 * 99.5) / 2.0));
+        // 板が足りない分は最後の価格
+        assert_eq!(book.sweep_price(OrderSide::Buy, 5.0), Some(102.0));
+
+        // home通貨単位: 50(0.5@100.0) + 25(25/99.5@99.5)
+        assert_eq!(book.sweep_value_price(OrderSide::Sell, 75.0), Some(75.0 / (0.5 + 25.0 / 99.5)));
+        assert_eq!(book.sweep_value_price(OrderSide::Buy, 1_000.0), Some(102.0));
+
+        // 新しいスナップショットで置き換える
+        book.apply(&BookUpdate::new(3, true, OrderSide::Buy, 90.0, 1.0));
+        assert_eq!(book.depth(OrderSide::Buy, 0), vec![(90.0, 1.0)]);
+        assert!(book.is_ready() == false);
+    }
+
+    #[test]
+    fn test_parse_book_line() {
+        let mut parser = BookParser::new();
+
+        let updates = parser.parse_line(
+            r#"{"topic":"orderbook.500.BTCUSDT","type":"snapshot","ts":1000,"data":{"s":"BTCUSDT","b":[["100.5","1"]],"a":[["101.0","2"],["101.5","3"]],"u":1,"seq":1},"cts":999}"#,
+        ).unwrap();
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0], BookUpdate::new(1_000_000, true, OrderSide::Buy, 100.5, 1.0));
+        assert_eq!(updates[2], BookUpdate::new(1_000_000, true, OrderSide::Sell, 101.5, 3.0));
+
+        let updates = parser.parse_line(
+            r#"{"topic":"orderbook.500.BTCUSDT","type":"delta","ts":2000,"data":{"s":"BTCUSDT","b":[],"a":[["101.0","0"]],"u":2,"seq":2},"cts":1999}"#,
+        ).unwrap();
+        assert_eq!(updates, vec![BookUpdate::new(2_000_000, false, OrderSide::Sell, 101.0, 0.0)]);
+        assert!(parser.gaps.is_empty());
+
+        let updates = parser.parse_line(
+            r#"{"e":"depthUpdate","E":3000,"s":"BTCUSDT","U":157,"u":160,"b":[["100.0","10"]],"a":[]}"#,
+        ).unwrap();
+        assert_eq!(updates, vec![BookUpdate::new(3_000_000, false, OrderSide::Buy, 100.0, 10.0)]);
+
+        let updates = parser.parse_line(
+            r#"{"lastUpdateId":1027024,"E":4000,"T":3999,"bids":[["4.0","431.0"]],"asks":[["4.2","12.0"]]}"#,
+        ).unwrap();
+        assert_eq!(updates.len(), 2);
+        assert!(updates[1].snapshot);
+
+        // 板以外のトピックは空
+        assert!(parser.parse_line(r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":1000,"data":[]}"#).unwrap().is_empty());
+        assert!(parser.parse_line("").unwrap().is_empty());
+
+        // 時刻のないスナップショット、bookDepthのCSV、読めない行はエラー
+        assert!(parser.parse_line(r#"{"lastUpdateId":1027024,"bids":[["4.0","431.0"]],"asks":[]}"#).is_err());
+        assert!(parser.parse_line("timestamp,percentage,depth,notional").is_err());
+        assert!(parser.parse_line("2023-06-01 00:00:08,-5,1234.5,56789012.3").is_err());
+    }
+
+    #[test]
+    fn test_book_update_gap() {
+        // spot: Uが前のu+1以下なら連続
+        let mut parser = BookParser::new();
+        parser.parse_line(r#"{"e":"depthUpdate","E":1000,"s":"BTCUSDT","U":157,"u":160,"b":[],"a":[]}"#).unwrap();
+        parser.parse_line(r#"{"e":"depthUpdate","E":2000,"s":"BTCUSDT","U":161,"u":165,"b":[],"a":[]}"#).unwrap();
+        assert!(parser.gaps.is_empty());
+
+        parser.parse_line(r#"{"e":"depthUpdate","E":3000,"s":"BTCUSDT","U":170,"u":172,"b":[],"a":[]}"#).unwrap();
+        assert_eq!(parser.gaps, vec![(3_000_000, 165, 170)]);
+
+        // futures: puが前のuと一致すること
+        let mut parser = BookParser::new();
+        parser.parse_line(r#"{"lastUpdateId":100,"E":1000,"T":999,"bids":[],"asks":[]}"#).unwrap();
+        parser.parse_line(r#"{"e":"depthUpdate","E":2000,"s":"BTCUSDT","U":95,"u":110,"pu":100,"b":[],"a":[]}"#).unwrap();
+        parser.parse_line(r#"{"e":"depthUpdate","E":3000,"s":"BTCUSDT","U":111,"u":120,"pu":109,"b":[],"a":[]}"#).unwrap();
+        assert_eq!(parser.gaps, vec![(3_000_000, 110, 111)]);
+
+        // Bybit: スナップショットでリセットし、差分のuは1ずつ増える
+        let mut parser = BookParser::new();
+        parser.parse_line(r#"{"topic":"orderbook.500.BTCUSDT","type":"snapshot","ts":1000,"data":{"b":[],"a":[],"u":10}}"#).unwrap();
+        parser.parse_line(r#"{"topic":"orderbook.500.BTCUSDT","type":"delta","ts":2000,"data":{"b":[],"a":[],"u":12}}"#).unwrap();
+        assert_eq!(parser.gaps, vec![(2_000_000, 10, 12)]);
+    }
+
+    #[test]
+    fn test_read_book_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("BTCUSDT_ob500.data.gz");
+
+        {
+            let file = File::create(&path).unwrap();
+            let mut gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+            writeln!(gz, r#"{{"topic":"orderbook.500.BTCUSDT","type":"snapshot","ts":1000,"data":{{"s":"BTCUSDT","b":[["100","1"],["99","1"]],"a":[["101","1"]]}}}}"#).unwrap();
+            writeln!(gz, r#"{{"topic":"orderbook.500.BTCUSDT","type":"delta","ts":2000,"data":{{"s":"BTCUSDT","b":[["100","0"]],"a":[]}}}}"#).unwrap();
+            gz.finish().unwrap();
+        }
+
+        let mut chunks: Vec<usize> = vec![];
+        let count = read_book_file(&path, 2, |updates| {
+            chunks.push(updates.len());
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(count, 4);
+        assert_eq!(chunks, vec![3, 1]);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::common::order::Trade;
+use std::fs::File;
+use std::path::Path;
+
+use crate::common::order::{OrderSide, Trade};
 use crate::common::time::{MicroSec, SEC, time_string};
 use polars::prelude::ChunkCompare;
 use polars::prelude::DataFrame;
@@ -8,6 +11,7 @@ use polars::prelude::NamedFrom;
 use polars::prelude::Series;
 use polars::prelude::BooleanType;
 use polars::prelude::ChunkedArray;
+use polars::prelude::{IpcReader, IpcWriter, ParquetReader, ParquetWriter, PolarsResult, SerReader, SerWriter};
 use polars_core::prelude::SortOptions;
 use polars_lazy::prelude::IntoLazy;
 use polars_lazy::prelude::col;
@@ -33,14 +37,12 @@ pub mod KEY {
     pub const low: &str = "low";
     pub const close: &str = "close";
     pub const vol: &str = "vol";
-    #[allow(unused)]    
     pub const sell_vol: &str = "sell_vol";
-    #[allow(unused)]    
     pub const sell_count: &str = "sell_count";
-    #[allow(unused)]    
     pub const buy_vol: &str = "buy_vol";
-    #[allow(unused)]    
     pub const buy_count: &str = "buy_count";
+    pub const vwap: &str = "vwap";
+    pub const delta: &str = "delta"; // buy_vol - sell_vol
     pub const start_time: &str = "start_time";
     pub const end_time: &str = "end_time";
     pub const count: &str = "count";
@@ -342,7 +344,6 @@ impl TradeBuffer {
         };
     }
 
-    #[allow(unused)]
     pub fn clear(&mut self) {
         self.time_stamp.clear();
         self.price.clear();
@@ -398,4 +399,93 @@ pub fn make_empty_ohlcv() -> DataFrame {
     .unwrap();
 
     return df;
-}
\ No newline at end of file
+}
+
+/// TradeTableのエクスポート形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Parquet,
+    Ipc,
+}
+
+impl ExportFormat {
+    pub fn from_str(format: &str) -> Result<Self, String> {
+        match format.to_lowercase().as_str() {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "ipc" | "arrow" | "feather" => Ok(ExportFormat::Ipc),
+            _ => Err(format!("Unknown export format {} / use parquet or ipc", format)),
+        }
+    }
+
+    /// ファイル名の拡張子から形式を判定する。
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+
+        return ExportFormat::from_str(ext);
+    }
+
+    pub fn extension(&self) -> &str {
+        match self {
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Ipc => "ipc",
+        }
+    }
+}
+
+/// エクスポート用のDataFrame（SQLiteのtradesテーブルと同じ項目）
+pub fn trades_to_export_df(trades: &[Trade]) -> DataFrame {
+    let time_stamp = Series::new(KEY::time_stamp, trades.iter().map(|t| t.time).collect::<Vec<MicroSec>>());
+    let order_side = Series::new(KEY::order_side, trades.iter().map(|t| t.order_side.to_string()).collect::<Vec<String>>());
+    let price = Series::new(KEY::price, trades.iter().map(|t| t.price).collect::<Vec<f64>>());
+    let size = Series::new(KEY::size, trades.iter().map(|t| t.size).collect::<Vec<f64>>());
+    let id = Series::new(KEY::id, trades.iter().map(|t| t.id.clone()).collect::<Vec<String>>());
+
+    return DataFrame::new(vec![time_stamp, order_side, price, size, id]).unwrap();
+}
+
+/// エクスポートしたDataFrameをTradeへ戻す。
+pub fn export_df_to_trades(df: &DataFrame) -> PolarsResult<Vec<Trade>> {
+    let time_stamp = df.column(KEY::time_stamp)?.i64()?;
+    let order_side = df.column(KEY::order_side)?.utf8()?;
+    let price = df.column(KEY::price)?.f64()?;
+    let size = df.column(KEY::size)?.f64()?;
+    let id = df.column(KEY::id)?.utf8()?;
+
+    let mut trades: Vec<Trade> = Vec::with_capacity(df.height());
+
+    for i in 0..df.height() {
+        trades.push(Trade::new(
+            time_stamp.get(i).unwrap_or_default(),
+            OrderSide::from_str(order_side.get(i).unwrap_or_default()),
+            price.get(i).unwrap_or_default(),
+            size.get(i).unwrap_or_default(),
+            id.get(i).unwrap_or_default().to_string(),
+        ));
+    }
+
+    return Ok(trades);
+}
+
+pub fn write_df_file(df: &mut DataFrame, path: &Path, format: ExportFormat) -> PolarsResult<()> {
+    let file = File::create(path)?;
+
+    match format {
+        ExportFormat::Parquet => {
+            ParquetWriter::new(file).finish(df)?;
+        }
+        ExportFormat::Ipc => {
+            IpcWriter::new(file).finish(df)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_df_file(path: &Path, format: ExportFormat) -> PolarsResult<DataFrame> {
+    let file = File::open(path)?;
+
+    match format {
+        ExportFormat::Parquet => ParquetReader::new(file).finish(),
+        ExportFormat::Ipc => IpcReader::new(file).finish(),
+    }
+}
@@ -1,27 +1,19 @@
 // use crate::exchange::ftx::FtxMarket;
 
-use crate::exchange::binance::BinanceMarket;
+use crate::exchange::market::open_market;
 use self::sqlite::TradeTable;
 
 pub mod sqlite;
 pub mod df;
+pub mod audit;
+pub mod ohlcv;
+pub mod bars;
+pub mod book;
+pub mod stream;
 
-pub fn open_db(exchange_name: &str, market_name: &str) -> TradeTable {
-    match exchange_name.to_uppercase().as_str() {
-        /*
-        "FTX" => {
-            let ftx = FtxMarket::new(market_name, true);
+/// 取引所コード(BN, BN-UM, BN-CM, BN-AGG, BN-UM-AGG, BN-CM-AGG, BB)とマーケット名からDBを開く。
+pub fn open_db(exchange_name: &str, market_name: &str) -> Result<TradeTable, String> {
+    let market = open_market(exchange_name, market_name, true)?;
 
-            return ftx.db;
-        }
-        */
-        "BN" => {
-            let binance = BinanceMarket::new(market_name, true);
-
-            return binance.db;
-        }
-        _ => {
-            panic!("Unknown exchange {}", exchange_name);
-        }
-    }
-}
\ No newline at end of file
+    return Ok(market.into_db());
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+
+use polars::prelude::DataFrame;
+use polars::prelude::NamedFrom;
+use polars::prelude::Series;
+
+use crate::common::order::{OrderSide, Trade};
+use crate::common::time::{MicroSec, FLOOR};
+use crate::db::df::KEY;
+
+/// DBのorder_sideカラムの値（Sell=0, Buy=1, Unknown=2）
+pub fn order_side_code(order_side: OrderSide) -> i64 {
+    match order_side {
+        OrderSide::Sell => 0,
+        OrderSide::Buy => 1,
+        OrderSide::Unknown => 2,
+    }
+}
+
+pub fn order_side_from_code(code: i64) -> OrderSide {
+    match code {
+        0 => OrderSide::Sell,
+        1 => OrderSide::Buy,
+        _ => OrderSide::Unknown,
+    }
+}
+
+/// DBに保存するOHLCVの１本（order_sideごとに１行。ohlcv_dfと同じレイアウト）
+#[derive(Debug, Clone, PartialEq)]
+pub struct OhlcvBar {
+    pub time_stamp: MicroSec,
+    pub order_side: OrderSide,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub vol: f64,
+    pub value: f64, // 売買代金 sum(price * size)
+    pub count: i64,
+    pub start_time: MicroSec,
+    pub end_time: MicroSec,
+}
+
+impl OhlcvBar {
+    fn from_trade(time_stamp: MicroSec, trade: &Trade) -> Self {
+        return OhlcvBar {
+            time_stamp,
+            order_side: trade.order_side,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            vol: trade.size,
+            value: trade.price * trade.size,
+            count: 1,
+            start_time: trade.time,
+            end_time: trade.time,
+        };
+    }
+
+    /// 時刻順に追加する
+    fn append_trade(&mut self, trade: &Trade) {
+        if self.high < trade.price {
+            self.high = trade.price;
+        }
+        if trade.price < self.low {
+            self.low = trade.price;
+        }
+        self.close = trade.price;
+        self.vol += trade.size;
+        self.value += trade.price * trade.size;
+        self.count += 1;
+        self.end_time = trade.time;
+    }
+
+    /// 時刻順に追加する
+    fn append_bar(&mut self, bar: &OhlcvBar) {
+        if self.high < bar.high {
+            self.high = bar.high;
+        }
+        if bar.low < self.low {
+            self.low = bar.low;
+        }
+        self.close = bar.close;
+        self.vol += bar.vol;
+        self.value += bar.value;
+        self.count += bar.count;
+        self.end_time = bar.end_time;
+    }
+}
+
+/// 時刻順の約定からwindow_sec足を作る
+pub fn trades_to_bars(trades: &[Trade], window_sec: i64) -> Vec<OhlcvBar> {
+    let mut bars: BTreeMap<(MicroSec, i64), OhlcvBar> = BTreeMap::new();
+
+    for trade in trades {
+        let time_stamp = FLOOR(trade.time, window_sec);
+        let key = (time_stamp, order_side_code(trade.order_side));
+
+        match bars.get_mut(&key) {
+            Some(bar) => bar.append_trade(trade),
+            None => {
+                bars.insert(key, OhlcvBar::from_trade(time_stamp, trade));
+            }
+        }
+    }
+
+    return bars.into_values().collect();
+}
+
+/// 時刻順の短い足からwindow_sec足を作る
+pub fn bars_to_bars(bars: &[OhlcvBar], window_sec: i64) -> Vec<OhlcvBar> {
+    let mut result: BTreeMap<(MicroSec, i64), OhlcvBar> = BTreeMap::new();
+
+    for bar in bars {
+        let time_stamp = FLOOR(bar.time_stamp, window_sec);
+        let key = (time_stamp, order_side_code(bar.order_side));
+
+        match result.get_mut(&key) {
+            Some(b) => b.append_bar(bar),
+            None => {
+                let mut b = bar.clone();
+                b.time_stamp = time_stamp;
+                result.insert(key, b);
+            }
+        }
+    }
+
+    return result.into_values().collect();
+}
+
+/// ohlcv_from_ohlcv_dfで集計できるDataFrameへ変換する
+/// order_sideはBuyのみtrue（約定のDataFrame(TradeBuffer)と同じく、Unknownは売り側に集計される）
+pub fn bars_to_df(bars: &[OhlcvBar]) -> DataFrame {
+    let time_stamp = Series::new(KEY::time_stamp, bars.iter().map(|b| b.time_stamp).collect::<Vec<MicroSec>>());
+    let order_side = Series::new(KEY::order_side, bars.iter().map(|b| b.order_side.is_buy_side()).collect::<Vec<bool>>());
+    let open = Series::new(KEY::open, bars.iter().map(|b| b.open).collect::<Vec<f64>>());
+    let high = Series::new(KEY::high, bars.iter().map(|b| b.high).collect::<Vec<f64>>());
+    let low = Series::new(KEY::low, bars.iter().map(|b| b.low).collect::<Vec<f64>>());
+    let close = Series::new(KEY::close, bars.iter().map(|b| b.close).collect::<Vec<f64>>());
+    let vol = Series::new(KEY::vol, bars.iter().map(|b| b.vol).collect::<Vec<f64>>());
+    let count = Series::new(KEY::count, bars.iter().map(|b| b.count).collect::<Vec<i64>>());
+    let start_time = Series::new(KEY::start_time, bars.iter().map(|b| b.start_time).collect::<Vec<MicroSec>>());
+    let end_time = Series::new(KEY::end_time, bars.iter().map(|b| b.end_time).collect::<Vec<MicroSec>>());
+
+    return DataFrame::new(vec![
+        time_stamp, order_side, open, high, low, close, vol, count, start_time, end_time,
+    ])
+    .unwrap();
+}
+
+/// 売買を１行にまとめたOHLCV
+#[derive(Debug, Clone, PartialEq)]
+pub struct OhlcvvRow {
+    pub time_stamp: MicroSec,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub vol: f64,
+    pub value: f64,
+    pub buy_vol: f64,
+    pub sell_vol: f64,
+    pub buy_count: i64,
+    pub sell_count: i64,
+    pub count: i64,
+    pub start_time: MicroSec,
+    pub end_time: MicroSec,
+}
+
+impl OhlcvvRow {
+    fn new(time_stamp: MicroSec, bar: &OhlcvBar) -> Self {
+        let mut row = OhlcvvRow {
+            time_stamp,
+            open: bar.open,
+            high: bar.high,
+            low: bar.low,
+            close: bar.close,
+            vol: 0.0,
+            value: 0.0,
+            buy_vol: 0.0,
+            sell_vol: 0.0,
+            buy_count: 0,
+            sell_count: 0,
+            count: 0,
+            start_time: bar.start_time,
+            end_time: bar.end_time,
+        };
+        row.append_bar(bar);
+
+        return row;
+    }
+
+    /// 売りと買いの足は時刻が前後するので、start_time/end_timeで始値と終値を決める。
+    fn append_bar(&mut self, bar: &OhlcvBar) {
+        if bar.start_time < self.start_time {
+            self.start_time = bar.start_time;
+            self.open = bar.open;
+        }
+        if self.end_time <= bar.end_time {
+            self.end_time = bar.end_time;
+            self.close = bar.close;
+        }
+        if self.high < bar.high {
+            self.high = bar.high;
+        }
+        if bar.low < self.low {
+            self.low = bar.low;
+        }
+
+        self.vol += bar.vol;
+        self.value += bar.value;
+        self.count += bar.count;
+
+        // 売買方向が不明な約定はvol/countには含めるが、buy/sellには含めない（deltaに影響しない）
+        match bar.order_side {
+            OrderSide::Buy => {
+                self.buy_vol += bar.vol;
+                self.buy_count += bar.count;
+            }
+            OrderSide::Sell => {
+                self.sell_vol += bar.vol;
+                self.sell_count += bar.count;
+            }
+            OrderSide::Unknown => {}
+        }
+    }
+
+    pub fn vwap(&self) -> f64 {
+        if self.vol == 0.0 {
+            return 0.0;
+        }
+
+        return self.value / self.vol;
+    }
+
+    pub fn delta(&self) -> f64 {
+        return self.buy_vol - self.sell_vol;
+    }
+}
+
+/// order_sideごとの足からwindow_secごとに１行のOHLCVを作る
+pub fn bars_to_ohlcvv_rows(bars: &[OhlcvBar], window_sec: i64) -> Vec<OhlcvvRow> {
+    let mut rows: BTreeMap<MicroSec, OhlcvvRow> = BTreeMap::new();
+
+    for bar in bars {
+        let time_stamp = FLOOR(bar.time_stamp, window_sec);
+
+        match rows.get_mut(&time_stamp) {
+            Some(row) => row.append_bar(bar),
+            None => {
+                rows.insert(time_stamp, OhlcvvRow::new(time_stamp, bar));
+            }
+        }
+    }
+
+    return rows.into_values().collect();
+}
+
+pub fn ohlcvv_rows_to_df(rows: &[OhlcvvRow]) -> DataFrame {
+    let time_stamp = Series::new(KEY::time_stamp, rows.iter().map(|r| r.time_stamp).collect::<Vec<MicroSec>>());
+    let open = Series::new(KEY::open, rows.iter().map(|r| r.open).collect::<Vec<f64>>());
+    let high = Series::new(KEY::high, rows.iter().map(|r| r.high).collect::<Vec<f64>>());
+    let low = Series::new(KEY::low, rows.iter().map(|r| r.low).collect::<Vec<f64>>());
+    let close = Series::new(KEY::close, rows.iter().map(|r| r.close).collect::<Vec<f64>>());
+    let vol = Series::new(KEY::vol, rows.iter().map(|r| r.vol).collect::<Vec<f64>>());
+    let buy_vol = Series::new(KEY::buy_vol, rows.iter().map(|r| r.buy_vol).collect::<Vec<f64>>());
+    let sell_vol = Series::new(KEY::sell_vol, rows.iter().map(|r| r.sell_vol).collect::<Vec<f64>>());
+    let buy_count = Series::new(KEY::buy_count, rows.iter().map(|r| r.buy_count).collect::<Vec<i64>>());
+    let sell_count = Series::new(KEY::sell_count, rows.iter().map(|r| r.sell_count).collect::<Vec<i64>>());
+    let count = Series::new(KEY::count, rows.iter().map(|r| r.count).collect::<Vec<i64>>());
+    let vwap = Series::new(KEY::vwap, rows.iter().map(|r| r.vwap()).collect::<Vec<f64>>());
+    let delta = Series::new(KEY::delta, rows.iter().map(|r| r.delta()).collect::<Vec<f64>>());
+    let start_time = Series::new(KEY::start_time, rows.iter().map(|r| r.start_time).collect::<Vec<MicroSec>>());
+    let end_time = Series::new(KEY::end_time, rows.iter().map(|r| r.end_time).collect::<Vec<MicroSec>>());
+
+    return DataFrame::new(vec![
+        time_stamp, open, high, low, close, vol, buy_vol, sell_vol, buy_count, sell_count, count, vwap, delta,
+        start_time, end_time,
+    ])
+    .unwrap();
+}
+
+#[cfg(test)]
+mod test_ohlcv {
+    use super::*;
+    use crate::common::order::OrderSide;
+    use crate::common::time::SEC;
+
+    fn make_trade(time: MicroSec, side: OrderSide, price: f64, size: f64) -> Trade {
+        Trade::new(time, side, price, size, time.to_string())
+    }
+
+    #[test]
+    fn test_trades_to_bars() {
+        let trades = vec![
+            make_trade(SEC(1), OrderSide::Buy, 100.0, 1.0),
+            make_trade(SEC(2), OrderSide::Sell, 99.0, 2.0),
+            make_trade(SEC(30), OrderSide::Buy, 102.0, 1.0),
+            make_trade(SEC(59), OrderSide::Buy, 101.0, 3.0),
+            make_trade(SEC(61), OrderSide::Buy, 103.0, 1.0),
+        ];
+
+        let bars = trades_to_bars(&trades, 60);
+        assert_eq!(bars.len(), 3);
+
+        // (0, Sell), (0, Buy), (60, Buy)の順
+        assert_eq!(bars[0].order_side, OrderSide::Sell);
+        assert_eq!(bars[0].vol, 2.0);
+
+        let buy = &bars[1];
+        assert_eq!(buy.time_stamp, 0);
+        assert_eq!((buy.open, buy.high, buy.low, buy.close), (100.0, 102.0, 100.0, 101.0));
+        assert_eq!(buy.vol, 5.0);
+        assert_eq!(buy.count, 3);
+        assert_eq!((buy.start_time, buy.end_time), (SEC(1), SEC(59)));
+
+        assert_eq!(bars[2].time_stamp, SEC(60));
+
+        // 1秒足から作った1分足は約定から作った1分足と同じ
+        let sec_bars = trades_to_bars(&trades, 1);
+        assert_eq!(sec_bars.len(), 5);
+        assert_eq!(bars_to_bars(&sec_bars, 60), bars);
+    }
+
+    #[test]
+    fn test_bars_to_ohlcvv_rows() {
+        let trades = vec![
+            make_trade(SEC(1), OrderSide::Sell, 100.0, 1.0),
+            make_trade(SEC(2), OrderSide::Buy, 101.0, 2.0),
+            make_trade(SEC(3), OrderSide::Sell, 98.0, 1.0),
+            make_trade(SEC(30), OrderSide::Buy, 102.0, 1.0),
+            make_trade(SEC(40), OrderSide::Sell, 99.0, 4.0),
+            make_trade(SEC(61), OrderSide::Buy, 103.0, 1.0),
+        ];
+
+        let rows = bars_to_ohlcvv_rows(&trades_to_bars(&trades, 1), 60);
+        assert_eq!(rows.len(), 2);
+
+        let row = &rows[0];
+        assert_eq!(row.time_stamp, 0);
+        assert_eq!((row.open, row.high, row.low, row.close), (100.0, 102.0, 98.0, 99.0));
+        assert_eq!((row.buy_vol, row.sell_vol, row.buy_count, row.sell_count), (3.0, 6.0, 2, 3));
+        assert_eq!(row.delta(), -3.0);
+        // (100 + 202 + 98 + 102 + 396) / 9
+        assert_eq!(row.vwap(), 898.0 / 9.0);
+        assert_eq!((row.start_time, row.end_time), (SEC(1), SEC(40)));
+
+        assert_eq!(rows[1].vwap(), 103.0);
+
+        let df = ohlcvv_rows_to_df(&rows);
+        assert_eq!(df.shape(), (2, 15));
+    }
+
+    #[test]
+    fn test_unknown_side() {
+        let trades = vec![
+            make_trade(SEC(1), OrderSide::Buy, 100.0, 1.0),
+            make_trade(SEC(2), OrderSide::Sell, 99.0, 2.0),
+            make_trade(SEC(3), OrderSide::Unknown, 98.0, 4.0),
+        ];
+
+        let bars = trades_to_bars(&trades, 60);
+        assert_eq!(bars.len(), 3);
+        assert_eq!(bars[2].order_side, OrderSide::Unknown);
+
+        let rows = bars_to_ohlcvv_rows(&bars, 60);
+        let row = &rows[0];
+        assert_eq!((row.vol, row.buy_vol, row.sell_vol), (7.0, 1.0, 2.0));
+        assert_eq!((row.buy_count, row.sell_count, row.count), (1, 1, 3));
+        assert_eq!(row.delta(), -1.0);
+        assert_eq!(row.close, 98.0);
+    }
+
+    #[test]
+    fn test_bars_to_df() {
+        let trades = vec![make_trade(SEC(1), OrderSide::Buy, 100.0, 1.0)];
+        let df = bars_to_df(&trades_to_bars(&trades, 1));
+
+        assert_eq!(df.shape(), (1, 10));
+    }
+}
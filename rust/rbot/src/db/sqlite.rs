@@ -1,24 +1,36 @@
 use crate::common::order::{TimeChunk, Trade};
-use crate::common::time::{time_string, MicroSec, CEIL, DAYS, FLOOR, MICRO_SECOND, NOW, SEC, FLOOR_DAY};
+use crate::common::time::{time_string, to_naive_datetime, MicroSec, CEIL, DAYS, FLOOR, MICRO_SECOND, NOW, SEC, FLOOR_DAY};
 use crate::OrderSide;
 use numpy::PyArray2;
 use numpy::IntoPyArray;
 use polars::prelude::DataFrame;
+use pyo3::exceptions::PyTypeError;
 use pyo3::{PyResult, Py, Python};
-use rusqlite::{params, params_from_iter, Connection, Error, Result, Statement};
+use rusqlite::{params, params_from_iter, Connection, Error, Result, Row, Statement};
 
 
+use super::audit::{AuditConfig, AuditIssue, TradeAuditor};
+use super::book::{read_book_file, BookUpdate, OrderBook};
+use super::bars::{info_bars_to_df, BarBuilder, BarType};
+use super::ohlcv::{
+    bars_to_bars, bars_to_df, bars_to_ohlcvv_rows, ohlcvv_rows_to_df, order_side_code, order_side_from_code, trades_to_bars,
+    OhlcvBar,
+};
 use super::df::{merge_df, ohlcv_from_ohlcv_df};
 use crate::db::df::{end_time_df, make_empty_ohlcv};
 use crate::db::df::ohlcv_df;
 use crate::db::df::select_df;
 use crate::db::df::start_time_df;
 use crate::db::df::TradeBuffer;
+use crate::db::stream::{trades_df_to_array, TradeIterator};
 
 use log::log_enabled;
 use log::Level::Debug;
 
 use crate::db::df::KEY;
+use crate::db::df::{export_df_to_trades, read_df_file, trades_to_export_df, write_df_file, ExportFormat};
+use std::collections::BTreeSet;
+use std::path::Path;
 use polars::prelude::Float64Type;
 
 /*
@@ -111,6 +123,196 @@ fn check_skip_time(trades: &Vec<Trade>) {
     }
 }
 
+/// 時間選択は左側は含み、右側は含まない。to_timeが0の場合は最後まで。
+fn select_trades_vec(conn: &Connection, from_time: MicroSec, to_time: MicroSec) -> Vec<Trade> {
+    let mut statement = conn
+        .prepare("select time_stamp, action, price, size, id from trades where $1 <= time_stamp and ($2 = 0 or time_stamp < $2) order by time_stamp")
+        .unwrap();
+    let mut trades: Vec<Trade> = vec![];
+
+    let iter = statement
+        .query_map(params![from_time, to_time], |row| {
+            let bs_str: String = row.get_unwrap(1);
+
+            Ok(Trade {
+                time: row.get_unwrap(0),
+                price: row.get_unwrap(2),
+                size: row.get_unwrap(3),
+                order_side: OrderSide::from_str(bs_str.as_str()),
+                id: row.get_unwrap(4),
+            })
+        })
+        .unwrap();
+
+    for trade in iter {
+        match trade {
+            Ok(t) => trades.push(t),
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    return trades;
+}
+
+fn select_bars(conn: &Connection, table: &str, from_time: MicroSec, to_time: MicroSec) -> Vec<OhlcvBar> {
+    let sql = format!(
+        "select time_stamp, order_side, open, high, low, close, vol, value, count, start_time, end_time from {}
+         where $1 <= time_stamp and ($2 = 0 or time_stamp < $2) order by time_stamp, order_side",
+        table
+    );
+
+    let mut bars: Vec<OhlcvBar> = vec![];
+
+    let mut statement = match conn.prepare(sql.as_str()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("{:?}", e);
+            return bars;
+        }
+    };
+
+    let iter = statement
+        .query_map(params![from_time, to_time], |row| {
+            let order_side: i64 = row.get_unwrap(1);
+
+            Ok(OhlcvBar {
+                time_stamp: row.get_unwrap(0),
+                order_side: order_side_from_code(order_side),
+                open: row.get_unwrap(2),
+                high: row.get_unwrap(3),
+                low: row.get_unwrap(4),
+                close: row.get_unwrap(5),
+                vol: row.get_unwrap(6),
+                value: row.get_unwrap(7),
+                count: row.get_unwrap(8),
+                start_time: row.get_unwrap(9),
+                end_time: row.get_unwrap(10),
+            })
+        })
+        .unwrap();
+
+    for bar in iter {
+        match bar {
+            Ok(b) => bars.push(b),
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    return bars;
+}
+
+/// from_time〜to_time(含まない)の足を置き換える（呼び出し側のトランザクションの中で実行する）
+fn write_bars(conn: &Connection, table: &str, from_time: MicroSec, to_time: MicroSec, bars: &[OhlcvBar]) -> Result<(), Error> {
+    conn.execute(
+        format!("delete from {} where $1 <= time_stamp and time_stamp < $2", table).as_str(),
+        params![from_time, to_time],
+    )?;
+
+    let sql = format!(
+        "insert or replace into {} (time_stamp, order_side, open, high, low, close, vol, value, count, start_time, end_time)
+         values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        table
+    );
+    let mut statement = conn.prepare_cached(sql.as_str())?;
+
+    for bar in bars {
+        statement.execute(params![
+            bar.time_stamp,
+            order_side_code(bar.order_side),
+            bar.open,
+            bar.high,
+            bar.low,
+            bar.close,
+            bar.vol,
+            bar.value,
+            bar.count,
+            bar.start_time,
+            bar.end_time
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// 足の開始時刻を、連続する範囲[start, end)にまとめる
+fn bar_ranges(times: &BTreeSet<MicroSec>, window_sec: i64) -> Vec<(MicroSec, MicroSec)> {
+    let mut ranges: Vec<(MicroSec, MicroSec)> = vec![];
+
+    for t in times {
+        match ranges.last_mut() {
+            Some((_start, end)) if *end == *t => *end = *t + SEC(window_sec),
+            _ => ranges.push((*t, *t + SEC(window_sec))),
+        }
+    }
+
+    return ranges;
+}
+
+/// 追加した約定を含む足だけを作り直す（約定を追加したトランザクションの中で実行する）。
+/// 1秒足は約定から、1分足は1秒足から、1時間足は1分足から作るので、既存の約定の読み込みは追加した約定と同じ秒のみ。
+fn update_ohlcv_bars(conn: &Connection, trades: &[Trade]) -> Result<(), Error> {
+    let (window_sec, table) = TradeTable::OHLCV_TABLES[0];
+    let mut times: BTreeSet<MicroSec> = trades.iter().map(|t| FLOOR(t.time, window_sec)).collect();
+
+    for (start, end) in bar_ranges(&times, window_sec) {
+        let bars = trades_to_bars(&select_trades_vec(conn, start, end), window_sec);
+        write_bars(conn, table, start, end, &bars)?;
+    }
+
+    for level in 1..TradeTable::OHLCV_TABLES.len() {
+        let (_lower_sec, lower_table) = TradeTable::OHLCV_TABLES[level - 1];
+        let (window_sec, table) = TradeTable::OHLCV_TABLES[level];
+        times = times.iter().map(|t| FLOOR(*t, window_sec)).collect();
+
+        for (start, end) in bar_ranges(&times, window_sec) {
+            let bars = bars_to_bars(&select_bars(conn, lower_table, start, end), window_sec);
+            write_bars(conn, table, start, end, &bars)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// start_time〜end_time(含む)の全ての足を約定から作り直す（１日ずつ処理する）
+fn rebuild_ohlcv_bars(conn: &Connection, start_time: MicroSec, end_time: MicroSec) -> Result<(), Error> {
+    for (_window_sec, table) in TradeTable::OHLCV_TABLES {
+        conn.execute(format!("delete from {}", table).as_str(), ())?;
+    }
+
+    let mut day = FLOOR_DAY(start_time);
+    while day <= end_time {
+        let trades = select_trades_vec(conn, day, day + DAYS(1));
+
+        for (window_sec, table) in TradeTable::OHLCV_TABLES {
+            write_bars(conn, table, day, day + DAYS(1), &trades_to_bars(&trades, window_sec))?;
+        }
+        day += DAYS(1);
+    }
+
+    Ok(())
+}
+
+/// select_book_statementの行をBookUpdateへ変換する
+pub fn book_from_row(row: &Row) -> rusqlite::Result<BookUpdate> {
+    Ok(BookUpdate::new(
+        row.get_unwrap(0),
+        row.get_unwrap::<_, i64>(1) != 0,
+        OrderSide::from_buy_side(row.get_unwrap::<_, i64>(2) != 0),
+        row.get_unwrap(3),
+        row.get_unwrap(4),
+    ))
+}
+
+/// DataFrameのcolumnsを列の順にnumpy配列(f64)へ変換してPythonへ返す
+fn df_to_pyarray(df: &DataFrame, columns: &[&str]) -> PyResult<Py<PyArray2<f64>>> {
+    let array: ndarray::Array2<f64> = df
+        .select(columns)
+        .and_then(|df| df.to_ndarray::<Float64Type>())
+        .map_err(|e| PyTypeError::new_err(e.to_string()))?;
+
+    return Ok(Python::with_gil(|py| array.into_pyarray(py).to_owned()));
+}
+
 #[derive(Debug)]
 pub struct TradeTable {
     file_name: String,
@@ -122,6 +324,9 @@ pub struct TradeTable {
 impl TradeTable {
     const OHLCV_WINDOW_SEC: i64 = 60; // min
 
+    /// DBに保存する足（秒, テーブル名）。insert_recordsで更新する。
+    pub const OHLCV_TABLES: [(i64, &'static str); 3] = [(1, "ohlcv_1s"), (60, "ohlcv_1m"), (3600, "ohlcv_1h")];
+
     pub fn ohlcv_start(t: MicroSec) -> MicroSec {
         return FLOOR(t, TradeTable::OHLCV_WINDOW_SEC);
     }
@@ -170,6 +375,161 @@ impl TradeTable {
             "CREATE index if not exists time_index on trades(time_stamp)",
             (),
         );
+
+        let _r = self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS loaded_files (
+                file_name   TEXT primary key,
+                rec         INTEGER,
+                loaded_at   INTEGER
+            )",
+            (),
+        );
+
+        let _r = self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS funding (
+                time_stamp    INTEGER primary key,
+                rate    NUMBER
+            )",
+            (),
+        );
+
+        let _r = self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS book (
+                time_stamp  INTEGER,
+                snapshot    INTEGER,
+                side        INTEGER,
+                price       NUMBER,
+                size        NUMBER,
+                primary key (time_stamp, snapshot, side, price)
+            )",
+            (),
+        );
+
+        for (_window_sec, table) in TradeTable::OHLCV_TABLES {
+            let _r = self.connection.execute(
+                format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        time_stamp  INTEGER,
+                        order_side  INTEGER,
+                        open        NUMBER,
+                        high        NUMBER,
+                        low         NUMBER,
+                        close       NUMBER,
+                        vol         NUMBER,
+                        value       NUMBER,
+                        count       INTEGER,
+                        start_time  INTEGER,
+                        end_time    INTEGER,
+                        primary key (time_stamp, order_side)
+                    )",
+                    table
+                )
+                .as_str(),
+                (),
+            );
+        }
+
+        if self.has_ohlcv_tables() == false {
+            log::warn!("ohlcv tables are empty / call migrate_ohlcv_tables to build them from trades");
+        }
+    }
+
+    /// DBのスキーマバージョン(PRAGMA user_version)。未設定のDBは0。
+    pub fn schema_version(&self) -> i64 {
+        return self
+            .connection
+            .query_row("PRAGMA user_version", (), |row| row.get(0))
+            .unwrap_or(0);
+    }
+
+    pub fn set_schema_version(&self, version: i64) -> Result<(), Error> {
+        return self
+            .connection
+            .execute_batch(format!("PRAGMA user_version = {}", version).as_str());
+    }
+
+    /// 全約定の売買方向(Buy/Sell)を反転し、スキーマバージョンをversionにする（同じトランザクションで実行）。
+    /// 売買方向を逆に登録していたDBの移行用。足のテーブルも作り直す。反転した件数を返す。
+    pub fn migrate_flip_order_side(&mut self, version: i64) -> Result<i64, Error> {
+        let rebuild_ohlcv = self.has_ohlcv_tables();
+        let range = (self.start_time(), self.end_time());
+
+        let tx = self.connection.transaction()?;
+
+        let count = tx.execute(
+            "update trades set action = case action when 'Buy' then 'Sell' when 'Sell' then 'Buy' else action end",
+            (),
+        )?;
+
+        if let (true, (Ok(start_time), Ok(end_time))) = (rebuild_ohlcv, range) {
+            rebuild_ohlcv_bars(&tx, start_time, end_time)?;
+        }
+
+        tx.execute_batch(format!("PRAGMA user_version = {}", version).as_str())?;
+
+        tx.commit()?;
+
+        return Ok(count as i64);
+    }
+
+    /// 足のテーブルが全ての約定を反映しているか。
+    /// 足のテーブルを追加する前に作ったDB（約定はあるが足がない）は、migrate_ohlcv_tablesを呼ぶまで約定から計算する。
+    pub fn has_ohlcv_tables(&self) -> bool {
+        let exists = TradeTable::OHLCV_TABLES.iter().all(|(_window_sec, table)| {
+            let sql = format!("select value from {} limit 0", table);
+            self.connection.prepare(sql.as_str()).is_ok()
+        });
+
+        if exists == false {
+            return false;
+        }
+
+        let has_row = |table: &str| {
+            let sql = format!("select 1 from {} limit 1", table);
+            self.connection.query_row(sql.as_str(), (), |_row| Ok(())).is_ok()
+        };
+
+        let (_window_sec, table_1h) = TradeTable::OHLCV_TABLES[2];
+
+        return has_row("trades") == false || has_row(table_1h);
+    }
+
+    /// time_window_secを割り切れる一番長い足のテーブル
+    fn ohlcv_table_for(time_window_sec: i64) -> (i64, &'static str) {
+        let mut table = TradeTable::OHLCV_TABLES[0];
+
+        for t in TradeTable::OHLCV_TABLES {
+            if time_window_sec % t.0 == 0 {
+                table = t;
+            }
+        }
+
+        return table;
+    }
+
+    /// 全期間の足を約定から作り直す（足のテーブルを追加する前に作ったDBの移行用）。
+    /// １つのトランザクションで処理するので、途中で失敗した場合は元のまま。
+    pub fn migrate_ohlcv_tables(&mut self) -> Result<(), Error> {
+        let (start_time, end_time) = match (self.start_time(), self.end_time()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return Ok(()),
+        };
+
+        log::info!("rebuild ohlcv {} -> {}", time_string(start_time), time_string(end_time));
+
+        let tx = self.connection.transaction()?;
+        rebuild_ohlcv_bars(&tx, start_time, end_time)?;
+
+        return tx.commit();
+    }
+
+    pub(crate) fn select_trades_vec(&self, from_time: MicroSec, to_time: MicroSec) -> Vec<Trade> {
+        return select_trades_vec(&self.connection, from_time, to_time);
+    }
+
+    /// 足を時刻順に取得する。時間選択は左側は含み、右側は含まない。to_timeが0の場合は最後まで。
+    pub fn select_bars(&self, table: &str, from_time: MicroSec, to_time: MicroSec) -> Vec<OhlcvBar> {
+        return select_bars(&self.connection, table, from_time, to_time);
     }
 
     pub fn drop_table(&self) {
@@ -187,6 +547,17 @@ impl TradeTable {
     pub fn select<F>(&mut self, from_time: MicroSec, to_time: MicroSec, mut f: F)
     where
         F: FnMut(&Trade),
+    {
+        self.select_while(from_time, to_time, |trade| {
+            f(trade);
+            true
+        });
+    }
+
+    /// selectと同じだが、fがfalseを返したらカーソルを閉じて終了する。
+    pub fn select_while<F>(&mut self, from_time: MicroSec, to_time: MicroSec, mut f: F)
+    where
+        F: FnMut(&Trade) -> bool,
     {
         let sql: &str;
         let param: Vec<i64>;
@@ -224,7 +595,9 @@ impl TradeTable {
         for trade in _transaction_iter {
             match trade {
                 Ok(t) => {
-                    f(&t);
+                    if f(&t) == false {
+                        break;
+                    }
                 }
                 Err(e) => log::error!("{:?}", e),
             }
@@ -236,6 +609,13 @@ impl TradeTable {
         return statement;
     }
 
+    /// 時間範囲を指定するステートメント。パラメータは(from_time, to_time)
+    /// 時間選択は左側は含み、右側は含まない。to_timeに0をいれたときは最後まで検索
+    pub fn select_range_statement(&self) -> Statement {
+        let statement = self.connection.prepare("select time_stamp, action, price, size, id from trades where $1 <= time_stamp and ($2 = 0 or time_stamp < $2) order by time_stamp").unwrap();
+        return statement;
+    }
+
     pub fn select_df_from_db(&mut self, from_time: MicroSec, to_time: MicroSec) -> DataFrame {
         let mut buffer = TradeBuffer::new();
 
@@ -345,6 +725,14 @@ impl TradeTable {
         to_time: MicroSec,
         time_window_sec: i64,
     ) -> DataFrame {
+        // DBに保存した足から作る（約定を読み込まない）
+        if self.has_ohlcv_tables() {
+            let (window_sec, table) = TradeTable::ohlcv_table_for(time_window_sec);
+            let bars = self.select_bars(table, FLOOR(from_time, window_sec), to_time);
+
+            return ohlcv_from_ohlcv_df(&bars_to_df(&bars), from_time, to_time, time_window_sec);
+        }
+
         self.update_cache_df(from_time, to_time);
 
         if time_window_sec % TradeTable::OHLCV_WINDOW_SEC == 0 {
@@ -354,6 +742,49 @@ impl TradeTable {
         }
     }
 
+    /// 売買を１行にまとめたOHLCV（buy_vol, sell_vol, buy_count, sell_count, vwap, deltaを含む）
+    pub fn ohlcvv_df(&mut self, from_time: MicroSec, to_time: MicroSec, time_window_sec: i64) -> DataFrame {
+        let bars = if self.has_ohlcv_tables() {
+            let (_window_sec, table) = TradeTable::ohlcv_table_for(time_window_sec);
+            self.select_bars(table, from_time, to_time)
+        } else {
+            trades_to_bars(&self.select_trades_vec(from_time, to_time), time_window_sec)
+        };
+
+        return ohlcvv_rows_to_df(&bars_to_ohlcvv_rows(&bars, time_window_sec));
+    }
+
+    pub fn py_ohlcvv_merged(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        window_sec: i64,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        let from_time = TradeTable::ohlcv_start(from_time);
+        let df = self.ohlcvv_df(from_time, to_time, window_sec);
+
+        return df_to_pyarray(
+            &df,
+            &[
+                KEY::time_stamp,
+                KEY::open,
+                KEY::high,
+                KEY::low,
+                KEY::close,
+                KEY::vol,
+                KEY::buy_vol,
+                KEY::sell_vol,
+                KEY::buy_count,
+                KEY::sell_count,
+                KEY::count,
+                KEY::vwap,
+                KEY::delta,
+                KEY::start_time,
+                KEY::end_time,
+            ],
+        );
+    }
+
     pub fn py_ohlcvv(
         &mut self,
         from_time: MicroSec,
@@ -404,6 +835,40 @@ impl TradeTable {
         array
     }
 
+    /// 約定数・出来高・売買代金・不均衡で区切った足（カラムはohlcv_dfと同じ）
+    pub fn info_bars_df(&mut self, from_time: MicroSec, to_time: MicroSec, bar_type: BarType) -> DataFrame {
+        let mut builder = BarBuilder::new(bar_type);
+
+        self.select(from_time, to_time, |trade| builder.push(trade));
+
+        return info_bars_to_df(&builder.finish());
+    }
+
+    pub fn py_info_bars(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        bar_type: BarType,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        let df = self.info_bars_df(from_time, to_time, bar_type);
+
+        return df_to_pyarray(
+            &df,
+            &[
+                KEY::time_stamp,
+                KEY::order_side,
+                KEY::open,
+                KEY::high,
+                KEY::low,
+                KEY::close,
+                KEY::vol,
+                KEY::count,
+                KEY::start_time,
+                KEY::end_time,
+            ],
+        );
+    }
+
     pub fn py_select_trades(
         &mut self,
         from_time: MicroSec,
@@ -421,22 +886,15 @@ impl TradeTable {
     }
 
     pub fn select_array(&mut self, from_time: MicroSec, to_time: MicroSec) -> ndarray::Array2<f64> {
-        self.update_cache_df(from_time, to_time);
-
         let trades = self.select_df_from_db(from_time, to_time);
 
-        let array: ndarray::Array2<f64> = trades
-            .select(&[
-                KEY::time_stamp,
-                KEY::price,
-                KEY::size,
-                KEY::order_side,
-            ])
-            .unwrap()
-            .to_ndarray::<Float64Type>()
-            .unwrap();
+        return trades_df_to_array(&trades);
+    }
 
-        array
+    /// from_time〜to_timeの約定をchunk_rows件ずつ返すイテレータ。
+    /// 別スレッドで同じDBファイルを開き、カーソルから読んだ分だけを渡す。
+    pub fn iter_trades(&self, from_time: MicroSec, to_time: MicroSec, chunk_rows: usize) -> TradeIterator {
+        return TradeIterator::new(&self.file_name, from_time, to_time, chunk_rows);
     }
 
     pub fn info(&mut self) -> String {
@@ -727,7 +1185,259 @@ impl TradeTable {
         return days;
     }
 
+    /// 指定期間のログを１つのファイル(Parquet/Arrow IPC)へ書き出す。書き出した件数を返す。
+    pub fn export_file(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        path: &Path,
+        format: ExportFormat,
+    ) -> Result<i64, String> {
+        let mut trades: Vec<Trade> = vec![];
+        self.select(from_time, to_time, |trade| trades.push(trade.clone()));
+
+        let mut df = trades_to_export_df(&trades);
+        write_df_file(&mut df, path, format).map_err(|e| e.to_string())?;
+
+        log::debug!("export {} rec to {:?}", trades.len(), path);
+
+        Ok(trades.len() as i64)
+    }
+
+    /// 指定期間のログを１日１ファイルで書き出す（ファイル名は<dir>/<prefix>-YYYY-MM-DD.<ext>）
+    /// ログのない日はファイルを作らない。書き出したファイルのパスを返す。
+    pub fn export_daily(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        dir: &Path,
+        prefix: &str,
+        format: ExportFormat,
+    ) -> Result<Vec<String>, String> {
+        let from_time = if from_time == 0 {
+            self.start_time().map_err(|e| e.to_string())?
+        } else {
+            from_time
+        };
+
+        let to_time = if to_time == 0 {
+            self.end_time().map_err(|e| e.to_string())? + 1
+        } else {
+            to_time
+        };
+
+        let mut files: Vec<String> = vec![];
+        let mut day = FLOOR_DAY(from_time);
+
+        while day < to_time {
+            let start = day.max(from_time);
+            let end = (day + DAYS(1)).min(to_time);
+
+            let path = dir.join(format!(
+                "{}-{}.{}",
+                prefix,
+                to_naive_datetime(day).format("%Y-%m-%d"),
+                format.extension()
+            ));
+
+            let mut trades: Vec<Trade> = vec![];
+            self.select(start, end, |trade| trades.push(trade.clone()));
+
+            if trades.len() != 0 {
+                let mut df = trades_to_export_df(&trades);
+                write_df_file(&mut df, &path, format).map_err(|e| e.to_string())?;
+                files.push(path.to_string_lossy().to_string());
+            }
+
+            day += DAYS(1);
+        }
+
+        Ok(files)
+    }
+
+    /// export_file/export_dailyで書き出したファイルを読み込む。形式は拡張子で判定する。
+    /// insert_recordsと同じく、idが同じレコードは置き換える。
+    pub fn import_file(&mut self, path: &Path) -> Result<i64, String> {
+        let format = ExportFormat::from_path(path)?;
+        let df = read_df_file(path, format).map_err(|e| e.to_string())?;
+        let trades = export_df_to_trades(&df).map_err(|e| e.to_string())?;
+
+        let mut insert_rec = 0;
+        for chunk in trades.chunks(10_000) {
+            insert_rec += self.insert_records(&chunk.to_vec()).map_err(|e| e.to_string())?;
+        }
+
+        log::debug!("import {} rec from {:?}", insert_rec, path);
+
+        Ok(insert_rec)
+    }
+
+    /// ローカルのログファイルを読み込み済みか（ファイル名で判定）
+    pub fn is_file_loaded(&self, file_name: &str) -> bool {
+        let r = self.connection.query_row(
+            "select count(*) from loaded_files where file_name = ?1",
+            params![file_name],
+            |row| row.get::<_, i64>(0),
+        );
+
+        return r.unwrap_or(0) != 0;
+    }
+
+    /// ローカルのログファイルを読み込み済みとして記録する。
+    pub fn mark_file_loaded(&self, file_name: &str, rec: i64) -> Result<(), Error> {
+        self.connection.execute(
+            "insert or replace into loaded_files (file_name, rec, loaded_at) values (?1, ?2, ?3)",
+            params![file_name, rec, NOW()],
+        )?;
+
+        Ok(())
+    }
+
+    /// FundingRate(時刻, レート)を登録する。同じ時刻のレコードは置き換える。
+    pub fn insert_funding(&mut self, funding: &Vec<(MicroSec, f64)>) -> Result<i64, Error> {
+        let tx = self.connection.transaction()?;
+
+        let mut insert_len = 0;
+        let sql = r#"insert or replace into funding (time_stamp, rate) values (?1, ?2) "#;
+
+        for (time, rate) in funding {
+            insert_len += tx.execute(sql, params![time, rate])?;
+        }
+
+        tx.commit()?;
+
+        Ok(insert_len as i64)
+    }
+
+    /// 指定期間のログの品質を確認する（gap、id、価格・サイズ、OrderSide::Unknown、価格の外れ値、日毎の件数）
+    /// 時間選択は左側は含み、右側は含まない。0をいれたときは全件検索
+    pub fn audit(&mut self, from_time: MicroSec, to_time: MicroSec, config: AuditConfig) -> Vec<AuditIssue> {
+        let mut auditor = TradeAuditor::new(config);
+
+        self.select(from_time, to_time, |trade| auditor.push(trade));
+
+        return auditor.finish(from_time, to_time);
+    }
+
+    /// FundingRate(時刻, レート)を時刻順に取得する。
+    /// 時間選択は左側は含み、右側は含まない。0をいれたときは全件検索
+    pub fn select_funding(&self, from_time: MicroSec, to_time: MicroSec) -> Vec<(MicroSec, f64)> {
+        let sql: &str;
+        let param: Vec<i64>;
+
+        if 0 < to_time {
+            sql = "select time_stamp, rate from funding where $1 <= time_stamp and time_stamp < $2 order by time_stamp";
+            param = vec![from_time, to_time];
+        } else {
+            sql = "select time_stamp, rate from funding where $1 <= time_stamp order by time_stamp";
+            param = vec![from_time];
+        }
+
+        let mut statement = self.connection.prepare(sql).unwrap();
+
+        let iter = statement
+            .query_map(params_from_iter(param.iter()), |row| {
+                Ok((row.get_unwrap(0), row.get_unwrap(1)))
+            })
+            .unwrap();
+
+        let mut funding: Vec<(MicroSec, f64)> = vec![];
+
+        for rec in iter {
+            match rec {
+                Ok(r) => funding.push(r),
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+
+        return funding;
+    }
+
+    /// 板の更新を登録する。同じ時刻・価格のレコードは置き換える。
+    pub fn insert_book(&mut self, updates: &Vec<BookUpdate>) -> Result<i64, Error> {
+        let tx = self.connection.transaction()?;
+
+        let mut insert_len = 0;
+        let sql = r#"insert or replace into book (time_stamp, snapshot, side, price, size) values (?1, ?2, ?3, ?4, ?5) "#;
+
+        for u in updates {
+            let side = if u.side.is_buy_side() { 1 } else { 0 };
+            insert_len += tx.execute(sql, params![u.time, u.snapshot as i64, side, u.price, u.size])?;
+        }
+
+        tx.commit()?;
+
+        Ok(insert_len as i64)
+    }
+
+    /// 板のファイル（Bybitの板アーカイブ、Binanceのdepth）を読み込む。
+    pub fn import_book_file(&mut self, path: &Path) -> Result<i64, String> {
+        let count = read_book_file(path, 10_000, |updates| {
+            self.insert_book(&updates).map(|_len| ()).map_err(|e| e.to_string())
+        })?;
+
+        log::debug!("import {} book rec from {:?}", count, path);
+
+        Ok(count)
+    }
+
+    /// from_time時点の板を復元できるよう、from_time以前の最後のスナップショットの時刻を返す（なければfrom_time）。
+    pub fn book_replay_start(&self, from_time: MicroSec) -> MicroSec {
+        return self
+            .connection
+            .query_row(
+                "select max(time_stamp) from book where snapshot = 1 and time_stamp <= ?1",
+                params![from_time],
+                |row| row.get::<_, Option<i64>>(0),
+            )
+            .unwrap_or(None)
+            .unwrap_or(from_time);
+    }
+
+    /// 板の更新を時刻順（同じ時刻はスナップショットが先）に読むステートメント。パラメータは(from_time, to_time)
+    /// 時間選択は左側は含み、右側は含まない。to_timeに0をいれたときは最後まで検索
+    /// BackTesterはbook_replay_startから読み、約定ログと時刻をそろえて再生する。
+    pub fn select_book_statement(&self) -> Statement {
+        let statement = self.connection.prepare("select time_stamp, snapshot, side, price, size from book where $1 <= time_stamp and ($2 = 0 or time_stamp < $2) order by time_stamp, snapshot desc").unwrap();
+        return statement;
+    }
+
+    /// 板の更新を時刻順（同じ時刻はスナップショットが先）に取得する。
+    /// from_time時点の板を復元できるよう、from_time以前の最後のスナップショットから返す。
+    /// 時間選択は右側は含まない。to_time=0の場合は最後まで
+    pub fn select_book(&self, from_time: MicroSec, to_time: MicroSec) -> Vec<BookUpdate> {
+        let start_time = self.book_replay_start(from_time);
+
+        let mut statement = self.select_book_statement();
+
+        let iter = statement.query_map(params![start_time, to_time], book_from_row).unwrap();
+
+        let mut updates: Vec<BookUpdate> = vec![];
+
+        for rec in iter {
+            match rec {
+                Ok(r) => updates.push(r),
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+
+        return updates;
+    }
+
+    /// time時点の板
+    pub fn book_at(&self, time: MicroSec) -> OrderBook {
+        let mut book = OrderBook::new();
+
+        for u in self.select_book(time, time + 1) {
+            book.apply(&u);
+        }
+
+        return book;
+    }
+
     pub fn insert_records(&mut self, trades: &Vec<Trade>) -> Result<i64, Error> {
+        let update_ohlcv = self.has_ohlcv_tables();
+
         let tx = self.connection.transaction()?;
 
         // let trades_len = trades.len();
@@ -761,12 +1471,18 @@ impl TradeTable {
             }
         }
 
+        // 保存している足を同じトランザクションで更新する
+        if update_ohlcv {
+            update_ohlcv_bars(&tx, trades)?;
+        }
+
         let result = tx.commit();
 
-        match result {
-            Ok(_) => Ok(insert_len as i64),
-            Err(e) => return Err(e),
+        if let Err(e) = result {
+            return Err(e);
         }
+
+        Ok(insert_len as i64)
     }
 }
 
@@ -815,6 +1531,166 @@ mod test_transaction_table {
         let _r = tr.insert_records(&vec![rec1, rec2, rec3]);
     }
 
+    #[test]
+    fn test_insert_funding() {
+        let mut tr = TradeTable::open("test.db").unwrap();
+        tr.create_table_if_not_exists();
+        let _r = tr.connection.execute("delete from funding", ());
+
+        let r = tr.insert_funding(&vec![(3, 0.0003), (1, 0.0001), (2, -0.0002)]);
+        assert_eq!(r.unwrap(), 3);
+
+        // 同じ時刻は置き換え
+        let _r = tr.insert_funding(&vec![(2, 0.0002)]);
+
+        assert_eq!(tr.select_funding(0, 0), vec![(1, 0.0001), (2, 0.0002), (3, 0.0003)]);
+        assert_eq!(tr.select_funding(2, 3), vec![(2, 0.0002)]);
+    }
+
+    #[test]
+    fn test_insert_book() {
+        use crate::db::book::BookUpdate;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut tr = TradeTable::open(dir.path().join("book.db").to_str().unwrap()).unwrap();
+        tr.create_table_if_not_exists();
+
+        let r = tr.insert_book(&vec![
+            BookUpdate::new(10, true, OrderSide::Buy, 100.0, 1.0),
+            BookUpdate::new(10, true, OrderSide::Sell, 101.0, 1.0),
+            BookUpdate::new(20, false, OrderSide::Sell, 101.0, 0.0),
+            BookUpdate::new(20, false, OrderSide::Sell, 102.0, 2.0),
+            BookUpdate::new(30, true, OrderSide::Buy, 90.0, 1.0),
+            BookUpdate::new(30, true, OrderSide::Sell, 91.0, 1.0),
+            BookUpdate::new(40, false, OrderSide::Buy, 90.5, 3.0),
+        ]);
+        assert_eq!(r.unwrap(), 7);
+
+        // 直前のスナップショットから返す
+        let updates = tr.select_book(35, 0);
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0].time, 30);
+
+        let updates = tr.select_book(25, 40);
+        assert_eq!(updates.len(), 6);
+        assert_eq!(updates[0].time, 10);
+
+        let updates = tr.select_book(20, 0);
+        assert_eq!(updates.len(), 7);
+
+        let book = tr.book_at(25);
+        assert_eq!(book.best_bid(), Some(100.0));
+        assert_eq!(book.best_ask(), Some(102.0));
+
+        let book = tr.book_at(40);
+        assert_eq!(book.best_bid(), Some(90.5));
+        assert_eq!(book.best_ask(), Some(91.0));
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut tr = TradeTable::open(dir.path().join("export.db").to_str().unwrap()).unwrap();
+        tr.create_table_if_not_exists();
+
+        let rec1 = Trade::new(DAYS(1) + 1, OrderSide::Buy, 10.0, 10.0, "abc1".to_string());
+        let rec2 = Trade::new(DAYS(1) + 2, OrderSide::Sell, 10.1, 10.2, "abc2".to_string());
+        let rec3 = Trade::new(DAYS(2) + 3, OrderSide::Unknown, 10.2, 10.1, "abc3".to_string());
+        let _r = tr.insert_records(&vec![rec1, rec2, rec3]);
+
+        for format in [ExportFormat::Parquet, ExportFormat::Ipc] {
+            let path = dir.path().join(format!("all.{}", format.extension()));
+            assert_eq!(tr.export_file(0, 0, &path, format).unwrap(), 3);
+
+            let files = tr.export_daily(0, 0, dir.path(), "TEST", format).unwrap();
+            assert_eq!(files.len(), 2);
+            assert!(files[0].ends_with(&format!("TEST-1970-01-02.{}", format.extension())));
+
+            let mut tr2 = TradeTable::open(dir.path().join(format!("import-{}.db", format.extension())).to_str().unwrap()).unwrap();
+            tr2.create_table_if_not_exists();
+
+            assert_eq!(tr2.import_file(&path).unwrap(), 3);
+            // idが同じレコードは重複しない
+            for file in files {
+                let _r = tr2.import_file(Path::new(&file));
+            }
+
+            let mut trades: Vec<Trade> = vec![];
+            tr2.select(0, 0, |trade| trades.push(trade.clone()));
+            assert_eq!(trades.len(), 3);
+            assert_eq!(trades[1].order_side, OrderSide::Sell);
+            assert_eq!(trades[1].price, 10.1);
+            assert_eq!(trades[2].id, "abc3");
+            assert_eq!(trades[2].order_side, OrderSide::Unknown);
+        }
+    }
+
+    #[test]
+    fn test_ohlcv_tables() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut tr = TradeTable::open(dir.path().join("ohlcv.db").to_str().unwrap()).unwrap();
+        tr.create_table_if_not_exists();
+
+        // 2時間分を分割して登録する
+        let trades: Vec<Trade> = (0..720)
+            .map(|i| {
+                let side = if i % 3 == 0 { OrderSide::Sell } else { OrderSide::Buy };
+                Trade::new(DAYS(1) + SEC(i * 10), side, 100.0 + (i % 7) as f64, 1.0 + (i % 5) as f64, i.to_string())
+            })
+            .collect();
+        for chunk in trades.chunks(100) {
+            tr.insert_records(&chunk.to_vec()).unwrap();
+        }
+
+        assert_eq!(tr.select_bars("ohlcv_1s", 0, 0).len(), 720);
+        assert_eq!(tr.select_bars("ohlcv_1m", 0, 0).len(), 240); // 120分 x buy/sell
+        assert_eq!(tr.select_bars("ohlcv_1h", 0, 0).len(), 4);
+        assert_eq!(tr.select_bars("ohlcv_1h", 0, 0), trades_to_bars(&trades, 3600));
+
+        // 同じ約定を再登録しても足は変わらない
+        tr.insert_records(&trades[100..200].to_vec()).unwrap();
+        assert_eq!(tr.select_bars("ohlcv_1s", 0, 0).len(), 720);
+        assert_eq!(tr.select_bars("ohlcv_1h", 0, 0), trades_to_bars(&trades, 3600));
+
+        // 足のテーブルから作ったOHLCVは約定から作ったOHLCVと同じ
+        let columns = [KEY::time_stamp, KEY::open, KEY::high, KEY::low, KEY::close, KEY::vol, KEY::count];
+        for window_sec in [10, 120, 3600, 7200] {
+            let from_time = DAYS(1);
+            let to_time = DAYS(1) + SEC(7200);
+
+            let from_table = tr
+                .ohlcv_df(from_time, to_time, window_sec)
+                .sort([KEY::time_stamp, KEY::order_side], vec![false, false])
+                .unwrap();
+            let from_trades = ohlcv_df(&tr.select_df_from_db(from_time, to_time), from_time, to_time, window_sec)
+                .sort([KEY::time_stamp, KEY::order_side], vec![false, false])
+                .unwrap();
+
+            assert_eq!(
+                from_table.select(&columns).unwrap().to_ndarray::<Float64Type>().unwrap(),
+                from_trades.select(&columns).unwrap().to_ndarray::<Float64Type>().unwrap(),
+            );
+        }
+
+        // 足のテーブルがないDBは、migrate_ohlcv_tablesを呼ぶまで約定から計算する
+        tr.connection.execute("drop table ohlcv_1s", ()).unwrap();
+        tr.connection.execute("drop table ohlcv_1m", ()).unwrap();
+        tr.connection.execute("drop table ohlcv_1h", ()).unwrap();
+        assert!(tr.has_ohlcv_tables() == false);
+
+        tr.create_table_if_not_exists();
+        assert!(tr.has_ohlcv_tables() == false);
+        assert_eq!(tr.select_bars("ohlcv_1m", 0, 0).len(), 0);
+        assert_eq!(tr.ohlcv_df(DAYS(1), DAYS(1) + SEC(7200), 3600).shape().0, 4);
+
+        tr.migrate_ohlcv_tables().unwrap();
+        assert!(tr.has_ohlcv_tables());
+        assert_eq!(tr.select_bars("ohlcv_1m", 0, 0).len(), 240);
+        assert_eq!(tr.select_bars("ohlcv_1h", 0, 0), trades_to_bars(&trades, 3600));
+    }
+
     #[test]
     fn test_select_fn() {
         test_insert_table();
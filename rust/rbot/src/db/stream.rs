@@ -0,0 +1,148 @@
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+
+use numpy::IntoPyArray;
+use numpy::PyArray2;
+use polars::prelude::DataFrame;
+use polars::prelude::Float64Type;
+use pyo3::prelude::*;
+
+use crate::common::time::MicroSec;
+use crate::db::df::TradeBuffer;
+use crate::db::df::KEY;
+use crate::db::sqlite::TradeTable;
+
+/// 約定のDataFrameをselect_tradesと同じ配列(time_stamp, price, size, order_side)へ変換する
+pub fn trades_df_to_array(df: &DataFrame) -> ndarray::Array2<f64> {
+    return df
+        .select(&[KEY::time_stamp, KEY::price, KEY::size, KEY::order_side])
+        .unwrap()
+        .to_ndarray::<Float64Type>()
+        .unwrap();
+}
+
+/// 約定をchunk_rows件ずつ返すイテレータ（Pythonのforで使う）
+/// 読み出しスレッドは１チャンク先までしか読まないので、期間が長くてもメモリは一定。
+/// イテレータを捨てるとスレッドはカーソルを閉じて終了する。
+/// chunk_rowsが0の場合は1件ずつ返す。
+#[pyclass(name = "_TradeIterator")]
+pub struct TradeIterator {
+    rx: Option<Receiver<ndarray::Array2<f64>>>,
+}
+
+impl TradeIterator {
+    pub fn new(db_name: &str, from_time: MicroSec, to_time: MicroSec, chunk_rows: usize) -> Self {
+        let (tx, rx): (SyncSender<ndarray::Array2<f64>>, Receiver<ndarray::Array2<f64>>) =
+            mpsc::sync_channel(1);
+        let db_name = db_name.to_string();
+        let chunk_rows = chunk_rows.max(1);
+
+        let _handle = thread::spawn(move || {
+            let mut db = match TradeTable::open(&db_name) {
+                Ok(db) => db,
+                Err(e) => {
+                    log::error!("open db error {} {:?}", db_name, e);
+                    return;
+                }
+            };
+
+            let mut buffer = TradeBuffer::new();
+            let mut connected = true;
+
+            db.select_while(from_time, to_time, |trade| {
+                buffer.push_trade(trade);
+
+                if chunk_rows <= buffer.time_stamp.len() {
+                    connected = tx.send(trades_df_to_array(&buffer.to_dataframe())).is_ok();
+                    buffer.clear();
+                }
+
+                connected
+            });
+
+            if connected && buffer.time_stamp.len() != 0 {
+                let _result = tx.send(trades_df_to_array(&buffer.to_dataframe()));
+            }
+        });
+
+        return TradeIterator { rx: Some(rx) };
+    }
+
+    /// 次のチャンク。最後まで読んだらNone
+    pub fn next_chunk(&mut self) -> Option<ndarray::Array2<f64>> {
+        let chunk = match &self.rx {
+            Some(rx) => rx.recv().ok(),
+            None => None,
+        };
+
+        if chunk.is_none() {
+            self.rx = None;
+        }
+
+        return chunk;
+    }
+}
+
+#[pymethods]
+impl TradeIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>) -> Option<Py<PyArray2<f64>>> {
+        let chunk = slf.next_chunk()?;
+
+        return Some(Python::with_gil(|py| {
+            let py_array2: &PyArray2<f64> = chunk.into_pyarray(py);
+
+            return py_array2.to_owned();
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test_stream {
+    use super::*;
+    use crate::common::order::{OrderSide, Trade};
+
+    #[test]
+    fn test_iter_trades() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_name = dir.path().join("stream.db");
+        let db_name = db_name.to_str().unwrap();
+
+        let mut db = TradeTable::open(db_name).unwrap();
+        db.create_table_if_not_exists();
+
+        let trades: Vec<Trade> = (0..25)
+            .map(|i| Trade::new(i * 10, OrderSide::Buy, 100.0 + i as f64, 1.0, i.to_string()))
+            .collect();
+        db.insert_records(&trades).unwrap();
+
+        let mut iter = db.iter_trades(0, 0, 10);
+        let mut rows: Vec<usize> = vec![];
+        let mut times: Vec<f64> = vec![];
+        while let Some(chunk) = iter.next_chunk() {
+            assert_eq!(chunk.ncols(), 4);
+            rows.push(chunk.nrows());
+            times.extend(chunk.column(0).iter());
+        }
+        assert_eq!(rows, vec![10, 10, 5]);
+        assert_eq!(times, (0..25).map(|i| (i * 10) as f64).collect::<Vec<f64>>());
+        assert!(iter.next_chunk().is_none());
+
+        // 右側は含まない
+        let mut iter = db.iter_trades(50, 100, 3);
+        let mut count = 0;
+        while let Some(chunk) = iter.next_chunk() {
+            count += chunk.nrows();
+        }
+        assert_eq!(count, 5);
+
+        // 途中で捨てても読み出しスレッドは止まる
+        let mut iter = db.iter_trades(0, 0, 1);
+        assert!(iter.next_chunk().is_some());
+        drop(iter);
+    }
+}
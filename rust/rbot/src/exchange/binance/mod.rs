@@ -1,23 +1,133 @@
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
+pub mod rest;
+
+use std::path::Path;
 
 use chrono::{DateTime, Datelike, NaiveDateTime, NaiveTime};
 use csv::StringRecord;
 use numpy::PyArray2;
+use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
 //use pyo3::prelude::pymethods;
 
 use crate::common::init_debug_log;
-use crate::common::order::{OrderSide, Trade, TimeChunk};
-use crate::common::time::SEC;
-use crate::common::time::{time_string, DAYS};
+use crate::common::order::{OrderSide, Trade};
+use crate::common::time::time_string;
 use crate::common::time::{to_naive_datetime, MicroSec};
-use crate::common::time::{HHMM, NOW};
+use crate::db::df::ExportFormat;
 use crate::db::sqlite::TradeTable;
+use crate::db::stream::TradeIterator;
 use crate::fs::db_full_path;
 
-use super::{gzip_log_download, log_download};
+use super::market::{Market, RecordParser};
+use super::ws::{WsConfig, WsParser};
+use super::{gzip_log_download, import_log_files, ArchiveFormat};
+use serde_derive::Deserialize;
+
+const HISTORY_WEB_BASE: &str = "https://data.binance.vision/data";
+
+/// data.binance.visionのマーケット種別
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinanceMarketType {
+    Spot,
+    FuturesUm, // USDⓈ-M
+    FuturesCm, // COIN-M
+}
+
+impl BinanceMarketType {
+    pub fn from_str(market_type: &str) -> Result<Self, String> {
+        match market_type.to_lowercase().as_str() {
+            "spot" => Ok(BinanceMarketType::Spot),
+            "um" | "futures/um" => Ok(BinanceMarketType::FuturesUm),
+            "cm" | "futures/cm" => Ok(BinanceMarketType::FuturesCm),
+            _ => Err(format!("Unknown market type {} / use spot, um or cm", market_type)),
+        }
+    }
+
+    fn url_path(&self) -> &str {
+        match self {
+            BinanceMarketType::Spot => "spot",
+            BinanceMarketType::FuturesUm => "futures/um",
+            BinanceMarketType::FuturesCm => "futures/cm",
+        }
+    }
+
+    fn ws_base(&self) -> &str {
+        match self {
+            BinanceMarketType::Spot => "wss://stream.binance.com:9443/ws",
+            BinanceMarketType::FuturesUm => "wss://fstream.binance.com/ws",
+            BinanceMarketType::FuturesCm => "wss://dstream.binance.com/ws",
+        }
+    }
+
+    fn db_suffix(&self) -> &str {
+        match self {
+            BinanceMarketType::Spot => "",
+            BinanceMarketType::FuturesUm => "-UM",
+            BinanceMarketType::FuturesCm => "-CM",
+        }
+    }
+}
+
+/// ログの種別とCSVのレイアウト
+///   trades:    id, price, qty, quote_qty(cmはbase_qty), time, is_buyer_maker[, is_best_match]
+///   aggTrades: agg_trade_id, price, quantity, first_trade_id, last_trade_id, transact_time, is_buyer_maker[, is_best_match]
+/// spotはヘッダーなし、futuresはヘッダーありのファイルがある。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinanceDataType {
+    Trades,
+    AggTrades,
+}
+
+impl BinanceDataType {
+    pub fn from_str(data_type: &str) -> Result<Self, String> {
+        match data_type.to_lowercase().as_str() {
+            "trades" => Ok(BinanceDataType::Trades),
+            "aggtrades" => Ok(BinanceDataType::AggTrades),
+            _ => Err(format!("Unknown data type {} / use trades or aggTrades", data_type)),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            BinanceDataType::Trades => "trades",
+            BinanceDataType::AggTrades => "aggTrades",
+        }
+    }
+
+    /// WebSocketのストリーム名(<symbol>@trade / <symbol>@aggTrade)
+    fn ws_stream(&self) -> &str {
+        match self {
+            BinanceDataType::Trades => "trade",
+            BinanceDataType::AggTrades => "aggTrade",
+        }
+    }
+
+    fn db_suffix(&self) -> &str {
+        match self {
+            BinanceDataType::Trades => "",
+            BinanceDataType::AggTrades => "-AGG",
+        }
+    }
+
+    fn time_column(&self) -> usize {
+        match self {
+            BinanceDataType::Trades => 4,
+            BinanceDataType::AggTrades => 5,
+        }
+    }
+
+    fn buyer_maker_column(&self) -> usize {
+        match self {
+            BinanceDataType::Trades => 5,
+            BinanceDataType::AggTrades => 6,
+        }
+    }
+}
+
+/// マーケット種別・ログ種別ごとのDB(db_full_pathのexchange名) 例: BN, BN-UM, BN-CM-AGG
+pub fn binance_db_exchange_name(market_type: BinanceMarketType, data_type: BinanceDataType) -> String {
+    return format!("BN{}{}", market_type.db_suffix(), data_type.db_suffix());
+}
 
 #[derive(Debug)]
 #[pyclass(name = "_BinanceMarket")]
@@ -25,135 +135,173 @@ pub struct BinanceMarket {
     name: String,
     pub dummy: bool,
     pub db: TradeTable,
+    market_type: BinanceMarketType,
+    data_type: BinanceDataType,
+    #[pyo3(get, set)]
+    history_web_base: String, // テスト用にローカルのHTTPサーバへ切り替えられる
 }
 
 #[pymethods]
 impl BinanceMarket {
+    /// market_type: "spot" | "um" | "cm",  data_type: "trades" | "aggTrades"
     #[new]
-    pub fn new(market_name: &str, dummy: bool) -> Self {
-        let db_name = db_full_path("BN", &market_name);
-
-        let db = TradeTable::open(db_name.to_str().unwrap()).expect("cannot open db");
-        db.create_table_if_not_exists();
+    #[args(market_type = "\"spot\"", data_type = "\"trades\"")]
+    pub fn py_new(market_name: &str, dummy: bool, market_type: &str, data_type: &str) -> PyResult<Self> {
+        let market_type = BinanceMarketType::from_str(market_type).map_err(PyTypeError::new_err)?;
+        let data_type = BinanceDataType::from_str(data_type).map_err(PyTypeError::new_err)?;
 
-        return BinanceMarket {
-            name: market_name.to_string(),
-            dummy,
-            db,
-        };
+        return Ok(BinanceMarket::open(market_name, dummy, market_type, data_type));
     }
 
     pub fn download(&mut self, ndays: i64, force: bool) -> i64 {
-        let (tx, rx): (Sender<Vec<Trade>>, Receiver<Vec<Trade>>) = mpsc::channel();
+        return Market::download(self, ndays, force);
+    }
 
-        let mut download_rec: i64 = 0;
-        let from_time = NOW() - DAYS(ndays+1);
-        let to_time = NOW() - DAYS(1);
+    pub fn select_trades(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        return Market::select_trades(self, from_time, to_time);
+    }
 
-        let time_gap =
-            if force {
-                vec![TimeChunk{
-                    start: from_time,
-                    end: to_time
-                }]
-            }
-            else {
-            self.db
-                .select_gap_chunks(NOW() - DAYS(ndays + 1), NOW() - DAYS(1), HHMM(12, 0)) 
-            };
-        
-        let days_gap = TradeTable::time_chunks_to_days(&time_gap);
-        log::debug!("GAP TIME: {:?}", time_gap);        
-        log::debug!("GAP DAYS: {:?}", days_gap);                
-
-        let mut urls: Vec<String> = vec![];
-        for day in days_gap {
-            urls.push(self.make_historical_data_url_timestamp(day));
-        }
+    /// 約定をchunk_rows件ずつnumpy配列(time_stamp, price, size, order_side)で返すイテレータ
+    #[args(chunk_rows = "100000")]
+    pub fn iter_trades(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        chunk_rows: usize,
+    ) -> TradeIterator {
+        return Market::iter_trades(self, from_time, to_time, chunk_rows);
+    }
 
-        let _handle = thread::spawn(move || {
-            for url in urls {
-                log::debug!("download url = {}", url);
+    #[args(split_side = "true")]
+    pub fn ohlcvv(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        window_sec: i64,
+        split_side: bool,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        return Market::ohlcvv(self, from_time, to_time, window_sec, split_side);
+    }
 
-                let mut buffer: Vec<Trade> = vec![];
+    /// ダウンロード済みのログ(<market>-trades-YYYY-MM-DD.zip / <market>-aggTrades-YYYY-MM-DD.zip)をdirから読み込む。
+    ///   format: "trades" | "aggtrades" | "bybit"（<market>YYYY-MM-DD.csv.gz）、省略時はマーケットのデータ種別
+    /// 読み込み済みのファイルはforceを指定しない限りスキップする。
+    /// ファイルごとに(ファイル名, レコード数, 状態)を返す。
+    #[args(force = "false", format = "\"\"")]
+    pub fn import_log_dir(&mut self, dir: &str, force: bool, format: &str) -> PyResult<Vec<(String, i64, String)>> {
+        let format = if format.is_empty() {
+            match self.data_type {
+                BinanceDataType::Trades => ArchiveFormat::BinanceTradesZip,
+                BinanceDataType::AggTrades => ArchiveFormat::BinanceAggTradesZip,
+            }
+        } else {
+            ArchiveFormat::from_str(format).map_err(PyTypeError::new_err)?
+        };
 
-                let result = log_download(url.as_str(), false, |rec| {
-                    let trade = BinanceMarket::rec_to_trade(&rec);
+        let prefix = match format {
+            ArchiveFormat::BinanceTradesZip => format!("{}-trades-", self.name),
+            ArchiveFormat::BinanceAggTradesZip => format!("{}-aggTrades-", self.name),
+            ArchiveFormat::BybitCsvGz => self.name.clone(),
+        };
 
-                    buffer.push(trade);
+        return import_log_files(&mut self.db, Path::new(dir), prefix.as_str(), &[format], force)
+        .map_err(PyTypeError::new_err);
+    }
 
-                    if 2000 < buffer.len() {
-                        let _result = tx.send(buffer.to_vec());
-                        buffer.clear();
-                    }
-                    // TODO: check send error
-                });
+    /// 指定期間のログをParquet/Arrow IPCファイルへ書き出す。
+    #[args(format = "\"parquet\"")]
+    pub fn export(&mut self, from_time: MicroSec, to_time: MicroSec, path: &str, format: &str) -> PyResult<i64> {
+        let format = ExportFormat::from_str(format).map_err(PyTypeError::new_err)?;
 
-                if buffer.len() != 0 {
-                    let _result = tx.send(buffer.to_vec());
-                    buffer.clear();
-                }
+        return self
+            .db
+            .export_file(from_time, to_time, Path::new(path), format)
+            .map_err(PyTypeError::new_err);
+    }
 
-                match result {
-                    Ok(count) => {
-                        log::debug!("Downloaded rec = {} ", count);
-                        download_rec += count;
-                    }
-                    Err(e) => {
-                        log::error!("extract err = {}", e);
-                    }
-                }
-            }
+    /// 指定期間のログを１日１ファイルで書き出す（<dir>/<market>-YYYY-MM-DD.<ext>）
+    #[args(format = "\"parquet\"")]
+    pub fn export_daily(&mut self, from_time: MicroSec, to_time: MicroSec, dir: &str, format: &str) -> PyResult<Vec<String>> {
+        let format = ExportFormat::from_str(format).map_err(PyTypeError::new_err)?;
 
-            log::debug!("download rec = {}", download_rec);
-        });
+        return self
+            .db
+            .export_daily(from_time, to_time, Path::new(dir), self.name.as_str(), format)
+            .map_err(PyTypeError::new_err);
+    }
 
-        let mut insert_rec_no = 0;
+    /// export/export_dailyで書き出したファイルをDBへ読み込む（idが同じレコードは置き換え）
+    pub fn import_files(&mut self, paths: Vec<String>) -> PyResult<i64> {
+        let mut insert_rec = 0;
 
-        // TODO: sqlite クラスへ移動させて共通にする。
-        loop {
-            match rx.recv() {
-                Ok(trades) => {
-                    let result = &self.db.insert_records(&trades);
-                    match result {
-                        Ok(rec_no) => {
-                            insert_rec_no += rec_no;
-                        }
-                        Err(e) => {
-                            log::warn!("insert error {:?}", e);
-                        }
-                    }
-                }
-                Err(_e) => {
-                    break;
-                }
-            }
+        for path in paths {
+            insert_rec += self
+                .db
+                .import_file(Path::new(&path))
+                .map_err(PyTypeError::new_err)?;
         }
 
-        log::debug!("insert rec={}", insert_rec_no);
-
-        return insert_rec_no;
+        return Ok(insert_rec);
     }
 
-    pub fn select_trades(
+    /// 約定数・出来高・売買代金・不均衡で区切った足
+    ///   kind: "tick" | "volume" | "dollar" | "tick_imbalance" | "volume_imbalance"
+    pub fn bars(
         &mut self,
         from_time: MicroSec,
         to_time: MicroSec,
+        kind: &str,
+        threshold: f64,
     ) -> PyResult<Py<PyArray2<f64>>> {
-        return self.db.py_select_trades(from_time, to_time);
+        return Market::bars(self, from_time, to_time, kind, threshold);
     }
 
-    pub fn ohlcvv(
+    /// 足のテーブル(ohlcv_1s, ohlcv_1m, ohlcv_1h)を約定から作り直す（足のテーブルがない古いDBの移行用）。
+    pub fn migrate_ohlcv_tables(&mut self) -> PyResult<()> {
+        return Market::migrate_ohlcv_tables(self);
+    }
+
+    /// ログの品質を確認し、問題を(kind, start_time, end_time, id, value)のリストで返す。
+    #[args(gap_sec = "60", outlier_window = "100", outlier_rate = "0.05", min_day_rate = "0.5")]
+    pub fn audit(
         &mut self,
         from_time: MicroSec,
         to_time: MicroSec,
-        window_sec: i64,
-    ) -> PyResult<Py<PyArray2<f64>>> {
-        return self.db.py_ohlcvv(from_time, to_time, window_sec);
+        gap_sec: i64,
+        outlier_window: usize,
+        outlier_rate: f64,
+        min_day_rate: f64,
+    ) -> Vec<(String, MicroSec, MicroSec, String, f64)> {
+        return Market::audit(self, from_time, to_time, gap_sec, outlier_window, outlier_rate, min_day_rate);
+    }
+
+    /// FundingRate(時刻, レート)をDBへ登録する（BackTesterで利用）
+    pub fn insert_funding(&mut self, funding: Vec<(MicroSec, f64)>) -> PyResult<i64> {
+        return Market::insert_funding(self, funding);
+    }
+
+    pub fn select_funding(&mut self, from_time: MicroSec, to_time: MicroSec) -> Vec<(MicroSec, f64)> {
+        return Market::select_funding(self, from_time, to_time);
+    }
+
+    /// 板のファイル（Bybitの板アーカイブ、Binanceのdepth。JSON Linesを.zip/.gzのまま読める）をDBへ読み込む。
+    /// 読み込んだ板はBackTesterでDummySessionの板として再生される。
+    pub fn import_book(&mut self, paths: Vec<String>) -> PyResult<i64> {
+        return Market::import_book(self, paths);
+    }
+
+    /// time時点の板を([(bid価格, サイズ)], [(ask価格, サイズ)])で返す（先頭からdepth件）
+    #[args(depth = "10")]
+    pub fn book(&mut self, time: MicroSec, depth: usize) -> (Vec<(f64, f64)>, Vec<(f64, f64)>) {
+        return Market::book(self, time, depth);
     }
 
     pub fn info(&mut self) -> String {
-        return self.db.info();
+        return Market::info(self);
     }
 
     pub fn _repr_html_(&self) -> String {
@@ -161,9 +309,159 @@ impl BinanceMarket {
     }
 }
 
+impl Market for BinanceMarket {
+    fn exchange_name(&self) -> String {
+        return binance_db_exchange_name(self.market_type, self.data_type);
+    }
+
+    fn market_name(&self) -> String {
+        return self.name.clone();
+    }
+
+    fn db(&mut self) -> &mut TradeTable {
+        return &mut self.db;
+    }
+
+    fn into_db(self: Box<Self>) -> TradeTable {
+        return self.db;
+    }
+
+    fn history_url(&self, day: MicroSec) -> String {
+        return self.make_historical_data_url_timestamp(day);
+    }
+
+    fn log_has_header(&self) -> bool {
+        return false; // futuresのヘッダー行はrec_to_tradeでスキップする
+    }
+
+    fn record_parser(&self) -> RecordParser {
+        let data_type = self.data_type;
+
+        return Box::new(move |rec| BinanceMarket::rec_to_trade(data_type, rec));
+    }
+
+    /// 接続URLでストリームを指定するのでsubscribeは不要。pingはサーバから送られる。
+    fn ws_config(&self) -> WsConfig {
+        let url = format!(
+            "{}/{}@{}",
+            self.market_type.ws_base(),
+            self.name.to_lowercase(),
+            self.data_type.ws_stream()
+        );
+
+        return WsConfig::new(&url);
+    }
+
+    fn ws_parser(&self) -> WsParser {
+        return Box::new(BinanceMarket::ws_message_to_trades);
+    }
+}
+
+/// WebSocketの約定メッセージ(trade / aggTrade)
+/// {"e":"trade","E":123456789,"s":"BNBBTC","t":12345,"p":"0.001","q":"100","b":88,"a":50,"T":123456785,"m":true,"M":true}
+/// {"e":"aggTrade","E":123456789,"s":"BTCUSDT","a":5933014,"p":"0.001","q":"100","f":100,"l":105,"T":123456785,"m":true}
+#[derive(Debug, Deserialize)]
+struct BinanceWsTrade {
+    e: String,
+    #[serde(rename = "T")]
+    time: i64,
+    p: String,
+    q: String,
+    m: bool,
+    t: Option<i64>,
+    a: Option<i64>,
+}
+
+pub fn open_spot_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BinanceMarket::open(market_name, dummy, BinanceMarketType::Spot, BinanceDataType::Trades));
+}
+
+pub fn open_um_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BinanceMarket::open(market_name, dummy, BinanceMarketType::FuturesUm, BinanceDataType::Trades));
+}
+
+pub fn open_cm_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BinanceMarket::open(market_name, dummy, BinanceMarketType::FuturesCm, BinanceDataType::Trades));
+}
+
+pub fn open_spot_agg_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BinanceMarket::open(market_name, dummy, BinanceMarketType::Spot, BinanceDataType::AggTrades));
+}
+
+pub fn open_um_agg_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BinanceMarket::open(market_name, dummy, BinanceMarketType::FuturesUm, BinanceDataType::AggTrades));
+}
+
+pub fn open_cm_agg_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BinanceMarket::open(market_name, dummy, BinanceMarketType::FuturesCm, BinanceDataType::AggTrades));
+}
 
-const HISTORY_WEB_BASE: &str = "https://data.binance.vision/data/spot/daily/trades";
 impl BinanceMarket {
+    /// DBのスキーマバージョン
+    ///   1: is_buyer_makerの売買方向を修正（それ以前のspotのDBは売買方向が逆に登録されている）
+    pub const DB_VERSION: i64 = 1;
+
+    pub fn new(market_name: &str, dummy: bool) -> Self {
+        return BinanceMarket::open(market_name, dummy, BinanceMarketType::Spot, BinanceDataType::Trades);
+    }
+
+    pub fn open(
+        market_name: &str,
+        dummy: bool,
+        market_type: BinanceMarketType,
+        data_type: BinanceDataType,
+    ) -> Self {
+        let db_name = db_full_path(&binance_db_exchange_name(market_type, data_type), &market_name);
+
+        return BinanceMarket::open_db_path(market_name, dummy, market_type, data_type, db_name.to_str().unwrap());
+    }
+
+    pub fn open_db_path(
+        market_name: &str,
+        dummy: bool,
+        market_type: BinanceMarketType,
+        data_type: BinanceDataType,
+        db_path: &str,
+    ) -> Self {
+        let mut db = TradeTable::open(db_path).expect("cannot open db");
+        db.create_table_if_not_exists();
+        BinanceMarket::migrate_db(&mut db, market_type);
+
+        return BinanceMarket {
+            name: market_name.to_string(),
+            dummy,
+            db,
+            market_type,
+            data_type,
+            history_web_base: HISTORY_WEB_BASE.to_string(),
+        };
+    }
+
+    /// 古いバージョンのDBを移行する。
+    /// バージョン0のspotのDBは売買方向を反転する（futuresのDBはバージョン1から作成している）。
+    fn migrate_db(db: &mut TradeTable, market_type: BinanceMarketType) {
+        let version = db.schema_version();
+        if BinanceMarket::DB_VERSION <= version {
+            return;
+        }
+
+        if version < 1 && market_type == BinanceMarketType::Spot {
+            match db.migrate_flip_order_side(BinanceMarket::DB_VERSION) {
+                Ok(count) => {
+                    log::info!("migrate db version {} -> {}: flip order side of {} trades", version, BinanceMarket::DB_VERSION, count);
+                }
+                Err(e) => {
+                    log::error!("migrate db error {:?}", e);
+                }
+            }
+            return;
+        }
+
+        if let Err(e) = db.set_schema_version(BinanceMarket::DB_VERSION) {
+            log::error!("set db version error {:?}", e);
+        }
+    }
+
     fn make_historical_data_url_timestamp(&self, t: MicroSec) -> String {
         let timestamp = to_naive_datetime(t);
 
@@ -176,14 +474,29 @@ impl BinanceMarket {
 
     fn make_historical_data_url(&self, yyyy: i64, mm: i64, dd: i64) -> String {
         // https://data.binance.vision/data/spot/daily/trades/BTCBUSD/BTCBUSD-trades-2022-11-19.zip
+        // https://data.binance.vision/data/futures/um/daily/aggTrades/BTCUSDT/BTCUSDT-aggTrades-2022-11-19.zip
         return format!(
-            "{}/{}/{}-trades-{:04}-{:02}-{:02}.zip",
-            HISTORY_WEB_BASE, self.name, self.name, yyyy, mm, dd
+            "{}/{}/daily/{}/{}/{}-{}-{:04}-{:02}-{:02}.zip",
+            self.history_web_base,
+            self.market_type.url_path(),
+            self.data_type.name(),
+            self.name,
+            self.name,
+            self.data_type.name(),
+            yyyy,
+            mm,
+            dd
         );
     }
 
-    fn rec_to_trade(rec: &StringRecord) -> Trade {
+    /// CSVのレコードをTradeへ変換する。ヘッダー行の場合はNoneを返す。
+    /// is_buyer_maker=trueは売りのTakerが買いのMakerに約定したもの(Sell)。spotは"True"、futuresは"true"で表記される。
+    pub fn rec_to_trade(data_type: BinanceDataType, rec: &StringRecord) -> Option<Trade> {
         let id = rec.get(0).unwrap_or_default().to_string();
+        if id.parse::<i64>().is_err() {
+            return None;
+        }
+
         let price = rec
             .get(1)
             .unwrap_or_default()
@@ -195,21 +508,48 @@ impl BinanceMarket {
             .parse::<f64>()
             .unwrap_or_default();
         let timestamp = rec
-            .get(4)
+            .get(data_type.time_column())
             .unwrap_or_default()
             .parse::<MicroSec>()
             .unwrap_or_default()
             * 1_000;
-        let is_buyer_make = rec.get(5).unwrap_or_default();
-        let order_side = match is_buyer_make {
-            "True" => OrderSide::Buy,
-            "False" => OrderSide::Sell,
+        let is_buyer_maker = rec.get(data_type.buyer_maker_column()).unwrap_or_default();
+        let order_side = match is_buyer_maker.to_lowercase().as_str() {
+            "true" => OrderSide::Sell,
+            "false" => OrderSide::Buy,
             _ => OrderSide::Unknown,
         };
 
         let trade = Trade::new(timestamp, order_side, price, size, id);
 
-        return trade;
+        return Some(trade);
+    }
+
+    /// WebSocketのメッセージをTradeへ変換する。約定以外のメッセージ（subscribeの応答など）は空。
+    pub fn ws_message_to_trades(message: &str) -> Vec<Trade> {
+        let trade = match serde_json::from_str::<BinanceWsTrade>(message) {
+            Ok(t) => t,
+            Err(e) => {
+                log::debug!("skip message {} {:?}", message, e);
+                return vec![];
+            }
+        };
+
+        let id = match (trade.e.as_str(), trade.t, trade.a) {
+            ("trade", Some(id), _) => id,
+            ("aggTrade", _, Some(id)) => id,
+            _ => return vec![],
+        };
+
+        let order_side = if trade.m { OrderSide::Sell } else { OrderSide::Buy };
+
+        return vec![Trade::new(
+            trade.time * 1_000,
+            order_side,
+            trade.p.parse::<f64>().unwrap_or_default(),
+            trade.q.parse::<f64>().unwrap_or_default(),
+            id.to_string(),
+        )];
     }
 
     /*
@@ -326,6 +666,183 @@ mod binance_test {
         );
     }
 
+    #[test]
+    fn test_make_historical_data_url_futures() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("BTCUSDT.db");
+        let market = BinanceMarket::open_db_path(
+            "BTCUSDT",
+            true,
+            BinanceMarketType::FuturesUm,
+            BinanceDataType::AggTrades,
+            db_path.to_str().unwrap(),
+        );
+        assert_eq!(
+            market.make_historical_data_url(2022, 11, 19),
+            "https://data.binance.vision/data/futures/um/daily/aggTrades/BTCUSDT/BTCUSDT-aggTrades-2022-11-19.zip"
+        );
+
+        let db_path = dir.path().join("BTCUSD_PERP.db");
+        let market = BinanceMarket::open_db_path(
+            "BTCUSD_PERP",
+            true,
+            BinanceMarketType::FuturesCm,
+            BinanceDataType::Trades,
+            db_path.to_str().unwrap(),
+        );
+        assert_eq!(
+            market.make_historical_data_url(2022, 11, 19),
+            "https://data.binance.vision/data/futures/cm/daily/trades/BTCUSD_PERP/BTCUSD_PERP-trades-2022-11-19.zip"
+        );
+
+        assert_eq!(binance_db_exchange_name(BinanceMarketType::Spot, BinanceDataType::Trades), "BN");
+        assert_eq!(binance_db_exchange_name(BinanceMarketType::FuturesCm, BinanceDataType::AggTrades), "BN-CM-AGG");
+    }
+
+    #[test]
+    fn test_rec_to_trade() {
+        // spot trades (ヘッダーなし)
+        let rec = StringRecord::from(vec!["1", "16000.0", "0.1", "1600.0", "1668816000000", "True", "True"]);
+        let trade = BinanceMarket::rec_to_trade(BinanceDataType::Trades, &rec).unwrap();
+        assert_eq!(trade.time, 1668816000000_000);
+        assert_eq!(trade.order_side, OrderSide::Sell);
+        assert_eq!(trade.price, 16000.0);
+        assert_eq!(trade.size, 0.1);
+        assert_eq!(trade.id, "1");
+
+        // futures aggTrades (ヘッダーあり)
+        let header = StringRecord::from(vec![
+            "agg_trade_id", "price", "quantity", "first_trade_id", "last_trade_id", "transact_time", "is_buyer_maker",
+        ]);
+        assert!(BinanceMarket::rec_to_trade(BinanceDataType::AggTrades, &header).is_none());
+
+        let rec = StringRecord::from(vec!["2", "16001.0", "0.2", "10", "12", "1668816000001", "false"]);
+        let trade = BinanceMarket::rec_to_trade(BinanceDataType::AggTrades, &rec).unwrap();
+        assert_eq!(trade.time, 1668816000001_000);
+        assert_eq!(trade.order_side, OrderSide::Buy);
+        assert_eq!(trade.size, 0.2);
+    }
+
+    /// 売買方向が逆に登録されたバージョン0のspotのDBは、開いたときに一度だけ反転する。
+    #[test]
+    fn test_migrate_spot_order_side() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("BTCBUSD.db");
+        let db_path = db_path.to_str().unwrap();
+
+        {
+            let mut db = TradeTable::open(db_path).unwrap();
+            db.create_table_if_not_exists();
+            db.insert_records(&vec![
+                Trade::new(1_000_000, OrderSide::Buy, 16000.0, 0.1, "1".to_string()),
+                Trade::new(2_000_000, OrderSide::Sell, 16001.0, 0.2, "2".to_string()),
+            ])
+            .unwrap();
+            assert_eq!(db.schema_version(), 0);
+        }
+
+        let sides = |market: &mut BinanceMarket| -> Vec<OrderSide> {
+            let trades = market.db.select_trades_vec(0, 3_000_000);
+            trades.iter().map(|t| t.order_side).collect()
+        };
+
+        let mut market = BinanceMarket::open_db_path("BTCBUSD", true, BinanceMarketType::Spot, BinanceDataType::Trades, db_path);
+        assert_eq!(market.db.schema_version(), BinanceMarket::DB_VERSION);
+        assert_eq!(sides(&mut market), vec![OrderSide::Sell, OrderSide::Buy]);
+        drop(market);
+
+        // 移行済みのDBはそのまま
+        let mut market = BinanceMarket::open_db_path("BTCBUSD", true, BinanceMarketType::Spot, BinanceDataType::Trades, db_path);
+        assert_eq!(sides(&mut market), vec![OrderSide::Sell, OrderSide::Buy]);
+
+        // futuresのDBは反転しない
+        let db_path = dir.path().join("BTCUSDT.db");
+        let db_path = db_path.to_str().unwrap();
+        {
+            let mut db = TradeTable::open(db_path).unwrap();
+            db.create_table_if_not_exists();
+            db.insert_records(&vec![Trade::new(1_000_000, OrderSide::Buy, 16000.0, 0.1, "1".to_string())])
+                .unwrap();
+        }
+        let mut market = BinanceMarket::open_db_path("BTCUSDT", true, BinanceMarketType::FuturesUm, BinanceDataType::Trades, db_path);
+        assert_eq!(market.db.schema_version(), BinanceMarket::DB_VERSION);
+        assert_eq!(sides(&mut market), vec![OrderSide::Buy]);
+    }
+
+    #[test]
+    fn test_ws_message_to_trades() {
+        let message = r#"{"e":"trade","E":123456789,"s":"BNBBTC","t":12345,"p":"0.001","q":"100","b":88,"a":50,"T":123456785,"m":true,"M":true}"#;
+        let trades = BinanceMarket::ws_message_to_trades(message);
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].time, 123456785_000);
+        assert_eq!(trades[0].order_side, OrderSide::Sell);
+        assert_eq!((trades[0].price, trades[0].size), (0.001, 100.0));
+        assert_eq!(trades[0].id, "12345");
+
+        let message = r#"{"e":"aggTrade","E":123456789,"s":"BTCUSDT","a":5933014,"p":"0.001","q":"100","f":100,"l":105,"T":123456785,"m":false}"#;
+        let trades = BinanceMarket::ws_message_to_trades(message);
+        assert_eq!(trades[0].order_side, OrderSide::Buy);
+        assert_eq!(trades[0].id, "5933014");
+
+        assert!(BinanceMarket::ws_message_to_trades(r#"{"result":null,"id":1}"#).is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let market = BinanceMarket::open_db_path(
+            "BTCUSDT",
+            true,
+            BinanceMarketType::FuturesUm,
+            BinanceDataType::AggTrades,
+            dir.path().join("ws.db").to_str().unwrap(),
+        );
+        assert_eq!(market.ws_config().url, "wss://fstream.binance.com/ws/btcusdt@aggTrade");
+    }
+
+    /// ローカルのHTTPサーバからfuturesのaggTradesをダウンロードする
+    #[test]
+    fn test_download_futures_from_local_server() {
+        use crate::exchange::rest::test_rest::{mock_http_server, request_paths};
+        use std::io::Write;
+
+        let mut zip_data = std::io::Cursor::new(Vec::new());
+        {
+            let mut zip = zip::ZipWriter::new(&mut zip_data);
+            zip.start_file("BTCUSDT-aggTrades.csv", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"agg_trade_id,price,quantity,first_trade_id,last_trade_id,transact_time,is_buyer_maker\n").unwrap();
+            zip.write_all(b"1,16000.0,0.1,10,11,1668816000000,true\n").unwrap();
+            zip.write_all(b"2,16001.0,0.2,12,12,1668816000001,false\n").unwrap();
+            zip.finish().unwrap();
+        }
+        let zip_data = zip_data.into_inner();
+
+        let (url, requests) = mock_http_server(move |_method, _path| (200, "application/zip", zip_data.clone()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("BTCUSDT.db");
+        let mut market = BinanceMarket::open_db_path(
+            "BTCUSDT",
+            true,
+            BinanceMarketType::FuturesUm,
+            BinanceDataType::AggTrades,
+            db_path.to_str().unwrap(),
+        );
+        market.history_web_base = url;
+
+        market.download(1, true);
+
+        let paths = request_paths(&requests);
+        assert!(paths.len() != 0);
+        for path in paths.iter() {
+            assert!(path.starts_with("/futures/um/daily/aggTrades/BTCUSDT/BTCUSDT-aggTrades-"));
+            assert!(path.ends_with(".zip"));
+        }
+
+        let mut trades: Vec<Trade> = vec![];
+        market.db.select(0, 0, |t| trades.push(t.clone()));
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].order_side, OrderSide::Sell);
+        assert_eq!(trades[1].order_side, OrderSide::Buy);
+    }
+
     /*
     #[tokio::test]
     async fn test_download_async_function() {
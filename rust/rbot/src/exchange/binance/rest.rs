@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+
+use reqwest::blocking::RequestBuilder;
+use reqwest::Method;
+use serde_derive::Deserialize;
+
+use crate::common::order::{Order, OrderSide, OrderStatus};
+use crate::common::time::{MicroSec, DAYS, NOW, SEC};
+use crate::exchange::rest::{hmac_sha256_hex, make_query, Fill, OrderClient, RestError};
+
+const REST_BASE: &str = "https://api.binance.com";
+
+/// Binance(spot)の署名付きオーダーAPI
+/// オーダーサイズ(home通貨単位)は価格で割って取引所の数量(quantity, foreign通貨単位)へ変換する。
+/// 成行はquoteOrderQtyでhome通貨単位のまま送る。価格と数量はexchangeInfoの刻みに丸める。
+#[derive(Debug)]
+pub struct BinanceOrderClient {
+    symbol: String,
+    api_key: String,
+    api_secret: String,
+    rest_base: String,
+    recv_window: i64,
+    start_time: MicroSec, // 約定を取得するまでは、この時刻から24時間以内の約定を取得する
+    last_trade_id: Option<i64>,
+    order_ids: HashMap<i64, String>, // 取引所のorderId -> 発注時のオーダーID
+    filter: Option<SymbolFilter>,    // 最初の発注時にexchangeInfoから取得する
+    client: reqwest::blocking::Client,
+}
+
+/// 価格・数量の刻み（"0.01000000"のような文字列から作る）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    size: f64,
+    decimals: usize,
+}
+
+impl Step {
+    pub fn new(step: &str) -> Self {
+        let decimals = match step.trim_end_matches('0').split_once('.') {
+            Some((_int, frac)) => frac.len(),
+            None => 0,
+        };
+
+        return Step {
+            size: step.parse::<f64>().unwrap_or_default(),
+            decimals,
+        };
+    }
+
+    pub fn from_decimals(decimals: usize) -> Self {
+        return Step {
+            size: 10f64.powi(-(decimals as i32)),
+            decimals,
+        };
+    }
+
+    /// 刻みの倍数へ切り捨てる
+    pub fn floor(&self, value: f64) -> String {
+        return self.format(value, f64::floor);
+    }
+
+    /// 刻みの倍数へ切り上げる
+    pub fn ceil(&self, value: f64) -> String {
+        return self.format(value, f64::ceil);
+    }
+
+    /// 浮動小数点の誤差で1刻みずれないよう、刻みの倍数に十分近い場合はその倍数にする。
+    fn format(&self, value: f64, round: fn(f64) -> f64) -> String {
+        if self.size == 0.0 {
+            return "0".to_string();
+        }
+
+        let units = value / self.size;
+        let units = if (units - units.round()).abs() < 1e-6 {
+            units.round()
+        } else {
+            round(units)
+        };
+
+        return format!("{:.*}", self.decimals, units * self.size);
+    }
+}
+
+/// シンボルの価格・数量の刻み
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolFilter {
+    pub tick_size: Step,  // PRICE_FILTER.tickSize
+    pub step_size: Step,  // LOT_SIZE.stepSize
+    pub quote_step: Step, // quoteAssetPrecision（成行のquoteOrderQty）
+}
+
+impl SymbolFilter {
+    /// Makerとして板に載るよう、買いは切り捨て・売りは切り上げる。
+    pub fn price(&self, side: OrderSide, price: f64) -> String {
+        match side {
+            OrderSide::Buy => self.tick_size.floor(price),
+            _ => self.tick_size.ceil(price),
+        }
+    }
+
+    /// オーダーサイズを超えないよう切り捨てる。
+    pub fn quantity(&self, quantity: f64) -> String {
+        return self.step_size.floor(quantity);
+    }
+
+    pub fn quote_quantity(&self, quote: f64) -> String {
+        return self.quote_step.floor(quote);
+    }
+}
+
+/// GET /api/v3/exchangeInfo
+#[derive(Debug, Deserialize)]
+struct BinanceExchangeInfo {
+    symbols: Vec<BinanceSymbolInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinanceSymbolInfo {
+    symbol: String,
+    #[serde(rename = "quoteAssetPrecision")]
+    quote_asset_precision: usize,
+    filters: Vec<BinanceSymbolFilter>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinanceSymbolFilter {
+    #[serde(rename = "filterType")]
+    filter_type: String,
+    #[serde(rename = "tickSize")]
+    tick_size: Option<String>,
+    #[serde(rename = "stepSize")]
+    step_size: Option<String>,
+}
+
+/// POST /api/v3/order (newOrderRespType=ACK), GET /api/v3/order
+#[derive(Debug, Deserialize)]
+struct BinanceOrderAck {
+    #[serde(rename = "orderId")]
+    order_id: i64,
+    #[serde(rename = "clientOrderId")]
+    client_order_id: String,
+}
+
+/// GET /api/v3/myTrades
+#[derive(Debug, Deserialize)]
+struct BinanceMyTrade {
+    id: i64,
+    #[serde(rename = "orderId")]
+    order_id: i64,
+    price: String,
+    qty: String,
+    time: i64,
+    #[serde(rename = "isMaker")]
+    is_maker: bool,
+}
+
+/// {"code":-2010,"msg":"Order would immediately match and take."}
+#[derive(Debug, Deserialize)]
+struct BinanceError {
+    code: i64,
+    msg: String,
+}
+
+impl BinanceOrderClient {
+    pub fn new(symbol: &str, api_key: &str, api_secret: &str) -> Self {
+        return BinanceOrderClient {
+            symbol: symbol.to_ascii_uppercase(),
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            rest_base: REST_BASE.to_string(),
+            recv_window: 5000,
+            start_time: NOW(),
+            last_trade_id: None,
+            order_ids: HashMap::new(),
+            filter: None,
+            client: reqwest::blocking::Client::new(),
+        };
+    }
+
+    /// timestamp, recvWindow, signatureを付けてリクエストし、レスポンスのbodyを返す。
+    fn signed_request(&self, method: Method, path: &str, params: &[(&str, String)]) -> Result<String, RestError> {
+        let mut params = params.to_vec();
+        params.push(("recvWindow", self.recv_window.to_string()));
+        params.push(("timestamp", (NOW() / 1_000).to_string()));
+
+        let query = make_query(&params);
+        let signature = hmac_sha256_hex(&self.api_secret, &query);
+        let url = format!("{}{}?{}&signature={}", self.rest_base, path, query, signature);
+
+        let request = self.client.request(method, &url).header("X-MBX-APIKEY", &self.api_key);
+
+        return self.send(path, request);
+    }
+
+    /// 署名なしのGETリクエスト
+    fn public_request(&self, path: &str, params: &[(&str, String)]) -> Result<String, RestError> {
+        let url = format!("{}{}?{}", self.rest_base, path, make_query(params));
+
+        return self.send(path, self.client.get(&url));
+    }
+
+    fn send(&self, path: &str, request: RequestBuilder) -> Result<String, RestError> {
+        let response = request
+            .send()
+            .map_err(|e| RestError::new(OrderStatus::Error, &e.to_string()))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .map_err(|e| RestError::new(OrderStatus::Error, &e.to_string()))?;
+
+        if status.is_success() {
+            return Ok(body);
+        }
+
+        log::warn!("binance rest error {} {} {}", path, status, body);
+
+        return Err(BinanceOrderClient::parse_error(&body));
+    }
+
+    /// エラーをOrderResultのステータスへ変換する。
+    pub fn parse_error(body: &str) -> RestError {
+        let error = match serde_json::from_str::<BinanceError>(body) {
+            Ok(e) => e,
+            Err(_e) => {
+                return RestError::new(OrderStatus::Error, body);
+            }
+        };
+
+        let msg = error.msg.to_lowercase();
+
+        let status = match error.code {
+            -2010 if msg.contains("immediately match") => OrderStatus::PostOnlyError,
+            -2010 if msg.contains("insufficient balance") => OrderStatus::NoMoney,
+            -2011 | -2013 => OrderStatus::NoAction, // Unknown order sent / Order does not exist
+            _ => OrderStatus::Error,
+        };
+
+        return RestError::new(status, &error.msg);
+    }
+
+    fn parse_json<'a, T: serde::Deserialize<'a>>(body: &'a str) -> Result<T, RestError> {
+        return serde_json::from_str::<T>(body).map_err(|e| RestError::new(OrderStatus::Error, &e.to_string()));
+    }
+
+    /// exchangeInfoのレスポンスからシンボルの刻みを取り出す。
+    pub fn parse_symbol_filter(body: &str, symbol: &str) -> Result<SymbolFilter, RestError> {
+        let info: BinanceExchangeInfo = BinanceOrderClient::parse_json(body)?;
+
+        let symbol_info = info
+            .symbols
+            .into_iter()
+            .find(|s| s.symbol == symbol)
+            .ok_or(RestError::new(OrderStatus::Error, &format!("Unknown symbol {}", symbol)))?;
+
+        let find_step = |filter_type: &str, step: fn(&BinanceSymbolFilter) -> &Option<String>| -> Result<Step, RestError> {
+            return symbol_info
+                .filters
+                .iter()
+                .find(|f| f.filter_type == filter_type)
+                .and_then(|f| step(f).as_deref())
+                .map(Step::new)
+                .ok_or(RestError::new(OrderStatus::Error, &format!("{} is not found for {}", filter_type, symbol)));
+        };
+
+        return Ok(SymbolFilter {
+            tick_size: find_step("PRICE_FILTER", |f| &f.tick_size)?,
+            step_size: find_step("LOT_SIZE", |f| &f.step_size)?,
+            quote_step: Step::from_decimals(symbol_info.quote_asset_precision),
+        });
+    }
+
+    fn symbol_filter(&mut self) -> Result<SymbolFilter, RestError> {
+        if let Some(filter) = &self.filter {
+            return Ok(filter.clone());
+        }
+
+        let body = self.public_request("/api/v3/exchangeInfo", &[("symbol", self.symbol.clone())])?;
+        let filter = BinanceOrderClient::parse_symbol_filter(&body, &self.symbol)?;
+        self.filter = Some(filter.clone());
+
+        return Ok(filter);
+    }
+
+    /// クライアントオーダーIDでオーダーを問い合わせ、取引所のorderIdを返す。
+    /// オーダーがない場合はNoActionのエラー。
+    fn query_order(&self, client_order_id: &str) -> Result<i64, RestError> {
+        let params: Vec<(&str, String)> = vec![
+            ("symbol", self.symbol.clone()),
+            ("origClientOrderId", client_order_id.to_string()),
+        ];
+
+        let body = self.signed_request(Method::GET, "/api/v3/order", &params)?;
+        let order: BinanceOrderAck = BinanceOrderClient::parse_json(&body)?;
+
+        return Ok(order.order_id);
+    }
+}
+
+impl OrderClient for BinanceOrderClient {
+    fn rest_base(&self) -> String {
+        return self.rest_base.clone();
+    }
+
+    fn set_rest_base(&mut self, rest_base: &str) {
+        self.rest_base = rest_base.trim_end_matches('/').to_string();
+    }
+
+    fn new_order(&mut self, order: &Order) -> Result<Order, RestError> {
+        let filter = self.symbol_filter()?;
+        let mut accepted = order.clone();
+
+        let mut params: Vec<(&str, String)> = vec![
+            ("symbol", self.symbol.clone()),
+            ("side", order.order_side.to_string().to_uppercase()),
+        ];
+
+        if order.price == 0.0 {
+            let quote_quantity = filter.quote_quantity(order.size);
+            accepted.size = quote_quantity.parse::<f64>().unwrap_or_default();
+
+            params.push(("type", "MARKET".to_string()));
+            params.push(("quoteOrderQty", quote_quantity));
+        } else {
+            let quantity = filter.quantity(order.size / order.price);
+            if quantity.parse::<f64>().unwrap_or_default() == 0.0 {
+                return Err(RestError::new(
+                    OrderStatus::Error,
+                    &format!("Order size {} is less than the step size at price {}", order.size, order.price),
+                ));
+            }
+
+            if order.post_only {
+                params.push(("type", "LIMIT_MAKER".to_string()));
+            } else {
+                params.push(("type", "LIMIT".to_string()));
+                params.push(("timeInForce", "GTC".to_string()));
+            }
+            // 取引所に載るのは丸めた価格×丸めた数量
+            let price = filter.price(order.order_side, order.price);
+            accepted.price = price.parse::<f64>().unwrap_or_default();
+            accepted.size = accepted.price * quantity.parse::<f64>().unwrap_or_default();
+
+            params.push(("price", price));
+            params.push(("quantity", quantity));
+        }
+        accepted.remain_size = accepted.size;
+
+        params.push(("newClientOrderId", order.order_id.clone()));
+        params.push(("newOrderRespType", "ACK".to_string()));
+
+        let error = match self.signed_request(Method::POST, "/api/v3/order", &params) {
+            Ok(body) => {
+                let ack: BinanceOrderAck = BinanceOrderClient::parse_json(&body)?;
+                self.order_ids.insert(ack.order_id, ack.client_order_id);
+
+                return Ok(accepted);
+            }
+            Err(e) if e.status == OrderStatus::Error => e,
+            Err(e) => {
+                return Err(e);
+            }
+        };
+
+        // 通信エラーなどで受け付けられたか分からない場合は、クライアントオーダーIDで問い合わせる。
+        match self.query_order(&order.order_id) {
+            Ok(order_id) => {
+                log::warn!("order {} is accepted after error {:?}", order.order_id, error);
+                self.order_ids.insert(order_id, order.order_id.clone());
+
+                return Ok(accepted);
+            }
+            Err(e) => {
+                if e.status != OrderStatus::NoAction {
+                    log::error!("order {} may be accepted / query error {:?}", order.order_id, e);
+                }
+
+                return Err(error);
+            }
+        }
+    }
+
+    fn cancel_order(&mut self, order_id: &str) -> Result<(), RestError> {
+        let params: Vec<(&str, String)> = vec![
+            ("symbol", self.symbol.clone()),
+            ("origClientOrderId", order_id.to_string()),
+        ];
+
+        self.signed_request(Method::DELETE, "/api/v3/order", &params)?;
+
+        return Ok(());
+    }
+
+    /// 約定を取得するまではstartTime, endTime（24時間以内）で取得し、以降はfromIdで続きを取得する。
+    fn fetch_fills(&mut self) -> Result<Vec<Fill>, RestError> {
+        let mut params: Vec<(&str, String)> = vec![("symbol", self.symbol.clone())];
+
+        let end_time = (self.start_time + DAYS(1) - 1_000).min(NOW());
+
+        match self.last_trade_id {
+            Some(id) => params.push(("fromId", (id + 1).to_string())),
+            None => {
+                params.push(("startTime", (self.start_time / 1_000).to_string()));
+                params.push(("endTime", (end_time / 1_000).to_string()));
+            }
+        }
+        params.push(("limit", "1000".to_string()));
+
+        let body = self.signed_request(Method::GET, "/api/v3/myTrades", &params)?;
+        let trades: Vec<BinanceMyTrade> = BinanceOrderClient::parse_json(&body)?;
+
+        // 約定がなければ期間を進める（反映が遅れた約定を取りこぼさないよう1分重ねる）
+        if self.last_trade_id.is_none() && trades.is_empty() {
+            self.start_time = self.start_time.max(end_time - SEC(60));
+        }
+
+        let mut fills: Vec<Fill> = vec![];
+
+        for t in trades {
+            self.last_trade_id = Some(self.last_trade_id.map_or(t.id, |id| id.max(t.id)));
+
+            // このセッション以外で発注したオーダーの約定は無視する
+            let order_id = match self.order_ids.get(&t.order_id) {
+                Some(id) => id.clone(),
+                None => {
+                    log::debug!("skip fill of unknown order {}", t.order_id);
+                    continue;
+                }
+            };
+
+            fills.push(Fill {
+                order_id,
+                trade_id: t.id,
+                time: t.time * 1_000,
+                price: t.price.parse::<f64>().unwrap_or_default(),
+                size: t.qty.parse::<f64>().unwrap_or_default(),
+                is_maker: t.is_maker,
+            });
+        }
+
+        return Ok(fills);
+    }
+}
+
+#[cfg(test)]
+pub mod test_binance_rest {
+    use super::*;
+
+    #[test]
+    fn test_parse_error() {
+        let e = BinanceOrderClient::parse_error(r#"{"code":-2010,"msg":"Order would immediately match and take."}"#);
+        assert_eq!(e.status, OrderStatus::PostOnlyError);
+
+        let e = BinanceOrderClient::parse_error(
+            r#"{"code":-2010,"msg":"Account has insufficient balance for requested action."}"#,
+        );
+        assert_eq!(e.status, OrderStatus::NoMoney);
+
+        let e = BinanceOrderClient::parse_error(r#"{"code":-2011,"msg":"Unknown order sent."}"#);
+        assert_eq!(e.status, OrderStatus::NoAction);
+
+        let e = BinanceOrderClient::parse_error("<html>502 Bad Gateway</html>");
+        assert_eq!(e.status, OrderStatus::Error);
+    }
+
+    #[test]
+    fn test_symbol_filter() {
+        let filter = BinanceOrderClient::parse_symbol_filter(EXCHANGE_INFO, "BTCUSDT").unwrap();
+
+        assert_eq!(filter.price(OrderSide::Buy, 20_000.129), "20000.12");
+        assert_eq!(filter.price(OrderSide::Sell, 20_000.121), "20000.13");
+        assert_eq!(filter.price(OrderSide::Buy, 20_000.12), "20000.12");
+        assert_eq!(filter.price(OrderSide::Sell, 20_000.12), "20000.12");
+
+        // 100(USDT) / 20000 = 0.005(BTC)
+        assert_eq!(filter.quantity(100.0 / 20_000.0), "0.00500");
+        assert_eq!(filter.quantity(0.0000099), "0.00000");
+        assert_eq!(filter.quote_quantity(100.123456789), "100.12345678");
+
+        assert!(BinanceOrderClient::parse_symbol_filter(EXCHANGE_INFO, "ETHUSDT").is_err());
+    }
+
+    pub const EXCHANGE_INFO: &str = r#"{"timezone":"UTC","serverTime":1000,"symbols":[{"symbol":"BTCUSDT","status":"TRADING","baseAsset":"BTC","baseAssetPrecision":8,"quoteAsset":"USDT","quotePrecision":8,"quoteAssetPrecision":8,"filters":[{"filterType":"PRICE_FILTER","minPrice":"0.01000000","maxPrice":"1000000.00000000","tickSize":"0.01000000"},{"filterType":"LOT_SIZE","minQty":"0.00001000","maxQty":"9000.00000000","stepSize":"0.00001000"},{"filterType":"MIN_NOTIONAL","minNotional":"10.00000000","applyToMarket":true,"avgPriceMins":5}]}]}"#;
+}
@@ -0,0 +1,396 @@
+use std::path::Path;
+
+use chrono::Datelike;
+use csv::StringRecord;
+use numpy::PyArray2;
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+
+use crate::common::order::{OrderSide, Trade};
+use crate::common::time::{to_naive_datetime, MicroSec};
+use crate::db::sqlite::TradeTable;
+use crate::db::stream::TradeIterator;
+use crate::fs::db_full_path;
+
+use super::market::{Market, RecordParser};
+use super::ws::{WsConfig, WsParser};
+use super::{import_log_files, ArchiveFormat};
+use serde_derive::Deserialize;
+
+const HISTORY_WEB_BASE: &str = "https://public.bybit.com/trading";
+const WS_LINEAR: &str = "wss://stream.bybit.com/v5/public/linear";
+const WS_INVERSE: &str = "wss://stream.bybit.com/v5/public/inverse";
+
+#[derive(Debug)]
+#[pyclass(name = "_BybitMarket")]
+pub struct BybitMarket {
+    name: String,
+    pub dummy: bool,
+    pub db: TradeTable,
+    #[pyo3(get, set)]
+    history_web_base: String, // テスト用にローカルのHTTPサーバへ切り替えられる
+}
+
+#[pymethods]
+impl BybitMarket {
+    #[new]
+    pub fn new(market_name: &str, dummy: bool) -> Self {
+        let db_name = db_full_path("BB", &market_name);
+
+        return BybitMarket::open_db_path(market_name, dummy, db_name.to_str().unwrap());
+    }
+
+    pub fn download(&mut self, ndays: i64, force: bool) -> i64 {
+        return Market::download(self, ndays, force);
+    }
+
+    pub fn select_trades(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        return Market::select_trades(self, from_time, to_time);
+    }
+
+    /// 約定をchunk_rows件ずつnumpy配列(time_stamp, price, size, order_side)で返すイテレータ
+    #[args(chunk_rows = "100000")]
+    pub fn iter_trades(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        chunk_rows: usize,
+    ) -> TradeIterator {
+        return Market::iter_trades(self, from_time, to_time, chunk_rows);
+    }
+
+    #[args(split_side = "true")]
+    pub fn ohlcvv(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        window_sec: i64,
+        split_side: bool,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        return Market::ohlcvv(self, from_time, to_time, window_sec, split_side);
+    }
+
+    /// ダウンロード済みのログ(<market>YYYY-MM-DD.csv.gz)をdirから読み込む。
+    #[args(force = "false")]
+    pub fn import_log_dir(&mut self, dir: &str, force: bool) -> PyResult<Vec<(String, i64, String)>> {
+        return import_log_files(&mut self.db, Path::new(dir), self.name.as_str(), &[ArchiveFormat::BybitCsvGz], force)
+            .map_err(PyTypeError::new_err);
+    }
+
+    /// 約定数・出来高・売買代金・不均衡で区切った足
+    ///   kind: "tick" | "volume" | "dollar" | "tick_imbalance" | "volume_imbalance"
+    pub fn bars(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        kind: &str,
+        threshold: f64,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        return Market::bars(self, from_time, to_time, kind, threshold);
+    }
+
+    /// 足のテーブル(ohlcv_1s, ohlcv_1m, ohlcv_1h)を約定から作り直す（足のテーブルがない古いDBの移行用）。
+    pub fn migrate_ohlcv_tables(&mut self) -> PyResult<()> {
+        return Market::migrate_ohlcv_tables(self);
+    }
+
+    /// ログの品質を確認し、問題を(kind, start_time, end_time, id, value)のリストで返す。
+    #[args(gap_sec = "60", outlier_window = "100", outlier_rate = "0.05", min_day_rate = "0.5")]
+    pub fn audit(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        gap_sec: i64,
+        outlier_window: usize,
+        outlier_rate: f64,
+        min_day_rate: f64,
+    ) -> Vec<(String, MicroSec, MicroSec, String, f64)> {
+        return Market::audit(self, from_time, to_time, gap_sec, outlier_window, outlier_rate, min_day_rate);
+    }
+
+    /// FundingRate(時刻, レート)をDBへ登録する（BackTesterで利用）
+    pub fn insert_funding(&mut self, funding: Vec<(MicroSec, f64)>) -> PyResult<i64> {
+        return Market::insert_funding(self, funding);
+    }
+
+    pub fn select_funding(&mut self, from_time: MicroSec, to_time: MicroSec) -> Vec<(MicroSec, f64)> {
+        return Market::select_funding(self, from_time, to_time);
+    }
+
+    /// 板のファイル（Bybitの板アーカイブ、Binanceのdepth。JSON Linesを.zip/.gzのまま読める）をDBへ読み込む。
+    /// 読み込んだ板はBackTesterでDummySessionの板として再生される。
+    pub fn import_book(&mut self, paths: Vec<String>) -> PyResult<i64> {
+        return Market::import_book(self, paths);
+    }
+
+    /// time時点の板を([(bid価格, サイズ)], [(ask価格, サイズ)])で返す（先頭からdepth件）
+    #[args(depth = "10")]
+    pub fn book(&mut self, time: MicroSec, depth: usize) -> (Vec<(f64, f64)>, Vec<(f64, f64)>) {
+        return Market::book(self, time, depth);
+    }
+
+    pub fn info(&mut self) -> String {
+        return Market::info(self);
+    }
+
+    pub fn _repr_html_(&self) -> String {
+        return self.db._repr_html_();
+    }
+}
+
+impl Market for BybitMarket {
+    fn exchange_name(&self) -> String {
+        return "BB".to_string();
+    }
+
+    fn market_name(&self) -> String {
+        return self.name.clone();
+    }
+
+    fn db(&mut self) -> &mut TradeTable {
+        return &mut self.db;
+    }
+
+    fn into_db(self: Box<Self>) -> TradeTable {
+        return self.db;
+    }
+
+    fn history_url(&self, day: MicroSec) -> String {
+        return self.make_historical_data_url_timestamp(day);
+    }
+
+    fn log_has_header(&self) -> bool {
+        return true;
+    }
+
+    fn record_parser(&self) -> RecordParser {
+        return Box::new(|rec| Some(rec_to_trade(rec)));
+    }
+
+    /// USDT/USDC建て(BTCUSDT, BTCPERP)はlinear、それ以外(BTCUSD)はinverse。pingは20秒ごとに送る。
+    fn ws_config(&self) -> WsConfig {
+        let name = self.name.to_uppercase();
+        let url = if name.ends_with("USDT") || name.ends_with("PERP") {
+            WS_LINEAR
+        } else {
+            WS_INVERSE
+        };
+
+        let mut config = WsConfig::new(url);
+        config.subscribe = vec![format!(r#"{{"op":"subscribe","args":["publicTrade.{}"]}}"#, name)];
+        config.ping = Some(r#"{"op":"ping"}"#.to_string());
+
+        return config;
+    }
+
+    fn ws_parser(&self) -> WsParser {
+        return Box::new(ws_message_to_trades);
+    }
+}
+
+pub fn open_market(market_name: &str, dummy: bool) -> Box<dyn Market> {
+    return Box::new(BybitMarket::new(market_name, dummy));
+}
+
+impl BybitMarket {
+    pub fn open_db_path(market_name: &str, dummy: bool, db_path: &str) -> Self {
+        let db = TradeTable::open(db_path).expect("cannot open db");
+        db.create_table_if_not_exists();
+
+        return BybitMarket {
+            name: market_name.to_string(),
+            dummy,
+            db,
+            history_web_base: HISTORY_WEB_BASE.to_string(),
+        };
+    }
+
+    fn make_historical_data_url_timestamp(&self, t: MicroSec) -> String {
+        let timestamp = to_naive_datetime(t);
+
+        return self.make_historical_data_url(
+            timestamp.year() as i64,
+            timestamp.month() as i64,
+            timestamp.day() as i64,
+        );
+    }
+
+    fn make_historical_data_url(&self, yyyy: i64, mm: i64, dd: i64) -> String {
+        // https://public.bybit.com/trading/BTCUSD/BTCUSD2022-11-17.csv.gz
+        return format!(
+            "{}/{}/{}{:04}-{:02}-{:02}.csv.gz",
+            self.history_web_base, self.name, self.name, yyyy, mm, dd
+        );
+    }
+}
+
+/// public.bybit.comのログ（*.csv.gz）のレコードをTradeへ変換する。
+/// timestamp,symbol,side,size,price,tickDirection,trdMatchID,grossValue,homeNotional,foreignNotional
+/// 1668728606.5432,BTCUSD,Buy,611,16661.00,ZeroMinusTick,00fcc5e9-d36f-51ae-9906-11f83ed505a7,3.6672468639337374e+06,611,0.036672468639337374
+pub fn rec_to_trade(rec: &StringRecord) -> Trade {
+    let timestamp = rec
+        .get(0)
+        .unwrap_or_default()
+        .parse::<f64>()
+        .unwrap_or_default();
+    let timestamp = (timestamp * 1_000_000.0).round() as MicroSec;
+
+    let order_side = OrderSide::from_str(rec.get(2).unwrap_or_default());
+    let size = rec
+        .get(3)
+        .unwrap_or_default()
+        .parse::<f64>()
+        .unwrap_or_default();
+    let price = rec
+        .get(4)
+        .unwrap_or_default()
+        .parse::<f64>()
+        .unwrap_or_default();
+    let id = rec.get(6).unwrap_or_default().to_string();
+
+    return Trade::new(timestamp, order_side, price, size, id);
+}
+
+/// WebSocket(v5)の約定メッセージ
+/// {"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":1672304486868,
+///  "data":[{"T":1672304486865,"s":"BTCUSDT","S":"Buy","v":"0.001","p":"16578.50","L":"PlusTick","i":"20f43950-d8dd-5b31-9112-a178eb6023af","BT":false}]}
+#[derive(Debug, Deserialize)]
+struct BybitWsMessage {
+    topic: Option<String>,
+    #[serde(default)]
+    data: Vec<BybitWsTrade>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BybitWsTrade {
+    #[serde(rename = "T")]
+    time: i64,
+    #[serde(rename = "S")]
+    side: String,
+    v: String,
+    p: String,
+    i: String,
+}
+
+/// WebSocketのメッセージをTradeへ変換する。約定以外のメッセージ（pong, subscribeの応答など）は空。
+pub fn ws_message_to_trades(message: &str) -> Vec<Trade> {
+    let message = match serde_json::from_str::<BybitWsMessage>(message) {
+        Ok(m) => m,
+        Err(e) => {
+            log::debug!("skip message {} {:?}", message, e);
+            return vec![];
+        }
+    };
+
+    if message.topic.map_or(true, |topic| topic.starts_with("publicTrade.") == false) {
+        return vec![];
+    }
+
+    return message
+        .data
+        .iter()
+        .map(|t| {
+            Trade::new(
+                t.time * 1_000,
+                OrderSide::from_str(&t.side),
+                t.p.parse::<f64>().unwrap_or_default(),
+                t.v.parse::<f64>().unwrap_or_default(),
+                t.i.clone(),
+            )
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod bybit_test {
+    use super::*;
+
+    #[test]
+    fn test_make_historical_data_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let market = BybitMarket::open_db_path("BTCUSD", true, dir.path().join("BTCUSD.db").to_str().unwrap());
+
+        assert_eq!(
+            market.make_historical_data_url(2022, 11, 17),
+            "https://public.bybit.com/trading/BTCUSD/BTCUSD2022-11-17.csv.gz"
+        );
+        assert_eq!(
+            market.make_historical_data_url_timestamp(1),
+            "https://public.bybit.com/trading/BTCUSD/BTCUSD1970-01-01.csv.gz"
+        );
+    }
+
+    #[test]
+    fn test_ws_message_to_trades() {
+        let message = r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":1672304486868,"data":[{"T":1672304486865,"s":"BTCUSDT","S":"Buy","v":"0.001","p":"16578.50","L":"PlusTick","i":"20f43950-d8dd-5b31-9112-a178eb6023af","BT":false},{"T":1672304486866,"s":"BTCUSDT","S":"Sell","v":"0.002","p":"16578.00","L":"MinusTick","i":"20f43950-d8dd-5b31-9112-a178eb6023b0","BT":false}]}"#;
+        let trades = ws_message_to_trades(message);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].time, 1672304486865_000);
+        assert_eq!(trades[0].order_side, OrderSide::Buy);
+        assert_eq!((trades[1].price, trades[1].size), (16578.0, 0.002));
+        assert_eq!(trades[1].id, "20f43950-d8dd-5b31-9112-a178eb6023b0");
+
+        assert!(ws_message_to_trades(r#"{"success":true,"ret_msg":"pong","conn_id":"1","op":"ping"}"#).is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let market = BybitMarket::open_db_path("BTCUSDT", true, dir.path().join("ws.db").to_str().unwrap());
+        let config = market.ws_config();
+        assert_eq!(config.url, WS_LINEAR);
+        assert_eq!(config.subscribe, vec![r#"{"op":"subscribe","args":["publicTrade.BTCUSDT"]}"#]);
+    }
+
+    /// ローカルのHTTPサーバからダウンロードする
+    #[test]
+    fn test_download_from_local_server() {
+        use crate::exchange::rest::test_rest::{mock_http_server, request_paths};
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"timestamp,symbol,side,size,price,tickDirection,trdMatchID,grossValue,homeNotional,foreignNotional\n").unwrap();
+        gz.write_all(b"1668728606.5432,BTCUSD,Buy,611,16661.00,ZeroMinusTick,a1,3.6e+06,611,0.0366\n").unwrap();
+        gz.write_all(b"1668728607.0,BTCUSD,Sell,100,16660.00,MinusTick,a2,6.0e+05,100,0.0060\n").unwrap();
+        let gz_data = gz.finish().unwrap();
+
+        let (url, requests) = mock_http_server(move |_method, _path| (200, "application/octet-stream", gz_data.clone()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut market = BybitMarket::open_db_path("BTCUSD", true, dir.path().join("BTCUSD.db").to_str().unwrap());
+        market.history_web_base = url;
+
+        market.download(1, true);
+
+        let paths = request_paths(&requests);
+        assert!(paths.len() != 0);
+        for path in paths.iter() {
+            assert!(path.starts_with("/BTCUSD/BTCUSD"));
+            assert!(path.ends_with(".csv.gz"));
+        }
+
+        let mut trades: Vec<Trade> = vec![];
+        market.db.select(0, 0, |t| trades.push(t.clone()));
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].order_side, OrderSide::Buy);
+        assert_eq!(trades[1].order_side, OrderSide::Sell);
+    }
+
+    #[test]
+    fn test_rec_to_trade() {
+        let rec = StringRecord::from(vec![
+            "1668728606.5432", "BTCUSD", "Sell", "611", "16661.00", "ZeroMinusTick",
+            "00fcc5e9-d36f-51ae-9906-11f83ed505a7", "3.6672468639337374e+06", "611", "0.036672468639337374",
+        ]);
+
+        let trade = rec_to_trade(&rec);
+        assert_eq!(trade.time, 1668728606_543200);
+        assert_eq!(trade.order_side, OrderSide::Sell);
+        assert_eq!(trade.size, 611.0);
+        assert_eq!(trade.price, 16661.0);
+        assert_eq!(trade.id, "00fcc5e9-d36f-51ae-9906-11f83ed505a7");
+    }
+}
@@ -0,0 +1,397 @@
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+
+use csv::StringRecord;
+use numpy::PyArray2;
+use pyo3::exceptions::PyTypeError;
+use pyo3::{Py, PyResult};
+
+use crate::common::order::{OrderSide, TimeChunk, Trade};
+use crate::common::time::{MicroSec, DAYS, HHMM, NOW, SEC};
+use crate::db::audit::AuditConfig;
+use crate::db::bars::BarType;
+use crate::db::sqlite::TradeTable;
+use crate::db::stream::TradeIterator;
+
+use super::binance;
+use super::bybit;
+use super::log_download;
+use super::ws::{WsConfig, WsParser};
+
+/// ログのレコードをTradeへ変換する関数（ヘッダー行などはNone）。ダウンロード用のスレッドで実行する。
+pub type RecordParser = Box<dyn Fn(&StringRecord) -> Option<Trade> + Send>;
+
+/// 取引所ごとのマーケット
+/// 新しい取引所を追加する場合は、ログのURL生成とレコードの変換を実装し、MARKET_REGISTRYへ登録する。
+pub trait Market: Send {
+    fn exchange_name(&self) -> String;
+
+    fn market_name(&self) -> String;
+
+    fn db(&mut self) -> &mut TradeTable;
+
+    fn into_db(self: Box<Self>) -> TradeTable;
+
+    /// 指定日(UTC)のログのURL
+    fn history_url(&self, day: MicroSec) -> String;
+
+    /// ログのCSVにヘッダー行があるか
+    fn log_has_header(&self) -> bool;
+
+    fn record_parser(&self) -> RecordParser;
+
+    /// 約定ストリーム(WebSocket)の接続設定
+    fn ws_config(&self) -> WsConfig;
+
+    fn ws_parser(&self) -> WsParser;
+
+    /// ダウンロードするログのURL（force=falseの場合はDBにない日のみ）
+    fn download_urls(&mut self, ndays: i64, force: bool) -> Vec<String> {
+        let from_time = NOW() - DAYS(ndays + 1);
+        let to_time = NOW() - DAYS(1);
+
+        let time_gap = if force {
+            vec![TimeChunk {
+                start: from_time,
+                end: to_time,
+            }]
+        } else {
+            self.db().select_gap_chunks(from_time, to_time, HHMM(12, 0))
+        };
+
+        let days_gap = TradeTable::time_chunks_to_days(&time_gap);
+        log::debug!("GAP TIME: {:?}", time_gap);
+        log::debug!("GAP DAYS: {:?}", days_gap);
+
+        return days_gap.iter().map(|day| self.history_url(*day)).collect();
+    }
+
+    fn download(&mut self, ndays: i64, force: bool) -> i64 {
+        let urls = self.download_urls(ndays, force);
+        let has_header = self.log_has_header();
+        let parser = self.record_parser();
+
+        return download_logs(self.db(), urls, has_header, parser);
+    }
+
+    fn select_trades(&mut self, from_time: MicroSec, to_time: MicroSec) -> PyResult<Py<PyArray2<f64>>> {
+        return self.db().py_select_trades(from_time, to_time);
+    }
+
+    /// split_side=trueの場合は売りと買いを別の行で返す。
+    /// falseの場合は１行にまとめ、buy_vol, sell_vol, buy_count, sell_count, vwap, deltaを返す。
+    fn ohlcvv(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        window_sec: i64,
+        split_side: bool,
+    ) -> PyResult<Py<PyArray2<f64>>> {
+        if split_side {
+            return self.db().py_ohlcvv(from_time, to_time, window_sec);
+        }
+
+        return self.db().py_ohlcvv_merged(from_time, to_time, window_sec);
+    }
+
+    /// 約定数・出来高・売買代金・不均衡で区切った足
+    fn bars(&mut self, from_time: MicroSec, to_time: MicroSec, kind: &str, threshold: f64) -> PyResult<Py<PyArray2<f64>>> {
+        let bar_type = BarType::from_str(kind, threshold).map_err(PyTypeError::new_err)?;
+
+        return self.db().py_info_bars(from_time, to_time, bar_type);
+    }
+
+    fn iter_trades(&mut self, from_time: MicroSec, to_time: MicroSec, chunk_rows: usize) -> TradeIterator {
+        return self.db().iter_trades(from_time, to_time, chunk_rows);
+    }
+
+    fn info(&mut self) -> String {
+        return self.db().info();
+    }
+
+    /// 足のテーブルを追加する前に作ったDBの足を、約定から作り直す。
+    fn migrate_ohlcv_tables(&mut self) -> PyResult<()> {
+        return self
+            .db()
+            .migrate_ohlcv_tables()
+            .map_err(|e| PyTypeError::new_err(e.to_string()));
+    }
+
+    /// ログの品質を確認し、問題を(kind, start_time, end_time, id, value)のリストで返す。
+    fn audit(
+        &mut self,
+        from_time: MicroSec,
+        to_time: MicroSec,
+        gap_sec: i64,
+        outlier_window: usize,
+        outlier_rate: f64,
+        min_day_rate: f64,
+    ) -> Vec<(String, MicroSec, MicroSec, String, f64)> {
+        let config = AuditConfig {
+            gap: SEC(gap_sec),
+            outlier_window,
+            outlier_rate,
+            min_day_rate,
+        };
+
+        return self
+            .db()
+            .audit(from_time, to_time, config)
+            .iter()
+            .map(|issue| issue.to_tuple())
+            .collect();
+    }
+
+    /// FundingRate(時刻, レート)をDBへ登録する（BackTesterで利用）
+    fn insert_funding(&mut self, funding: Vec<(MicroSec, f64)>) -> PyResult<i64> {
+        return self
+            .db()
+            .insert_funding(&funding)
+            .map_err(|e| PyTypeError::new_err(e.to_string()));
+    }
+
+    fn select_funding(&mut self, from_time: MicroSec, to_time: MicroSec) -> Vec<(MicroSec, f64)> {
+        return self.db().select_funding(from_time, to_time);
+    }
+
+    /// 板のファイルをDBへ読み込む（BackTesterで板として再生する）
+    fn import_book(&mut self, paths: Vec<String>) -> PyResult<i64> {
+        let mut insert_rec = 0;
+
+        for path in paths {
+            insert_rec += self
+                .db()
+                .import_book_file(Path::new(&path))
+                .map_err(PyTypeError::new_err)?;
+        }
+
+        return Ok(insert_rec);
+    }
+
+    /// time時点の板を([(bid価格, サイズ)], [(ask価格, サイズ)])で返す（先頭からdepth件）
+    fn book(&mut self, time: MicroSec, depth: usize) -> (Vec<(f64, f64)>, Vec<(f64, f64)>) {
+        let book = self.db().book_at(time);
+
+        return (book.depth(OrderSide::Buy, depth), book.depth(OrderSide::Sell, depth));
+    }
+}
+
+/// ログをダウンロードするスレッドからchannel経由でTradeを受け取り、DBへ登録する。
+/// 登録したレコード数を返す。
+pub fn download_logs(db: &mut TradeTable, urls: Vec<String>, has_header: bool, parser: RecordParser) -> i64 {
+    let (tx, rx): (Sender<Vec<Trade>>, Receiver<Vec<Trade>>) = mpsc::channel();
+
+    let _handle = thread::spawn(move || {
+        let mut download_rec: i64 = 0;
+
+        for url in urls {
+            log::debug!("download url = {}", url);
+
+            let mut buffer: Vec<Trade> = vec![];
+
+            let result = log_download(url.as_str(), has_header, |rec| {
+                if let Some(trade) = parser(rec) {
+                    buffer.push(trade);
+                }
+
+                if 2000 < buffer.len() {
+                    let _result = tx.send(buffer.to_vec());
+                    buffer.clear();
+                }
+            });
+
+            if buffer.len() != 0 {
+                let _result = tx.send(buffer.to_vec());
+                buffer.clear();
+            }
+
+            match result {
+                Ok(count) => {
+                    log::debug!("Downloaded rec = {} ", count);
+                    download_rec += count;
+                }
+                Err(e) => {
+                    log::error!("extract err = {}", e);
+                }
+            }
+        }
+
+        log::debug!("download rec = {}", download_rec);
+    });
+
+    let mut insert_rec_no = 0;
+
+    loop {
+        match rx.recv() {
+            Ok(trades) => match db.insert_records(&trades) {
+                Ok(rec_no) => {
+                    insert_rec_no += rec_no;
+                }
+                Err(e) => {
+                    log::warn!("insert error {:?}", e);
+                }
+            },
+            Err(_e) => {
+                break;
+            }
+        }
+    }
+
+    log::debug!("insert rec={}", insert_rec_no);
+
+    return insert_rec_no;
+}
+
+/// 取引所コードとマーケットを開く関数
+pub struct MarketEntry {
+    pub exchange_name: &'static str,
+    pub open: fn(market_name: &str, dummy: bool) -> Box<dyn Market>,
+    pub coin_margined: bool, // 証拠金と損益が基軸通貨(BTCUSD_PERPならBTC)のマーケット
+}
+
+pub static MARKET_REGISTRY: &[MarketEntry] = &[
+    MarketEntry {
+        exchange_name: "BN",
+        open: binance::open_spot_market,
+        coin_margined: false,
+    },
+    MarketEntry {
+        exchange_name: "BN-UM",
+        open: binance::open_um_market,
+        coin_margined: false,
+    },
+    MarketEntry {
+        exchange_name: "BN-CM",
+        open: binance::open_cm_market,
+        coin_margined: true,
+    },
+    MarketEntry {
+        exchange_name: "BN-AGG",
+        open: binance::open_spot_agg_market,
+        coin_margined: false,
+    },
+    MarketEntry {
+        exchange_name: "BN-UM-AGG",
+        open: binance::open_um_agg_market,
+        coin_margined: false,
+    },
+    MarketEntry {
+        exchange_name: "BN-CM-AGG",
+        open: binance::open_cm_agg_market,
+        coin_margined: true,
+    },
+    MarketEntry {
+        exchange_name: "BB",
+        open: bybit::open_market,
+        coin_margined: false,
+    },
+];
+
+pub fn exchange_names() -> Vec<&'static str> {
+    return MARKET_REGISTRY.iter().map(|entry| entry.exchange_name).collect();
+}
+
+pub fn find_market(exchange_name: &str) -> Option<&'static MarketEntry> {
+    let exchange_name = exchange_name.to_uppercase();
+
+    return MARKET_REGISTRY
+        .iter()
+        .find(|entry| entry.exchange_name == exchange_name);
+}
+
+/// 登録されている取引所コードに正規化する（"bn"→"BN"）。登録されていない場合はエラー。
+pub fn registered_exchange_name(exchange_name: &str) -> Result<&'static str, String> {
+    match find_market(exchange_name) {
+        Some(entry) => Ok(entry.exchange_name),
+        None => Err(format!(
+            "Unknown exchange {} / use one of {:?}",
+            exchange_name,
+            exchange_names()
+        )),
+    }
+}
+
+/// 証拠金と損益の通貨（USDT, BUSDなどのドル建てはUSDにまとめる）
+/// コインマージンのマーケットはシンボルの先頭の通貨、それ以外はシンボルの末尾の通貨。
+pub fn settlement_currency(exchange_name: &str, market_name: &str) -> Result<String, String> {
+    let entry = find_market(registered_exchange_name(exchange_name)?).unwrap();
+    let market_name = market_name.to_uppercase();
+
+    if entry.coin_margined {
+        return match market_name.find("USD") {
+            Some(pos) if 0 < pos => Ok(market_name[..pos].to_string()),
+            _ => Err(format!("Unknown coin margined market {}", market_name)),
+        };
+    }
+
+    for (quote, currency) in QUOTE_CURRENCIES {
+        if market_name.ends_with(quote) && quote.len() < market_name.len() {
+            return Ok(currency.to_string());
+        }
+    }
+
+    return Ok(market_name);
+}
+
+const QUOTE_CURRENCIES: &[(&str, &str)] = &[
+    ("USDT", "USD"),
+    ("BUSD", "USD"),
+    ("USDC", "USD"),
+    ("TUSD", "USD"),
+    ("USD", "USD"),
+    ("BTC", "BTC"),
+    ("ETH", "ETH"),
+    ("BNB", "BNB"),
+];
+
+pub fn open_market(exchange_name: &str, market_name: &str, dummy: bool) -> Result<Box<dyn Market>, String> {
+    let exchange_name = registered_exchange_name(exchange_name)?;
+    let entry = find_market(exchange_name).unwrap();
+
+    return Ok((entry.open)(market_name, dummy));
+}
+
+#[cfg(test)]
+mod test_market {
+    use super::*;
+
+    #[test]
+    fn test_find_market() {
+        assert_eq!(exchange_names(), vec!["BN", "BN-UM", "BN-CM", "BN-AGG", "BN-UM-AGG", "BN-CM-AGG", "BB"]);
+
+        assert_eq!(find_market("bn").unwrap().exchange_name, "BN");
+        assert_eq!(find_market("BB").unwrap().exchange_name, "BB");
+        assert_eq!(find_market("bn-um-agg").unwrap().exchange_name, "BN-UM-AGG");
+        assert!(find_market("FTX").is_none());
+
+        assert!(open_market("FTX", "BTC-PERP", true).is_err());
+    }
+
+    #[test]
+    fn test_open_market() {
+        let market = open_market("BN", "BTCBUSD", true).unwrap();
+        assert_eq!(market.exchange_name(), "BN");
+        assert_eq!(market.market_name(), "BTCBUSD");
+        assert_eq!(
+            market.history_url(1),
+            "https://data.binance.vision/data/spot/daily/trades/BTCBUSD/BTCBUSD-trades-1970-01-01.zip"
+        );
+
+        let market = open_market("BN-UM-AGG", "BTCUSDT", true).unwrap();
+        assert_eq!(market.exchange_name(), "BN-UM-AGG");
+    }
+
+    #[test]
+    fn test_settlement_currency() {
+        assert_eq!(settlement_currency("BN", "BTCBUSD").unwrap(), "USD");
+        assert_eq!(settlement_currency("BB", "btcusdt").unwrap(), "USD");
+        assert_eq!(settlement_currency("BN", "ETHBTC").unwrap(), "BTC");
+        assert_eq!(settlement_currency("BN-CM", "BTCUSD_PERP").unwrap(), "BTC");
+        assert_eq!(settlement_currency("BN-CM-AGG", "ETHUSD_230331").unwrap(), "ETH");
+
+        assert!(settlement_currency("BN-CM", "USD_PERP").is_err());
+        assert!(settlement_currency("FTX", "BTC-PERP").is_err());
+    }
+}
@@ -1,10 +1,14 @@
 pub mod binance;
+pub mod bybit;
+pub mod market;
+pub mod rest;
+pub mod ws;
 
 // pub mod ftx;
 
 use std::{
     fs::File,
-    io::{copy, Write, BufReader, Cursor},
+    io::{copy, Write, BufReader, Cursor, Read},
     path::Path,
 };
 
@@ -14,6 +18,16 @@ use tempfile::tempdir;
 use tokio::runtime::Runtime;
 use zip::ZipArchive;
 
+use crate::common::order::Trade;
+use crate::db::sqlite::TradeTable;
+
+/// has_headerの指定を反映したcsv reader（csv::Readerのデフォルトはヘッダーあり）
+fn csv_reader<R: Read>(reader: R, has_header: bool) -> csv::Reader<R> {
+    return csv::ReaderBuilder::new()
+        .has_headers(has_header)
+        .from_reader(reader);
+}
+
 pub async fn log_download_tmp(url: &str, tmp_dir: &Path) -> Result<String, String> {
     let response = match reqwest::get(url).await {
         Ok(r) => r,
@@ -99,9 +113,7 @@ where
     log::debug!("let's extract = {}", file_path);
    
     if url.ends_with("gz") || url.ends_with("GZ") {
-        // TODO: implmeent extract gzip file.
-//        return gzip_log_download(response, has_header, f);
-        return Ok(0);
+        return extract_gzip_log(&file_path, has_header, f);
     } else if url.ends_with("zip") || url.ends_with("ZIP") {
         return extract_zip_log(&file_path, has_header, f);    
     } else {
@@ -164,10 +176,7 @@ where
         Ok(b) => {
             let gz = GzDecoder::new(b.as_ref());
 
-            let mut reader = csv::Reader::from_reader(gz);
-            if has_header {
-                reader.has_headers();
-            }
+            let mut reader = csv_reader(gz, has_header);
 
             for rec in reader.records() {
                 if let Ok(string_rec) = rec {
@@ -207,11 +216,8 @@ where
                     continue;
                 }
 
-                let mut csv_reader = csv::Reader::from_reader(file);
-                if has_header {
-                    csv_reader.has_headers();
-                }
-                for rec in csv_reader.records() {
+                let mut reader = csv_reader(file, has_header);
+                for rec in reader.records() {
                     if let Ok(string_rec) = rec {
                         f(&string_rec);
                         rec_count += 1;
@@ -227,7 +233,7 @@ where
     Ok(rec_count)
 }
 
-fn extract_zip_log<F>(path: &String, has_header: bool, mut f: F) -> Result<i64, String>
+pub fn extract_zip_log<F>(path: &String, has_header: bool, mut f: F) -> Result<i64, String>
 where
     F: FnMut(&StringRecord),
 {
@@ -264,11 +270,8 @@ where
             log::debug!("processing {}", file.name());
         }
 
-        let mut csv_reader = csv::Reader::from_reader(file);
-        if has_header {
-            csv_reader.has_headers();
-        }
-        for rec in csv_reader.records() {
+        let mut reader = csv_reader(file, has_header);
+        for rec in reader.records() {
             if let Ok(string_rec) = rec {
                 f(&string_rec);
                 rec_count += 1;
@@ -279,6 +282,192 @@ where
     Ok(rec_count)
 }
 
+pub fn extract_gzip_log<F>(path: &String, has_header: bool, mut f: F) -> Result<i64, String>
+where
+    F: FnMut(&StringRecord),
+{
+    log::debug!("extract gzip = {}", path);
+    let mut rec_count = 0;
+
+    let file_path = Path::new(path);
+
+    if file_path.exists() == false {
+        log::error!("File Not Found {}", path);
+        return Err(format!("File Not Found {}", path));
+    }
+
+    let file = match File::open(file_path) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(e.to_string());
+        }
+    };
+    let gz = GzDecoder::new(BufReader::new(file));
+
+    let mut reader = csv_reader(gz, has_header);
+    for rec in reader.records() {
+        match rec {
+            Ok(string_rec) => {
+                f(&string_rec);
+                rec_count += 1;
+            }
+            Err(e) => {
+                log::warn!("csv read error {}", e);
+            }
+        }
+    }
+
+    Ok(rec_count)
+}
+
+/// ローカルに保存したログアーカイブの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    BinanceTradesZip,    // <SYMBOL>-trades-YYYY-MM-DD.zip (spotはヘッダーなし、futuresはヘッダーあり)
+    BinanceAggTradesZip, // <SYMBOL>-aggTrades-YYYY-MM-DD.zip
+    BybitCsvGz,       // <SYMBOL>YYYY-MM-DD.csv.gz (ヘッダーあり)
+}
+
+impl ArchiveFormat {
+    pub fn from_str(format: &str) -> Result<Self, String> {
+        match format.to_lowercase().as_str() {
+            "binance" | "trades" | "zip" => Ok(ArchiveFormat::BinanceTradesZip),
+            "aggtrades" => Ok(ArchiveFormat::BinanceAggTradesZip),
+            "bybit" | "csv.gz" | "gz" => Ok(ArchiveFormat::BybitCsvGz),
+            _ => Err(format!("Unknown archive format {} / use trades, aggtrades or bybit", format)),
+        }
+    }
+
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        let name = file_name.to_lowercase();
+
+        if name.ends_with(".zip") && name.contains("-trades-") {
+            Some(ArchiveFormat::BinanceTradesZip)
+        } else if name.ends_with(".zip") && name.contains("-aggtrades-") {
+            Some(ArchiveFormat::BinanceAggTradesZip)
+        } else if name.ends_with(".csv.gz") {
+            Some(ArchiveFormat::BybitCsvGz)
+        } else {
+            None
+        }
+    }
+
+    /// ヘッダー行はNone
+    fn rec_to_trade(&self, rec: &StringRecord) -> Option<Trade> {
+        match self {
+            ArchiveFormat::BinanceTradesZip => {
+                binance::BinanceMarket::rec_to_trade(binance::BinanceDataType::Trades, rec)
+            }
+            ArchiveFormat::BinanceAggTradesZip => {
+                binance::BinanceMarket::rec_to_trade(binance::BinanceDataType::AggTrades, rec)
+            }
+            ArchiveFormat::BybitCsvGz => Some(bybit::rec_to_trade(rec)),
+        }
+    }
+}
+
+/// ダウンロード済みのログアーカイブをdirから読み込み、DBへ登録する。
+///   prefix: ファイル名の先頭（シンボル名など）が一致するファイルのみ対象（空の場合は全て）
+///   formats: 対象とするアーカイブ形式
+///   force: falseの場合、読み込み済みのファイルはスキップする。
+/// ファイルごとに(ファイル名, レコード数, 状態["loaded" | "skipped" | エラー内容])を返す。
+pub fn import_log_files(
+    db: &mut TradeTable,
+    dir: &Path,
+    prefix: &str,
+    formats: &[ArchiveFormat],
+    force: bool,
+) -> Result<Vec<(String, i64, String)>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => {
+            return Err(format!("cannot read dir {:?} {}", dir, e));
+        }
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+
+    let mut report: Vec<(String, i64, String)> = vec![];
+
+    for path in paths {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+        if file_name.starts_with(prefix) == false {
+            continue;
+        }
+
+        let format = match ArchiveFormat::from_file_name(&file_name) {
+            Some(f) if formats.contains(&f) => f,
+            _ => continue,
+        };
+
+        if force == false && db.is_file_loaded(&file_name) {
+            log::debug!("skip loaded file {}", file_name);
+            report.push((file_name, 0, "skipped".to_string()));
+            continue;
+        }
+
+        let mut buffer: Vec<Trade> = vec![];
+        let mut insert_error: Option<String> = None;
+
+        let mut insert = |db: &mut TradeTable, buffer: &mut Vec<Trade>| {
+            if let Err(e) = db.insert_records(buffer) {
+                log::warn!("insert error {:?}", e);
+                insert_error = Some(e.to_string());
+            }
+            buffer.clear();
+        };
+
+        let path_string = path.to_string_lossy().to_string();
+        let result = match format {
+            ArchiveFormat::BinanceTradesZip | ArchiveFormat::BinanceAggTradesZip => extract_zip_log(&path_string, false, |rec| {
+                if let Some(trade) = format.rec_to_trade(rec) {
+                    buffer.push(trade);
+                }
+                if 2000 < buffer.len() {
+                    insert(db, &mut buffer);
+                }
+            }),
+            ArchiveFormat::BybitCsvGz => extract_gzip_log(&path_string, true, |rec| {
+                if let Some(trade) = format.rec_to_trade(rec) {
+                    buffer.push(trade);
+                }
+                if 2000 < buffer.len() {
+                    insert(db, &mut buffer);
+                }
+            }),
+        };
+
+        if buffer.len() != 0 {
+            insert(db, &mut buffer);
+        }
+
+        match result {
+            Ok(count) if insert_error.is_none() => {
+                log::debug!("loaded {} rec={}", file_name, count);
+                if let Err(e) = db.mark_file_loaded(&file_name, count) {
+                    log::warn!("mark loaded error {:?}", e);
+                }
+                report.push((file_name, count, "loaded".to_string()));
+            }
+            Ok(_) => {
+                report.push((file_name, 0, insert_error.unwrap_or_default()));
+            }
+            Err(e) => {
+                log::error!("extract err = {}", e);
+                report.push((file_name, 0, e));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
 /* remove async ver
 async fn gziped_log_download<F>(url: &str, mut f:F) -> Result<i64, String>
 where F: FnMut(&StringRecord) {
@@ -345,6 +534,67 @@ mod test_exchange {
         init_debug_log();
     }
 
+    #[test]
+    fn test_archive_format() {
+        assert_eq!(ArchiveFormat::from_file_name("BTCBUSD-trades-2022-11-19.zip"), Some(ArchiveFormat::BinanceTradesZip));
+        assert_eq!(ArchiveFormat::from_file_name("BTCUSD2022-11-17.csv.gz"), Some(ArchiveFormat::BybitCsvGz));
+        assert_eq!(ArchiveFormat::from_file_name("BTCBUSD-aggTrades-2022-11-19.zip"), Some(ArchiveFormat::BinanceAggTradesZip));
+        assert_eq!(ArchiveFormat::from_file_name("BTCBUSD-klines-2022-11-19.zip"), None);
+
+        assert_eq!(ArchiveFormat::from_str("bybit"), Ok(ArchiveFormat::BybitCsvGz));
+        assert_eq!(ArchiveFormat::from_str("AggTrades"), Ok(ArchiveFormat::BinanceAggTradesZip));
+        assert!(ArchiveFormat::from_str("klines").is_err());
+    }
+
+    #[test]
+    fn test_import_log_files() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let dir = tempdir().unwrap();
+
+        // Binance (ヘッダーなし)
+        let zip_file = File::create(dir.path().join("BTCBUSD-trades-2022-11-19.zip")).unwrap();
+        let mut zip = zip::ZipWriter::new(zip_file);
+        zip.start_file("BTCBUSD-trades-2022-11-19.csv", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(b"1,16000.0,0.1,1600.0,1668816000000,True,True\n2,16001.0,0.2,3200.2,1668816000001,False,True\n").unwrap();
+        zip.finish().unwrap();
+
+        // Bybit (ヘッダーあり)
+        let gz_file = File::create(dir.path().join("BTCUSD2022-11-17.csv.gz")).unwrap();
+        let mut gz = GzEncoder::new(gz_file, Compression::default());
+        gz.write_all(b"timestamp,symbol,side,size,price,tickDirection,trdMatchID,grossValue,homeNotional,foreignNotional\n").unwrap();
+        gz.write_all(b"1668728606.5432,BTCUSD,Buy,611,16661.00,ZeroMinusTick,a1,3.6e+06,611,0.0366\n").unwrap();
+        gz.write_all(b"1668728607.0,BTCUSD,Sell,100,16660.00,MinusTick,a2,6.0e+05,100,0.0060\n").unwrap();
+        gz.write_all(b"1668728608.0,BTCUSD,Sell,100,16660.00,MinusTick,a3,6.0e+05,100,0.0060\n").unwrap();
+        gz.finish().unwrap();
+
+        let mut db = TradeTable::open(dir.path().join("import.db").to_str().unwrap()).unwrap();
+        db.create_table_if_not_exists();
+
+        let formats = [ArchiveFormat::BinanceTradesZip, ArchiveFormat::BybitCsvGz];
+        let report = import_log_files(&mut db, dir.path(), "", &formats, false).unwrap();
+        assert_eq!(
+            report,
+            vec![
+                ("BTCBUSD-trades-2022-11-19.zip".to_string(), 2, "loaded".to_string()),
+                ("BTCUSD2022-11-17.csv.gz".to_string(), 3, "loaded".to_string()),
+            ]
+        );
+
+        // 読み込み済みのファイルはスキップ
+        let report = import_log_files(&mut db, dir.path(), "BTCBUSD", &formats, false).unwrap();
+        assert_eq!(report, vec![("BTCBUSD-trades-2022-11-19.zip".to_string(), 0, "skipped".to_string())]);
+
+        let report = import_log_files(&mut db, dir.path(), "BTCBUSD", &formats, true).unwrap();
+        assert_eq!(report[0].2, "loaded");
+
+        let mut trades: Vec<Trade> = vec![];
+        db.select(0, 0, |t| trades.push(t.clone()));
+        assert_eq!(trades.len(), 5);
+    }
+
     #[tokio::test]
     async fn log_download_temp_test() {
         init_debug_log();
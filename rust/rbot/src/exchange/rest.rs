@@ -0,0 +1,159 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::common::order::{Order, OrderStatus};
+use crate::common::time::MicroSec;
+
+/// 取引所から取得した約定（自分のオーダーの約定）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub order_id: String, // 発注時に指定したオーダーID
+    pub trade_id: i64,
+    pub time: MicroSec,
+    pub price: f64,
+    pub size: f64, // 取引所の数量（foreign通貨単位、BTCUSDTならBTC）
+    pub is_maker: bool,
+}
+
+/// 発注・キャンセルのエラー
+/// statusはOrderResultへ記録するステータス（PostOnlyError, NoMoney）。それ以外のエラーはError。
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestError {
+    pub status: OrderStatus,
+    pub message: String,
+}
+
+impl RestError {
+    pub fn new(status: OrderStatus, message: &str) -> Self {
+        return RestError {
+            status,
+            message: message.to_string(),
+        };
+    }
+}
+
+/// 取引所のオーダーAPI
+/// 新しい取引所を追加する場合は、このtraitを実装しLiveSessionから使えるようにする。
+pub trait OrderClient: Send {
+    /// RESTのエンドポイント（テスト用にローカルのモックサーバへ切り替えられる）
+    fn rest_base(&self) -> String;
+
+    fn set_rest_base(&mut self, rest_base: &str);
+
+    /// オーダーを発行する。order.order_idを取引所のクライアントオーダーIDとして使う。
+    /// price=0.0の場合は成行、post_only=trueの場合はMakerのみのオーダーとする。
+    /// order.sizeはhome通貨単位なので、取引所の数量へ変換して送る。
+    /// 受け付けられたオーダー（価格と数量を取引所の刻みに丸めたもの）を返す。
+    fn new_order(&mut self, order: &Order) -> Result<Order, RestError>;
+
+    /// オーダーをキャンセルする。オーダーが見つからない（約定済みなど）場合はNoActionのエラー。
+    fn cancel_order(&mut self, order_id: &str) -> Result<(), RestError>;
+
+    /// 前回の呼び出し以降の約定（古い順）
+    fn fetch_fills(&mut self) -> Result<Vec<Fill>, RestError>;
+}
+
+/// HMAC-SHA256で署名し、16進文字列で返す。
+pub fn hmac_sha256_hex(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(message.as_bytes());
+
+    return hex::encode(mac.finalize().into_bytes());
+}
+
+/// クエリ文字列を作る（値は英数字と記号のみの想定なのでエスケープしない）
+pub fn make_query(params: &[(&str, String)]) -> String {
+    return params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>()
+        .join("&");
+}
+
+#[cfg(test)]
+pub mod test_rest {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+
+    /// HTTPのモックサーバ。handlerはリクエストの(method, path)から(ステータス, Content-Type, ボディ)を返す。
+    /// 受信したリクエスト（ヘッダーを含む全体）を記録する。
+    pub fn mock_http_server<F>(handler: F) -> (String, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str, &str) -> (u16, &'static str, Vec<u8>) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                log.lock().unwrap().push(request.clone());
+
+                let mut line = request.split_whitespace();
+                let method = line.next().unwrap_or_default();
+                let path = line.next().unwrap_or_default();
+
+                let (status, content_type, body) = handler(method, path);
+
+                let header = format!(
+                    "HTTP/1.1 {} OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content_type,
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(&body);
+            }
+        });
+
+        return (url, requests);
+    }
+
+    /// 記録したリクエストのパス
+    pub fn request_paths(requests: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+        return requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.split_whitespace().nth(1).unwrap_or_default().to_string())
+            .collect();
+    }
+
+    /// Binanceのドキュメントにある署名の例
+    #[test]
+    fn test_hmac_sha256_hex() {
+        let secret = "NhqPtmdSJYdKjVHjA7PZj4Mge3R5YNiP1e3UZjInClVN65XAbvqqM6A7H5fATj0j";
+        let query = make_query(&[
+            ("symbol", "LTCBTC".to_string()),
+            ("side", "BUY".to_string()),
+            ("type", "LIMIT".to_string()),
+            ("timeInForce", "GTC".to_string()),
+            ("quantity", "1".to_string()),
+            ("price", "0.1".to_string()),
+            ("recvWindow", "5000".to_string()),
+            ("timestamp", "1499827319559".to_string()),
+        ]);
+
+        assert_eq!(
+            query,
+            "symbol=LTCBTC&side=BUY&type=LIMIT&timeInForce=GTC&quantity=1&price=0.1&recvWindow=5000&timestamp=1499827319559"
+        );
+        assert_eq!(
+            hmac_sha256_hex(secret, &query),
+            "c8db56825ae71d6d79447849e617115f4a920fa2acdcab2b053c4b2838bd6b71"
+        );
+    }
+}
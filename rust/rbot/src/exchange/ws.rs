@@ -0,0 +1,200 @@
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use tungstenite::error::Error as WsError;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{connect, Message, WebSocket};
+
+use crate::common::order::Trade;
+
+/// WebSocketのメッセージをTradeへ変換する関数（約定以外のメッセージは空）。受信用のスレッドで実行する。
+pub type WsParser = Box<dyn Fn(&str) -> Vec<Trade> + Send>;
+
+/// 約定ストリームの接続設定
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    pub url: String,
+    pub subscribe: Vec<String>, // 接続後に送るメッセージ
+    pub ping: Option<String>,   // ping_interval受信がなかった場合に送るメッセージ（取引所独自のping）
+    pub ping_interval: Duration,
+    pub reconnect: bool, // 切断された場合に再接続する
+}
+
+impl WsConfig {
+    pub fn new(url: &str) -> Self {
+        return WsConfig {
+            url: url.to_string(),
+            subscribe: vec![],
+            ping: None,
+            ping_interval: Duration::from_secs(20),
+            reconnect: true,
+        };
+    }
+}
+
+/// 別スレッドでWebSocketへ接続し、受信した約定をchannelへ送る。
+/// 受信側(Receiver)を捨てるとスレッドは終了する。reconnect=falseの場合は切断でchannelが閉じる。
+/// 再接続する前に空の約定を送る（受信側は再接続で約定が欠けたことを検出できる）。
+pub fn spawn_trade_stream(config: WsConfig, parser: WsParser) -> Receiver<Vec<Trade>> {
+    let (tx, rx): (Sender<Vec<Trade>>, Receiver<Vec<Trade>>) = mpsc::channel();
+
+    let _handle = thread::spawn(move || {
+        let mut retry_wait = Duration::from_secs(1);
+
+        loop {
+            match receive_trades(&config, &parser, &tx) {
+                Ok(()) => {
+                    log::debug!("websocket closed {}", config.url);
+                    retry_wait = Duration::from_secs(1);
+                }
+                Err(e) => {
+                    log::warn!("websocket error {} {:?}", config.url, e);
+                }
+            }
+
+            if config.reconnect == false {
+                break;
+            }
+
+            // 受信側が終了していたら再接続しない
+            if tx.send(vec![]).is_err() {
+                break;
+            }
+
+            thread::sleep(retry_wait);
+            retry_wait = std::cmp::min(retry_wait * 2, Duration::from_secs(60));
+        }
+    });
+
+    return rx;
+}
+
+/// １回の接続で受信を続ける。サーバから切断された場合はOk
+fn receive_trades(config: &WsConfig, parser: &WsParser, tx: &Sender<Vec<Trade>>) -> Result<(), WsError> {
+    let (mut socket, _response) = connect(config.url.as_str())?;
+    log::debug!("websocket connected {}", config.url);
+
+    set_read_timeout(&mut socket, config.ping_interval);
+
+    for message in &config.subscribe {
+        socket.write_message(Message::Text(message.clone()))?;
+    }
+
+    loop {
+        match socket.read_message() {
+            Ok(Message::Text(text)) => {
+                let trades = parser(&text);
+
+                if trades.is_empty() == false && tx.send(trades).is_err() {
+                    let _r = socket.close(None);
+                    return Ok(());
+                }
+            }
+            Ok(Message::Close(_frame)) => {
+                return Ok(());
+            }
+            Ok(_message) => {
+                // Ping/Pongはtungsteniteが処理する。
+            }
+            Err(WsError::Io(e))
+                if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                if let Some(ping) = &config.ping {
+                    socket.write_message(Message::Text(ping.clone()))?;
+                }
+            }
+            Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => {
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn set_read_timeout(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, timeout: Duration) {
+    let result = match socket.get_mut() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(timeout)),
+        MaybeTlsStream::NativeTls(stream) => stream.get_mut().set_read_timeout(Some(timeout)),
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        log::warn!("set read timeout error {:?}", e);
+    }
+}
+
+#[cfg(test)]
+pub mod test_ws {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::common::order::OrderSide;
+
+    /// 記録したメッセージを接続ごとに送信して切断するモックサーバ。
+    /// 最初に受信したメッセージ（subscribe）を返す。
+    pub fn mock_ws_server(messages: Vec<String>) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (stream, _addr) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+
+            let subscribe = match socket.read_message() {
+                Ok(Message::Text(text)) => text,
+                _ => "".to_string(),
+            };
+
+            for message in messages {
+                socket.write_message(Message::Text(message)).unwrap();
+            }
+            let _r = socket.close(None);
+            let _r = socket.write_pending();
+
+            subscribe
+        });
+
+        return (url, handle);
+    }
+
+    #[test]
+    fn test_trade_stream() {
+        let (url, handle) = mock_ws_server(vec![
+            "1,Buy,100.0".to_string(),
+            "pong".to_string(),
+            "2,Sell,99.5".to_string(),
+        ]);
+
+        let mut config = WsConfig::new(&url);
+        config.subscribe = vec!["subscribe".to_string()];
+        config.reconnect = false;
+
+        let parser: WsParser = Box::new(|message| {
+            let fields: Vec<&str> = message.split(',').collect();
+            if fields.len() != 3 {
+                return vec![];
+            }
+
+            vec![Trade::new(
+                fields[0].parse().unwrap(),
+                OrderSide::from_str(fields[1]),
+                fields[2].parse().unwrap(),
+                1.0,
+                fields[0].to_string(),
+            )]
+        });
+
+        let rx = spawn_trade_stream(config, parser);
+        let trades: Vec<Trade> = rx.iter().flatten().collect();
+
+        assert_eq!(handle.join().unwrap(), "subscribe");
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].order_side, OrderSide::Buy);
+        assert_eq!(trades[1].price, 99.5);
+    }
+}
@@ -16,10 +16,16 @@ use common::{
 };
 // use exchange::ftx::FtxMarket;
 use exchange::binance::BinanceMarket;
+use exchange::bybit::BybitMarket;
 
 use common::time::*;
 use sim::session::DummySession;
+use sim::multi::MultiSession;
+use sim::paper::PaperSession;
+use sim::live::LiveSession;
 use sim::back::BackTester;
+use sim::report::BacktestReport;
+use db::stream::TradeIterator;
 
 
 
@@ -40,8 +46,14 @@ fn rbot(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<OrderSide>()?;
     //m.add_class::<FtxMarket>()?;
     m.add_class::<BinanceMarket>()?;
+    m.add_class::<BybitMarket>()?;
     m.add_class::<DummySession>()?; 
+    m.add_class::<MultiSession>()?;
+    m.add_class::<PaperSession>()?;
+    m.add_class::<LiveSession>()?;
     m.add_class::<BackTester>()?; 
+    m.add_class::<BacktestReport>()?;
+    m.add_class::<TradeIterator>()?;
 
     Ok(())
 }
@@ -1,38 +1,371 @@
-use pyo3::{pyclass, pymethods, Py, PyAny, Python};
-use rusqlite::params;
+use numpy::{IntoPyArray, PyArray2};
+use pyo3::exceptions::PyTypeError;
+use pyo3::types::{PyDict, PyTuple};
+use pyo3::{pyclass, pymethods, IntoPy, Py, PyAny, PyObject, PyResult, Python, ToPyObject};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rusqlite::{params, Row, Statement};
+use std::iter::Peekable;
+use std::path::PathBuf;
 
 use crate::{
     common::{
-        order::{log_order_result, make_log_buffer, OrderResult, OrderSide, OrderStatus, Trade},
-        time::{MicroSec, CEIL},
+        order::{log_order_result, make_log_buffer, OrderResult, OrderSide, Trade},
+        time::{MicroSec, CEIL, FLOOR, SEC},
     },
+    db::book::BookUpdate,
     db::open_db,
+    db::sqlite::{book_from_row, TradeTable},
+    exchange::market::{find_market, registered_exchange_name, settlement_currency},
+    sim::latency::{make_latency_model, PyLatencyModel},
+    sim::multi::{MergeTrades, MultiSession},
+    sim::report::BacktestReport,
     sim::session::DummySession,
 };
 
+const EQUITY_COLUMNS: usize = 8;
+
 #[pyclass]
 pub struct BackTester {
-    exchange_name: String,
-    market_name: String,
+    markets: Vec<(String, String)>, // (exchange_name, market_name) 最初のマーケットはnewで指定したもの
     agent_on_tick: bool,
     agent_on_clock: bool,
     agent_on_update: bool,
+    #[pyo3(get, set)]
+    start_time: MicroSec, // 0の場合はDBの最初から
+    #[pyo3(get, set)]
+    end_time: MicroSec, // 0の場合はDBの最後まで
+    #[pyo3(get, set)]
+    warm_up: MicroSec, // start_timeより前のwarm_up期間はセッションのみ更新し、Agentへイベントを送らない
+    #[pyo3(get, set)]
+    maker_fee: f64,
+    #[pyo3(get, set)]
+    taker_fee: f64,
+    #[pyo3(get, set)]
+    taker_slippage: f64,
+    #[pyo3(get, set)]
+    initial_balance: f64, // 0の場合は証拠金チェックを行わない。複数マーケットの場合は合計額をマーケットごとに等分する
+    #[pyo3(get, set)]
+    leverage: f64,
+    #[pyo3(get, set)]
+    partial_fill: bool,
+    #[pyo3(get, set)]
+    fill_model: String,
+    #[pyo3(get, set)]
+    order_latency: MicroSec, // オーダーがサーバへ届くまでの遅延
+    #[pyo3(get, set)]
+    cancel_latency: MicroSec, // キャンセルがサーバへ届くまでの遅延
+    #[pyo3(get, set)]
+    decision_latency: MicroSec, // Agentがmake_orderしてから発注するまでの遅延
+    #[pyo3(get, set)]
+    latency_jitter: MicroSec, // 0以外の場合、遅延に平均latency_jitterの乱数を加える
+    #[pyo3(get, set)]
+    latency_seed: u64,
+    #[pyo3(get, set)]
+    latency_model: Option<PyObject>, // Pythonの遅延モデル。設定した場合はorder_latencyなどの設定より優先する
+    #[pyo3(get, set)]
+    equity_interval_sec: i64, // 0の場合はAgentのclock_intervalを利用する
+    equity_curve: Vec<[f64; EQUITY_COLUMNS]>,
+    db_dir: Option<PathBuf>, // DBのディレクトリ（テスト用。Noneの場合は取引所ごとのDBを開く）
 }
 
 #[pymethods]
 impl BackTester {
+    /// exchange_nameは取引所コード(BN, BN-UM, BN-CM, BN-AGG, BN-UM-AGG, BN-CM-AGG, BB)。登録されていない場合はエラー。
     #[new]
-    pub fn new(exchange_name: &str, market_name: &str) -> Self {
-        return BackTester {
-            exchange_name: exchange_name.to_string(),
-            market_name: market_name.to_string(),
+    pub fn new(exchange_name: &str, market_name: &str) -> PyResult<Self> {
+        let session = DummySession::new(exchange_name, market_name)?;
+
+        return Ok(BackTester {
+            markets: vec![(exchange_name.to_string(), market_name.to_string())],
             agent_on_tick: false,
             agent_on_clock: false,
             agent_on_update: false,
+            start_time: 0,
+            end_time: 0,
+            warm_up: SEC(60),
+            maker_fee: session.maker_fee,
+            taker_fee: session.taker_fee,
+            taker_slippage: session.taker_slippage,
+            initial_balance: 0.0,
+            leverage: session.leverage,
+            partial_fill: session.get_partial_fill(),
+            fill_model: session.get_fill_model(),
+            order_latency: 100_000,
+            cancel_latency: 100_000,
+            decision_latency: 0,
+            latency_jitter: 0,
+            latency_seed: 0,
+            latency_model: None,
+            equity_interval_sec: 0,
+            equity_curve: vec![],
+            db_dir: None,
+        });
+    }
+
+    /// 同時に再生するマーケットを追加する（取引所をまたいでもよい）。
+    /// 複数マーケットの場合、Agentには_MultiSessionとTickのマーケット("BN/BTCBUSD")が渡される。
+    /// 口座残高を合計するため、証拠金の通貨が異なるマーケット（BN-CMとUSD建てなど）は混在できない。
+    pub fn add_market(&mut self, exchange_name: &str, market_name: &str) -> PyResult<()> {
+        check_exchange(exchange_name)?;
+
+        let key = market_key(exchange_name, market_name);
+        if self.markets.iter().any(|(e, m)| market_key(e, m) == key) {
+            return Err(PyTypeError::new_err(format!("market {} is already added", key)));
+        }
+
+        let currency = settlement_currency(exchange_name, market_name).map_err(PyTypeError::new_err)?;
+        let (first_exchange, first_market) = &self.markets[0];
+        let first_currency = settlement_currency(first_exchange, first_market).map_err(PyTypeError::new_err)?;
+        if currency != first_currency {
+            return Err(PyTypeError::new_err(format!(
+                "market {} settles in {} but {} settles in {}",
+                key,
+                currency,
+                market_key(first_exchange, first_market),
+                first_currency
+            )));
+        }
+
+        self.markets.push((exchange_name.to_string(), market_name.to_string()));
+
+        Ok(())
+    }
+
+    /// 再生するマーケットのキー一覧
+    #[getter]
+    pub fn get_markets(&self) -> Vec<String> {
+        return self.markets.iter().map(|(e, m)| market_key(e, m)).collect();
+    }
+
+    pub fn run(&mut self, agent: &PyAny) -> PyResult<Vec<OrderResult>> {
+        if 1 < self.markets.len() {
+            return self.run_multi(agent);
+        }
+
+        let clock_interval = self.prepare_agent(agent);
+
+        let (exchange_name, market_name) = self.markets[0].clone();
+        let db = self.open_db(exchange_name.as_str(), market_name.as_str()).map_err(PyTypeError::new_err)?;
+        let mut statement = db.select_range_statement();
+        let mut book_statement = db.select_book_statement();
+        let funding = db.select_funding(0, 0);
+
+        let (replay_from, event_start) = self.replay_range(self.start_time);
+
+        let iter = statement
+            .query_map(params![replay_from, self.end_time], trade_from_row)
+            .unwrap()
+            .filter_map(ok_trade);
+        let book = book_statement
+            .query_map(params![db.book_replay_start(replay_from), self.end_time], book_from_row)
+            .unwrap()
+            .filter_map(ok_book);
+
+        return Python::with_gil(|py| {
+            self.replay(py, agent, iter, event_start, clock_interval, &funding, book)
+        });
+    }
+
+    /// パラメータの組み合わせごとにバックテストを行い、成績（BacktestReport.to_dictにパラメータを加えたもの）のリストを返す。
+    /// 組み合わせごとに新しいDummySessionで再生する。
+    /// ログはメモリへ全件読み込まず、組み合わせ×期間ごとに同じDBのカーソルから読み直す（DBの読み込みは組み合わせ数に比例する）。
+    ///   agent_factory: パラメータ(dict)を受け取りAgentを返すcallable
+    ///   grid: {パラメータ名: [値のリスト]}　全ての組み合わせを実行する。
+    ///   samples: 0以外の場合、組み合わせの中からランダムにsamples個を選ぶ（seedで再現可能）
+    ///   splits: [(train_start, train_end, test_start, test_end)]　walk forward_splitsの結果。
+    ///           組ごとに学習期間(phase="train")と検証期間(phase="test")で実行する。
+    ///           空の場合はstart_time/end_timeの期間で実行する(phase="all")。
+    #[args(samples = "0", seed = "0", splits = "vec![]", bar_sec = "3600")]
+    pub fn sweep(
+        &mut self,
+        py: Python,
+        agent_factory: &PyAny,
+        grid: &PyDict,
+        samples: usize,
+        seed: u64,
+        splits: Vec<(MicroSec, MicroSec, MicroSec, MicroSec)>,
+        bar_sec: i64,
+    ) -> PyResult<Vec<PyObject>> {
+        if 1 < self.markets.len() {
+            return Err(PyTypeError::new_err("sweep supports a single market"));
+        }
+
+        // (split, phase, start_time, end_time)
+        let windows: Vec<(usize, &str, MicroSec, MicroSec)> = if splits.is_empty() {
+            vec![(0, "all", self.start_time, self.end_time)]
+        } else {
+            splits
+                .iter()
+                .enumerate()
+                .flat_map(|(i, (train_start, train_end, test_start, test_end))| {
+                    vec![(i, "train", *train_start, *train_end), (i, "test", *test_start, *test_end)]
+                })
+                .collect()
         };
+
+        let mut names: Vec<String> = vec![];
+        let mut values: Vec<Vec<PyObject>> = vec![];
+        for (key, value) in grid.iter() {
+            names.push(key.extract::<String>()?);
+            values.push(value.extract::<Vec<PyObject>>()?);
+        }
+
+        let mut combinations = grid_combinations(&values.iter().map(|v| v.len()).collect::<Vec<usize>>());
+        if samples != 0 && samples < combinations.len() {
+            let mut rng = StdRng::seed_from_u64(seed);
+            combinations.shuffle(&mut rng);
+            combinations.truncate(samples);
+        }
+
+        // DBとステートメントは使い回し、ログは再生ごとにカーソルから読み込む（メモリへ全件は読み込まない）
+        let (exchange_name, market_name) = self.markets[0].clone();
+        let db = self.open_db(exchange_name.as_str(), market_name.as_str()).map_err(PyTypeError::new_err)?;
+        let mut statement = db.select_range_statement();
+        let mut book_statement = db.select_book_statement();
+        let funding = db.select_funding(0, 0);
+
+        log::debug!("sweep: {} combinations x {} windows", combinations.len(), windows.len());
+
+        let mut table: Vec<PyObject> = vec![];
+
+        for combination in combinations {
+            let param = PyDict::new(py);
+            for (i, index) in combination.iter().enumerate() {
+                param.set_item(names[i].as_str(), &values[i][*index])?;
+            }
+
+            for (split_index, phase, start_time, end_time) in windows.iter() {
+                let agent = agent_factory.call1((param,))?;
+                let clock_interval = self.prepare_agent(agent);
+
+                let (replay_from, event_start) = self.replay_range(*start_time);
+                let trades = statement
+                    .query_map(params![replay_from, *end_time], trade_from_row)
+                    .unwrap()
+                    .filter_map(ok_trade);
+                let book = book_statement
+                    .query_map(params![db.book_replay_start(replay_from), *end_time], book_from_row)
+                    .unwrap()
+                    .filter_map(ok_book);
+
+                let results = self.replay(py, agent, trades, event_start, clock_interval, &funding, book)?;
+
+                let report = BacktestReport::new(results, bar_sec, *start_time, *end_time);
+
+                let row = report.to_pydict(py)?;
+                row.set_item("split", split_index)?;
+                row.set_item("phase", phase)?;
+                for (key, value) in param.iter() {
+                    row.set_item(key, value)?;
+                }
+
+                table.push(row.to_object(py));
+            }
+        }
+
+        return Ok(table);
+    }
+
+    /// start_timeからend_timeまでをn+1個の連続した期間に分け、
+    /// n組の(train_start, train_end, test_start, test_end)を返す（sweepのwalk forward用）。
+    /// i番目の組はi番目の期間で学習し、次の期間で検証する。
+    /// anchored=trueの場合、学習期間は常にstart_timeから始まる（期間が伸びていく）。
+    #[staticmethod]
+    #[args(anchored = "false")]
+    pub fn walk_forward_splits(
+        start_time: MicroSec,
+        end_time: MicroSec,
+        n: i64,
+        anchored: bool,
+    ) -> Vec<(MicroSec, MicroSec, MicroSec, MicroSec)> {
+        if n <= 0 || end_time <= start_time {
+            return vec![];
+        }
+
+        let width = (end_time - start_time) / (n + 1);
+
+        return (0..n)
+            .map(|i| {
+                let train_start = if anchored { start_time } else { start_time + width * i };
+                let train_end = start_time + width * (i + 1);
+                let test_end = if i == n - 1 { end_time } else { start_time + width * (i + 2) };
+                (train_start, train_end, train_end, test_end)
+            })
+            .collect();
     }
 
-    pub fn run(&mut self, agent: &PyAny) -> Vec<OrderResult> {
+    /// runの間にequity_interval_secごとに記録した口座の状態
+    /// [timestamp, wallet_balance, realized_pnl, unrealized_pnl, long_position_size, short_position_size, long_order_size, short_order_size]
+    #[getter]
+    pub fn get_equity_curve(&self) -> Py<PyArray2<f64>> {
+        let array = ndarray::Array2::from_shape_vec(
+            (self.equity_curve.len(), EQUITY_COLUMNS),
+            self.equity_curve.concat(),
+        )
+        .unwrap();
+
+        return Python::with_gil(|py| array.into_pyarray(py).to_owned());
+    }
+}
+
+impl BackTester {
+    /// マーケットのDBを開く。db_dirが指定されている場合はその下の"{取引所}-{マーケット}.db"を開く。
+    fn open_db(&self, exchange_name: &str, market_name: &str) -> Result<TradeTable, String> {
+        match &self.db_dir {
+            Some(dir) => {
+                let path = dir.join(format!("{}.db", market_key(exchange_name, market_name).replace('/', "-")));
+                let db = TradeTable::open(path.to_str().unwrap()).map_err(|e| e.to_string())?;
+                db.create_table_if_not_exists();
+
+                Ok(db)
+            }
+            None => open_db(exchange_name, market_name),
+        }
+    }
+
+    /// 全マーケットのログを時刻順にマージして再生する。
+    fn run_multi(&mut self, agent: &PyAny) -> PyResult<Vec<OrderResult>> {
+        let clock_interval = self.prepare_agent(agent);
+
+        let dbs = self
+            .markets
+            .iter()
+            .map(|(exchange_name, market_name)| self.open_db(exchange_name, market_name))
+            .collect::<Result<Vec<TradeTable>, String>>()
+            .map_err(PyTypeError::new_err)?;
+        let funding: Vec<Vec<(MicroSec, f64)>> = dbs.iter().map(|db| db.select_funding(0, 0)).collect();
+
+        let (replay_from, event_start) = self.replay_range(self.start_time);
+
+        let mut statements: Vec<Statement> = dbs.iter().map(|db| db.select_range_statement()).collect();
+        let mut streams = vec![];
+        for statement in statements.iter_mut() {
+            let iter = statement
+                .query_map(params![replay_from, self.end_time], trade_from_row)
+                .unwrap()
+                .filter_map(ok_trade);
+            streams.push(iter);
+        }
+
+        let mut book_statements: Vec<Statement> = dbs.iter().map(|db| db.select_book_statement()).collect();
+        let mut books = vec![];
+        for (db, statement) in dbs.iter().zip(book_statements.iter_mut()) {
+            let iter = statement
+                .query_map(params![db.book_replay_start(replay_from), self.end_time], book_from_row)
+                .unwrap()
+                .filter_map(ok_book);
+            books.push(iter);
+        }
+
+        return Python::with_gil(|py| {
+            self.replay_multi(py, agent, MergeTrades::new(streams), event_start, clock_interval, &funding, books)
+        });
+    }
+
+    /// Agentが受け取るイベントを調べ、clock intervalを返す。
+    fn prepare_agent(&mut self, agent: &PyAny) -> i64 {
         self.agent_on_tick = self.has_want_event(agent, "on_tick");
         self.agent_on_clock = self.has_want_event(agent, "on_clock");
         self.agent_on_update = self.has_want_event(agent, "on_update");
@@ -44,81 +377,221 @@ impl BackTester {
         let clock_interval = self.clock_interval(agent);
         log::debug!("clock interval {:?}", clock_interval);
 
-        let db = open_db(self.exchange_name.as_str(), self.market_name.as_str());
-        let mut statement = db.select_all_statement();
+        return clock_interval;
+    }
+
+    /// 再生を開始する時刻と、Agentへイベントを送り始める時刻
+    /// start_timeの指定がない場合は、最初のログからwarm_up期間後にイベントを開始する。
+    fn replay_range(&self, start_time: MicroSec) -> (MicroSec, Option<MicroSec>) {
+        if start_time == 0 {
+            return (0, None);
+        }
+
+        return ((start_time - self.warm_up).max(0), Some(start_time));
+    }
+
+    /// ログを新しいDummySessionで再生し、約定履歴を返す。
+    /// 板のデータ(book)がある場合は、Tickの時刻までの更新をセッションへ渡して板として再生する。
+    fn replay<I, B>(
+        &mut self,
+        py: Python,
+        agent: &PyAny,
+        trades: I,
+        mut event_start: Option<MicroSec>,
+        clock_interval: i64,
+        funding: &[(MicroSec, f64)],
+        book: B,
+    ) -> PyResult<Vec<OrderResult>>
+    where
+        I: Iterator<Item = Trade>,
+        B: Iterator<Item = BookUpdate>,
+    {
+        let equity_interval = if self.equity_interval_sec == 0 {
+            clock_interval
+        } else {
+            self.equity_interval_sec
+        };
+        self.equity_curve.clear();
 
         let mut order_history: Vec<OrderResult> = make_log_buffer();
 
-        Python::with_gil(|py| {
-            let iter = statement
-                .query_map(params![], |row| {
-                    let bs_str: String = row.get_unwrap(1);
-                    let bs = OrderSide::from_str(bs_str.as_str());
-
-                    Ok(Trade {
-                        time: row.get_unwrap(0),
-                        price: row.get_unwrap(2),
-                        size: row.get_unwrap(3),
-                        order_side: bs,
-                        id: row.get_unwrap(4),
-                    })
-                })
-                .unwrap();
-
-            let mut session =
-                DummySession::new(self.exchange_name.as_str(), self.market_name.as_str());
-            let mut s = Py::new(py, session).unwrap();
-            let mut last_clock: i64 = 0;
-
-            // TODO: change hardcording its time.
-            let mut skip_tick = 100;
-
-            for trade in iter {
-                match trade {
-                    Ok(t) => {
-                        if skip_tick == 0 {
-                            if self.agent_on_clock {
-                                let current_clock = CEIL(t.time, clock_interval);
-                                if current_clock != last_clock {
-                                    s = self.clock(s, agent, current_clock);
-                                    last_clock = current_clock;
-                                }
-                            }
-                        }
-                        else {
-                            skip_tick -= 1;                            
-                        }
-
-                        session = s.extract::<DummySession>(py).unwrap();
-
-                        let mut tick_result: Vec<OrderResult> = vec![];
-
-                        session.process_trade(&t, &mut tick_result);
-                        s = Py::new(py, session).unwrap();
-                        s = self.tick(s, agent, &t);
-
-                        for mut r in tick_result {
-                            // TODO calc fee and profit
-                            r = self.calc_profit(r);
-
-                            if self.agent_on_update {
-                                s = self.update(s, agent, r.update_time, r.clone());
-                            }
-                            log_order_result(&mut order_history, r);
-                        }
+        let (exchange_name, market_name) = self.markets[0].clone();
+        let mut session = self.create_session(&exchange_name, &market_name)?;
+        session.set_funding_rates(funding.to_vec());
+        // セッションは一つのPyオブジェクトを使い回す（Tickごとに複製しない）
+        let mut s = Py::new(py, session).unwrap();
+        let mut book = BookFeed::new(book);
+        let mut last_clock: i64 = 0;
+        let mut last_equity_clock: i64 = 0;
+        let mut realized_pnl = 0.0;
+
+        for t in trades {
+            let start = *event_start.get_or_insert(t.time + self.warm_up);
+            let warm_up = t.time < start;
+
+            if !warm_up && self.agent_on_clock {
+                let current_clock = CEIL(t.time, clock_interval);
+                if current_clock != last_clock {
+                    s = self.clock(s, agent, current_clock);
+                    last_clock = current_clock;
+                }
+            }
+
+            let mut tick_result: Vec<OrderResult> = vec![];
+
+            {
+                let mut session = s.borrow_mut(py);
+                book.feed(&mut session, t.time);
+                session.process_trade(&t, &mut tick_result);
+                realized_pnl += tick_result.iter().fold(0.0, |sum, r| sum + r.total_profit);
+
+                if !warm_up {
+                    let equity_clock = FLOOR(t.time, equity_interval);
+                    if equity_clock != last_equity_clock {
+                        self.sample_equity(equity_clock, &session, realized_pnl);
+                        last_equity_clock = equity_clock;
                     }
-                    Err(e) => {
-                        log::warn!("err {}", e);
+                }
+            }
+
+            if !warm_up {
+                s = self.tick(s, agent, &t);
+            }
+
+            for r in tick_result {
+                if self.agent_on_update {
+                    s = self.update(s, agent, r.update_time, r.clone());
+                }
+                log_order_result(&mut order_history, r);
+            }
+        }
+
+        return Ok(order_history);
+    }
+
+    /// 複数マーケットのログを新しいMultiSessionで再生し、約定履歴を返す。
+    /// コールバックの最後の引数はTick（on_updateは約定）のマーケット。on_clockはclockを進めたTickのマーケット。
+    fn replay_multi<I, B>(
+        &mut self,
+        py: Python,
+        agent: &PyAny,
+        trades: I,
+        mut event_start: Option<MicroSec>,
+        clock_interval: i64,
+        funding: &[Vec<(MicroSec, f64)>],
+        books: Vec<B>,
+    ) -> PyResult<Vec<OrderResult>>
+    where
+        I: Iterator<Item = (usize, Trade)>,
+        B: Iterator<Item = BookUpdate>,
+    {
+        let equity_interval = if self.equity_interval_sec == 0 {
+            clock_interval
+        } else {
+            self.equity_interval_sec
+        };
+        self.equity_curve.clear();
+
+        let mut order_history: Vec<OrderResult> = make_log_buffer();
+
+        let sessions: Vec<DummySession> = self
+            .markets
+            .iter()
+            .zip(funding)
+            .map(|((exchange_name, market_name), rates)| {
+                let mut session = self.create_session(exchange_name, market_name)?;
+                session.set_funding_rates(rates.clone());
+                Ok(session)
+            })
+            .collect::<PyResult<Vec<DummySession>>>()?;
+        let keys = self.get_markets();
+        let mut books: Vec<BookFeed<B>> = books.into_iter().map(BookFeed::new).collect();
+
+        let s = Py::new(py, MultiSession::new(sessions)).unwrap();
+        let mut last_clock: i64 = 0;
+        let mut last_equity_clock: i64 = 0;
+        let mut realized_pnl = 0.0;
+
+        for (index, t) in trades {
+            let market = keys[index].as_str();
+            let start = *event_start.get_or_insert(t.time + self.warm_up);
+            let warm_up = t.time < start;
+
+            if !warm_up && self.agent_on_clock {
+                let current_clock = CEIL(t.time, clock_interval);
+                if current_clock != last_clock {
+                    call_agent(agent, "_on_clock", (current_clock, &s, market));
+                    last_clock = current_clock;
+                }
+            }
+
+            let mut tick_result: Vec<OrderResult> = vec![];
+
+            {
+                let mut session = s.borrow_mut(py);
+                books[index].feed(&mut session.sessions[index], t.time);
+                session.process_trade(index, &t, &mut tick_result);
+                realized_pnl += tick_result.iter().fold(0.0, |sum, r| sum + r.total_profit);
+
+                if !warm_up {
+                    let equity_clock = FLOOR(t.time, equity_interval);
+                    if equity_clock != last_equity_clock {
+                        self.sample_equity_multi(equity_clock, &session, realized_pnl);
+                        last_equity_clock = equity_clock;
                     }
                 }
             }
-        });
 
-        return order_history;
+            if !warm_up && self.agent_on_tick {
+                call_agent(
+                    agent,
+                    "_on_tick",
+                    (t.time, &s, t.order_side.to_string(), t.price, t.size, market),
+                );
+            }
+
+            for r in tick_result {
+                if self.agent_on_update {
+                    let result_market = r.market.clone();
+                    call_agent(agent, "_on_update", (r.update_time, &s, r.clone(), result_market));
+                }
+                log_order_result(&mut order_history, r);
+            }
+        }
+
+        return Ok(order_history);
+    }
+
+    /// 全マーケットの合計を記録する（ポジションとオーダーのサイズは単純な合計）
+    fn sample_equity_multi(&mut self, time: MicroSec, session: &MultiSession, realized_pnl: f64) {
+        let sum = |f: fn(&DummySession) -> f64| -> f64 { session.sessions.iter().map(f).sum() };
+
+        self.equity_curve.push([
+            time as f64,
+            session.get_wallet_balance(),
+            realized_pnl,
+            session.get_unrealized_pnl(),
+            sum(DummySession::get_long_position_size),
+            sum(DummySession::get_short_position_size),
+            sum(DummySession::get_long_order_size),
+            sum(DummySession::get_short_order_size),
+        ]);
+    }
+
+    /// 口座の状態を記録する。含み損益はcenter priceで評価する。
+    fn sample_equity(&mut self, time: MicroSec, session: &DummySession, realized_pnl: f64) {
+        self.equity_curve.push([
+            time as f64,
+            session.wallet_balance,
+            realized_pnl,
+            session.get_unrealized_pnl(),
+            session.get_long_position_size(),
+            session.get_short_position_size(),
+            session.get_long_order_size(),
+            session.get_short_order_size(),
+        ]);
     }
-}
 
-impl BackTester {
     fn tick(
         &mut self,
         session: Py<DummySession>,
@@ -198,21 +671,139 @@ impl BackTester {
         return interval_sec;
     }
 
-    // トータルだけ損益を計算する。
-    // TODO: MakerとTakerでも両率を変更する。
-    fn calc_profit(&self, mut order_result: OrderResult) -> OrderResult {
-        if order_result.status == OrderStatus::OpenPosition
-            || order_result.status == OrderStatus::ClosePosition
-        {
-            let fee_rate = 0.0001;
-            order_result.fee = order_result.order_foreign_size * fee_rate;
-            order_result.total_profit = order_result.profit - order_result.fee;
+    /// BackTesterの設定を反映したセッションを作る。
+    /// 手数料と損益はセッション側で計算する。
+    fn create_session(&self, exchange_name: &str, market_name: &str) -> PyResult<DummySession> {
+        let mut session = DummySession::new(exchange_name, market_name)?;
+
+        session.maker_fee = self.maker_fee;
+        session.taker_fee = self.taker_fee;
+        session.taker_slippage = self.taker_slippage;
+        session.leverage = self.leverage;
+        session.set_partial_fill(self.partial_fill);
+
+        if let Err(e) = session._set_fill_model(self.fill_model.as_str()) {
+            log::error!("{}", e);
+        }
+
+        if let Some(model) = &self.latency_model {
+            let latency = Python::with_gil(|py| PyLatencyModel::new(model.as_ref(py)));
+            session.latency = Box::new(latency.map_err(PyTypeError::new_err)?);
+        } else {
+            match make_latency_model(
+                self.order_latency,
+                self.cancel_latency,
+                self.decision_latency,
+                self.latency_jitter,
+                self.latency_seed,
+            ) {
+                Ok(latency) => session.latency = latency,
+                Err(e) => log::error!("{}", e),
+            }
         }
 
-        order_result
+        if self.initial_balance != 0.0 {
+            session.deposit(self.initial_balance / self.markets.len() as f64);
+        }
+
+        Ok(session)
+    }
+}
+
+/// Agentのコールバックを呼ぶ。Agentの例外はログに出して続行する（PaperSession, LiveSessionと共通）。
+pub fn call_agent(agent: &PyAny, method: &str, args: impl IntoPy<Py<PyTuple>>) {
+    if let Err(e) = agent.call_method1(method, args) {
+        log::warn!("Call {} Error {:?}", method, e);
     }
 }
 
+fn check_exchange(exchange_name: &str) -> PyResult<()> {
+    registered_exchange_name(exchange_name).map_err(PyTypeError::new_err)?;
+
+    Ok(())
+}
+
+/// DummySession.market_keyと同じ形式（"BN/BTCBUSD"）
+fn market_key(exchange_name: &str, market_name: &str) -> String {
+    let exchange_name = match find_market(exchange_name) {
+        Some(entry) => entry.exchange_name.to_string(),
+        None => exchange_name.to_uppercase(),
+    };
+
+    return format!("{}/{}", exchange_name, market_name.to_uppercase());
+}
+
+fn trade_from_row(row: &Row) -> rusqlite::Result<Trade> {
+    let bs_str: String = row.get_unwrap(1);
+    let bs = OrderSide::from_str(bs_str.as_str());
+
+    Ok(Trade {
+        time: row.get_unwrap(0),
+        price: row.get_unwrap(2),
+        size: row.get_unwrap(3),
+        order_side: bs,
+        id: row.get_unwrap(4),
+    })
+}
+
+fn ok_trade(trade: rusqlite::Result<Trade>) -> Option<Trade> {
+    match trade {
+        Ok(t) => Some(t),
+        Err(e) => {
+            log::warn!("err {}", e);
+            None
+        }
+    }
+}
+
+fn ok_book(update: rusqlite::Result<BookUpdate>) -> Option<BookUpdate> {
+    match update {
+        Ok(u) => Some(u),
+        Err(e) => {
+            log::warn!("err {}", e);
+            None
+        }
+    }
+}
+
+/// DBのカーソルから板の更新を読み、Tickの時刻までの更新をセッションへ渡す（板を全件メモリへ読み込まない）。
+struct BookFeed<B: Iterator<Item = BookUpdate>> {
+    updates: Peekable<B>,
+}
+
+impl<B: Iterator<Item = BookUpdate>> BookFeed<B> {
+    fn new(updates: B) -> Self {
+        return BookFeed {
+            updates: updates.peekable(),
+        };
+    }
+
+    fn feed(&mut self, session: &mut DummySession, time: MicroSec) {
+        while let Some(update) = self.updates.next_if(|u| u.time <= time) {
+            session.push_book_update(update);
+        }
+    }
+}
+
+/// グリッドの全組み合わせ（各パラメータの値のindex）
+fn grid_combinations(lengths: &[usize]) -> Vec<Vec<usize>> {
+    let mut combinations: Vec<Vec<usize>> = vec![vec![]];
+
+    for len in lengths {
+        let mut next: Vec<Vec<usize>> = vec![];
+        for c in &combinations {
+            for i in 0..*len {
+                let mut c = c.clone();
+                c.push(i);
+                next.push(c);
+            }
+        }
+        combinations = next;
+    }
+
+    return combinations;
+}
+
 #[cfg(test)]
 mod back_testr_test {
     use super::*;
@@ -220,23 +811,227 @@ mod back_testr_test {
 
     #[test]
     fn test_create() {
-        let _b = BackTester::new("FTX", "BTC-PERP");
+        let _b = BackTester::new("BN", "BTCBUSD").unwrap();
+
+        // 登録されていない取引所
+        assert!(BackTester::new("FTX", "BTC-PERP").is_err());
+    }
+
+    #[test]
+    fn test_add_market() {
+        let mut b = BackTester::new("BN", "BTCBUSD").unwrap();
+        b.add_market("bb", "btcusdt").unwrap();
+        assert_eq!(b.get_markets(), vec!["BN/BTCBUSD", "BB/BTCUSDT"]);
+
+        assert!(b.add_market("BB", "BTCUSDT").is_err());
+        assert!(b.add_market("FTX", "BTC-PERP").is_err());
+
+        // コインマージンとUSD建ては口座残高を合計できない
+        assert!(b.add_market("BN-CM", "BTCUSD_PERP").is_err());
+        assert_eq!(b.get_markets(), vec!["BN/BTCBUSD", "BB/BTCUSDT"]);
+
+        // 初期残高はマーケットごとに等分する
+        b.initial_balance = 1000.0;
+        let session = b.create_session("BB", "BTCUSDT").unwrap();
+        assert_eq!(session.wallet_balance, 500.0);
+    }
+
+    #[test]
+    fn test_create_session_latency() {
+        let mut b = BackTester::new("BN", "BTCBUSD").unwrap();
+        b.order_latency = SEC(1);
+        let session = b.create_session("BN", "BTCBUSD").unwrap();
+        assert_eq!(session.get_server_delay(), SEC(1));
+
+        // Pythonの遅延モデルはorder_latencyなどの設定より優先する
+        Python::with_gil(|py| {
+            let latency = PyModule::from_code(
+                py,
+                r#"
+class Latency:
+    def order_latency(self):
+        return 5
+
+    def cancel_latency(self):
+        return 5
+
+    def decision_latency(self):
+        return 0
+"#,
+                "latency.py",
+                "latency",
+            )
+            .unwrap()
+            .getattr("Latency")
+            .unwrap()
+            .call0()
+            .unwrap();
+
+            b.latency_model = Some(latency.into());
+        });
+
+        let mut session = b.create_session("BN", "BTCBUSD").unwrap();
+        assert_eq!(session.get_latency_model(), "Python");
+        assert_eq!(session.latency.order_latency(), 5);
+        assert_eq!(session.get_server_delay(), 5);
+
+        b.latency_model = Some(Python::with_gil(|py| py.None()));
+        assert!(b.create_session("BN", "BTCBUSD").is_err());
+    }
+
+    #[test]
+    fn test_book_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = TradeTable::open(dir.path().join("book.db").to_str().unwrap()).unwrap();
+        db.create_table_if_not_exists();
+        db.insert_book(&vec![
+            BookUpdate::new(SEC(1), true, OrderSide::Buy, 100.0, 1.0),
+            BookUpdate::new(SEC(1), true, OrderSide::Sell, 101.0, 1.0),
+            BookUpdate::new(SEC(3), false, OrderSide::Sell, 101.0, 0.0),
+            BookUpdate::new(SEC(5), true, OrderSide::Buy, 99.0, 1.0),
+            BookUpdate::new(SEC(5), true, OrderSide::Sell, 100.0, 1.0),
+        ])
+        .unwrap();
+
+        // SEC(4)から再生する場合はSEC(1)のスナップショットから読む
+        let mut statement = db.select_book_statement();
+        let updates = statement
+            .query_map(params![db.book_replay_start(SEC(4)), 0], book_from_row)
+            .unwrap()
+            .filter_map(ok_book);
+        let mut book = BookFeed::new(updates);
+
+        // Tickの時刻までの更新だけをセッションへ渡す
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        book.feed(&mut session, SEC(2));
+        assert_eq!(session.book_updates.len(), 2);
+        book.feed(&mut session, SEC(4));
+        assert_eq!(session.book_updates.len(), 3);
+        book.feed(&mut session, SEC(10));
+        assert_eq!(session.book_updates.len(), 5);
+    }
+
+    #[test]
+    fn test_grid_combinations() {
+        assert_eq!(grid_combinations(&[]), vec![Vec::<usize>::new()]);
+        assert_eq!(
+            grid_combinations(&[2, 3]),
+            vec![vec![0, 0], vec![0, 1], vec![0, 2], vec![1, 0], vec![1, 1], vec![1, 2]]
+        );
+        assert_eq!(grid_combinations(&[2, 0]).len(), 0);
+    }
+
+    #[test]
+    fn test_walk_forward_splits() {
+        assert_eq!(
+            BackTester::walk_forward_splits(0, 100, 3, false),
+            vec![(0, 25, 25, 50), (25, 50, 50, 75), (50, 75, 75, 100)]
+        );
+        assert_eq!(
+            BackTester::walk_forward_splits(0, 100, 3, true),
+            vec![(0, 25, 25, 50), (0, 50, 50, 75), (0, 75, 75, 100)]
+        );
+        assert_eq!(BackTester::walk_forward_splits(100, 0, 3, false).len(), 0);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut b = BackTester::new("BN", "BTCBUSD").unwrap();
+        b.db_dir = Some(dir.path().to_path_buf());
+        b.warm_up = SEC(10);
+
+        // 1秒ごとに100と101で交互に約定するログ
+        let mut db = b.open_db("BN", "BTCBUSD").unwrap();
+        let trades: Vec<Trade> = (0..400)
+            .map(|i| {
+                let side = if i % 2 == 0 { OrderSide::Buy } else { OrderSide::Sell };
+                let price = if i % 2 == 0 { 101.0 } else { 100.0 };
+                Trade::new(SEC(i), side, price, 1.0, i.to_string())
+            })
+            .collect();
+        db.insert_records(&trades).unwrap();
+
+        Python::with_gil(|py| {
+            let agent_class = PyModule::from_code(
+                py,
+                r#"
+class Agent:
+    def __init__(self, param):
+        self.size = param["size"]
+        self.ticks = 0
+
+    def clock_interval(self):
+        return 60
+
+    def on_tick(self, time, session, side, price, size):
+        pass
+
+    def _on_tick(self, time, session, side, price, size):
+        self.ticks += 1
+        if self.ticks % 20 == 0:
+            session.make_market_order("Buy" if self.ticks % 40 == 0 else "Sell", self.size, "")
+"#,
+                "agent.py",
+                "agent",
+            )
+            .unwrap()
+            .getattr("Agent")
+            .unwrap();
+
+            let grid = PyDict::new(py);
+            grid.set_item("size", vec![1.0, 2.0]).unwrap();
+
+            let splits = BackTester::walk_forward_splits(SEC(0), SEC(400), 3, false);
+            let table = b.sweep(py, agent_class, grid, 0, 0, splits, 60).unwrap();
+
+            // 2組のパラメータ x 3組の(train, test)
+            assert_eq!(table.len(), 2 * 3 * 2);
+
+            let row: &PyDict = table[0].extract(py).unwrap();
+            assert_eq!(row.get_item("split").unwrap().extract::<usize>().unwrap(), 0);
+            assert_eq!(row.get_item("phase").unwrap().extract::<String>().unwrap(), "train");
+            assert_eq!(row.get_item("size").unwrap().extract::<f64>().unwrap(), 1.0);
+
+            let row: &PyDict = table[1].extract(py).unwrap();
+            assert_eq!(row.get_item("phase").unwrap().extract::<String>().unwrap(), "test");
+
+            // 期間ごとに約定がある
+            let row: &PyDict = table[11].extract(py).unwrap();
+            assert_eq!(row.get_item("size").unwrap().extract::<f64>().unwrap(), 2.0);
+            assert!(0 < row.get_item("trade_count").unwrap().extract::<i64>().unwrap());
+        });
     }
 
     #[test]
     fn test_run() {
-        let b = &mut BackTester::new("FTX", "BTC-PERP");
+        let dir = tempfile::tempdir().unwrap();
+        let b = &mut BackTester::new("BN", "BTCBUSD").unwrap();
+        b.db_dir = Some(dir.path().to_path_buf());
+        b.warm_up = 0;
+
+        let mut db = b.open_db("BN", "BTCBUSD").unwrap();
+        let trades: Vec<Trade> = (0..10)
+            .map(|i| Trade::new(SEC(i), OrderSide::Buy, 100.0 + i as f64, 1.0, i.to_string()))
+            .collect();
+        db.insert_records(&trades).unwrap();
 
         Python::with_gil(|py| {
             let agent_class = PyModule::from_code(
                 py,
                 r#"
 class Agent:
-    def __init__():
+    def __init__(self):
+        self.ticks = 0
+
+    def clock_interval(self):
+        return 60
+
+    def on_tick(self, time, session, side, price, size):
         pass
 
-    def on_tick(session, time, side, price, size):
-        print(time, side, price, size)
+    def _on_tick(self, time, session, side, price, size):
+        self.ticks += 1
 "#,
                 "agent.py",
                 "agent",
@@ -247,7 +1042,8 @@ class Agent:
 
             let agent = agent_class.call0().unwrap();
 
-            b.run(agent);
+            b.run(agent).unwrap();
+            assert_eq!(agent.getattr("ticks").unwrap().extract::<i64>().unwrap(), 10);
         });
     }
 }
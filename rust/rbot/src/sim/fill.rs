@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+use crate::common::order::{Order, Trade};
+use crate::common::time::{MicroSec, SEC};
+
+/// Makerオーダーの約定モデル
+/// OrderQueueが約定ログごとに呼び出し、オーダーに割り当てられる約定サイズを決める。
+pub trait FillModel: Debug + Send {
+    fn name(&self) -> String;
+
+    /// 約定ログを観測する（キューの状態推定用）
+    fn on_trade(&mut self, _trade: &Trade) {}
+
+    /// オーダーがキューに入るときに、前に並んでいるサイズを推定する。
+    fn queue_ahead(&self, _order: &Order) -> f64 {
+        return 0.0;
+    }
+
+    /// 約定ログのうちorderへ割り当てられるサイズを返す。
+    /// 価格条件を満たさない場合はNoneを返す（キューはソートされているので以降のオーダーは処理しない）
+    fn exec_size(&self, buy_queue: bool, trade: &Trade, order: &mut Order, size: f64) -> Option<f64>;
+
+    fn clone_box(&self) -> Box<dyn FillModel>;
+}
+
+impl Clone for Box<dyn FillModel> {
+    fn clone(&self) -> Self {
+        return self.clone_box();
+    }
+}
+
+/// 約定価格がオーダー価格を越えたか（一つ下の刻みのログが発生したか）
+fn is_through_price(buy_queue: bool, trade_price: f64, order_price: f64) -> bool {
+    return (buy_queue && trade_price < order_price) || (!buy_queue && order_price < trade_price);
+}
+
+/// 約定ログの価格がオーダー価格を越えた場合に約定したとみなす（従来のモデル）
+#[derive(Debug, Clone)]
+pub struct TradeThroughModel {}
+
+impl TradeThroughModel {
+    pub const NAME: &'static str = "TradeThrough";
+
+    pub fn new() -> Self {
+        return TradeThroughModel {};
+    }
+}
+
+impl FillModel for TradeThroughModel {
+    fn name(&self) -> String {
+        return TradeThroughModel::NAME.to_string();
+    }
+
+    fn exec_size(&self, buy_queue: bool, trade: &Trade, order: &mut Order, size: f64) -> Option<f64> {
+        if is_through_price(buy_queue, trade.price, order.price) {
+            return Some(size);
+        }
+
+        return None;
+    }
+
+    fn clone_box(&self) -> Box<dyn FillModel> {
+        return Box::new(self.clone());
+    }
+}
+
+/// キューの位置を考慮した約定モデル
+/// ・オーダー受付時に、直近window期間中に同じ価格で約定したサイズを前に並んでいるサイズと推定する。
+///   （板のデータがある場合は、推定ではなく板の数量を使う。DummySession::queue_maker_order）
+/// ・先に同じ価格へ出している自分のオーダーの残りは、OrderQueueが前に並んでいるサイズに加える。
+/// ・同じ価格の約定ログは、前に並んでいるサイズを消化してから約定に割り当てる。
+/// ・価格を越えた約定ログが発生したら、その価格の板は全て消化されたとみなす。
+#[derive(Debug, Clone)]
+pub struct QueuePositionModel {
+    window: MicroSec,
+    trades: VecDeque<(MicroSec, f64, f64)>, // (time, price, size)
+}
+
+impl QueuePositionModel {
+    pub const NAME: &'static str = "QueuePosition";
+
+    pub fn new(window: MicroSec) -> Self {
+        return QueuePositionModel {
+            window,
+            trades: VecDeque::new(),
+        };
+    }
+}
+
+impl FillModel for QueuePositionModel {
+    fn name(&self) -> String {
+        return QueuePositionModel::NAME.to_string();
+    }
+
+    fn on_trade(&mut self, trade: &Trade) {
+        self.trades.push_back((trade.time, trade.price, trade.size));
+
+        while let Some((time, _price, _size)) = self.trades.front() {
+            if *time + self.window < trade.time {
+                self.trades.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// 板に並んでいる数量は約定ログからはわからないため、直近window期間中にその価格で約定した出来高で代用する。
+    /// 板の数量そのものではないので、出来高の少ない価格では前に並んでいるサイズを小さく見積もる。
+    fn queue_ahead(&self, order: &Order) -> f64 {
+        return self
+            .trades
+            .iter()
+            .filter(|(_time, price, _size)| *price == order.price)
+            .fold(0.0, |sum, (_time, _price, size)| sum + size);
+    }
+
+    fn exec_size(&self, buy_queue: bool, trade: &Trade, order: &mut Order, size: f64) -> Option<f64> {
+        if is_through_price(buy_queue, trade.price, order.price) {
+            order.queue_ahead = 0.0;
+            return Some(size);
+        }
+
+        if trade.price == order.price {
+            if size <= order.queue_ahead {
+                order.queue_ahead -= size;
+                return Some(0.0);
+            }
+
+            let exec_size = size - order.queue_ahead;
+            order.queue_ahead = 0.0;
+            return Some(exec_size);
+        }
+
+        return None;
+    }
+
+    fn clone_box(&self) -> Box<dyn FillModel> {
+        return Box::new(self.clone());
+    }
+}
+
+/// 名前から約定モデルを作る（Pythonからの設定用）
+pub fn fill_model_from_str(name: &str) -> Result<Box<dyn FillModel>, String> {
+    match name {
+        TradeThroughModel::NAME => Ok(Box::new(TradeThroughModel::new())),
+        QueuePositionModel::NAME => Ok(Box::new(QueuePositionModel::new(SEC(60)))),
+        _ => Err(format!(
+            "Unknown fill model {} / use {} or {}",
+            name,
+            TradeThroughModel::NAME,
+            QueuePositionModel::NAME
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test_fill_model {
+    use super::*;
+    use crate::common::order::OrderSide;
+
+    fn make_order(price: f64) -> Order {
+        Order::new(1, "order".to_string(), OrderSide::Buy, true, 100, price, 10.0, "".to_string())
+    }
+
+    fn make_trade(time: MicroSec, price: f64, size: f64) -> Trade {
+        Trade { time, order_side: OrderSide::Sell, price, size, id: "".to_string() }
+    }
+
+    #[test]
+    fn test_trade_through() {
+        let model = TradeThroughModel::new();
+        let mut order = make_order(100.0);
+
+        assert_eq!(model.exec_size(true, &make_trade(1, 100.0, 5.0), &mut order, 5.0), None);
+        assert_eq!(model.exec_size(true, &make_trade(1, 99.0, 5.0), &mut order, 5.0), Some(5.0));
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let mut model = QueuePositionModel::new(SEC(60));
+        model.on_trade(&make_trade(SEC(1), 100.0, 3.0));
+        model.on_trade(&make_trade(SEC(2), 100.0, 4.0));
+        model.on_trade(&make_trade(SEC(3), 101.0, 4.0));
+
+        let mut order = make_order(100.0);
+        order.queue_ahead = model.queue_ahead(&order);
+        assert_eq!(order.queue_ahead, 7.0);
+
+        // 前に並んでいる分が消化されるまでは約定しない
+        assert_eq!(model.exec_size(true, &make_trade(SEC(4), 100.0, 5.0), &mut order, 5.0), Some(0.0));
+        assert_eq!(order.queue_ahead, 2.0);
+        assert_eq!(model.exec_size(true, &make_trade(SEC(5), 100.0, 5.0), &mut order, 5.0), Some(3.0));
+        assert_eq!(order.queue_ahead, 0.0);
+
+        // 価格が届かない場合
+        assert_eq!(model.exec_size(true, &make_trade(SEC(5), 100.5, 5.0), &mut order, 5.0), None);
+
+        // 価格を越えたら全て約定対象
+        let mut order = make_order(100.0);
+        order.queue_ahead = 10.0;
+        assert_eq!(model.exec_size(true, &make_trade(SEC(6), 99.5, 5.0), &mut order, 5.0), Some(5.0));
+    }
+
+    #[test]
+    fn test_queue_position_window() {
+        let mut model = QueuePositionModel::new(SEC(60));
+        model.on_trade(&make_trade(SEC(1), 100.0, 3.0));
+        model.on_trade(&make_trade(SEC(100), 100.0, 4.0));
+
+        // window外の約定は数えない
+        assert_eq!(model.queue_ahead(&make_order(100.0)), 4.0);
+    }
+
+    #[test]
+    fn test_fill_model_from_str() {
+        assert_eq!(fill_model_from_str("TradeThrough").unwrap().name(), "TradeThrough");
+        assert_eq!(fill_model_from_str("QueuePosition").unwrap().name(), "QueuePosition");
+        assert!(fill_model_from_str("Unknown").is_err());
+    }
+}
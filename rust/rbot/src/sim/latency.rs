@@ -0,0 +1,338 @@
+use std::fmt::Debug;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+use pyo3::{PyAny, PyObject, PyResult, Python};
+
+use crate::common::time::MicroSec;
+
+/// オーダー・キャンセルの遅延モデル
+/// DummySessionがオーダー・キャンセルの受付ごとに呼び出し、遅延（マイクロ秒）を決める。
+pub trait LatencyModel: Debug + Send {
+    fn name(&self) -> String;
+
+    /// オーダーがサーバへ届くまでの遅延
+    fn order_latency(&mut self) -> MicroSec;
+
+    /// キャンセルがサーバへ届くまでの遅延
+    fn cancel_latency(&mut self) -> MicroSec;
+
+    /// エージェントがオーダーを判断するまでの遅延（この時間が経過した時点の板でオーダーを出す）
+    fn decision_latency(&mut self) -> MicroSec;
+
+    /// 直近のorder_latencyの値（まだ呼び出していない場合は基準の遅延、Pythonのモデルでは0）
+    /// 乱数やPythonのオブジェクトの状態を進めずに参照するために使う。
+    fn last_order_latency(&self) -> MicroSec;
+
+    fn clone_box(&self) -> Box<dyn LatencyModel>;
+}
+
+impl Clone for Box<dyn LatencyModel> {
+    fn clone(&self) -> Self {
+        return self.clone_box();
+    }
+}
+
+/// 固定の遅延
+#[derive(Debug, Clone)]
+pub struct FixedLatency {
+    order: MicroSec,
+    cancel: MicroSec,
+    decision: MicroSec,
+}
+
+impl FixedLatency {
+    pub const NAME: &'static str = "Fixed";
+
+    pub fn new(order: MicroSec, cancel: MicroSec, decision: MicroSec) -> Self {
+        return FixedLatency { order, cancel, decision };
+    }
+}
+
+impl LatencyModel for FixedLatency {
+    fn name(&self) -> String {
+        return FixedLatency::NAME.to_string();
+    }
+
+    fn order_latency(&mut self) -> MicroSec {
+        return self.order;
+    }
+
+    fn cancel_latency(&mut self) -> MicroSec {
+        return self.cancel;
+    }
+
+    fn decision_latency(&mut self) -> MicroSec {
+        return self.decision;
+    }
+
+    fn last_order_latency(&self) -> MicroSec {
+        return self.order;
+    }
+
+    fn clone_box(&self) -> Box<dyn LatencyModel> {
+        return Box::new(self.clone());
+    }
+}
+
+/// 固定の遅延に、平均jitterの指数分布の乱数を加える。
+/// 乱数はseedで初期化するので、同じ設定のバックテストは同じ結果になる。
+#[derive(Debug, Clone)]
+pub struct JitterLatency {
+    base: FixedLatency,
+    jitter: MicroSec,
+    rng: StdRng,
+    last_order: MicroSec,
+}
+
+impl JitterLatency {
+    pub const NAME: &'static str = "Jitter";
+
+    pub fn new(base: FixedLatency, jitter: MicroSec, seed: u64) -> Self {
+        let last_order = base.order;
+
+        return JitterLatency {
+            base,
+            jitter,
+            rng: StdRng::seed_from_u64(seed),
+            last_order,
+        };
+    }
+
+    fn sample(&mut self) -> MicroSec {
+        let u: f64 = self.rng.gen();
+
+        return (-(self.jitter as f64) * (1.0 - u).ln()).round() as MicroSec;
+    }
+}
+
+impl LatencyModel for JitterLatency {
+    fn name(&self) -> String {
+        return JitterLatency::NAME.to_string();
+    }
+
+    fn order_latency(&mut self) -> MicroSec {
+        self.last_order = self.base.order + self.sample();
+
+        return self.last_order;
+    }
+
+    fn cancel_latency(&mut self) -> MicroSec {
+        return self.base.cancel + self.sample();
+    }
+
+    fn decision_latency(&mut self) -> MicroSec {
+        if self.base.decision == 0 {
+            return 0;
+        }
+
+        return self.base.decision + self.sample();
+    }
+
+    fn last_order_latency(&self) -> MicroSec {
+        return self.last_order;
+    }
+
+    fn clone_box(&self) -> Box<dyn LatencyModel> {
+        return Box::new(self.clone());
+    }
+}
+
+/// Pythonで実装した遅延モデル
+/// order_latency(), cancel_latency(), decision_latency()を持つオブジェクトを呼び出す。
+/// 呼び出しに失敗した場合や負の値が返った場合は遅延0とする。
+#[derive(Debug)]
+pub struct PyLatencyModel {
+    model: PyObject,
+    last_order: MicroSec,
+}
+
+impl PyLatencyModel {
+    pub const NAME: &'static str = "Python";
+    const METHODS: [&'static str; 3] = ["order_latency", "cancel_latency", "decision_latency"];
+
+    pub fn new(model: &PyAny) -> Result<Self, String> {
+        for method in PyLatencyModel::METHODS {
+            if !model.hasattr(method).unwrap_or(false) {
+                return Err(format!("Latency model must implement {}()", method));
+            }
+        }
+
+        return Ok(PyLatencyModel {
+            model: model.into(),
+            last_order: 0,
+        });
+    }
+
+    fn call(&self, method: &str) -> MicroSec {
+        let latency: PyResult<MicroSec> = Python::with_gil(|py| {
+            return self.model.call_method0(py, method)?.extract::<MicroSec>(py);
+        });
+
+        match latency {
+            Ok(latency) if 0 <= latency => latency,
+            Ok(latency) => {
+                log::warn!("{} returned negative latency {}", method, latency);
+                0
+            }
+            Err(e) => {
+                log::warn!("Call {} Error {:?}", method, e);
+                0
+            }
+        }
+    }
+}
+
+impl LatencyModel for PyLatencyModel {
+    fn name(&self) -> String {
+        return PyLatencyModel::NAME.to_string();
+    }
+
+    fn order_latency(&mut self) -> MicroSec {
+        self.last_order = self.call("order_latency");
+
+        return self.last_order;
+    }
+
+    fn cancel_latency(&mut self) -> MicroSec {
+        return self.call("cancel_latency");
+    }
+
+    fn decision_latency(&mut self) -> MicroSec {
+        return self.call("decision_latency");
+    }
+
+    fn last_order_latency(&self) -> MicroSec {
+        return self.last_order;
+    }
+
+    fn clone_box(&self) -> Box<dyn LatencyModel> {
+        let model = Python::with_gil(|py| self.model.clone_ref(py));
+
+        return Box::new(PyLatencyModel {
+            model,
+            last_order: self.last_order,
+        });
+    }
+}
+
+/// 遅延の設定から遅延モデルを作る（Pythonからの設定用）
+/// jitterが0の場合は固定の遅延になる。
+pub fn make_latency_model(
+    order: MicroSec,
+    cancel: MicroSec,
+    decision: MicroSec,
+    jitter: MicroSec,
+    seed: u64,
+) -> Result<Box<dyn LatencyModel>, String> {
+    if order < 0 || cancel < 0 || decision < 0 || jitter < 0 {
+        return Err(format!(
+            "Latency must not be negative / order={} cancel={} decision={} jitter={}",
+            order, cancel, decision, jitter
+        ));
+    }
+
+    let base = FixedLatency::new(order, cancel, decision);
+
+    if jitter == 0 {
+        return Ok(Box::new(base));
+    }
+
+    return Ok(Box::new(JitterLatency::new(base, jitter, seed)));
+}
+
+#[cfg(test)]
+mod test_latency_model {
+    use super::*;
+    use pyo3::types::PyModule;
+
+    #[test]
+    fn test_fixed_latency() {
+        let mut model = make_latency_model(100, 200, 0, 0, 0).unwrap();
+        assert_eq!(model.name(), "Fixed");
+        assert_eq!(model.order_latency(), 100);
+        assert_eq!(model.cancel_latency(), 200);
+        assert_eq!(model.decision_latency(), 0);
+        assert_eq!(model.last_order_latency(), 100);
+
+        assert!(make_latency_model(-1, 0, 0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_jitter_latency() {
+        let mut model = make_latency_model(100, 200, 50, 1_000, 1).unwrap();
+        assert_eq!(model.name(), "Jitter");
+        assert_eq!(model.last_order_latency(), 100);
+
+        let samples: Vec<MicroSec> = (0..100).map(|_| model.order_latency()).collect();
+        assert_eq!(model.last_order_latency(), samples[99]);
+        assert!(samples.iter().all(|l| 100 <= *l));
+        assert!(samples.iter().any(|l| *l != samples[0]));
+        assert!(200 <= model.cancel_latency());
+        assert!(50 <= model.decision_latency());
+
+        // 同じseedでは同じ遅延になる
+        let mut other = make_latency_model(100, 200, 50, 1_000, 1).unwrap();
+        let other_samples: Vec<MicroSec> = (0..100).map(|_| other.order_latency()).collect();
+        assert_eq!(samples, other_samples);
+
+        // 判断遅延が0の場合はジッタを加えない
+        let mut model = make_latency_model(100, 200, 0, 1_000, 1).unwrap();
+        assert_eq!(model.decision_latency(), 0);
+    }
+
+    #[test]
+    fn test_py_latency() {
+        Python::with_gil(|py| {
+            let module = PyModule::from_code(
+                py,
+                r#"
+class Latency:
+    def __init__(self):
+        self.count = 0
+
+    def order_latency(self):
+        self.count += 1
+        return 1000 * self.count
+
+    def cancel_latency(self):
+        return -1
+
+    def decision_latency(self):
+        return "error"
+
+class NoCancel:
+    def order_latency(self):
+        return 0
+
+    def decision_latency(self):
+        return 0
+"#,
+                "latency.py",
+                "latency",
+            )
+            .unwrap();
+
+            let latency = module.getattr("Latency").unwrap().call0().unwrap();
+            let mut model = PyLatencyModel::new(latency).unwrap();
+            assert_eq!(model.name(), "Python");
+            assert_eq!(model.last_order_latency(), 0);
+            assert_eq!(model.order_latency(), 1000);
+            assert_eq!(model.last_order_latency(), 1000);
+
+            // cloneは同じPythonオブジェクトを参照する
+            let mut other = model.clone_box();
+            assert_eq!(other.order_latency(), 2000);
+            assert_eq!(model.order_latency(), 3000);
+
+            // 負の値や呼び出しエラーは遅延0
+            assert_eq!(model.cancel_latency(), 0);
+            assert_eq!(model.decision_latency(), 0);
+
+            let no_cancel = module.getattr("NoCancel").unwrap().call0().unwrap();
+            assert!(PyLatencyModel::new(no_cancel).is_err());
+        });
+    }
+}
@@ -0,0 +1,604 @@
+use std::thread;
+use std::time::Duration;
+
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+
+use crate::common::order::{log_order_result, make_log_buffer, LogBuffer, Order, OrderResult, OrderSide, OrderStatus};
+use crate::common::time::{MicroSec, CEIL, NOW, SEC};
+use crate::exchange::binance::rest::BinanceOrderClient;
+use crate::exchange::market::find_market;
+use crate::exchange::rest::OrderClient;
+use crate::sim::back::call_agent;
+use crate::sim::session::DummySession;
+
+/// 取引所へオーダーを送るセッション
+/// DummySessionと同じAPI（make_order, cancel_order, ポジション・オーダーの参照）で使える。
+/// オーダー・ポジション・口座の管理はDummySessionで行い、約定は取引所からpollで取得して反映する。
+#[pyclass(name = "_LiveSession")]
+pub struct LiveSession {
+    session: DummySession,
+    client: Box<dyn OrderClient>,
+    pending_results: LogBuffer, // 次のpollで返すキャンセルの結果
+    #[pyo3(get, set)]
+    poll_interval_sec: f64,
+}
+
+#[pymethods]
+impl LiveSession {
+    /// 現在はBinance(spot)のみ対応
+    #[new]
+    pub fn new(exchange_name: &str, market_name: &str, api_key: &str, api_secret: &str) -> PyResult<Self> {
+        let client: Box<dyn OrderClient> = match find_market(exchange_name).map(|entry| entry.exchange_name) {
+            Some("BN") => Box::new(BinanceOrderClient::new(market_name, api_key, api_secret)),
+            _ => {
+                return Err(PyTypeError::new_err(format!(
+                    "LiveSession does not support exchange {} / use BN",
+                    exchange_name
+                )));
+            }
+        };
+
+        return Ok(LiveSession::from_client(DummySession::new(exchange_name, market_name)?, client));
+    }
+
+    /// RESTのエンドポイント。テスト用にローカルのモックサーバへ切り替えられる。
+    #[getter]
+    pub fn get_rest_base(&self) -> String {
+        return self.client.rest_base();
+    }
+
+    #[setter]
+    pub fn set_rest_base(&mut self, rest_base: &str) {
+        self.client.set_rest_base(rest_base);
+    }
+
+    /// オーダー・ポジション管理用のセッションのコピー
+    #[getter]
+    pub fn get_session(&self) -> DummySession {
+        return self.session.clone();
+    }
+
+    #[getter]
+    pub fn get_current_timestamp(&self) -> MicroSec {
+        return self.session.current_timestamp;
+    }
+
+    #[getter]
+    pub fn get_exchange_name(&self) -> String {
+        return self.session.exchange_name.clone();
+    }
+
+    #[getter]
+    pub fn get_market_name(&self) -> String {
+        return self.session.market_name.clone();
+    }
+
+    #[getter]
+    pub fn get_market_key(&self) -> String {
+        return self.session.get_market_key();
+    }
+
+    #[getter]
+    pub fn get_maker_fee(&self) -> f64 {
+        return self.session.maker_fee;
+    }
+
+    #[setter]
+    pub fn set_maker_fee(&mut self, fee: f64) {
+        self.session.maker_fee = fee;
+    }
+
+    #[getter]
+    pub fn get_taker_fee(&self) -> f64 {
+        return self.session.taker_fee;
+    }
+
+    #[setter]
+    pub fn set_taker_fee(&mut self, fee: f64) {
+        self.session.taker_fee = fee;
+    }
+
+    #[getter]
+    pub fn get_wallet_balance(&self) -> f64 {
+        return self.session.wallet_balance;
+    }
+
+    #[getter]
+    pub fn get_long_order_size(&self) -> f64 {
+        return self.session.get_long_order_size();
+    }
+
+    #[getter]
+    pub fn get_long_orders(&self) -> Vec<Order> {
+        return self.session.get_long_orders();
+    }
+
+    #[getter]
+    pub fn get_short_order_size(&self) -> f64 {
+        return self.session.get_short_order_size();
+    }
+
+    #[getter]
+    pub fn get_short_orders(&self) -> Vec<Order> {
+        return self.session.get_short_orders();
+    }
+
+    #[getter]
+    pub fn get_long_position_size(&self) -> f64 {
+        return self.session.get_long_position_size();
+    }
+
+    #[getter]
+    pub fn get_long_position_price(&self) -> f64 {
+        return self.session.get_long_position_price();
+    }
+
+    #[getter]
+    pub fn get_short_position_size(&self) -> f64 {
+        return self.session.get_short_position_size();
+    }
+
+    #[getter]
+    pub fn get_short_position_price(&self) -> f64 {
+        return self.session.get_short_position_price();
+    }
+
+    /// 指値オーダーを取引所へ送る。
+    /// 受け付けられた場合はInOrder。PostOnlyError/NoMoneyの場合は結果を次のpollで返す。
+    #[args(post_only = "true")]
+    pub fn make_order(
+        &mut self,
+        side: &str,
+        price: f64,
+        size: f64,
+        duration_sec: i64,
+        message: String,
+        post_only: bool,
+    ) -> PyResult<OrderStatus> {
+        self.session.current_timestamp = NOW();
+
+        let order = self
+            .session
+            .new_order(OrderSide::from_str(side), price, size, duration_sec, message, post_only)
+            .map_err(PyTypeError::new_err)?;
+
+        return self.send_order(order);
+    }
+
+    /// 成行オーダーを取引所へ送る。
+    pub fn make_market_order(&mut self, side: &str, size: f64, message: String) -> PyResult<OrderStatus> {
+        self.session.current_timestamp = NOW();
+
+        let order = self
+            .session
+            .new_order(OrderSide::from_str(side), 0.0, size, 0, message, false)
+            .map_err(PyTypeError::new_err)?;
+
+        return self.send_order(order);
+    }
+
+    /// IDで指定したオーダーをキャンセルする。キャンセルの結果は次のpollで返す。
+    /// オーダーがない（約定済みなど）場合はNoActionを返す。
+    pub fn cancel_order(&mut self, order_id: &str) -> OrderStatus {
+        if self.session.long_orders.has_order(order_id) == false && self.session.short_orders.has_order(order_id) == false {
+            log::warn!("cancel order not found {}", order_id);
+            return OrderStatus::NoAction;
+        }
+
+        self.session.current_timestamp = NOW();
+
+        match self.client.cancel_order(order_id) {
+            Ok(()) => {
+                return self.session.close_order(order_id, OrderStatus::Cancel, &mut self.pending_results);
+            }
+            Err(e) => {
+                // 約定済みの場合は次のpollで約定が反映される。
+                log::warn!("cancel order error {} {:?}", order_id, e);
+                return e.status;
+            }
+        }
+    }
+
+    /// 指定したサイドのオーダーを全てキャンセルする（"BUY"/"SELL"以外は両サイド）。
+    /// キャンセルしたオーダーIDの一覧を返す。
+    pub fn cancel_all(&mut self, side: &str) -> Vec<String> {
+        let order_ids = match OrderSide::from_str(side) {
+            OrderSide::Buy => self.session.long_orders.get_order_ids(),
+            OrderSide::Sell => self.session.short_orders.get_order_ids(),
+            _ => {
+                let mut ids = self.session.long_orders.get_order_ids();
+                ids.append(&mut self.session.short_orders.get_order_ids());
+                ids
+            }
+        };
+
+        return order_ids
+            .into_iter()
+            .filter(|order_id| self.cancel_order(order_id) == OrderStatus::Cancel)
+            .collect();
+    }
+
+    /// 取引所から約定を取得し、OrderResult(OpenPosition/ClosePositionなど)へ変換して返す。
+    /// 期限切れ(valid_until)のオーダーはキャンセルしてExpireOrderを返す。
+    /// 通信エラーはログへ出力し、次のpollで再取得する。
+    pub fn poll(&mut self) -> Vec<OrderResult> {
+        self.session.current_timestamp = NOW();
+
+        let mut results = std::mem::take(&mut self.pending_results);
+        self.session.flush_pending_results(&mut results);
+
+        match self.client.fetch_fills() {
+            Ok(fills) => {
+                for fill in fills {
+                    if let Err(e) = self.session.apply_fill(&fill, &mut results) {
+                        log::warn!("fill is not applied {:?} {:?}", fill, e);
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("fetch fills error {:?}", e);
+            }
+        }
+
+        self.expire_orders(&mut results);
+
+        return results;
+    }
+
+    /// duration_sec秒間（0の場合はCtrl-Cまで）poll_interval_secごとにpollし、Agentのon_updateを呼ぶ。
+    /// on_clockは実時間で呼ばれる。約定履歴を返す。
+    #[args(duration_sec = "0")]
+    pub fn run(slf: &PyCell<Self>, agent: &PyAny, duration_sec: i64) -> PyResult<Vec<OrderResult>> {
+        let py = slf.py();
+
+        let agent_on_clock = agent.hasattr("on_clock")?;
+        let agent_on_update = agent.hasattr("on_update")?;
+        let clock_interval = agent.call_method0("clock_interval")?.extract::<i64>()?;
+
+        let end_time = if duration_sec == 0 { 0 } else { NOW() + SEC(duration_sec) };
+        let interval = Duration::from_secs_f64(slf.borrow().poll_interval_sec);
+
+        let mut order_history: Vec<OrderResult> = make_log_buffer();
+        let mut last_clock: i64 = 0;
+
+        loop {
+            py.check_signals()?;
+
+            if end_time != 0 && end_time < NOW() {
+                break;
+            }
+
+            if agent_on_clock {
+                let current_clock = CEIL(NOW(), clock_interval);
+                if current_clock != last_clock {
+                    call_agent(agent, "_on_clock", (current_clock, slf));
+                    last_clock = current_clock;
+                }
+            }
+
+            // Agentからの発注でborrow_mutできるよう、pollの結果を受け取ってから呼び出す。
+            let results = slf.borrow_mut().poll();
+
+            for r in results {
+                if agent_on_update {
+                    call_agent(agent, "_on_update", (r.update_time, slf, r.clone()));
+                }
+                log_order_result(&mut order_history, r);
+            }
+
+            py.allow_threads(|| thread::sleep(interval));
+        }
+
+        return Ok(order_history);
+    }
+}
+
+impl LiveSession {
+    /// 約定は取引所から一部約定ごとに反映するので、一部約定モードで管理する（キャンセル時は残りのサイズを報告する）。
+    pub fn from_client(mut session: DummySession, client: Box<dyn OrderClient>) -> Self {
+        session.order_id_prefix = session_token();
+        session.set_partial_fill(true);
+
+        return LiveSession {
+            session,
+            client,
+            pending_results: vec![],
+            poll_interval_sec: 1.0,
+        };
+    }
+
+    /// 発注し、受け付けられたオーダーをキューへ入れる（成行はtaker_orders）。
+    /// キューには取引所が受け付けた（刻みに丸めた）価格とサイズで入れる。
+    fn send_order(&mut self, order: Order) -> PyResult<OrderStatus> {
+        match self.client.new_order(&order) {
+            Ok(order) => {
+                if order.price == 0.0 {
+                    self.session.taker_orders.push(order);
+                } else {
+                    self.session.queue_maker_order(&order);
+                }
+
+                return Ok(OrderStatus::InOrder);
+            }
+            Err(e) if order.price != 0.0 && (e.status == OrderStatus::PostOnlyError || e.status == OrderStatus::NoMoney) => {
+                let result = OrderResult::from_order(self.session.current_timestamp, &order, e.status);
+                self.session.pending_results.push(result);
+
+                return Ok(e.status);
+            }
+            Err(e) => {
+                return Err(PyTypeError::new_err(format!("{:?} {}", e.status, e.message)));
+            }
+        }
+    }
+
+    /// 期限切れのオーダーをキャンセルする。
+    /// 約定済みでキャンセルできなかった場合はキューに残し、次のpollで約定を反映する。
+    fn expire_orders(&mut self, results: &mut LogBuffer) {
+        let mut orders = self.session.get_long_orders();
+        orders.append(&mut self.session.get_short_orders());
+
+        for order in orders {
+            if self.session.current_timestamp <= order.valid_until {
+                continue;
+            }
+
+            match self.client.cancel_order(&order.order_id) {
+                Ok(()) => {
+                    self.session.close_order(&order.order_id, OrderStatus::ExpireOrder, results);
+                }
+                Err(e) => {
+                    log::warn!("expire order error {} {:?}", order.order_id, e);
+                }
+            }
+        }
+    }
+}
+
+/// クライアントオーダーIDの接頭辞（開始時刻と乱数）。
+/// 再起動や同じアカウントの別のBotとクライアントオーダーIDが重なると、取引所で拒否されたり、
+/// 問い合わせ・キャンセルで別のオーダーを対象にしてしまうため、セッションごとに一意にする。
+fn session_token() -> String {
+    return format!("{:x}{:04x}", NOW(), rand::random::<u16>());
+}
+
+#[cfg(test)]
+mod test_live {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::exchange::binance::rest::test_binance_rest::EXCHANGE_INFO;
+    use crate::exchange::rest::test_rest::mock_http_server;
+
+    /// Binanceのオーダー APIのモック。リクエストを記録する。
+    /// orderIdはクライアントオーダーID("<接頭辞>-0000-0001")の番号。成行の買いは502を返すが、オーダーは受け付けている。
+    /// 約定はstartTime, endTime（24時間以内）の指定時にorderId=1の1件(20000で0.005BTC)のみ返す。
+    fn mock_exchange_server() -> (String, Arc<Mutex<Vec<String>>>) {
+        return mock_http_server(|method, path| {
+            let param = |key: &str| -> String {
+                path.split(|c| c == '?' || c == '&')
+                    .find_map(|kv| kv.strip_prefix(&format!("{}=", key)))
+                    .unwrap_or_default()
+                    .to_string()
+            };
+
+            let ack = |client_order_id: String| -> String {
+                let order_id: i64 = client_order_id.rsplit('-').next().unwrap().parse().unwrap();
+                format!(
+                    r#"{{"symbol":"BTCUSDT","orderId":{},"orderListId":-1,"clientOrderId":"{}","transactTime":1000}}"#,
+                    order_id, client_order_id
+                )
+            };
+
+            let (status, body) = match (method, path.split('?').next().unwrap_or_default()) {
+                ("GET", "/api/v3/exchangeInfo") => (200, EXCHANGE_INFO.to_string()),
+                ("POST", "/api/v3/order") => {
+                    if param("type") == "LIMIT_MAKER" && param("side") == "SELL" {
+                        (400, r#"{"code":-2010,"msg":"Order would immediately match and take."}"#.to_string())
+                    } else if param("type") == "MARKET" && param("side") == "BUY" {
+                        (502, "<html>502 Bad Gateway</html>".to_string())
+                    } else {
+                        (200, ack(param("newClientOrderId")))
+                    }
+                }
+                ("GET", "/api/v3/order") => (200, ack(param("origClientOrderId"))),
+                ("DELETE", "/api/v3/order") => (
+                    200,
+                    format!(
+                        r#"{{"symbol":"BTCUSDT","origClientOrderId":"{}","orderId":2,"status":"CANCELED"}}"#,
+                        param("origClientOrderId")
+                    ),
+                ),
+                ("GET", "/api/v3/myTrades") if param("startTime") != "" => {
+                    let start_time: i64 = param("startTime").parse().unwrap();
+                    let end_time: i64 = param("endTime").parse().unwrap();
+                    assert!(start_time <= end_time && end_time - start_time < 24 * 60 * 60 * 1_000);
+
+                    (
+                        200,
+                        r#"[{"symbol":"BTCUSDT","id":10,"orderId":1,"orderListId":-1,"price":"20000.00000000","qty":"0.00500000","quoteQty":"100.00000000","commission":"0","commissionAsset":"USDT","time":1000,"isBuyer":true,"isMaker":true,"isBestMatch":true}]"#.to_string(),
+                    )
+                }
+                ("GET", "/api/v3/myTrades") => (200, "[]".to_string()),
+                _ => (404, r#"{"code":-1,"msg":"not found"}"#.to_string()),
+            };
+
+            (status, "application/json", body.into_bytes())
+        });
+    }
+
+    fn make_live_session(url: &str) -> LiveSession {
+        let mut live = LiveSession::from_client(
+            DummySession::new("BN", "BTCUSDT").unwrap(),
+            Box::new(BinanceOrderClient::new("BTCUSDT", "key", "secret")),
+        );
+        live.set_rest_base(url);
+
+        return live;
+    }
+
+    #[test]
+    fn test_live_session_poll() {
+        let (url, requests) = mock_exchange_server();
+        let mut live = make_live_session(&url);
+        let prefix = live.session.order_id_prefix.clone();
+        let id = |n: &str| format!("{}-0000-{}", prefix, n);
+
+        // 20000で100(USDT)の買い
+        assert_eq!(live.make_order("Buy", 20_000.0, 100.0, 600, "".to_string(), true).unwrap(), OrderStatus::InOrder);
+        assert_eq!(live.get_long_order_size(), 100.0);
+
+        assert_eq!(
+            live.make_order("Sell", 19_000.0, 100.0, 600, "".to_string(), true).unwrap(),
+            OrderStatus::PostOnlyError
+        );
+
+        // すぐに期限切れになるオーダー。キューには取引所に載ったサイズ(0.01025 x 19500 = 199.875)で入る
+        assert_eq!(live.make_order("Buy", 19_500.005, 200.0, 0, "".to_string(), true).unwrap(), OrderStatus::InOrder);
+        assert!((live.get_long_order_size() - 299.875).abs() < 1e-9);
+        let queued = live.session.get_long_orders();
+        assert_eq!(queued[1].price, 19_500.0);
+        assert!((queued[1].remain_size - 199.875).abs() < 1e-9);
+        thread::sleep(Duration::from_millis(10));
+
+        let results = live.poll();
+        let status: Vec<OrderStatus> = results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            status,
+            vec![OrderStatus::PostOnlyError, OrderStatus::OpenPosition, OrderStatus::ExpireOrder]
+        );
+        assert_eq!(results[1].order_id, id("0001"));
+        assert_eq!(results[1].market, "BN/BTCUSDT");
+        assert_eq!(results[2].order_id, id("0003"));
+
+        assert_eq!(live.get_long_position_size(), 100.0);
+        assert_eq!(live.get_long_position_price(), 20_000.0);
+        assert_eq!(live.get_long_order_size(), 0.0);
+
+        // 取得済みの約定は再度返さない
+        assert!(live.poll().is_empty());
+
+        // 502でもクライアントオーダーIDで問い合わせ、受け付けられていれば発注済みとする
+        assert_eq!(live.make_market_order("Buy", 100.0, "".to_string()).unwrap(), OrderStatus::InOrder);
+        assert_eq!(live.session.taker_orders.len(), 1);
+
+        assert_eq!(live.cancel_order("0000-9999"), OrderStatus::NoAction);
+
+        // exchangeInfo以外は署名付き
+        let requests: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.starts_with("GET /api/v3/exchangeInfo?symbol=BTCUSDT") == false)
+            .cloned()
+            .collect();
+        for request in requests.iter() {
+            assert!(request.to_lowercase().contains("x-mbx-apikey: key"));
+            assert!(request.contains("&signature="));
+        }
+        assert!(requests.iter().any(|r| r.starts_with(&format!(
+            "POST /api/v3/order?symbol=BTCUSDT&side=BUY&type=LIMIT_MAKER&price=20000.00&quantity=0.00500&newClientOrderId={}",
+            id("0001")
+        ))));
+        // 価格は刻みに切り捨て、数量は切り捨てる
+        assert!(requests.iter().any(|r| r.starts_with(&format!(
+            "POST /api/v3/order?symbol=BTCUSDT&side=BUY&type=LIMIT_MAKER&price=19500.00&quantity=0.01025&newClientOrderId={}",
+            id("0003")
+        ))));
+        assert!(requests.iter().any(|r| r.starts_with(&format!(
+            "POST /api/v3/order?symbol=BTCUSDT&side=BUY&type=MARKET&quoteOrderQty=100.00000000&newClientOrderId={}",
+            id("0004")
+        ))));
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with(&format!("GET /api/v3/order?symbol=BTCUSDT&origClientOrderId={}", id("0004")))));
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with(&format!("DELETE /api/v3/order?symbol=BTCUSDT&origClientOrderId={}", id("0003")))));
+        assert!(requests.iter().any(|r| r.starts_with("GET /api/v3/myTrades?symbol=BTCUSDT&fromId=11")));
+    }
+
+    #[test]
+    fn test_live_session_restart() {
+        let (url, requests) = mock_exchange_server();
+
+        let mut first = make_live_session(&url);
+        assert_eq!(first.make_order("Buy", 20_000.0, 100.0, 600, "".to_string(), true).unwrap(), OrderStatus::InOrder);
+        let first_id = first.session.long_orders.get_order_ids()[0].clone();
+        drop(first);
+
+        // 再起動したセッションは同じ番号でも別のクライアントオーダーIDで発注する
+        let mut second = make_live_session(&url);
+        assert_eq!(second.make_order("Buy", 20_000.0, 100.0, 600, "".to_string(), true).unwrap(), OrderStatus::InOrder);
+        let second_id = second.session.long_orders.get_order_ids()[0].clone();
+
+        assert!(first_id.ends_with("-0000-0001"));
+        assert!(second_id.ends_with("-0000-0001"));
+        assert_ne!(first_id, second_id);
+
+        // 前のセッションのオーダーはキャンセルしない
+        assert_eq!(second.cancel_order(&first_id), OrderStatus::NoAction);
+
+        let client_ids: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.starts_with("POST /api/v3/order"))
+            .map(|r| r.split("newClientOrderId=").nth(1).unwrap().split('&').next().unwrap().to_string())
+            .collect();
+        assert_eq!(client_ids, vec![first_id, second_id]);
+    }
+
+    #[test]
+    fn test_live_session_run() {
+        let (url, _requests) = mock_exchange_server();
+        let mut live = make_live_session(&url);
+        live.poll_interval_sec = 0.1;
+
+        Python::with_gil(|py| {
+            let agent_class = PyModule::from_code(
+                py,
+                r#"
+class Agent:
+    def __init__(self):
+        self.updates = []
+
+    def clock_interval(self):
+        return 60
+
+    def on_clock(self):
+        pass
+
+    def on_update(self):
+        pass
+
+    def _on_clock(self, time, session):
+        if session.long_order_size == 0 and session.long_position_size == 0:
+            session.make_order("Buy", 20000.0, 100.0, 600, "", True)
+
+    def _on_update(self, time, session, result):
+        self.updates.append(result.order_id)
+        session.cancel_all("")
+"#,
+                "agent.py",
+                "agent",
+            )
+            .unwrap()
+            .getattr("Agent")
+            .unwrap();
+
+            let agent = agent_class.call0().unwrap();
+            let order_id = format!("{}-0000-0001", live.session.order_id_prefix);
+            let live = PyCell::new(py, live).unwrap();
+
+            let results = LiveSession::run(live, agent, 1).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].status, OrderStatus::OpenPosition);
+
+            let updates: Vec<String> = agent.getattr("updates").unwrap().extract().unwrap();
+            assert_eq!(updates, vec![order_id]);
+            assert_eq!(live.borrow().get_long_position_size(), 100.0);
+        });
+    }
+}
@@ -5,10 +5,11 @@ use crate::common::{
 use std::cmp::Ordering;
 use std::iter::Iterator;
 use crate::common::order::Trade;
+use crate::sim::fill::{FillModel, TradeThroughModel};
 
 /// 未実現オーダーリストを整理する。
 /// ・　オーダーの追加
-/// ・　オーダの削除（あとで実装）
+/// ・　オーダの削除
 /// ・　オーダー中のマージン計算
 /// ・　オーダーのExpire
 /// ・　オーダーの約定
@@ -16,6 +17,8 @@ use crate::common::order::Trade;
 #[derive(Debug, Clone)]
 pub struct OrderQueue {
     buy_queue: bool,
+    partial_fill: bool, // 一部約定のたびに結果を返す
+    fill_model: Box<dyn FillModel>,
     q: Vec<Order>,
 }
 
@@ -23,52 +26,98 @@ impl OrderQueue {
     pub fn new(buy_order: bool) -> Self {
         return OrderQueue {
             buy_queue: buy_order,
+            partial_fill: false,
+            fill_model: Box::new(TradeThroughModel::new()),
             q: vec![],
         };
     }
 
+    pub fn get_partial_fill(&self) -> bool {
+        return self.partial_fill;
+    }
+
+    /// 一部約定モードの設定
+    /// trueの場合、一部約定ごとに約定サイズのOrderResultを返し、１つのログで複数のオーダーを約定させる。
+    pub fn set_partial_fill(&mut self, partial_fill: bool) {
+        self.partial_fill = partial_fill;
+    }
+
+    pub fn get_fill_model(&self) -> String {
+        return self.fill_model.name();
+    }
+
+    /// 約定モデルの設定（デフォルトはTradeThroughModel）
+    pub fn set_fill_model(&mut self, fill_model: Box<dyn FillModel>) {
+        self.fill_model = fill_model;
+    }
+
     pub fn get_q(&self) -> Vec<Order> {
         return self.q.clone();
     }
 
+    /// 一部約定モードでは約定済みの分をのぞいたサイズ
+    fn open_size(&self, order: &Order) -> f64 {
+        if self.partial_fill {
+            return order.remain_size;
+        }
+        return order.size;
+    }
+
+    /// キャンセル・期限切れの結果を作る。
+    /// 一部約定モードでは約定済みの分は結果を返しているので、残りのサイズだけを報告する。
+    fn close_result(&self, current_time: MicroSec, order: &Order, status: OrderStatus) -> OrderResult {
+        let mut result = OrderResult::from_order(current_time, order, status);
+
+        if self.partial_fill {
+            result.set_exec_size(order.remain_size);
+        }
+
+        return result;
+    }
+
     pub fn get_size(&self) -> f64 {
-        let sum: f64 = self.q.iter().fold(0.0, |sum, item| sum + item.size);
+        let sum: f64 = self.q.iter().fold(0.0, |sum, item| sum + self.open_size(item));
         return sum;
     }
 
     /// オーダーをキューに入れる。
+    /// 板のデータがない場合の処理で、前に並んでいるサイズは約定モデルが約定ログから推定する。
     pub fn queue_order(&mut self, order: &Order) {
-        self.q.push(order.clone());
-        self.sort();
+        let queue_ahead = self.fill_model.queue_ahead(order);
+        self.queue_order_behind(order, queue_ahead);
     }
 
-    /*
-    // TODO: キャンセルオーダーの実装
-    // TOD: ログ出力の実装（上位で実装？）
-    pub fn cancel_order(&mut self, order_id: String) -> bool {
-        let index = 0;
+    /// 同じ価格の板の数量（resting_size）の後ろに並ぶオーダーとしてキューに入れる。
+    /// 先に同じ価格へ出している自分のオーダーの残りも前に並んでいるサイズに加える（約定ログと同じforeign単位）。
+    pub fn queue_order_behind(&mut self, order: &Order, resting_size: f64) {
+        let own_size: f64 = self
+            .q
+            .iter()
+            .filter(|o| o.price == order.price)
+            .fold(0.0, |sum, o| sum + o.remain_size / o.price);
+
+        let mut order = order.clone();
+        order.queue_ahead = resting_size + own_size;
+        self.q.push(order);
+        self.sort();
+    }
 
-        for order in self.q {
-            if order.order_id == order_id {
-                order
-                return true;
-            }
-            index += 1;
-        }
-        return false;
+    /// キューに指定されたIDのオーダーが入っているか確認する。
+    pub fn has_order(&self, order_id: &str) -> bool {
+        return self.q.iter().any(|order| order.order_id == order_id);
     }
-    */
-    /*
-    pub fn margin(&mut self) -> f64 {
-        let mut m: f64 = 0.0;
 
-        for order in &self.q {
-            m += order.size;
-        }
+    /// キューに入っているオーダーのID一覧
+    pub fn get_order_ids(&self) -> Vec<String> {
+        return self.q.iter().map(|order| order.order_id.clone()).collect();
+    }
 
-        return m;
+    /// キューに入っているオーダーのforeign通貨単位のサイズ（合計）
+    /// オーダー中の証拠金計算に利用する。
+    pub fn get_foreign_size(&self) -> f64 {
+        let sum: f64 = self.q.iter().fold(0.0, |sum, item| sum + self.open_size(item) / item.price);
+        return sum;
     }
-    */
 
     // Sellオーダーを約定しやすい順番に整列させる
     //   *やすい順番
@@ -136,8 +185,7 @@ impl OrderQueue {
                     log::debug!("Div 0 in expire / order={:?}", order);
                 }
 
-                let close_order =
-                    OrderResult::from_order(current_time, &order, OrderStatus::ExpireOrder);
+                let close_order = self.close_result(current_time, &order, OrderStatus::ExpireOrder);
 
                 return Ok(close_order);
             }
@@ -148,59 +196,138 @@ impl OrderQueue {
 
     /// 約定履歴からオーダーを処理する。
     /// 優先度の高いほうから１つづつ処理することとし、先頭のオーダ一つが約定したらリターンする。
-    /// うまくいった場合はClosedOrderを返す（ほとんどの場合はErrを返す(前回から変化が小さいのでなにもしていない）
-    /// 約定は、一つ下の刻みのログが発生したらカウントする。
+    /// 約定したオーダーのClosedOrderを返す（ほとんどの場合は空(前回から変化が小さいのでなにもしていない）
+    /// 約定の判定は約定モデルで行う（デフォルトでは一つ下の刻みのログが発生したらカウントする）。
     /// 超巨大オーダがきた場合でも複数約定はさせず、次回に回す。
-    pub fn consume(
-        &mut self,
-        trade: &Trade,
-        server_delay: MicroSec
-    ) -> Result<OrderResult, OrderStatus> {
+    /// 一部約定モードの場合は、一部約定ごとに結果を返し、複数のオーダーを同時に約定させる。
+    /// サーバへ届いていない（arrival_time前の）オーダーは約定させない。
+    pub fn consume(&mut self, trade: &Trade) -> Vec<OrderResult> {
+        self.fill_model.on_trade(trade);
+
         if self.has_q() == false {
-            return Err(OrderStatus::NoAction);
+            return vec![];
         }
 
-        if self.execute_remain_size(trade, server_delay) {
-            return self.pop_closed_order(trade.time);
+        if self.partial_fill {
+            return self.execute_partial_size(trade);
         }
 
-        return Err(OrderStatus::NoAction);
+        if self.execute_remain_size(trade) {
+            if let Ok(close_order) = self.pop_closed_order(trade.time) {
+                return vec![close_order];
+            }
+        }
+
+        return vec![];
+    }
+
+    /// 一部約定モードの約定処理。
+    /// 優先度の高い順にログのサイズを割り当て、約定したサイズでClosedOrderを作る。
+    /// ログのサイズはforeign、オーダーのサイズはhome(オーダー価格で換算する)。
+    /// remain_sizeが０になったオーダーはキューから取り除く。
+    /// 一部約定ごとにorder_sub_idを2ずつ増やす（ポジションの分割(split_child)で+1を使うため）
+    fn execute_partial_size(&mut self, trade: &Trade) -> Vec<OrderResult> {
+        let mut results: Vec<OrderResult> = vec![];
+        let mut size_remain = trade.size;
+        let mut level_size: Option<f64> = None;
+        let mut i = 0;
+
+        while i < self.q.len() {
+            if trade.time < self.q[i].arrival_time {
+                i += 1;
+                continue;
+            }
+
+            let size = OrderQueue::size_for(trade, &self.q[i], size_remain, &mut level_size);
+            let available =
+                match self.fill_model.exec_size(self.buy_queue, trade, &mut self.q[i], size) {
+                    Some(size) => size,
+                    None => break, // ソートされているので全件検索は不要。
+                };
+
+            if available <= 0.0 {
+                i += 1;
+                continue;
+            }
+
+            // ログのサイズ(foreign)をオーダーのサイズ(home)へ換算して割り当てる。
+            let price = self.q[i].price;
+            let remain_foreign = self.q[i].remain_size / price;
+            let (exec_size, exec_foreign) = if remain_foreign <= available {
+                (self.q[i].remain_size, remain_foreign)
+            } else {
+                (available * price, available)
+            };
+            size_remain = (size_remain - exec_foreign).max(0.0);
+            self.q[i].remain_size -= exec_size;
+
+            let mut close_order =
+                OrderResult::from_order(trade.time, &self.q[i], OrderStatus::OrderComplete);
+            close_order.set_exec_size(exec_size);
+            close_order.order_sub_id = self.q[i].fill_count * 2;
+            self.q[i].fill_count += 1;
+            results.push(close_order);
+
+            if self.q[i].remain_size <= 0.0 {
+                self.q.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        return results;
+    }
+
+    /// 約定モデルへ渡すログのサイズ。
+    /// 価格を越えたオーダーには前のオーダーが使った残りを、ログと同じ価格のオーダーには
+    /// その価格に届いた時点のサイズを渡す（queue_aheadは同じ価格の自分のオーダーを含めた並び位置のため）。
+    fn size_for(trade: &Trade, order: &Order, size_remain: f64, level_size: &mut Option<f64>) -> f64 {
+        if order.price == trade.price {
+            return *level_size.get_or_insert(size_remain);
+        }
+
+        return size_remain;
     }
 
     /// キューの中に処理できるオーダーがあれば、size_remainをへらしていく。
     /// size_remainが０になったらオーダ完了の印。
     /// 実際の取り出しは pop_close_orderで実施する。
-    fn execute_remain_size(&mut self, trade: &Trade, server_delay: MicroSec) -> bool {
+    fn execute_remain_size(&mut self, trade: &Trade) -> bool {
         if self.has_q() == false {
             return false;
         }
 
         let l = self.q.len();
         let mut size_remain = trade.size;
+        let mut level_size: Option<f64> = None;
         let mut complete_order = false;
 
         // 順番に価格条件をみたしたものから約定したこととし、remain_sizeをへらしていく。
         for i in 0..l {
-            if trade.time + server_delay <= self.q[i].create_time {
+            if trade.time < self.q[i].arrival_time {
                 continue;
             }
 
-            if ((self.buy_queue == true) && (trade.price  < self.q[i].price))          // Buy Case
-                || ((self.buy_queue == false) && (self.q[i].price < trade.price))      // Sell case
-            {
-                if self.q[i].remain_size <= size_remain {
-                    complete_order = true;
-                    size_remain -= self.q[i].remain_size;
-                    self.q[i].remain_size = 0.0;
-                } else {
-                    self.q[i].remain_size -= size_remain;
-                    // size_remain = 0.0;
+            let size = OrderQueue::size_for(trade, &self.q[i], size_remain, &mut level_size);
+            match self.fill_model.exec_size(self.buy_queue, trade, &mut self.q[i], size) {
+                Some(available) => {
+                    if available <= 0.0 {
+                        continue;
+                    }
 
+                    if self.q[i].remain_size <= available {
+                        complete_order = true;
+                        size_remain = available - self.q[i].remain_size;
+                        self.q[i].remain_size = 0.0;
+                    } else {
+                        self.q[i].remain_size -= available;
+                        size_remain = 0.0;
+                    }
+                }
+                None => {
+                    // ソートされているので全件検索は不要。
                     break;
                 }
-            } else {
-                // ソートされているので全件検索は不要。
-                break;
             }
         }
 
@@ -230,16 +357,31 @@ impl OrderQueue {
         return Err(OrderStatus::NoAction);
     }
 
+    /// 取引所の約定（LiveSession）でIDを指定してオーダーのremain_sizeを減らす。
+    /// 約定後のオーダーを返す。全て約定したオーダーはキューから取り除く。
+    pub fn fill_order(&mut self, order_id: &str, size: f64) -> Option<Order> {
+        let i = self.q.iter().position(|o| o.order_id == order_id)?;
+
+        self.q[i].remain_size -= size;
+        let order = self.q[i].clone();
+
+        if is_filled(&order) {
+            self.q.remove(i);
+        }
+
+        return Some(order);
+    }
+
     /// ID で指定されたオーダをキャンセルする。
-    fn cancel_order(&mut self, current_time: MicroSec, order_id: String) -> Result<OrderResult, OrderStatus> {
+    /// ログ出力は上位（DummySession）で行う。
+    pub fn cancel_order(&mut self, current_time: MicroSec, order_id: String) -> Result<OrderResult, OrderStatus> {
         let l = self.q.len();
 
         for i in 0..l {
             if self.q[i].order_id == order_id {
                 let order = &self.q.remove(i);
 
-                let cancel_order =
-                    OrderResult::from_order(current_time, &order, OrderStatus::Cancel);
+                let cancel_order = self.close_result(current_time, &order, OrderStatus::Cancel);
 
                 return Ok(cancel_order);
             }
@@ -249,6 +391,11 @@ impl OrderQueue {
     }
 }
 
+/// 全て約定したか（取引所の約定は部分約定の合計に誤差が出るため、オーダーサイズに対する比率で判定する）
+pub fn is_filled(order: &Order) -> bool {
+    return order.remain_size <= order.size * 1e-9;
+}
+
 #[derive(Debug, Clone, Copy)]
 ///　ポジションの１項目
 /// 　Positionsでポジションリストを扱う。
@@ -309,22 +456,23 @@ impl Position {
         // オーダの全部クローズ（ポジションは残る）
         order.status = OrderStatus::ClosePosition;
 
-        // order.open_price = self.price;
+        order.open_price = self.price;
+        order.open_home_size = order.order_home_size;
+        order.open_foreign_size = order.order_home_size / self.price;
         order.close_price = order.order_price;
         order.close_home_size = order.order_home_size;
         order.close_foreign_size = order.order_foreign_size;
 
         match order.order_side {
             OrderSide::Buy => {
-                // order.profit = (order.open_price - order.close_price)/order.open_price * order.order_foreign_size;
-                               // ex) Sell Price  80              Buy Price 100  = -20
-                order.profit = order.open_foreign_size - order.close_foreign_size;                
-
+                // ショートのクローズ
+                // ex) Sell Price 100            Buy Price 80 =  size/80 - size/100 (>0)
+                order.profit = order.close_foreign_size - order.open_foreign_size;
             }
             OrderSide::Sell => {
-                // order.profit = (order.close_price - order.open_price)/order.open_price * order.order_foreign_size;
-                                // ex) Sell Price 100            Buy Price 80 =  20
-                order.profit = order.close_foreign_size - order.open_foreign_size;
+                // ロングのクローズ
+                // ex) Buy Price 80             Sell Price 100 = size/80 - size/100 (>0)
+                order.profit = order.open_foreign_size - order.close_foreign_size;
             }
             OrderSide::Unknown => {
                 log::error!("Unknown side");
@@ -371,29 +519,43 @@ impl Positions {
         return self.short_position.home_size;
     }
 
-    /*
-    fn long_volume(&self) -> f64 {
-        return self.long_position.calc_volume();
-    }
+    /// ポジションのforeign通貨単位のサイズ（取得単価ベース、ロングとショートの合計）
+    pub fn get_position_foreign_size(&self) -> f64 {
+        let mut size = 0.0;
+
+        if self.long_position.price != 0.0 {
+            size += self.long_position.home_size / self.long_position.price;
+        }
 
-    fn short_volume(&self) -> f64 {
-        return self.short_position.calc_volume();
+        if self.short_position.price != 0.0 {
+            size += self.short_position.home_size / self.short_position.price;
+        }
+
+        return size;
     }
-    */
 
-    /*
-    /// ポジションからできるマージンを計算する。
+    /// 現在価格でのポジションの含み損益（foreign通貨単位）
     /// 本来は手数料も込みだが、あとまわし　TODO: 手数料計算
-    pub fn get_margin(&self, center_price: f64) -> f64 {
-        let long_margin = (center_price - self.long_position.price)     // 購入単価 - 現在単価
-             * self.long_volume();
+    pub fn get_unrealized_pnl(&self, center_price: f64) -> f64 {
+        if center_price == 0.0 {
+            return 0.0;
+        }
+
+        let mut pnl = 0.0;
 
-        let short_margin = (self.short_position.price - center_price)    // 購入単価 - 現在単価
-            * self.short_volume();
+        if self.long_position.price != 0.0 {
+            // 購入単価 - 現在単価
+            pnl += self.long_position.home_size / self.long_position.price
+                - self.long_position.home_size / center_price;
+        }
+
+        if self.short_position.price != 0.0 {
+            pnl += self.short_position.home_size / center_price
+                - self.short_position.home_size / self.short_position.price;
+        }
 
-        return long_margin + short_margin;
+        return pnl;
     }
-     */
 
     pub fn update_position(&mut self, order: &mut OrderResult) -> Result<(), OrderStatus> {
         match self.update_small_position(order) {
@@ -641,6 +803,25 @@ mod test_order_positon {
 mod test_positions {
     use super::*;
 
+    #[test]
+    fn test_unrealized_pnl() {
+        let mut positions = Positions::new();
+        assert_eq!(positions.get_unrealized_pnl(100.0), 0.0);
+        assert_eq!(positions.get_position_foreign_size(), 0.0);
+
+        let order = Order::new(1, "long".to_string(), OrderSide::Buy, true, 100, 100.0, 1000.0, "".to_string());
+        let mut long = OrderResult::from_order(2, &order, OrderStatus::OrderComplete);
+        let _r = positions.update_position(&mut long);
+
+        assert_eq!(positions.get_position_foreign_size(), 10.0);
+        assert_eq!(positions.get_unrealized_pnl(100.0), 0.0);
+        assert_eq!(positions.get_unrealized_pnl(200.0), 1000.0 / 100.0 - 1000.0 / 200.0);
+        assert!(positions.get_unrealized_pnl(50.0) < 0.0);
+
+        // 価格が未定の場合は0
+        assert_eq!(positions.get_unrealized_pnl(0.0), 0.0);
+    }
+
     #[test]
     fn test_update_position() {
         // 新規だった場合はOpenOrderを返す。
@@ -840,22 +1021,22 @@ mod test_orders {
         assert_eq!(orders.q[1].remain_size, 200.0);
 
         // 書いオーダに対し、買いのログがきてもなにもしない。
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Buy, price: 1000.0, size:125.0, id: "".to_string() }, 0), false);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Buy, price: 1000.0, size:125.0, id: "".to_string() }), false);
         assert_eq!(orders.q[0].remain_size, 50.0);
         assert_eq!(orders.q[1].remain_size, 200.0);
 
         // 買いオーダーに対し、売りオーダーがきたら消費するが価格が同じ場合は消費しない。
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Sell, price: 200.0, size:125.0, id: "".to_string() }, 0), false);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Sell, price: 200.0, size:125.0, id: "".to_string() }), false);
         assert_eq!(orders.q[0].remain_size, 50.0);
         assert_eq!(orders.q[1].remain_size, 200.0);
 
-        // 買いオーダーに対し、売りオーダーがきたら消費する。200にたいし199なので消費するが、オーダー時間よりサーバディレイ分Delayしていないので消費しない。
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 0, order_side: OrderSide::Sell, price: 199.0, size:125.0, id: "".to_string() }, 0), false);
+        // 買いオーダーに対し、売りオーダーがきたら消費する。200にたいし199なので消費するが、オーダーがサーバへ届く前の約定なので消費しない。
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 0, order_side: OrderSide::Sell, price: 199.0, size:125.0, id: "".to_string() }), false);
         assert_eq!(orders.q[0].remain_size, 50.0);
         assert_eq!(orders.q[1].remain_size, 200.0);
 
         // 買いオーダーに対し、売りオーダーがきたら消費する。200にたいし199なので消費する。
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Sell, price: 199.0, size:125.0, id: "".to_string() }, 0), true);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Sell, price: 199.0, size:125.0, id: "".to_string() }), true);
         assert_eq!(orders.q[0].remain_size, 0.0);
         assert_eq!(orders.q[1].remain_size, 125.0);
 
@@ -878,8 +1059,8 @@ mod test_orders {
         assert_eq!(orders.q[0].remain_size, 100.0);
         assert_eq!(orders.q[1].remain_size, 50.0);
         // 高い値段で売られても買いは約定しない。
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 200, order_side: OrderSide::Sell, price: 1000.0, size:125.0, id: "".to_string() }, 0), false);
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 200, order_side: OrderSide::Sell, price: 1000.0, size:125.0, id: "".to_string() }, 0), false);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 200, order_side: OrderSide::Sell, price: 1000.0, size:125.0, id: "".to_string() }), false);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 200, order_side: OrderSide::Sell, price: 1000.0, size:125.0, id: "".to_string() }), false);
 
         // まだ約定していない。
         match orders.pop_closed_order(1000) {
@@ -892,7 +1073,7 @@ mod test_orders {
             }
         }
 
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 200, order_side: OrderSide::Sell, price: 100.1, size:125.0, id: "".to_string() }, 0), true);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 200, order_side: OrderSide::Sell, price: 100.1, size:125.0, id: "".to_string() }), true);
         println!("--after--");
         assert_eq!(orders.q[0].remain_size, 0.0);
         assert_eq!(orders.q[1].remain_size, 25.0);
@@ -920,7 +1101,7 @@ mod test_orders {
         }
 
         // ログをおくったら約定する。
-        assert_eq!(orders.execute_remain_size(&Trade{ time: 0, order_side: OrderSide::Buy, price: 100.1, size:125.0, id: "".to_string() }, 0), true);
+        assert_eq!(orders.execute_remain_size(&Trade{ time: 1000, order_side: OrderSide::Buy, price: 100.1, size:125.0, id: "".to_string() }), true);
         match orders.pop_closed_order(1001) {
             Ok(order) => {
                 assert_eq!(order.order_id, "low price but later");
@@ -933,6 +1114,141 @@ mod test_orders {
         }
     }
 
+    #[test]
+    fn test_partial_fill() {
+        let mut orders = make_orders(false);
+        orders.set_partial_fill(true);
+        assert_eq!(orders.get_size(), 400.0);
+
+        // 100.0のオーダー２つ(100.0, 50.0 = 1.0, 0.5 foreign)を一度に約定させ、残りは次のオーダーの一部約定にならない（価格条件）
+        let results = orders.consume(&Trade{ time: 200, order_side: OrderSide::Buy, price: 100.1, size: 1.75, id: "".to_string() });
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].order_id, "low price");
+        assert_eq!(results[0].order_home_size, 100.0);
+        assert_eq!(results[1].order_id, "low price but later");
+        assert_eq!(results[1].order_home_size, 50.0);
+        assert_eq!(orders.len(), 2);
+
+        // 一部約定は約定サイズで結果を返す（0.125 foreign x 200 = 25 home）。
+        let results = orders.consume(&Trade{ time: 300, order_side: OrderSide::Buy, price: 200.1, size: 0.125, id: "".to_string() });
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].order_id, "high price but first");
+        assert_eq!(results[0].order_home_size, 25.0);
+        assert_eq!(results[0].order_foreign_size, 0.125);
+        assert_eq!(results[0].order_sub_id, 0);
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders.get_size(), 225.0);
+
+        let results = orders.consume(&Trade{ time: 300, order_side: OrderSide::Buy, price: 200.1, size: 0.25, id: "".to_string() });
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].order_home_size, 25.0);
+        assert_eq!(results[0].order_sub_id, 2);    // 同じオーダーの２回目の一部約定
+        assert_eq!(results[1].order_id, "high price");
+        assert_eq!(results[1].order_sub_id, 0);
+        assert_eq!(results[1].order_home_size, 25.0);
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders.get_size(), 175.0);
+    }
+
+    #[test]
+    fn test_partial_fill_cancel() {
+        let mut orders = make_orders(false);
+        orders.set_partial_fill(true);
+
+        let results = orders.consume(&Trade{ time: 300, order_side: OrderSide::Buy, price: 200.1, size: 0.125, id: "".to_string() });
+        assert_eq!(results[0].order_home_size, 25.0);
+
+        // 一部約定したオーダーのキャンセル・期限切れは、約定していない残りのサイズを報告する
+        let r = orders.cancel_order(310, "high price but first".to_string()).unwrap();
+        assert_eq!(r.status, OrderStatus::Cancel);
+        assert_eq!(r.order_home_size, 25.0);
+        assert_eq!(r.order_foreign_size, 0.125);
+
+        let r = orders.expire(250).unwrap();
+        assert_eq!(r.order_home_size, 100.0);
+
+        // 一部約定モードでない場合はオーダーのサイズ
+        let mut orders = make_orders(false);
+        orders.consume(&Trade{ time: 300, order_side: OrderSide::Buy, price: 200.1, size: 30.0, id: "".to_string() });
+        let r = orders.cancel_order(310, "high price but first".to_string()).unwrap();
+        assert_eq!(r.order_home_size, 50.0);
+    }
+
+    #[test]
+    fn test_partial_fill_queue_ahead() {
+        let mut orders = OrderQueue::new(true);
+        orders.set_partial_fill(true);
+        orders.set_fill_model(Box::new(crate::sim::fill::QueuePositionModel::new(crate::common::time::SEC(60))));
+
+        let o1 = Order::new(1, "first".to_string(), OrderSide::Buy, true, 100, 100.0, 500.0, "".to_string());
+        let o2 = Order::new(2, "second".to_string(), OrderSide::Buy, true, 100, 100.0, 500.0, "".to_string());
+        orders.queue_order_behind(&o1, 5.0);
+        orders.queue_order_behind(&o2, 5.0);
+
+        // 後から出したオーダーは、先に出した自分のオーダー(500 / 100 = 5.0)の後ろに並ぶ。
+        assert_eq!(orders.q[0].queue_ahead, 5.0);
+        assert_eq!(orders.q[1].queue_ahead, 10.0);
+
+        // 同じ価格のログはそれぞれの並び位置を進める。
+        let results = orders.consume(&Trade{ time: 10, order_side: OrderSide::Sell, price: 100.0, size: 4.0, id: "".to_string() });
+        assert_eq!(results.len(), 0);
+        assert_eq!(orders.q[0].queue_ahead, 1.0);
+        assert_eq!(orders.q[1].queue_ahead, 6.0);
+
+        let results = orders.consume(&Trade{ time: 20, order_side: OrderSide::Sell, price: 100.0, size: 3.0, id: "".to_string() });
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].order_id, "first");
+        assert_eq!(results[0].order_home_size, 200.0);
+        assert_eq!(results[0].order_foreign_size, 2.0);
+        // 前に残っているのは先に出したオーダーの残り(3.0)
+        assert_eq!(orders.q[1].queue_ahead, 3.0);
+
+        // 先に出したオーダーの残りを約定させてから、後のオーダーが約定する。
+        let results = orders.consume(&Trade{ time: 30, order_side: OrderSide::Sell, price: 100.0, size: 5.0, id: "".to_string() });
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].order_id, "first");
+        assert_eq!(results[0].order_home_size, 300.0);
+        assert_eq!(results[1].order_id, "second");
+        assert_eq!(results[1].order_home_size, 200.0);
+        assert_eq!(orders.q[0].queue_ahead, 0.0);
+    }
+
+    #[test]
+    fn test_consume_without_partial_fill() {
+        let mut orders = make_orders(false);
+
+        // 一部約定モードでない場合は１つのオーダーのみ約定する。
+        let results = orders.consume(&Trade{ time: 200, order_side: OrderSide::Buy, price: 100.1, size: 170.0, id: "".to_string() });
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].order_home_size, 100.0);
+        assert_eq!(orders.len(), 3);
+    }
+
+    #[test]
+    fn test_cancel_order() {
+        let mut orders = make_orders(true);
+        assert_eq!(orders.has_order("high price"), true);
+
+        let r = orders.cancel_order(10, "high price".to_string());
+        match r {
+            Ok(order) => {
+                assert_eq!(order.status, OrderStatus::Cancel);
+                assert_eq!(order.order_id, "high price");
+                assert_eq!(order.update_time, 10);
+            }
+            Err(_e) => {
+                assert!(false);
+            }
+        }
+        assert_eq!(orders.len(), 3);
+        assert_eq!(orders.has_order("high price"), false);
+
+        // 存在しないオーダーはなにもしない
+        let r = orders.cancel_order(10, "high price".to_string());
+        assert_eq!(r.err(), Some(OrderStatus::NoAction));
+        assert_eq!(orders.get_order_ids().len(), 3);
+    }
+
     #[test]
     fn test_expire_order() {
         let mut orders = make_orders(true);
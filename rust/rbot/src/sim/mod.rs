@@ -1,4 +1,10 @@
 
+pub mod fill;
+pub mod latency;
 pub mod market;
 pub mod session;
+pub mod multi;
+pub mod paper;
+pub mod live;
 pub mod back;
+pub mod report;
@@ -0,0 +1,233 @@
+use std::iter::Peekable;
+
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+
+use crate::common::order::{OrderResult, OrderStatus, Trade};
+use crate::common::time::MicroSec;
+use crate::sim::session::DummySession;
+
+/// 複数マーケットのセッション
+/// マーケットごとにDummySession（オーダーキュー、ポジション、口座）を持つ。
+/// マーケットは"BN/BTCBUSD"のようなキー(DummySession.market_key)で指定する。
+#[pyclass(name = "_MultiSession")]
+#[derive(Clone, Debug)]
+pub struct MultiSession {
+    #[pyo3(get)]
+    pub current_timestamp: MicroSec,
+    pub sessions: Vec<DummySession>,
+}
+
+#[pymethods]
+impl MultiSession {
+    /// マーケットのキー一覧（BackTesterに追加した順）
+    #[getter]
+    pub fn get_markets(&self) -> Vec<String> {
+        return self.sessions.iter().map(|s| s.get_market_key()).collect();
+    }
+
+    /// 最初のマーケットの取引所（Session.ohlcvのデフォルト）
+    #[getter]
+    pub fn get_exchange_name(&self) -> String {
+        return self.sessions[0].exchange_name.clone();
+    }
+
+    #[getter]
+    pub fn get_market_name(&self) -> String {
+        return self.sessions[0].market_name.clone();
+    }
+
+    /// マーケットのセッションのコピー（ポジションやオーダーの参照用）
+    pub fn session(&self, market: &str) -> PyResult<DummySession> {
+        let index = self.index_of(market).map_err(PyTypeError::new_err)?;
+
+        return Ok(self.sessions[index].clone());
+    }
+
+    #[args(post_only = "true")]
+    pub fn make_order(
+        &mut self,
+        market: &str,
+        side: &str,
+        price: f64,
+        size: f64,
+        duration_sec: i64,
+        message: String,
+        post_only: bool,
+    ) -> PyResult<OrderStatus> {
+        let session = self.session_mut(market)?;
+
+        return session.make_order(side, price, size, duration_sec, message, post_only);
+    }
+
+    pub fn make_market_order(
+        &mut self,
+        market: &str,
+        side: &str,
+        size: f64,
+        message: String,
+    ) -> PyResult<OrderStatus> {
+        let session = self.session_mut(market)?;
+
+        return session.make_market_order(side, size, message);
+    }
+
+    pub fn cancel_order(&mut self, market: &str, order_id: &str) -> PyResult<OrderStatus> {
+        let session = self.session_mut(market)?;
+
+        return Ok(session.cancel_order(order_id));
+    }
+
+    pub fn cancel_all(&mut self, market: &str, side: &str) -> PyResult<Vec<String>> {
+        let session = self.session_mut(market)?;
+
+        return Ok(session.cancel_all(side));
+    }
+
+    /// 全マーケットの口座残高の合計（BackTester.add_marketで証拠金の通貨が同じマーケットに限っている）
+    #[getter]
+    pub fn get_wallet_balance(&self) -> f64 {
+        return self.sessions.iter().map(|s| s.wallet_balance).sum();
+    }
+
+    /// 全マーケットの含み損益の合計
+    #[getter]
+    pub fn get_unrealized_pnl(&self) -> f64 {
+        return self.sessions.iter().map(|s| s.get_unrealized_pnl()).sum();
+    }
+}
+
+impl MultiSession {
+    pub fn new(sessions: Vec<DummySession>) -> Self {
+        return MultiSession {
+            current_timestamp: 0,
+            sessions,
+        };
+    }
+
+    /// マーケットのキーからindexを探す（大文字小文字は区別しない）
+    pub fn index_of(&self, market: &str) -> Result<usize, String> {
+        let market = market.to_uppercase();
+
+        return self
+            .sessions
+            .iter()
+            .position(|s| s.get_market_key() == market)
+            .ok_or(format!("Unknown market {} / use one of {:?}", market, self.get_markets()));
+    }
+
+    /// オーダーの作成時刻を揃えるため、他のマーケットのTickで発注した場合も現在時刻へ進める。
+    fn session_mut(&mut self, market: &str) -> PyResult<&mut DummySession> {
+        let index = self.index_of(market).map_err(PyTypeError::new_err)?;
+        let session = &mut self.sessions[index];
+
+        if session.current_timestamp < self.current_timestamp {
+            session.current_timestamp = self.current_timestamp;
+        }
+
+        return Ok(session);
+    }
+
+    /// index番目のマーケットの約定ログを処理する
+    pub fn process_trade(&mut self, index: usize, trade: &Trade, tick_result: &mut Vec<OrderResult>) {
+        self.current_timestamp = trade.time;
+        self.sessions[index].process_trade(trade, tick_result);
+    }
+}
+
+/// 時刻順の約定ログを複数マージし、(マーケットのindex, Trade)を時刻順に返す。
+/// 同じ時刻の場合はindexの小さいマーケットを先にする。
+pub struct MergeTrades<I: Iterator<Item = Trade>> {
+    streams: Vec<Peekable<I>>,
+}
+
+impl<I: Iterator<Item = Trade>> MergeTrades<I> {
+    pub fn new(streams: Vec<I>) -> Self {
+        return MergeTrades {
+            streams: streams.into_iter().map(|s| s.peekable()).collect(),
+        };
+    }
+}
+
+impl<I: Iterator<Item = Trade>> Iterator for MergeTrades<I> {
+    type Item = (usize, Trade);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(usize, MicroSec)> = None;
+
+        for (index, stream) in self.streams.iter_mut().enumerate() {
+            if let Some(trade) = stream.peek() {
+                if next.map_or(true, |(_, time)| trade.time < time) {
+                    next = Some((index, trade.time));
+                }
+            }
+        }
+
+        let (index, _time) = next?;
+
+        return self.streams[index].next().map(|trade| (index, trade));
+    }
+}
+
+#[cfg(test)]
+mod test_multi {
+    use super::*;
+    use crate::common::order::OrderSide;
+
+    fn make_trades(times: &[MicroSec], price: f64) -> Vec<Trade> {
+        times
+            .iter()
+            .map(|t| Trade::new(*t, OrderSide::Buy, price, 1.0, t.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_trades() {
+        let a = make_trades(&[1, 3, 3, 7], 100.0);
+        let b = make_trades(&[2, 3, 8], 200.0);
+        let c = make_trades(&[], 300.0);
+
+        let merged: Vec<(usize, MicroSec)> = MergeTrades::new(vec![a.into_iter(), b.into_iter(), c.into_iter()])
+            .map(|(index, t)| (index, t.time))
+            .collect();
+
+        assert_eq!(merged, vec![(0, 1), (1, 2), (0, 3), (0, 3), (1, 3), (0, 7), (1, 8)]);
+    }
+
+    #[test]
+    fn test_multi_session_orders() {
+        let mut session = MultiSession::new(vec![
+            DummySession::new("BN", "BTCBUSD").unwrap(),
+            DummySession::new("BB", "BTCUSDT").unwrap(),
+        ]);
+        assert_eq!(session.get_markets(), vec!["BN/BTCBUSD", "BB/BTCUSDT"]);
+        assert_eq!(session.index_of("bb/btcusdt"), Ok(1));
+        assert!(session.index_of("BN/ETHBUSD").is_err());
+
+        let mut results: Vec<OrderResult> = vec![];
+        session.process_trade(0, &Trade::new(1, OrderSide::Buy, 100.0, 1.0, "a1".to_string()), &mut results);
+        session.process_trade(0, &Trade::new(2, OrderSide::Sell, 99.0, 1.0, "a2".to_string()), &mut results);
+        session.process_trade(1, &Trade::new(3, OrderSide::Buy, 200.0, 1.0, "b1".to_string()), &mut results);
+        session.process_trade(1, &Trade::new(4, OrderSide::Sell, 199.0, 1.0, "b2".to_string()), &mut results);
+
+        // BNのTickより後の時刻でBBへ発注する
+        session.process_trade(0, &Trade::new(5, OrderSide::Buy, 100.0, 1.0, "a3".to_string()), &mut results);
+        session
+            .make_order("BB/BTCUSDT", "Buy", 150.0, 1.0, 100, "".to_string(), true)
+            .unwrap();
+
+        let bb = session.session("BB/BTCUSDT").unwrap();
+        assert_eq!(bb.get_long_order_size(), 1.0);
+        assert_eq!(bb.get_long_orders()[0].create_time, 5);
+        assert_eq!(session.session("BN/BTCBUSD").unwrap().get_long_order_size(), 0.0);
+
+        // BBの約定ログでのみ約定し、結果にマーケットが入る
+        session.process_trade(0, &Trade::new(1_000_000, OrderSide::Sell, 140.0, 10.0, "a4".to_string()), &mut results);
+        assert!(results.is_empty());
+
+        session.process_trade(1, &Trade::new(1_000_000, OrderSide::Sell, 149.0, 10.0, "b3".to_string()), &mut results);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, OrderStatus::OpenPosition);
+        assert_eq!(results[0].market, "BB/BTCUSDT");
+    }
+}
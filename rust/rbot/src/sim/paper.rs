@@ -0,0 +1,307 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use pyo3::exceptions::PyTypeError;
+use pyo3::prelude::*;
+
+use crate::common::order::{log_order_result, make_log_buffer, OrderResult, Trade};
+use crate::common::time::{MicroSec, CEIL, NOW, SEC};
+use crate::exchange::market::{open_market, Market};
+use crate::exchange::ws::spawn_trade_stream;
+use crate::sim::back::call_agent;
+use crate::sim::session::DummySession;
+
+/// 取引所のWebSocketの約定で動かすペーパートレード
+/// オーダーの約定処理はDummySessionと同じ。受信した約定はTradeTableへ追記する。
+/// Agentのon_tick/on_clock/on_updateは受信したタイミングで呼ばれる（on_clockは実時間）。
+#[pyclass(name = "_PaperSession")]
+pub struct PaperSession {
+    market: Box<dyn Market>,
+    session: Py<DummySession>,
+    #[pyo3(get, set)]
+    ws_url: String, // テスト用にローカルのWebSocketサーバへ切り替えられる
+    #[pyo3(get, set)]
+    reconnect: bool,
+    #[pyo3(get, set)]
+    flush_interval_sec: i64, // 約定をDBへ書き込む間隔
+    #[pyo3(get)]
+    gaps: Vec<(MicroSec, MicroSec)>, // 再接続の間に受信できなかった期間(最後の約定, 再接続後の最初の約定)。downloadで補完する
+}
+
+#[pymethods]
+impl PaperSession {
+    /// exchange_nameは取引所コード(BN, BN-UM, BN-CM, BN-AGG, BN-UM-AGG, BN-CM-AGG, BB)。登録されていない場合はエラー。
+    #[new]
+    pub fn new(exchange_name: &str, market_name: &str) -> PyResult<Self> {
+        let market = open_market(exchange_name, market_name, true).map_err(PyTypeError::new_err)?;
+
+        return Ok(PaperSession::from_market(market));
+    }
+
+    /// 約定処理を行うセッション。手数料などはこのオブジェクトに設定する。
+    #[getter]
+    pub fn get_session(&self) -> Py<DummySession> {
+        return self.session.clone();
+    }
+
+    /// WebSocketへ接続し、duration_sec秒間（0の場合は切断されるかCtrl-Cまで）Agentを動かす。
+    /// 約定履歴を返す。
+    #[args(duration_sec = "0")]
+    pub fn run(&mut self, py: Python, agent: &PyAny, duration_sec: i64) -> PyResult<Vec<OrderResult>> {
+        let mut config = self.market.ws_config();
+        config.url = self.ws_url.clone();
+        config.reconnect = self.reconnect;
+
+        let rx = spawn_trade_stream(config, self.market.ws_parser());
+
+        let end_time = if duration_sec == 0 { 0 } else { NOW() + SEC(duration_sec) };
+
+        return self.receive(py, agent, rx, end_time);
+    }
+}
+
+impl PaperSession {
+    pub fn from_market(market: Box<dyn Market>) -> Self {
+        let session = DummySession::new(&market.exchange_name(), &market.market_name()).expect("market is registered");
+        let ws_url = market.ws_config().url;
+
+        return PaperSession {
+            market,
+            session: Python::with_gil(|py| Py::new(py, session).unwrap()),
+            ws_url,
+            reconnect: true,
+            flush_interval_sec: 1,
+            gaps: vec![],
+        };
+    }
+
+    /// channelから約定を受け取り、セッションとAgentへ渡す。
+    /// 空の約定は再接続の通知で、再接続前後の約定の間をgapsへ記録する。
+    fn receive(
+        &mut self,
+        py: Python,
+        agent: &PyAny,
+        mut rx: Receiver<Vec<Trade>>,
+        end_time: MicroSec,
+    ) -> PyResult<Vec<OrderResult>> {
+        let agent_on_tick = agent.hasattr("on_tick")?;
+        let agent_on_clock = agent.hasattr("on_clock")?;
+        let agent_on_update = agent.hasattr("on_update")?;
+        let clock_interval = agent.call_method0("clock_interval")?.extract::<i64>()?;
+
+        let mut order_history: Vec<OrderResult> = make_log_buffer();
+        let mut buffer: Vec<Trade> = vec![];
+        let mut last_flush = NOW();
+        let mut last_clock: i64 = 0;
+        let mut last_trade_time: MicroSec = 0;
+        let mut reconnected = false;
+
+        loop {
+            if let Err(e) = py.check_signals() {
+                self.flush(&mut buffer);
+                return Err(e);
+            }
+
+            if end_time != 0 && end_time < NOW() {
+                break;
+            }
+
+            // 受信待ちの間は他のPythonスレッドを動かす
+            let (returned_rx, received) = py.allow_threads(move || {
+                let received = rx.recv_timeout(Duration::from_millis(100));
+                (rx, received)
+            });
+            rx = returned_rx;
+
+            let trades = match received {
+                Ok(trades) if trades.is_empty() => {
+                    reconnected = true;
+                    trades
+                }
+                Ok(trades) => trades,
+                Err(RecvTimeoutError::Timeout) => vec![],
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            if let (true, Some(first)) = (reconnected, trades.first()) {
+                if last_trade_time != 0 {
+                    log::warn!(
+                        "websocket reconnected / trades between {} and {} may be missing",
+                        last_trade_time,
+                        first.time
+                    );
+                    self.gaps.push((last_trade_time, first.time));
+                }
+                reconnected = false;
+            }
+
+            if agent_on_clock {
+                let current_clock = CEIL(NOW(), clock_interval);
+                if current_clock != last_clock {
+                    call_agent(agent, "_on_clock", (current_clock, &self.session));
+                    last_clock = current_clock;
+                }
+            }
+
+            for t in trades {
+                let mut tick_result: Vec<OrderResult> = vec![];
+                self.session.borrow_mut(py).process_trade(&t, &mut tick_result);
+
+                if agent_on_tick {
+                    call_agent(
+                        agent,
+                        "_on_tick",
+                        (t.time, &self.session, t.order_side.to_string(), t.price, t.size),
+                    );
+                }
+
+                for r in tick_result {
+                    if agent_on_update {
+                        call_agent(agent, "_on_update", (r.update_time, &self.session, r.clone()));
+                    }
+                    log_order_result(&mut order_history, r);
+                }
+
+                last_trade_time = t.time;
+                buffer.push(t);
+            }
+
+            if SEC(self.flush_interval_sec) <= NOW() - last_flush {
+                self.flush(&mut buffer);
+                last_flush = NOW();
+            }
+        }
+
+        self.flush(&mut buffer);
+
+        return Ok(order_history);
+    }
+
+    /// 受信した約定をDBへ追記する
+    fn flush(&mut self, buffer: &mut Vec<Trade>) {
+        if buffer.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.market.db().insert_records(buffer) {
+            log::error!("insert error {:?}", e);
+        }
+        buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod test_paper {
+    use super::*;
+    use crate::common::order::OrderSide;
+    use crate::exchange::bybit::BybitMarket;
+    use crate::exchange::ws::test_ws::mock_ws_server;
+
+    const MESSAGES: [&str; 3] = [
+        r#"{"success":true,"ret_msg":"","conn_id":"1","op":"subscribe"}"#,
+        r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":1000,"data":[{"T":1000,"s":"BTCUSDT","S":"Buy","v":"1","p":"100.0","L":"PlusTick","i":"a1","BT":false},{"T":1001,"s":"BTCUSDT","S":"Sell","v":"1","p":"99.0","L":"MinusTick","i":"a2","BT":false}]}"#,
+        r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":2000,"data":[{"T":2000,"s":"BTCUSDT","S":"Sell","v":"5","p":"90.0","L":"MinusTick","i":"a3","BT":false}]}"#,
+    ];
+
+    #[test]
+    fn test_paper_session() {
+        let (url, handle) = mock_ws_server(MESSAGES.iter().map(|m| m.to_string()).collect());
+
+        let dir = tempfile::tempdir().unwrap();
+        let market = BybitMarket::open_db_path("BTCUSDT", true, dir.path().join("paper.db").to_str().unwrap());
+
+        let mut paper = PaperSession::from_market(Box::new(market));
+        paper.ws_url = url;
+        paper.reconnect = false;
+
+        Python::with_gil(|py| {
+            let agent_class = PyModule::from_code(
+                py,
+                r#"
+class Agent:
+    def __init__(self):
+        self.ticks = []
+        self.updates = []
+
+    def clock_interval(self):
+        return 60
+
+    def on_tick(self):
+        pass
+
+    def on_update(self):
+        pass
+
+    def _on_tick(self, time, session, side, price, size):
+        self.ticks.append((time, side, price))
+        if len(self.ticks) == 2:
+            session.make_order("Buy", 95.0, 1.0, 600, "", True)
+
+    def _on_update(self, time, session, result):
+        self.updates.append(str(result.status))
+"#,
+                "agent.py",
+                "agent",
+            )
+            .unwrap()
+            .getattr("Agent")
+            .unwrap();
+
+            let agent = agent_class.call0().unwrap();
+
+            let results = paper.run(py, agent, 0).unwrap();
+
+            let ticks: Vec<(MicroSec, String, f64)> = agent.getattr("ticks").unwrap().extract().unwrap();
+            assert_eq!(ticks.len(), 3);
+            assert_eq!(ticks[0], (1_000_000, "Buy".to_string(), 100.0));
+
+            // 95.0の買いが90.0の売りで約定する
+            assert_eq!(results.len(), 1);
+            let updates: Vec<String> = agent.getattr("updates").unwrap().extract().unwrap();
+            assert_eq!(updates.len(), 1);
+        });
+
+        assert!(handle.join().unwrap().contains("publicTrade.BTCUSDT"));
+
+        // 受信した約定はDBへ追記される
+        let trades = paper.market.db().select_df_from_db(0, 0);
+        assert_eq!(trades.shape().0, 3);
+        assert!(paper.gaps.is_empty());
+    }
+
+    #[test]
+    fn test_reconnect_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let market = BybitMarket::open_db_path("BTCUSDT", true, dir.path().join("paper.db").to_str().unwrap());
+        let mut paper = PaperSession::from_market(Box::new(market));
+
+        // 約定、再接続の通知（空の約定）、約定の順に受信する
+        let (tx, rx) = std::sync::mpsc::channel();
+        tx.send(vec![Trade::new(1_000, OrderSide::Buy, 100.0, 1.0, "a1".to_string())]).unwrap();
+        tx.send(vec![]).unwrap();
+        tx.send(vec![Trade::new(5_000, OrderSide::Sell, 99.0, 1.0, "a2".to_string())]).unwrap();
+        drop(tx);
+
+        Python::with_gil(|py| {
+            let agent = PyModule::from_code(
+                py,
+                r#"
+class Agent:
+    def clock_interval(self):
+        return 60
+"#,
+                "agent.py",
+                "agent",
+            )
+            .unwrap()
+            .getattr("Agent")
+            .unwrap()
+            .call0()
+            .unwrap();
+
+            paper.receive(py, agent, rx, 0).unwrap();
+        });
+
+        assert_eq!(paper.gaps, vec![(1_000, 5_000)]);
+    }
+}
@@ -0,0 +1,412 @@
+use pyo3::types::PyDict;
+use pyo3::{pyclass, pymethods, PyObject, PyResult, Python, ToPyObject};
+
+use crate::common::{
+    order::{OrderResult, OrderSide, OrderStatus},
+    time::{time_string, to_seconds, MicroSec, DAYS, FLOOR, SEC},
+};
+
+/// BackTester::runの結果（約定履歴）から計算した成績表
+/// 損益・手数料・ドローダウンはforeign通貨単位。
+/// Sharpe/Sortinoはbar_secごとの損益（リターンではない）から計算し、年率換算する。
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct BacktestReport {
+    #[pyo3(get)]
+    pub start_time: MicroSec,
+    #[pyo3(get)]
+    pub end_time: MicroSec,
+    #[pyo3(get)]
+    pub bar_sec: i64,
+    #[pyo3(get)]
+    pub total_profit: f64, // 手数料を含まない損益
+    #[pyo3(get)]
+    pub fee: f64,
+    #[pyo3(get)]
+    pub funding: f64,
+    #[pyo3(get)]
+    pub net_profit: f64, // 手数料・Fundingを含む損益
+    #[pyo3(get)]
+    pub trade_count: i64, // ポジションをクローズした回数（精算を含む）
+    #[pyo3(get)]
+    pub win_count: i64,
+    #[pyo3(get)]
+    pub win_rate: f64,
+    #[pyo3(get)]
+    pub profit_factor: f64,
+    #[pyo3(get)]
+    pub max_drawdown: f64,
+    #[pyo3(get)]
+    pub sharpe_ratio: f64,
+    #[pyo3(get)]
+    pub sortino_ratio: f64,
+    #[pyo3(get)]
+    pub avg_holding_time: MicroSec, // ポジションを持ってから０に戻るまでの平均時間
+    #[pyo3(get)]
+    pub exposure: f64, // 期間中にポジションを持っていた時間の割合
+}
+
+#[pymethods]
+impl BacktestReport {
+    /// start_time, end_timeが0の場合は、最初と最後の約定履歴の時刻を利用する。
+    #[new]
+    #[args(bar_sec = "3600", start_time = "0", end_time = "0")]
+    pub fn new(
+        results: Vec<OrderResult>,
+        bar_sec: i64,
+        start_time: MicroSec,
+        end_time: MicroSec,
+    ) -> Self {
+        let start_time = if start_time == 0 {
+            results.first().map_or(0, |r| r.update_time)
+        } else {
+            start_time
+        };
+
+        let end_time = if end_time == 0 {
+            results.last().map_or(0, |r| r.update_time)
+        } else {
+            end_time
+        };
+
+        let mut report = BacktestReport {
+            start_time,
+            end_time,
+            bar_sec,
+            total_profit: 0.0,
+            fee: 0.0,
+            funding: 0.0,
+            net_profit: 0.0,
+            trade_count: 0,
+            win_count: 0,
+            win_rate: 0.0,
+            profit_factor: 0.0,
+            max_drawdown: 0.0,
+            sharpe_ratio: 0.0,
+            sortino_ratio: 0.0,
+            avg_holding_time: 0,
+            exposure: 0.0,
+        };
+
+        report.calc_profit(&results);
+        report.calc_drawdown(&results);
+        report.calc_ratio(&results);
+        report.calc_holding_time(&results);
+
+        return report;
+    }
+
+    pub fn to_dict(&self, py: Python) -> PyResult<PyObject> {
+        return Ok(self.to_pydict(py)?.to_object(py));
+    }
+
+    pub fn __str__(&self) -> String {
+        return format!("{{start_time:{}, end_time:{}, bar_sec:{}, total_profit:{}, fee:{}, funding:{}, net_profit:{}, trade_count:{}, win_count:{}, win_rate:{}, profit_factor:{}, max_drawdown:{}, sharpe_ratio:{}, sortino_ratio:{}, avg_holding_time:{}, exposure:{}}}",
+            self.start_time,
+            self.end_time,
+            self.bar_sec,
+            self.total_profit,
+            self.fee,
+            self.funding,
+            self.net_profit,
+            self.trade_count,
+            self.win_count,
+            self.win_rate,
+            self.profit_factor,
+            self.max_drawdown,
+            self.sharpe_ratio,
+            self.sortino_ratio,
+            self.avg_holding_time,
+            self.exposure,
+        );
+    }
+
+    pub fn __repr__(&self) -> String {
+        return self.__str__();
+    }
+
+    pub fn _repr_html_(&self) -> String {
+        return format!(
+            r#"
+            <table>
+            <caption>Backtest report</caption>
+            <tr><th>item</th><th>value</th></tr>
+            <tr><td><b>start</b></td><td>{}</td></tr>
+            <tr><td><b>end</b></td><td>{}</td></tr>
+            <tr><td><b>total profit</b></td><td>{}</td></tr>
+            <tr><td><b>fee</b></td><td>{}</td></tr>
+            <tr><td><b>funding</b></td><td>{}</td></tr>
+            <tr><td><b>net profit</b></td><td>{}</td></tr>
+            <tr><td><b>trades</b></td><td>{}</td></tr>
+            <tr><td><b>win rate</b></td><td>{:.2}%</td></tr>
+            <tr><td><b>profit factor</b></td><td>{:.3}</td></tr>
+            <tr><td><b>max drawdown</b></td><td>{}</td></tr>
+            <tr><td><b>sharpe ratio ({}sec bar)</b></td><td>{:.3}</td></tr>
+            <tr><td><b>sortino ratio ({}sec bar)</b></td><td>{:.3}</td></tr>
+            <tr><td><b>avg holding time</b></td><td>{:.1} sec</td></tr>
+            <tr><td><b>exposure</b></td><td>{:.2}%</td></tr>
+            </table>
+            "#,
+            time_string(self.start_time),
+            time_string(self.end_time),
+            self.total_profit,
+            self.fee,
+            self.funding,
+            self.net_profit,
+            self.trade_count,
+            self.win_rate * 100.0,
+            self.profit_factor,
+            self.max_drawdown,
+            self.bar_sec,
+            self.sharpe_ratio,
+            self.bar_sec,
+            self.sortino_ratio,
+            to_seconds(self.avg_holding_time),
+            self.exposure * 100.0,
+        );
+    }
+}
+
+impl BacktestReport {
+    pub fn to_pydict<'a>(&self, py: Python<'a>) -> PyResult<&'a PyDict> {
+        let dict = PyDict::new(py);
+
+        dict.set_item("start_time", self.start_time)?;
+        dict.set_item("end_time", self.end_time)?;
+        dict.set_item("bar_sec", self.bar_sec)?;
+        dict.set_item("total_profit", self.total_profit)?;
+        dict.set_item("fee", self.fee)?;
+        dict.set_item("funding", self.funding)?;
+        dict.set_item("net_profit", self.net_profit)?;
+        dict.set_item("trade_count", self.trade_count)?;
+        dict.set_item("win_count", self.win_count)?;
+        dict.set_item("win_rate", self.win_rate)?;
+        dict.set_item("profit_factor", self.profit_factor)?;
+        dict.set_item("max_drawdown", self.max_drawdown)?;
+        dict.set_item("sharpe_ratio", self.sharpe_ratio)?;
+        dict.set_item("sortino_ratio", self.sortino_ratio)?;
+        dict.set_item("avg_holding_time", self.avg_holding_time)?;
+        dict.set_item("exposure", self.exposure)?;
+
+        return Ok(dict);
+    }
+
+    fn is_close(r: &OrderResult) -> bool {
+        return r.status == OrderStatus::ClosePosition || r.status == OrderStatus::Liquidation;
+    }
+
+    /// 損益・手数料・勝率・プロフィットファクター
+    fn calc_profit(&mut self, results: &[OrderResult]) {
+        let mut gross_win = 0.0;
+        let mut gross_loss = 0.0;
+
+        for r in results {
+            if r.status == OrderStatus::Funding {
+                self.funding += r.total_profit;
+            } else {
+                self.total_profit += r.profit;
+            }
+            self.fee += r.fee;
+            self.net_profit += r.total_profit;
+
+            if BacktestReport::is_close(r) {
+                self.trade_count += 1;
+
+                if 0.0 < r.total_profit {
+                    self.win_count += 1;
+                    gross_win += r.total_profit;
+                } else {
+                    gross_loss -= r.total_profit;
+                }
+            }
+        }
+
+        if self.trade_count != 0 {
+            self.win_rate = self.win_count as f64 / self.trade_count as f64;
+        }
+
+        if gross_loss != 0.0 {
+            self.profit_factor = gross_win / gross_loss;
+        } else if gross_win != 0.0 {
+            self.profit_factor = f64::INFINITY;
+        }
+    }
+
+    /// 確定損益（手数料込み）の累積からみた最大ドローダウン
+    fn calc_drawdown(&mut self, results: &[OrderResult]) {
+        let mut sum = 0.0;
+        let mut peak = 0.0;
+
+        for r in results {
+            sum += r.total_profit;
+
+            if peak < sum {
+                peak = sum;
+            }
+
+            if self.max_drawdown < peak - sum {
+                self.max_drawdown = peak - sum;
+            }
+        }
+    }
+
+    /// bar_secごとの損益からSharpe/Sortinoを計算する（損益のない足も０として数える）
+    fn calc_ratio(&mut self, results: &[OrderResult]) {
+        if self.bar_sec <= 0 || self.end_time <= self.start_time {
+            return;
+        }
+
+        let first_bar = FLOOR(self.start_time, self.bar_sec);
+        let bar_count = ((FLOOR(self.end_time, self.bar_sec) - first_bar) / SEC(self.bar_sec) + 1) as usize;
+
+        let mut bars = vec![0.0; bar_count];
+        for r in results {
+            if r.update_time < first_bar {
+                continue;
+            }
+
+            let index = ((FLOOR(r.update_time, self.bar_sec) - first_bar) / SEC(self.bar_sec)) as usize;
+            if index < bar_count {
+                bars[index] += r.total_profit;
+            }
+        }
+
+        let n = bars.len() as f64;
+        let mean = bars.iter().sum::<f64>() / n;
+        let std = (bars.iter().map(|b| (b - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let downside = (bars.iter().map(|b| b.min(0.0).powi(2)).sum::<f64>() / n).sqrt();
+
+        let annualize = (DAYS(365) as f64 / SEC(self.bar_sec) as f64).sqrt();
+
+        if std != 0.0 {
+            self.sharpe_ratio = mean / std * annualize;
+        }
+
+        if downside != 0.0 {
+            self.sortino_ratio = mean / downside * annualize;
+        }
+    }
+
+    /// ポジションを持ってから０に戻るまでの時間の平均と、期間中にポジションを持っていた割合
+    fn calc_holding_time(&mut self, results: &[OrderResult]) {
+        let mut long_size = 0.0;
+        let mut short_size = 0.0;
+        let mut open_time: MicroSec = 0;
+        let mut holding_count = 0;
+        let mut holding_time: MicroSec = 0;
+
+        for r in results {
+            let had_position = long_size != 0.0 || short_size != 0.0;
+
+            match (r.status, r.order_side) {
+                (OrderStatus::OpenPosition, OrderSide::Buy) => long_size += r.open_home_size,
+                (OrderStatus::OpenPosition, OrderSide::Sell) => short_size += r.open_home_size,
+                (OrderStatus::ClosePosition | OrderStatus::Liquidation, OrderSide::Sell) => {
+                    long_size = (long_size - r.close_home_size).max(0.0)
+                }
+                (OrderStatus::ClosePosition | OrderStatus::Liquidation, OrderSide::Buy) => {
+                    short_size = (short_size - r.close_home_size).max(0.0)
+                }
+                _ => {}
+            }
+
+            let has_position = long_size != 0.0 || short_size != 0.0;
+
+            if !had_position && has_position {
+                open_time = r.update_time;
+            } else if had_position && !has_position {
+                holding_count += 1;
+                holding_time += r.update_time - open_time;
+            }
+        }
+
+        let mut exposure_time = holding_time;
+        // 期間終了時にポジションが残っている場合
+        if long_size != 0.0 || short_size != 0.0 {
+            exposure_time += self.end_time - open_time;
+        }
+
+        if holding_count != 0 {
+            self.avg_holding_time = holding_time / holding_count;
+        }
+
+        if self.start_time < self.end_time {
+            self.exposure = exposure_time as f64 / (self.end_time - self.start_time) as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_report {
+    use super::*;
+    use crate::common::order::Order;
+
+    fn make_result(time: MicroSec, side: OrderSide, status: OrderStatus, size: f64, profit: f64, fee: f64) -> OrderResult {
+        let order = Order::new(time, "id".to_string(), side, true, 0, 100.0, size, "".to_string());
+        let mut result = OrderResult::from_order(time, &order, status);
+        result.open_home_size = size;
+        result.close_home_size = size;
+        result.profit = profit;
+        result.fee = fee;
+        result.total_profit = profit - fee;
+
+        result
+    }
+
+    fn make_results() -> Vec<OrderResult> {
+        vec![
+            make_result(SEC(0), OrderSide::Buy, OrderStatus::OpenPosition, 10.0, 0.0, 0.5),
+            make_result(SEC(10), OrderSide::Sell, OrderStatus::ClosePosition, 10.0, 3.0, 0.5),
+            make_result(SEC(20), OrderSide::Sell, OrderStatus::OpenPosition, 10.0, 0.0, 0.5),
+            make_result(SEC(25), OrderSide::Sell, OrderStatus::Funding, 10.0, 0.5, 0.0),
+            make_result(SEC(40), OrderSide::Buy, OrderStatus::ClosePosition, 10.0, -2.0, 0.5),
+            make_result(SEC(100), OrderSide::Buy, OrderStatus::OpenPosition, 10.0, 0.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn test_profit() {
+        let report = BacktestReport::new(make_results(), 60, 0, 0);
+
+        assert_eq!(report.start_time, SEC(0));
+        assert_eq!(report.end_time, SEC(100));
+        assert_eq!(report.total_profit, 1.0);
+        assert_eq!(report.fee, 2.0);
+        assert_eq!(report.funding, 0.5);
+        assert_eq!(report.net_profit, -0.5);
+        assert_eq!(report.trade_count, 2);
+        assert_eq!(report.win_count, 1);
+        assert_eq!(report.win_rate, 0.5);
+        assert_eq!(report.profit_factor, 2.5 / 2.5);
+    }
+
+    #[test]
+    fn test_drawdown() {
+        let report = BacktestReport::new(make_results(), 60, 0, 0);
+
+        // 累積: -0.5, 2.0, 1.5, 2.0, -0.5, -0.5
+        assert_eq!(report.max_drawdown, 2.5);
+    }
+
+    #[test]
+    fn test_holding_time() {
+        let report = BacktestReport::new(make_results(), 60, 0, SEC(200));
+
+        // 10sec + 20sec のポジション、100secからのポジションは期間終了まで
+        assert_eq!(report.avg_holding_time, SEC(15));
+        assert_eq!(report.exposure, 130.0 / 200.0);
+    }
+
+    #[test]
+    fn test_ratio() {
+        let report = BacktestReport::new(make_results(), 60, 0, 0);
+
+        // bar: [-0.5 + 2.5 - 0.5 + 0.5 - 2.5, 0.0] = [-0.5, 0.0]
+        assert!(report.sharpe_ratio < 0.0);
+        assert!(report.sortino_ratio < 0.0);
+
+        let report = BacktestReport::new(vec![], 60, 0, 0);
+        assert_eq!(report.sharpe_ratio, 0.0);
+        assert_eq!(report.trade_count, 0);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::VecDeque;
+
 use crate::common::order::LogBuffer;
 // use crate::common::order::MarketType;
 use crate::common::order::Order;
@@ -7,7 +9,13 @@ use crate::common::order::OrderStatus;
 
 use crate::common::order::Trade;
 use crate::common::order::log_order_result;
+use crate::exchange::rest::Fill;
 use crate::common::time::MicroSec;
+use crate::db::book::{BookUpdate, OrderBook};
+use crate::exchange::market::registered_exchange_name;
+use crate::sim::fill::fill_model_from_str;
+use crate::sim::latency::{make_latency_model, LatencyModel, PyLatencyModel};
+use crate::sim::market::is_filled;
 use crate::sim::market::OrderQueue;
 
 // use crate::sim::market::Position;
@@ -49,6 +57,7 @@ use pyo3::*;
 #[derive(Clone, Debug)]
 pub struct DummySession {
     _order_index: i64,
+    pub order_id_prefix: String, // オーダーIDの接頭辞（LiveSessionでセッションごとに一意にする）
     #[pyo3(get)]
     pub current_timestamp: i64,
     #[pyo3(get)]
@@ -59,35 +68,75 @@ pub struct DummySession {
     pub exchange_name: String,
     #[pyo3(get)]
     pub market_name: String,
-    #[pyo3(get)]
-    pub server_delay: MicroSec,
-    #[pyo3(get)]    
+    #[pyo3(get, set)]
     pub maker_fee: f64,
+    #[pyo3(get, set)]
+    pub taker_fee: f64,
+    #[pyo3(get, set)]
+    pub taker_slippage: f64, // Taker約定時のスリッページ（価格に対する比率）
     pub long_orders: OrderQueue,
     pub short_orders: OrderQueue,
+    pub latency: Box<dyn LatencyModel>, // オーダー・キャンセル・判断の遅延モデル
+    pub decided_orders: Vec<Order>, // 判断遅延の経過待ちのオーダー（create_timeになったら発注する）
+    pub taker_orders: Vec<Order>, // サーバ到着待ちのTakerオーダー
+    pub cancel_requests: Vec<(MicroSec, String)>, // サーバ到着待ちのキャンセル（到着時刻, order_id）
+    pub pending_results: LogBuffer, // 次のTickでログへ出力する結果（PostOnlyErrorなど）
     pub positions: Positions,
-    pub wallet_balance: f64, // 入金額
+    #[pyo3(get)]
+    pub wallet_balance: f64, // 入金額（foreign通貨単位）。確定損益と手数料を反映する。
+    #[pyo3(get, set)]
+    pub leverage: f64,
+    #[pyo3(get, set)]
+    pub maintenance_margin_rate: f64, // 維持証拠金率
+    pub margin_enabled: bool, // depositされた場合のみ証拠金チェックと精算を行う。
+    pub funding_rates: VecDeque<(MicroSec, f64)>, // 未処理のFundingRate（時刻, レート）
+    pub book: OrderBook, // 板（板のデータがある場合のみ。両側そろったら板の価格・数量で約定させる）
+    pub book_updates: VecDeque<BookUpdate>, // 未処理の板の更新
 }
 
 /// implement for Python export
 #[pymethods]
 impl DummySession {
+    /// exchange_nameはMARKET_REGISTRYの取引所コードに正規化する。登録されていない場合はエラー。
     #[new]
-    pub fn new(exchange_name: &str, market_name: &str) -> Self {
-        return DummySession {
+    pub fn new(exchange_name: &str, market_name: &str) -> PyResult<Self> {
+        let exchange_name = registered_exchange_name(exchange_name)
+            .map_err(PyTypeError::new_err)?
+            .to_string();
+
+        return Ok(DummySession {
             _order_index: 0,
+            order_id_prefix: "".to_string(),
             current_timestamp: 0,            
             sell_board_edge_price: 0.0,
             buy_board_edge_price: 0.0,
-            exchange_name: exchange_name.to_string().to_ascii_uppercase(),
+            exchange_name,
             market_name: market_name.to_string().to_ascii_uppercase(),
-            server_delay: 100_000,      // 0.1 sec
             maker_fee:  0.01 * 0.01,     // 0.01%
+            taker_fee:  0.05 * 0.01,     // 0.05%
+            taker_slippage: 0.0,
             long_orders: OrderQueue::new(true),
             short_orders: OrderQueue::new(false),
+            latency: make_latency_model(100_000, 100_000, 0, 0, 0).unwrap(), // 0.1 sec
+            decided_orders: vec![],
+            taker_orders: vec![],
+            cancel_requests: vec![],
+            pending_results: vec![],
             positions: Positions::new(),
             wallet_balance: 0.0,
-        };
+            leverage: 1.0,
+            maintenance_margin_rate: 0.5 * 0.01, // 0.5%
+            margin_enabled: false,
+            funding_rates: VecDeque::new(),
+            book: OrderBook::new(),
+            book_updates: VecDeque::new(),
+        });
+    }
+
+    /// マーケットのキー（"BN/BTCBUSD"）。複数マーケットのセッションでマーケットを指定するときに使う。
+    #[getter]
+    pub fn get_market_key(&self) -> String {
+        return format!("{}/{}", self.exchange_name, self.market_name);
     }
 
     #[getter]
@@ -99,11 +148,145 @@ impl DummySession {
         return (self.buy_board_edge_price + self.sell_board_edge_price) / 2.0;
     }
 
+    /// 証拠金を入金する（foreign通貨単位）。
+    /// 入金すると証拠金チェック（NoMoney）と強制精算（Liquidation）が有効になる。
+    pub fn deposit(&mut self, amount: f64) {
+        self.wallet_balance += amount;
+        self.margin_enabled = true;
+    }
+
+    /// FundingRate（時刻, レート）を設定する。
+    /// 時刻をすぎたTickでポジションに対して支払い（正のレートではlongが支払い、shortが受け取り）を行う。
+    pub fn set_funding_rates(&mut self, mut funding_rates: Vec<(MicroSec, f64)>) {
+        funding_rates.sort_by(|a, b| a.0.cmp(&b.0));
+
+        self.funding_rates = funding_rates
+            .into_iter()
+            .filter(|(time, _rate)| self.current_timestamp < *time)
+            .collect();
+    }
+
+    /// center priceで評価したポジションの含み損益
+    #[getter]
+    pub fn get_unrealized_pnl(&self) -> f64 {
+        return self.positions.get_unrealized_pnl(self.get_center_price());
+    }
+
+    /// ポジションに必要な証拠金
+    #[getter]
+    pub fn get_position_margin(&self) -> f64 {
+        return self.positions.get_position_foreign_size() / self.leverage;
+    }
+
+    /// オーダー中（未約定）の証拠金
+    #[getter]
+    pub fn get_order_margin(&self) -> f64 {
+        let mut size = self.long_orders.get_foreign_size() + self.short_orders.get_foreign_size();
+
+        for order in &self.taker_orders {
+            let price = if order.price != 0.0 { order.price } else { self.taker_price(order.order_side, order.size) };
+            if price != 0.0 {
+                size += order.size / price;
+            }
+        }
+
+        return size / self.leverage;
+    }
+
+    ///   if unrealised_pnl > 0:
+    ///      available_balance = wallet_balance - (position_margin + order_margin)
+    ///   if unrealised_pnl < 0:
+    ///      available_balance = wallet_balance - (position_margin + order_margin) + unrealised_pnl
     #[getter]
-    // TODO: 計算する。
     pub fn get_available_balance(&self) -> f64 {
-        assert!(false, "not implemented");
-        return 0.0;
+        let unrealized_pnl = self.get_unrealized_pnl();
+
+        let mut balance = self.wallet_balance - (self.get_position_margin() + self.get_order_margin());
+
+        if unrealized_pnl < 0.0 {
+            balance += unrealized_pnl;
+        }
+
+        return balance;
+    }
+
+    /// 一部約定モード
+    /// trueの場合、一部約定ごとにon_updateが呼ばれ、大きな約定ログで複数のオーダーが約定する。
+    #[getter]
+    pub fn get_partial_fill(&self) -> bool {
+        return self.long_orders.get_partial_fill();
+    }
+
+    #[setter]
+    pub fn set_partial_fill(&mut self, partial_fill: bool) {
+        self.long_orders.set_partial_fill(partial_fill);
+        self.short_orders.set_partial_fill(partial_fill);
+    }
+
+    /// Makerオーダーの約定モデル
+    /// "TradeThrough": 価格を越えた約定ログが発生したら約定（デフォルト）
+    /// "QueuePosition": 前に並んでいるサイズを推定し、その分が約定してから約定
+    #[getter]
+    pub fn get_fill_model(&self) -> String {
+        return self.long_orders.get_fill_model();
+    }
+
+    #[setter]
+    pub fn set_fill_model(&mut self, fill_model: &str) -> PyResult<()> {
+        match self._set_fill_model(fill_model) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(PyTypeError::new_err(e)),
+        }
+    }
+
+    /// 遅延モデルを設定する（単位はマイクロ秒）
+    ///   order_latency: オーダーがサーバへ届くまでの遅延
+    ///   cancel_latency: キャンセルがサーバへ届くまでの遅延
+    ///   decision_latency: make_orderからオーダーを出すまでの遅延。経過した時点の板でオーダーを処理する。
+    ///   jitter: 0以外の場合、それぞれの遅延に平均jitterの指数分布の乱数を加える（seedで再現可能）
+    #[args(decision_latency = "0", jitter = "0", seed = "0")]
+    pub fn set_latency(
+        &mut self,
+        order_latency: MicroSec,
+        cancel_latency: MicroSec,
+        decision_latency: MicroSec,
+        jitter: MicroSec,
+        seed: u64,
+    ) -> PyResult<()> {
+        self.latency = make_latency_model(order_latency, cancel_latency, decision_latency, jitter, seed)
+            .map_err(PyTypeError::new_err)?;
+
+        Ok(())
+    }
+
+    /// 遅延モデルの名前（"Fixed" | "Jitter" | "Python"）
+    #[getter]
+    pub fn get_latency_model(&self) -> String {
+        return self.latency.name();
+    }
+
+    /// Pythonで実装した遅延モデルを設定する。
+    /// order_latency(), cancel_latency(), decision_latency()がマイクロ秒の遅延を返すこと。
+    #[setter]
+    pub fn set_latency_model(&mut self, model: &PyAny) -> PyResult<()> {
+        let model = PyLatencyModel::new(model).map_err(PyTypeError::new_err)?;
+        self.latency = Box::new(model);
+
+        Ok(())
+    }
+
+    /// 互換用: 直近のオーダーの遅延（マイクロ秒）。
+    /// 遅延モデルを呼び出さないので、ジッタの乱数やPythonの遅延モデルの状態は進まない。
+    /// オーダーを出す前は基準の遅延（Pythonの遅延モデルでは0）。
+    #[getter]
+    pub fn get_server_delay(&self) -> MicroSec {
+        return self.latency.last_order_latency();
+    }
+
+    /// 互換用: オーダーとキャンセルの遅延を同じ固定値にする（set_latency(delay, delay)と同じ）
+    #[setter]
+    pub fn set_server_delay(&mut self, delay: MicroSec) -> PyResult<()> {
+        return self.set_latency(delay, delay, 0, 0, 0);
     }
 
     /// 未約定でキューに入っているlong orderのサイズ（合計）
@@ -152,8 +335,17 @@ impl DummySession {
         return self.positions.get_short_position_price();
     }
 
+    /// 現在の板を([(bid価格, サイズ)], [(ask価格, サイズ)])で返す（先頭からdepth件）。板のデータがない場合は空。
+    #[args(depth = "10")]
+    pub fn book(&self, depth: usize) -> (Vec<(f64, f64)>, Vec<(f64, f64)>) {
+        return (self.book.depth(OrderSide::Buy, depth), self.book.depth(OrderSide::Sell, depth));
+    }
+
     /// オーダー作りオーダーリストへ追加する。
     /// 最初にオーダー可能かどうか確認する（余力の有無）
+    /// post_only=Falseで板の反対側に届く価格の場合はTakerとして約定する。
+    /// 判断遅延（set_latency）がある場合はWaitを返し、遅延が経過した時点の板で発注する。
+    #[args(post_only = "true")]
     pub fn make_order(
         &mut self,
         side: &str,
@@ -161,8 +353,41 @@ impl DummySession {
         size: f64,
         duration_sec: i64,
         message: String,
+        post_only: bool,
     ) -> PyResult<OrderStatus> {
-        match self._make_order(OrderSide::from_str(side), price, size, duration_sec, message) {
+        match self._make_order(OrderSide::from_str(side), price, size, duration_sec, message, post_only) {
+            Ok(result) => {
+                Ok(result)
+            }
+            Err(e) => {
+                Err(PyTypeError::new_err(e.to_string()))
+            }
+        }
+    }
+
+    /// IDで指定したオーダーをキャンセルする（サーバ到着待ちのTaker・成行オーダーを含む）。
+    /// キャンセルはキャンセルの遅延の後に反映され、on_updateにCancelが通知される。
+    /// キャンセル対象のオーダーがない場合はNoActionを返す。
+    pub fn cancel_order(&mut self, order_id: &str) -> OrderStatus {
+        return self._cancel_order(order_id);
+    }
+
+    /// 指定したサイドのオーダーを全てキャンセルする。
+    /// "BUY"/"SELL"以外を指定した場合は両サイドともキャンセルする。
+    /// キャンセル要求をだしたオーダーIDの一覧を返す。
+    pub fn cancel_all(&mut self, side: &str) -> Vec<String> {
+        return self._cancel_all(OrderSide::from_str(side));
+    }
+
+    /// 成行オーダーを発行する。
+    /// オーダーの遅延の後、板の反対側の価格（スリッページ込み）で約定する。
+    pub fn make_market_order(
+        &mut self,
+        side: &str,
+        size: f64,
+        message: String,
+    ) -> PyResult<OrderStatus> {
+        match self._make_order(OrderSide::from_str(side), 0.0, size, 0, message, false) {
             Ok(result) => {
                 Ok(result)
             }
@@ -177,21 +402,166 @@ impl DummySession {
 /// Implement for Rust interface
 impl DummySession {
     /// price x sizeのオーダを発行できるか確認する。
-    ///   if unrealised_pnl > 0:
-    ///      available_balance = wallet_balance - (position_margin + occ_closing_fee + occ_funding_fee + order_margin)
-    ///      if unrealised_pnl < 0:
-    ///          available_balance = wallet_balance - (position_margin + occ_closing_fee + occ_funding_fee + order_margin) + unrealised_pnl
+    /// 反対側のポジションを減らす分については証拠金は不要とする。
+    /// TODO: occ_closing_fee, occ_funding_feeは計算していない。
+    fn check_margin(&self, side: OrderSide, price: f64, size: f64) -> bool {
+        if self.margin_enabled == false {
+            return true;
+        }
+
+        let close_size = match side {
+            OrderSide::Buy => self.positions.get_short_position_size(),
+            OrderSide::Sell => self.positions.get_long_position_size(),
+            _ => 0.0,
+        };
+
+        let open_size = size - close_size;
+        if open_size <= 0.0 {
+            return true;
+        }
+
+        if price == 0.0 {
+            return false;
+        }
+
+        let order_margin = open_size / price / self.leverage;
+
+        return order_margin <= self.get_available_balance();
+    }
+
+    /// 時刻をすぎたFundingRateをポジションに適用し、Fundingの結果をログへ出力する。
+    /// 支払額はcenter priceで評価したポジションサイズ（foreign通貨単位）にレートを掛けたもの。
+    fn update_funding(&mut self, tick_result: &mut LogBuffer) {
+        while let Some((funding_time, rate)) = self.funding_rates.front().cloned() {
+            if self.current_timestamp < funding_time {
+                break;
+            }
+            self.funding_rates.pop_front();
+
+            let center_price = self.get_center_price();
+
+            let long_size = self.positions.get_long_position_size();
+            if long_size != 0.0 {
+                let result = self.make_funding_result(funding_time, OrderSide::Buy, long_size, center_price, rate);
+                self.log_order_result(tick_result, result);
+            }
+
+            let short_size = self.positions.get_short_position_size();
+            if short_size != 0.0 {
+                let result = self.make_funding_result(funding_time, OrderSide::Sell, short_size, center_price, rate);
+                self.log_order_result(tick_result, result);
+            }
+        }
+    }
+
+    fn make_funding_result(
+        &self,
+        funding_time: MicroSec,
+        side: OrderSide,
+        home_size: f64,
+        price: f64,
+        rate: f64,
+    ) -> OrderResult {
+        let order = Order::new(
+            funding_time,
+            format!("funding-{}", funding_time),
+            side,
+            true,
+            funding_time,
+            price,
+            home_size,
+            format!("funding rate {}", rate),
+        );
 
-    /*
-    fn check_margin(&self, price: f64, volume: f64) -> bool {
-        const LEVERAGE: f64 = 1.0; // TODO: まずはレバレッジ１倍固定から始める。
-        let mut order_amount = price * volume;
+        let mut result = OrderResult::from_order(self.current_timestamp, &order, OrderStatus::Funding);
+        let payment = rate * result.order_foreign_size;
 
-        order_amount = order_amount / LEVERAGE;
+        result.profit = match side {
+            OrderSide::Buy => -payment,
+            _ => payment,
+        };
 
-        // return order_amount < self.get;
+        return result;
+    }
+
+    /// 維持証拠金を下回った場合、全てのオーダーをキャンセルしポジションを強制精算する。
+    fn check_liquidation(&mut self, tick_result: &mut LogBuffer) {
+        if self.margin_enabled == false {
+            return;
+        }
+
+        let position_size = self.positions.get_position_foreign_size();
+        if position_size == 0.0 {
+            return;
+        }
+
+        let maintenance_margin = position_size * self.maintenance_margin_rate;
+        let margin_balance = self.wallet_balance + self.get_unrealized_pnl();
+
+        if maintenance_margin <= margin_balance {
+            return;
+        }
+
+        log::debug!("Liquidation margin balance={} maintenance margin={}", margin_balance, maintenance_margin);
+
+        for order_id in self.long_orders.get_order_ids() {
+            if let Ok(r) = self.long_orders.cancel_order(self.current_timestamp, order_id) {
+                self.log_order_result(tick_result, r);
+            }
+        }
+        for order_id in self.short_orders.get_order_ids() {
+            if let Ok(r) = self.short_orders.cancel_order(self.current_timestamp, order_id) {
+                self.log_order_result(tick_result, r);
+            }
+        }
+        self.decided_orders.clear();
+        self.taker_orders.clear();
+        self.cancel_requests.clear();
+
+        let long_size = self.positions.get_long_position_size();
+        if long_size != 0.0 {
+            self.liquidate_position(tick_result, OrderSide::Sell, long_size);
+        }
+
+        let short_size = self.positions.get_short_position_size();
+        if short_size != 0.0 {
+            self.liquidate_position(tick_result, OrderSide::Buy, short_size);
+        }
+
+        // 口座残高以上の損失は発生しない（不足分は取引所が負担する）
+        if self.wallet_balance < 0.0 {
+            self.wallet_balance = 0.0;
+        }
+    }
+
+    /// ポジションを板の反対側の価格でクローズし、Liquidationとしてログに出力する。
+    fn liquidate_position(&mut self, tick_result: &mut LogBuffer, side: OrderSide, size: f64) {
+        let order_id = self.generate_id();
+        let order = Order::new(
+            self.current_timestamp,
+            order_id,
+            side,
+            false,
+            self.current_timestamp,
+            self.taker_price(side, size),
+            size,
+            "Liquidation".to_string(),
+        );
+
+        let mut order_result =
+            OrderResult::from_order(self.current_timestamp, &order, OrderStatus::OrderComplete);
+        order_result.is_maker = false;
+
+        match self.positions.update_small_position(&mut order_result) {
+            Ok(()) => {
+                order_result.status = OrderStatus::Liquidation;
+                self.log_order_result(tick_result, order_result);
+            }
+            Err(e) => {
+                log::error!("Liquidation error {:?} {:?}", e, order_result);
+            }
+        }
     }
-    */
 
     ///　ログイベントを処理してセッション情報を更新する。
     ///  0. AgentへTick更新イベントを発生させる。
@@ -235,6 +605,34 @@ impl DummySession {
         }
     }
 
+    /// 現在時刻までの板の更新を適用し、板がそろっている場合は板の先頭をエッジの価格とする。
+    fn update_book(&mut self) {
+        while let Some(update) = self.book_updates.front() {
+            if self.current_timestamp < update.time {
+                break;
+            }
+
+            self.book.apply(update);
+            self.book_updates.pop_front();
+        }
+
+        if self.book.is_ready() {
+            self.buy_board_edge_price = self.book.best_bid().unwrap();
+            self.sell_board_edge_price = self.book.best_ask().unwrap();
+        }
+    }
+
+    /// 板の更新（時刻順）を設定する。Tickの時刻までの更新がprocess_tradeで板へ適用される。
+    pub fn set_book_updates(&mut self, book_updates: Vec<BookUpdate>) {
+        self.book = OrderBook::new();
+        self.book_updates = book_updates.into();
+    }
+
+    /// 板の更新を追加する（BackTesterはDBのカーソルからTickの時刻までの更新を順に渡す）。
+    pub fn push_book_update(&mut self, update: BookUpdate) {
+        self.book_updates.push_back(update);
+    }
+
     /// オーダの期限切れ処理を行う。
     /// エラーは返すが、エラーが通常のため処理する必要はない。
     /// 逆にOKの場合のみ、上位でログ処理する。
@@ -268,15 +666,15 @@ impl DummySession {
     fn update_order_queue(
         &mut self,
         trade: &Trade
-    ) -> Result<OrderResult, OrderStatus> {
+    ) -> Vec<OrderResult> {
         return match trade.order_side {
             OrderSide::Buy => {
-                self.short_orders.consume(trade, self.server_delay)
+                self.short_orders.consume(trade)
             }
             OrderSide::Sell => {
-                self.long_orders.consume(trade, self.server_delay)
+                self.long_orders.consume(trade)
             }
-            _ => {Err(OrderStatus::Error)}
+            _ => {vec![]}
         }
     }
 
@@ -323,9 +721,11 @@ impl DummySession {
         let upper = index / 10000;
         let lower: i64 = index % 10000;
 
-        let id = format! {"{:04}-{:04}", upper, lower};
+        if self.order_id_prefix.is_empty() {
+            return format!("{:04}-{:04}", upper, lower);
+        }
 
-        return id.to_string();
+        return format!("{}-{:04}-{:04}", self.order_id_prefix, upper, lower);
     }
 
     /// order_resultのログを蓄積する（オンメモリ）
@@ -333,24 +733,27 @@ impl DummySession {
     /// 必要に応じて呼び出し側でCloneする。
     fn log_order_result(&mut self, tick_log: &mut LogBuffer, mut order_result: OrderResult) {
         order_result.update_time = self.current_timestamp;
+        order_result.market = self.get_market_key();
 
         self.calc_profit(&mut order_result);
+        self.wallet_balance += order_result.total_profit;
         log_order_result(tick_log, order_result);
     }
 
 
     fn calc_profit(&self, order: &mut OrderResult) {
+        let fee_rate = if order.is_maker { self.maker_fee } else { self.taker_fee };
+
         if order.status == OrderStatus::OpenPosition {
-            order.fee = order.open_foreign_size * self.maker_fee;
+            order.fee = order.open_foreign_size * fee_rate;
         }
-        else if order.status == OrderStatus::ClosePosition {
-            order.fee = order.close_foreign_size * self.maker_fee;
+        else if order.status == OrderStatus::ClosePosition || order.status == OrderStatus::Liquidation {
+            order.fee = order.close_foreign_size * fee_rate;
         }
         order.total_profit = order.profit - order.fee;
     }
 
 
-    /* TODO: マージンの計算とFundingRate計算はあとまわし */
     pub fn process_trade(
         &mut self,
         trade: &Trade,
@@ -365,12 +768,28 @@ impl DummySession {
 */
         self.update_trade_time(trade);
 
+        // make_orderで発生した結果をログへ出力
+        self.flush_pending_results(tick_result);
+
+        // キャンセルの遅延を経過したキャンセルを反映する。
+        self.update_cancel_orders(tick_result);
+
         self.update_edge_price(trade);
+        self.update_book();
         // 初期化未のためリターン。次のTickで処理。
-        if self.buy_board_edge_price == 0.0 || self.buy_board_edge_price == 0.0 {
+        if self.buy_board_edge_price == 0.0 || self.sell_board_edge_price == 0.0 {
             return;
         }
 
+        // FundingRateの支払い・受け取り
+        self.update_funding(tick_result);
+
+        // 判断遅延を経過したオーダーを現在の板で発注する。
+        self.update_decided_orders(tick_result);
+
+        // サーバへ届いたTakerオーダーを約定させる。
+        self.update_taker_orders(tick_result);
+
         // 　2. オーダ中のオーダーを更新する。
         // 　　　　　期限切れオーダーを削除する。
         //          毎秒１回実施する（イベントを間引く）
@@ -384,75 +803,434 @@ impl DummySession {
             }
         }
 
-        //現在のオーダーから執行可能な量を _partial_workから引き算し０になったらオーダ成立（一部約定はしない想定）
-        match self.update_order_queue(trade){
-            Ok(mut order_result) => {
-                //ポジションに追加する。
-                let _r = self.update_position(tick_result, &mut order_result);
+        //現在のオーダーから執行可能な量を _partial_workから引き算し０になったらオーダ成立
+        //（一部約定モードの場合は約定した分ごとにオーダ成立）
+        for mut order_result in self.update_order_queue(trade) {
+            //ポジションに追加する。
+            let _r = self.update_position(tick_result, &mut order_result);
+        }
+
+        // 維持証拠金のチェック
+        self.check_liquidation(tick_result);
+    }
+
+    /// キャンセル要求を受け付ける（キャンセルの遅延の後にupdate_cancel_ordersで処理する）
+    /// 判断遅延中のオーダーは、発注後にキャンセルする。
+    /// サーバ到着待ちのTakerオーダー（成行を含む）もキャンセルできる。
+    fn _cancel_order(&mut self, order_id: &str) -> OrderStatus {
+        if self.long_orders.has_order(order_id) == false
+            && self.short_orders.has_order(order_id) == false
+            && self.is_decided_order(order_id) == false
+            && self.taker_orders.iter().any(|o| o.order_id == order_id) == false
+        {
+            log::warn!("cancel order not found {}", order_id);
+            return OrderStatus::NoAction;
+        }
+
+        let arrival_time = self.current_timestamp + self.latency.cancel_latency();
+        self.cancel_requests.push((arrival_time, order_id.to_string()));
+
+        return OrderStatus::Wait;
+    }
+
+    fn _cancel_all(&mut self, side: OrderSide) -> Vec<String> {
+        let mut order_ids = match side {
+            OrderSide::Buy => self.long_orders.get_order_ids(),
+            OrderSide::Sell => self.short_orders.get_order_ids(),
+            _ => {
+                let mut ids = self.long_orders.get_order_ids();
+                ids.append(&mut self.short_orders.get_order_ids());
+                ids
             }
-            Err(_e) => {
-                // no
+        };
+
+        for order in self.decided_orders.iter().chain(self.taker_orders.iter()) {
+            if side == order.order_side || (side != OrderSide::Buy && side != OrderSide::Sell) {
+                order_ids.push(order.order_id.clone());
             }
         }
+
+        for order_id in &order_ids {
+            self._cancel_order(order_id);
+        }
+
+        return order_ids;
     }
 
-    /// make order with OrderSide (instead of string like, "BUY" and "SELL")
-    fn _make_order(
+    /// キャンセルの遅延を経過したキャンセル要求を処理する。
+    /// キャンセル前に約定・期限切れになったオーダーはなにもしない。
+    /// 判断遅延中のオーダーは発注されるまで待つ。
+    fn update_cancel_orders(&mut self, tick_result: &mut LogBuffer) {
+        if self.cancel_requests.is_empty() {
+            return;
+        }
+
+        let requests = std::mem::take(&mut self.cancel_requests);
+
+        for (arrival_time, order_id) in requests {
+            if self.current_timestamp < arrival_time || self.is_decided_order(&order_id) {
+                self.cancel_requests.push((arrival_time, order_id));
+                continue;
+            }
+
+            let result = match self.long_orders.cancel_order(self.current_timestamp, order_id.clone()) {
+                Ok(r) => Ok(r),
+                Err(_e) => match self.short_orders.cancel_order(self.current_timestamp, order_id.clone()) {
+                    Ok(r) => Ok(r),
+                    Err(_e) => self.cancel_taker_order(arrival_time, &order_id),
+                },
+            };
+
+            if let Ok(cancel_order) = result {
+                self.log_order_result(tick_result, cancel_order);
+            }
+        }
+    }
+
+    /// サーバへ届く前のTakerオーダーをキャンセルする。
+    /// キャンセルより先にオーダーがサーバへ届いた場合は約定するのでNoAction
+    fn cancel_taker_order(&mut self, cancel_arrival_time: MicroSec, order_id: &str) -> Result<OrderResult, OrderStatus> {
+        let i = self
+            .taker_orders
+            .iter()
+            .position(|o| o.order_id == order_id)
+            .ok_or(OrderStatus::NoAction)?;
+
+        if self.taker_orders[i].arrival_time <= cancel_arrival_time {
+            return Err(OrderStatus::NoAction);
+        }
+
+        let mut order = self.taker_orders.remove(i);
+
+        // 成行オーダーは価格がないので、板の反対側の価格で結果を作る。
+        if order.price == 0.0 {
+            order.price = self.taker_price(order.order_side, order.size);
+        }
+
+        if order.price == 0.0 {
+            log::warn!("cancel market order without price {}", order_id);
+            return Err(OrderStatus::NoAction);
+        }
+
+        return Ok(OrderResult::from_order(self.current_timestamp, &order, OrderStatus::Cancel));
+    }
+
+    /// 約定モデルをlong/shortのキューそれぞれに設定する。
+    pub fn _set_fill_model(&mut self, fill_model: &str) -> Result<(), String> {
+        self.long_orders.set_fill_model(fill_model_from_str(fill_model)?);
+        self.short_orders.set_fill_model(fill_model_from_str(fill_model)?);
+
+        Ok(())
+    }
+
+    /// make_orderで発生した結果（PostOnlyErrorなど）をTickのログへ移す。
+    pub fn flush_pending_results(&mut self, tick_result: &mut LogBuffer) {
+        let results = std::mem::take(&mut self.pending_results);
+
+        for r in results {
+            self.log_order_result(tick_result, r);
+        }
+    }
+
+    /// 板の反対側に届く（Takerになる）価格かどうかを判定する。
+    fn is_cross_price(&self, side: OrderSide, price: f64) -> bool {
+        match side {
+            OrderSide::Buy => {
+                self.sell_board_edge_price != 0.0 && self.sell_board_edge_price <= price
+            }
+            OrderSide::Sell => {
+                self.buy_board_edge_price != 0.0 && price <= self.buy_board_edge_price
+            }
+            _ => false,
+        }
+    }
+
+    /// Takerとして約定する価格（スリッページ込み）
+    /// 買いは売り板の先頭、売りは買い板の先頭で約定する。
+    /// 板がある場合はsize分の板を消化した平均価格（taker_slippageは使わない）
+    /// sizeはhome通貨単位、板の数量はforeign通貨単位なので、板は価格×数量で消化する。
+    fn taker_price(&self, side: OrderSide, size: f64) -> f64 {
+        if self.book.is_ready() {
+            if let Some(price) = self.book.sweep_value_price(side, size) {
+                return price;
+            }
+        }
+
+        match side {
+            OrderSide::Buy => self.sell_board_edge_price * (1.0 + self.taker_slippage),
+            OrderSide::Sell => self.buy_board_edge_price * (1.0 - self.taker_slippage),
+            _ => 0.0,
+        }
+    }
+
+    /// Makerオーダーとしてキューへ入れる。
+    /// 板がある場合は、同じ価格に並んでいる板の数量を前に並んでいるサイズとする。
+    /// 板がない場合は約定モデルの推定を使う。
+    /// 前に並んでいるサイズは約定ログのサイズで消化するので、板・約定ログと同じforeign通貨単位のままとする。
+    pub fn queue_maker_order(&mut self, order: &Order) {
+        let resting_size = if self.book.is_ready() {
+            Some(self.book.size_at(order.order_side, order.price))
+        } else {
+            None
+        };
+
+        let queue = match order.order_side {
+            OrderSide::Buy => &mut self.long_orders,
+            OrderSide::Sell => &mut self.short_orders,
+            _ => {
+                log::error!("Unknown order side {:?}", order);
+                return;
+            }
+        };
+
+        match resting_size {
+            Some(size) => queue.queue_order_behind(order, size),
+            None => queue.queue_order(order),
+        }
+    }
+
+    /// サーバへ届いたTakerオーダーを約定させる。
+    /// 指値のTakerオーダーで、板が動いて反対側に届かなくなった場合はMakerとしてキューへ入れる。
+    fn update_taker_orders(&mut self, tick_result: &mut LogBuffer) {
+        if self.taker_orders.is_empty() {
+            return;
+        }
+
+        let orders = std::mem::take(&mut self.taker_orders);
+
+        for mut order in orders {
+            if self.current_timestamp < order.arrival_time {
+                self.taker_orders.push(order);
+                continue;
+            }
+
+            let mut exec_price = self.taker_price(order.order_side, order.size);
+
+            // 価格0は成行。指値の場合は指値より不利な価格では約定しない。
+            if order.price != 0.0 {
+                if self.is_cross_price(order.order_side, order.price) == false {
+                    self.queue_maker_order(&order);
+                    continue;
+                }
+
+                exec_price = match order.order_side {
+                    OrderSide::Buy => exec_price.min(order.price),
+                    _ => exec_price.max(order.price),
+                };
+            }
+
+            order.price = exec_price;
+
+            let mut order_result =
+                OrderResult::from_order(self.current_timestamp, &order, OrderStatus::OrderComplete);
+            order_result.is_maker = false;
+
+            let _r = self.update_position(tick_result, &mut order_result);
+        }
+    }
+
+    /// オーダーの入力チェックを行い、IDを振ったOrderを作る（現在時刻で作成）。
+    pub fn new_order(
         &mut self,
         side: OrderSide,
         price: f64,
         size: f64,
         duration_sec: i64,
         message: String,
-    ) -> Result<OrderStatus,String> {
-        // TODO: 発注可能かチェックする
-        /*
-        if 証拠金不足
-            return Err(OrderStatus::NoMoney);
-        */
-
-        let timestamp = self.current_timestamp;
-
+        post_only: bool,
+    ) -> Result<Order, String> {
         if size == 0.0 {
             return Err("Order size cannot be 0".to_string());
         }
 
-        if price == 0.0 {
+        if price == 0.0 && post_only {
             return Err("Order price cannot be 0".to_string());
         }
 
+        if side != OrderSide::Buy && side != OrderSide::Sell {
+            let message = format!("Unknown order type {:?} / use B or S", side);
+            log::warn!("{}", message);
+
+            return Err(message);
+        }
+
         let order_id = self.generate_id();
-        let order = Order::new(
-            timestamp,
+
+        return Ok(Order::new(
+            self.current_timestamp,
             order_id,
             side,
-            true,   // TODO: post only 以外のオーダを検討する。
+            post_only,
             self.current_timestamp + SEC(duration_sec),
             price,
             size,
             message,
-        );
+        ));
+    }
 
-        // TODO: enqueue の段階でログに出力する。
-        match side {
-            OrderSide::Buy => {
-                // TODO: Takerになるかどうか確認
-                self.long_orders.queue_order(&order);
-                
-                Ok(OrderStatus::InOrder)
+    /// 取引所の約定をオーダーとポジションへ反映する（LiveSession用）。
+    /// 約定の数量(foreign通貨単位)は約定価格でhome通貨単位へ変換する。
+    /// 約定したサイズ・価格でOrderResultを作り、Open/ClosePositionとしてログへ出力する。
+    /// オーダーが見つからない場合はNoAction
+    pub fn apply_fill(&mut self, fill: &Fill, tick_result: &mut LogBuffer) -> Result<(), OrderStatus> {
+        let home_size = fill.size * fill.price;
+
+        let mut order = match self.long_orders.fill_order(&fill.order_id, home_size) {
+            Some(order) => order,
+            None => match self.short_orders.fill_order(&fill.order_id, home_size) {
+                Some(order) => order,
+                None => self.fill_taker_order(&fill.order_id, home_size).ok_or(OrderStatus::NoAction)?,
+            },
+        };
+
+        order.price = fill.price;
+
+        let mut order_result = OrderResult::from_order(fill.time, &order, OrderStatus::OrderComplete);
+        order_result.set_exec_size(home_size);
+        order_result.is_maker = fill.is_maker;
+
+        return self.update_position(tick_result, &mut order_result);
+    }
+
+    /// 成行オーダーの約定サイズを減らし、全て約定したら取り除く。
+    fn fill_taker_order(&mut self, order_id: &str, size: f64) -> Option<Order> {
+        let i = self.taker_orders.iter().position(|o| o.order_id == order_id)?;
+
+        self.taker_orders[i].remain_size -= size;
+        let order = self.taker_orders[i].clone();
+
+        if is_filled(&order) {
+            self.taker_orders.remove(i);
+        }
+
+        return Some(order);
+    }
+
+    /// オーダーをキューから取り除き、statusの結果をログへ出力する（取引所でキャンセル・期限切れになったオーダー）。
+    /// オーダーが見つからない場合はNoAction
+    pub fn close_order(&mut self, order_id: &str, status: OrderStatus, tick_result: &mut LogBuffer) -> OrderStatus {
+        let result = match self.long_orders.cancel_order(self.current_timestamp, order_id.to_string()) {
+            Ok(r) => Ok(r),
+            Err(_e) => self.short_orders.cancel_order(self.current_timestamp, order_id.to_string()),
+        };
+
+        match result {
+            Ok(mut order_result) => {
+                order_result.status = status;
+                self.log_order_result(tick_result, order_result);
+
+                return status;
             }
-            OrderSide::Sell => {
-                self.short_orders.queue_order(&order);
-                
-                Ok(OrderStatus::InOrder)
+            Err(e) => {
+                return e;
             }
-            _ => {
-                let message = format!("Unknown order type {:?} / use B or S", side);
-                log::warn!("{}", message);
+        }
+    }
+
+    /// make order with OrderSide (instead of string like, "BUY" and "SELL")
+    /// price=0.0 and post_only=false makes market order.
+    fn _make_order(
+        &mut self,
+        side: OrderSide,
+        price: f64,
+        size: f64,
+        duration_sec: i64,
+        message: String,
+        post_only: bool,
+    ) -> Result<OrderStatus,String> {
+        let mut order = self.new_order(side, price, size, duration_sec, message, post_only)?;
+
+        // 判断遅延がある場合は、経過した時点の板で発注する。
+        let decision_latency = self.latency.decision_latency();
+        if decision_latency != 0 {
+            order.create_time += decision_latency;
+            order.valid_until += decision_latency;
+            order.arrival_time = order.create_time;
+            self.decided_orders.push(order);
+
+            return Ok(OrderStatus::Wait);
+        }
+
+        return self.submit_order(order);
+    }
+
+    fn is_decided_order(&self, order_id: &str) -> bool {
+        return self.decided_orders.iter().any(|o| o.order_id == order_id);
+    }
+
+    /// 判断遅延を経過したオーダーを発注する。結果（PostOnlyErrorなど）はこのTickのログへ出力する。
+    fn update_decided_orders(&mut self, tick_result: &mut LogBuffer) {
+        if self.decided_orders.is_empty() {
+            return;
+        }
+
+        let orders = std::mem::take(&mut self.decided_orders);
 
-                Err(message)
+        for order in orders {
+            if self.current_timestamp < order.create_time {
+                self.decided_orders.push(order);
+                continue;
             }
+
+            if let Err(e) = self.submit_order(order) {
+                log::warn!("{}", e);
+            }
+        }
+
+        self.flush_pending_results(tick_result);
+    }
+
+    /// 証拠金と価格を確認し、オーダーをTakerまたはMakerとして受け付ける。
+    /// オーダーはオーダーの遅延の後にサーバへ届く（arrival_time）
+    fn submit_order(&mut self, mut order: Order) -> Result<OrderStatus, String> {
+        let timestamp = self.current_timestamp;
+        let side = order.order_side;
+        let price = order.price;
+        let size = order.size;
+        let post_only = order.post_only;
+
+        // 証拠金不足
+        let margin_price = if price != 0.0 { price } else { self.taker_price(side, size) };
+        if self.check_margin(side, margin_price, size) == false {
+            if margin_price == 0.0 {
+                return Err("Market price is not available".to_string());
+            }
+
+            let mut reject_order = order.clone();
+            reject_order.price = margin_price;
+
+            let result = OrderResult::from_order(timestamp, &reject_order, OrderStatus::NoMoney);
+            self.pending_results.push(result);
+
+            return Ok(OrderStatus::NoMoney);
+        }
+
+        order.arrival_time = timestamp + self.latency.order_latency();
+
+        // 成行オーダー
+        if price == 0.0 {
+            self.taker_orders.push(order);
+
+            return Ok(OrderStatus::InOrder);
         }
+
+        // 板の反対側に届く価格の場合、PostOnlyはエラー、それ以外はTakerとして処理する。
+        if self.is_cross_price(side, price) {
+            if post_only {
+                let result = OrderResult::from_order(timestamp, &order, OrderStatus::PostOnlyError);
+                self.pending_results.push(result);
+
+                return Ok(OrderStatus::PostOnlyError);
+            }
+
+            self.taker_orders.push(order);
+
+            return Ok(OrderStatus::InOrder);
+        }
+
+        self.queue_maker_order(&order);
+
+        Ok(OrderStatus::InOrder)
     }
 }
 
@@ -462,7 +1240,6 @@ impl DummySession {
 
 // TODO: EXPIRE時にFreeを計算してしまう（キャンセルオーダー扱い）
 // TODO: EXPIRE時にポジションか、オーダーキューに残っている。
-// TODO:　キャンセルオーダーの実装。
 
 #[cfg(test)]
 fn generate_trades_vec1(start_time: i64) -> Vec<Trade> {
@@ -486,18 +1263,21 @@ fn generate_trades_vec1(start_time: i64) -> Vec<Trade> {
 mod test_session {
     use crate::common::order::make_log_buffer;
     use crate::common::order::print_order_results;
+    use pyo3::types::PyModule;
 
     use super::*;
     #[test]
     fn test_new() {
-        let _session = DummySession::new("FTX", "BTC-PERP");
-        assert_eq!(_session.exchange_name, "FTX");
-        assert_eq!(_session.market_name, "BTC-PERP");
+        let _session = DummySession::new("bn", "btcbusd").unwrap();
+        assert_eq!(_session.exchange_name, "BN");
+        assert_eq!(_session.market_name, "BTCBUSD");
+
+        assert!(DummySession::new("FTX", "BTC-PERP").is_err());
     }
 
     #[test]
     fn test_session_generate_id() {
-        let mut session = DummySession::new("FTX", "BTC-PERP");
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
 
         // IDの生成テスト
         // self.generate_id
@@ -505,12 +1285,16 @@ mod test_session {
         let id2 = session.generate_id();
         println!("{}/{}", id1, id2);
         assert_ne!(id1, id2);       // check unique
+        assert_eq!(id1, "0000-0001");
+
+        session.order_id_prefix = "abc".to_string();
+        assert_eq!(session.generate_id(), "abc-0000-0003");
     }
         
     #[test]
     /// CenterPriceは刻みより小さい値を許容する。
     fn test_session_calc_center_price() {
-        let mut session = DummySession::new("FTX", "BTC-PERP");        
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();        
         // test center price
         session.buy_board_edge_price = 100.0;
         session.sell_board_edge_price = 100.5;
@@ -526,15 +1310,15 @@ mod test_session {
     #[test]
 fn test_100_orders_open_close() {
 
-    let mut session = DummySession::new("FTX", "BTC-PERP");
+    let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
     let mut result_log = make_log_buffer();
 
-    let _r = session._make_order(OrderSide::Buy, 100.0, 100.0, 100, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 100.0, 100.0, 100, "".to_string(), true);
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Sell, 60.0, 100.0, 100, "".to_string());
+    let _r = session._make_order(OrderSide::Sell, 60.0, 100.0, 100, "".to_string(), true);
     for t in generate_trades_vec1(100) {
         session.process_trade(&t, &mut result_log);
     }
@@ -545,15 +1329,15 @@ fn test_100_orders_open_close() {
 #[test]
 fn test_100_orders_open_expire() {
 
-    let mut session = DummySession::new("FTX", "BTC-PERP");
+    let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
     let mut result_log = make_log_buffer();
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Buy, 0.9, 100.0, 10, "".to_string());
-    let _r = session._make_order(OrderSide::Sell, 100.1, 100.0, 10, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 0.9, 100.0, 10, "".to_string(), true);
+    let _r = session._make_order(OrderSide::Sell, 100.1, 100.0, 10, "".to_string(), true);
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
@@ -569,19 +1353,19 @@ fn test_100_orders_open_expire() {
 #[test]
 fn test_100_orders_open_small_order() {
 
-    let mut session = DummySession::new("FTX", "BTC-PERP");
+    let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
     let mut result_log = make_log_buffer();
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Buy, 50.0, 0.1, 100, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 50.0, 0.1, 100, "".to_string(), true);
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
-    let _r = session._make_order(OrderSide::Sell, 60.0, 0.1, 100, "".to_string());
+    let _r = session._make_order(OrderSide::Sell, 60.0, 0.1, 100, "".to_string(), true);
     for t in generate_trades_vec1(100) {
         session.process_trade(&t, &mut result_log);
     }
@@ -593,14 +1377,14 @@ fn test_100_orders_open_small_order() {
 #[test]
 fn test_100_orders_open_big_order() {
 
-    let mut session = DummySession::new("FTX", "BTC-PERP");
+    let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
     let mut result_log = make_log_buffer();
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Buy, 50.0, 500.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 50.0, 500.0, 300, "".to_string(), true);
 
     for t in generate_trades_vec1(100) {
         session.process_trade(&t, &mut result_log);
@@ -609,7 +1393,7 @@ fn test_100_orders_open_big_order() {
     println!("price={:?} size={:?}", session.get_long_position_size(), session.get_long_position_price());
 
 
-    let _r = session._make_order(OrderSide::Sell, 55.0, 500.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Sell, 55.0, 500.0, 300, "".to_string(), true);
     for t in generate_trades_vec1(200) {
         session.process_trade(&t, &mut result_log);
     }
@@ -624,14 +1408,14 @@ fn test_100_orders_open_big_order() {
 #[test]
 fn test_100_orders_open_big_close_small_position() {
 
-    let mut session = DummySession::new("FTX", "BTC-PERP");
+    let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
     let mut result_log = make_log_buffer();
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Buy, 50.0, 500.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 50.0, 500.0, 300, "".to_string(), true);
 
     for t in generate_trades_vec1(100) {
         session.process_trade(&t, &mut result_log);
@@ -640,7 +1424,7 @@ fn test_100_orders_open_big_close_small_position() {
     println!("price={:?} size={:?}", session.get_long_position_size(), session.get_long_position_price());
 
 
-    let _r = session._make_order(OrderSide::Sell, 55.0, 400.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Sell, 55.0, 400.0, 300, "".to_string(), true);
     for t in generate_trades_vec1(200) {
         session.process_trade(&t, &mut result_log);
     }
@@ -648,7 +1432,7 @@ fn test_100_orders_open_big_close_small_position() {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Sell, 55.0, 100.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Sell, 55.0, 100.0, 300, "".to_string(), true);
     for t in generate_trades_vec1(400) {
         session.process_trade(&t, &mut result_log);
     }
@@ -662,14 +1446,14 @@ fn test_100_orders_open_big_close_small_position() {
 #[test]
 fn test_100_orders_open_small_close_big_position() {
 
-    let mut session = DummySession::new("FTX", "BTC-PERP");
+    let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
     let mut result_log = make_log_buffer();
 
     for t in generate_trades_vec1(0) {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Buy, 50.0, 500.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 50.0, 500.0, 300, "".to_string(), true);
 
     for t in generate_trades_vec1(100) {
         session.process_trade(&t, &mut result_log);
@@ -678,7 +1462,7 @@ fn test_100_orders_open_small_close_big_position() {
     println!("price={:?} size={:?}", session.get_long_position_size(), session.get_long_position_price());
 
 
-    let _r = session._make_order(OrderSide::Sell, 55.0, 600.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Sell, 55.0, 600.0, 300, "".to_string(), true);
     for t in generate_trades_vec1(200) {
         session.process_trade(&t, &mut result_log);
     }
@@ -686,7 +1470,7 @@ fn test_100_orders_open_small_close_big_position() {
         session.process_trade(&t, &mut result_log);
     }
 
-    let _r = session._make_order(OrderSide::Buy, 56.0, 100.0, 300, "".to_string());
+    let _r = session._make_order(OrderSide::Buy, 56.0, 100.0, 300, "".to_string(), true);
     for t in generate_trades_vec1(400) {
         session.process_trade(&t, &mut result_log);
     }
@@ -706,11 +1490,11 @@ fn test_100_orders_open_small_close_big_position() {
 
 #[test]
     fn test_exec_event_execute_order0() {
-        let mut session = DummySession::new("FTX", "BTC-PERP");
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
 
         let mut result_log = make_log_buffer();
 
-        let _r = session._make_order(OrderSide::Buy, 50.0, 10.0, 100, "".to_string());
+        let _r = session._make_order(OrderSide::Buy, 50.0, 10.0, 100, "".to_string(), true);
         println!("{:?}", session.long_orders);
         assert_eq!(session.get_long_order_size(), 10.0);
         assert_eq!(session.get_short_order_size(), 0.0);
@@ -735,7 +1519,7 @@ fn test_100_orders_open_small_close_big_position() {
 
     #[test]
     fn test_exec_event_execute_order() {
-        let mut session = DummySession::new("FTX", "BTC-PERP");
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
         assert_eq!(session.current_timestamp, 0); // 最初は０
 
         let mut tick_result: Vec<OrderResult> = vec![];
@@ -760,7 +1544,7 @@ fn test_100_orders_open_small_close_big_position() {
         println!("--make long order--");
 
         // TODO: 書庫金不足を確認する必要がある.
-        let _r = session._make_order(OrderSide::Buy, 50.0, 10.0, 100, "".to_string());
+        let _r = session._make_order(OrderSide::Buy, 50.0, 10.0, 100, "".to_string(), true);
         println!("{:?}", session.long_orders);
 
         // 売りよりも高い金額のオファーにはなにもしない。
@@ -810,12 +1594,12 @@ fn test_100_orders_open_small_close_big_position() {
 
         // 決裁オーダーTODO: 書庫金不足を確認する必要がある.
 
-        let _r = session._make_order(OrderSide::Sell, 40.0, 12.0, 100, "".to_string());
+        let _r = session._make_order(OrderSide::Sell, 40.0, 12.0, 100, "".to_string(), true);
         // println!("{:?}", session.order_history);
         println!("{:?}", session.short_orders);
         println!("{:?}", session.positions);
 
-        let _r = session._make_order(OrderSide::Sell, 41.0, 10.0, 100, "".to_string());
+        let _r = session._make_order(OrderSide::Sell, 41.0, 10.0, 100, "".to_string(), true);
         // println!("{:?}", session.order_history);
         println!("{:?}", session.short_orders);
         println!("{:?}", session.positions);
@@ -851,7 +1635,7 @@ fn test_100_orders_open_small_close_big_position() {
         println!("{:?}", tick_result);
 
         // 決裁オーダーTODO: 書庫金不足を確認する必要がある.
-        let _r = session._make_order(OrderSide::Buy, 80.0, 10.0, 100, "".to_string());
+        let _r = session._make_order(OrderSide::Buy, 80.0, 10.0, 100, "".to_string(), true);
         // println!("{:?}", session.order_history);
         println!("{:?}", session.short_orders);
         println!("{:?}", session.positions);
@@ -868,4 +1652,499 @@ fn test_100_orders_open_small_close_big_position() {
         println!("{:?}", session.positions);
         println!("{:?}", tick_result);
     }
+
+    /// 板の先頭価格を設定するためのウォームアップ（買い板100.0 / 売り板101.0）
+    fn warm_up_session(session: &mut DummySession, tick_result: &mut LogBuffer) {
+        session.process_trade(&Trade{time: SEC(1), order_side: OrderSide::Buy, price: 101.0, size: 1.0, id: "".to_string()}, tick_result);
+        session.process_trade(&Trade{time: SEC(2), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, tick_result);
+    }
+
+    #[test]
+    fn test_market_order() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        let r = session._make_order(OrderSide::Buy, 0.0, 10.0, 0, "".to_string(), false);
+        assert_eq!(r.unwrap(), OrderStatus::InOrder);
+        assert_eq!(session.taker_orders.len(), 1);
+
+        // サーバ遅延前は約定しない
+        session.process_trade(&Trade{time: SEC(2) + 10, order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 0.0);
+
+        // サーバ遅延後に売り板の先頭で約定
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.taker_orders.len(), 0);
+        assert_eq!(session.get_long_position_size(), 10.0);
+        assert_eq!(session.get_long_position_price(), 101.0);
+
+        let open = tick_result.last().unwrap();
+        assert_eq!(open.status, OrderStatus::OpenPosition);
+        assert_eq!(open.is_maker, false);
+        assert_eq!(open.fee, open.open_foreign_size * session.taker_fee);
+    }
+
+    #[test]
+    fn test_market_order_slippage() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        session.taker_slippage = 0.01;
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        let _r = session._make_order(OrderSide::Sell, 0.0, 10.0, 0, "".to_string(), false);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+
+        assert_eq!(session.get_short_position_size(), 10.0);
+        assert_eq!(session.get_short_position_price(), 100.0 * (1.0 - 0.01));
+    }
+
+    #[test]
+    fn test_post_only_error() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        // 売り板に届く買いのPostOnlyオーダーはエラー
+        let r = session._make_order(OrderSide::Buy, 101.0, 10.0, 100, "".to_string(), true);
+        assert_eq!(r.unwrap(), OrderStatus::PostOnlyError);
+        assert_eq!(session.get_long_order_size(), 0.0);
+
+        // 次のTickでログに出力される
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(tick_result.last().unwrap().status, OrderStatus::PostOnlyError);
+
+        // 板の内側はMakerとしてキューへ入る
+        let r = session._make_order(OrderSide::Buy, 100.5, 10.0, 100, "".to_string(), true);
+        assert_eq!(r.unwrap(), OrderStatus::InOrder);
+        assert_eq!(session.get_long_order_size(), 10.0);
+    }
+
+    #[test]
+    fn test_limit_taker_order() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        // 板に届く指値（PostOnlyではない）はTakerとして板の先頭で約定
+        let _r = session._make_order(OrderSide::Buy, 105.0, 10.0, 100, "".to_string(), false);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_price(), 101.0);
+
+        // 約定前に板が逃げた場合はMakerとしてキューに残る
+        let _r = session._make_order(OrderSide::Buy, 101.0, 10.0, 100, "".to_string(), false);
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Buy, price: 102.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.taker_orders.len(), 0);
+        assert_eq!(session.get_long_order_size(), 10.0);
+        assert_eq!(session.get_long_position_size(), 10.0);
+    }
+
+    #[test]
+    fn test_cancel_order() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        let _r = session._make_order(OrderSide::Buy, 99.0, 10.0, 100, "".to_string(), true);
+        let order_id = session.get_long_orders()[0].order_id.clone();
+
+        assert_eq!(session.cancel_order("no-such-order"), OrderStatus::NoAction);
+        assert_eq!(session.cancel_order(&order_id), OrderStatus::Wait);
+
+        // サーバ遅延前はキャンセルされない
+        session.process_trade(&Trade{time: SEC(2) + 10, order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_order_size(), 10.0);
+
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_order_size(), 0.0);
+        assert_eq!(session.cancel_requests.len(), 0);
+
+        let cancel = tick_result.last().unwrap();
+        assert_eq!(cancel.status, OrderStatus::Cancel);
+        assert_eq!(cancel.order_id, order_id);
+        assert_eq!(cancel.update_time, SEC(3));
+    }
+
+    #[test]
+    fn test_apply_fill() {
+        let mut session = DummySession::new("BN", "BTCUSDT").unwrap();
+        session.set_partial_fill(true);
+        let mut tick_result = make_log_buffer();
+
+        // 20000で100(USDT)の買い。取引所の数量は0.005(BTC)
+        let order = session.new_order(OrderSide::Buy, 20_000.0, 100.0, 600, "".to_string(), true).unwrap();
+        session.queue_maker_order(&order);
+
+        let fill = |trade_id: i64, size: f64| Fill {
+            order_id: order.order_id.clone(),
+            trade_id,
+            time: SEC(1),
+            price: 20_000.0,
+            size,
+            is_maker: true,
+        };
+
+        session.apply_fill(&fill(1, 0.002), &mut tick_result).unwrap();
+        assert!((session.get_long_order_size() - 60.0).abs() < 1e-9);
+        assert!((session.get_long_position_size() - 40.0).abs() < 1e-9);
+
+        session.apply_fill(&fill(2, 0.003), &mut tick_result).unwrap();
+        assert_eq!(session.get_long_order_size(), 0.0);
+        assert!((session.get_long_position_size() - 100.0).abs() < 1e-9);
+
+        let open = tick_result.last().unwrap();
+        assert_eq!(open.status, OrderStatus::OpenPosition);
+        assert!((open.open_home_size - 60.0).abs() < 1e-9);
+        assert!((open.open_foreign_size - 0.003).abs() < 1e-12);
+
+        // 約定済みのオーダー
+        assert_eq!(session.apply_fill(&fill(3, 0.001), &mut tick_result), Err(OrderStatus::NoAction));
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        let _r = session._make_order(OrderSide::Buy, 99.0, 10.0, 100, "".to_string(), true);
+        let _r = session._make_order(OrderSide::Buy, 98.0, 10.0, 100, "".to_string(), true);
+        let _r = session._make_order(OrderSide::Sell, 102.0, 10.0, 100, "".to_string(), true);
+
+        assert_eq!(session.cancel_all("BUY").len(), 2);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_order_size(), 0.0);
+        assert_eq!(session.get_short_order_size(), 10.0);
+
+        assert_eq!(session.cancel_all("").len(), 1);
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_short_order_size(), 0.0);
+
+        let cancel_count = tick_result.iter().filter(|r| r.status == OrderStatus::Cancel).count();
+        assert_eq!(cancel_count, 3);
+    }
+
+    #[test]
+    fn test_cancel_taker_order() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        // キャンセルがオーダーより先にサーバへ届く場合はキャンセルされる
+        session.set_latency(SEC(2), SEC(1), 0, 0, 0).unwrap();
+        let _r = session._make_order(OrderSide::Buy, 0.0, 10.0, 0, "".to_string(), false);
+        let order_id = session.taker_orders[0].order_id.clone();
+        assert_eq!(session.cancel_all("BUY"), vec![order_id.clone()]);
+
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.taker_orders.len(), 0);
+        assert_eq!(session.get_long_position_size(), 0.0);
+
+        let cancel = tick_result.last().unwrap();
+        assert_eq!(cancel.status, OrderStatus::Cancel);
+        assert_eq!(cancel.order_id, order_id);
+        assert_eq!(cancel.order_price, 101.0);
+
+        // オーダーが先に届く場合は約定する
+        session.set_latency(SEC(1), SEC(1), 0, 0, 0).unwrap();
+        let _r = session._make_order(OrderSide::Buy, 0.0, 10.0, 0, "".to_string(), false);
+        let order_id = session.taker_orders[0].order_id.clone();
+        assert_eq!(session.cancel_order(&order_id), OrderStatus::Wait);
+
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 10.0);
+        assert_eq!(session.cancel_requests.len(), 0);
+    }
+
+    #[test]
+    fn test_margin_no_money() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        session.deposit(1.0);
+        assert_eq!(session.wallet_balance, 1.0);
+        assert_eq!(session.get_available_balance(), 1.0);
+
+        // 証拠金以上のオーダーはNoMoney
+        let r = session._make_order(OrderSide::Buy, 99.0, 200.0, 100, "".to_string(), true);
+        assert_eq!(r.unwrap(), OrderStatus::NoMoney);
+        assert_eq!(session.get_long_order_size(), 0.0);
+
+        let r = session._make_order(OrderSide::Buy, 0.0, 200.0, 100, "".to_string(), false);
+        assert_eq!(r.unwrap(), OrderStatus::NoMoney);
+
+        let r = session._make_order(OrderSide::Buy, 99.0, 50.0, 100, "".to_string(), true);
+        assert_eq!(r.unwrap(), OrderStatus::InOrder);
+        assert_eq!(session.get_order_margin(), 50.0 / 99.0);
+        assert_eq!(session.get_available_balance(), 1.0 - 50.0 / 99.0);
+
+        // レバレッジを上げると発注できる
+        session.leverage = 10.0;
+        let r = session._make_order(OrderSide::Buy, 99.0, 200.0, 100, "".to_string(), true);
+        assert_eq!(r.unwrap(), OrderStatus::InOrder);
+
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        let no_money_count = tick_result.iter().filter(|r| r.status == OrderStatus::NoMoney).count();
+        assert_eq!(no_money_count, 2);
+    }
+
+    #[test]
+    fn test_liquidation() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        session.deposit(1.0);
+        session.leverage = 10.0;
+
+        let r = session._make_order(OrderSide::Buy, 0.0, 500.0, 0, "".to_string(), false);
+        assert_eq!(r.unwrap(), OrderStatus::InOrder);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 500.0);
+        assert!(session.wallet_balance < 1.0);     // 手数料分減る
+
+        // 含み損が証拠金以内であれば精算されない
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Sell, price: 70.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert!(session.get_unrealized_pnl() < 0.0);
+        assert_eq!(session.get_long_position_size(), 500.0);
+
+        // 維持証拠金を割り込んだら強制精算
+        session.process_trade(&Trade{time: SEC(5), order_side: OrderSide::Buy, price: 71.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 0.0);
+        assert_eq!(session.wallet_balance, 0.0);
+
+        let liquidation = tick_result.last().unwrap();
+        assert_eq!(liquidation.status, OrderStatus::Liquidation);
+        assert_eq!(liquidation.close_price, 70.0);
+        assert!(liquidation.profit < 0.0);
+    }
+
+    #[test]
+    fn test_partial_fill() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        session.set_partial_fill(true);
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        let _r = session._make_order(OrderSide::Buy, 99.0, 10.0, 100, "".to_string(), true);
+        let _r = session._make_order(OrderSide::Buy, 99.0, 10.0, 100, "".to_string(), true);
+
+        // 一部約定でポジションができる（約定ログの0.04はオーダー価格で3.96(home)）
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 98.0, size: 0.04, id: "".to_string()}, &mut tick_result);
+        assert!((session.get_long_position_size() - 3.96).abs() < 1e-9);
+        assert!((session.get_long_order_size() - 16.04).abs() < 1e-9);
+        assert!((tick_result.last().unwrap().open_home_size - 3.96).abs() < 1e-9);
+        assert!((tick_result.last().unwrap().open_foreign_size - 0.04).abs() < 1e-12);
+
+        // 大きな約定ログで２つのオーダーが同時に約定する
+        let log_len = tick_result.len();
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Sell, price: 98.0, size: 100.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 20.0);
+        assert_eq!(session.get_long_order_size(), 0.0);
+        assert_eq!(tick_result.len() - log_len, 2);
+    }
+
+    #[test]
+    fn test_queue_position_fill_model() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        assert_eq!(session.get_fill_model(), "TradeThrough");
+        session._set_fill_model("QueuePosition").unwrap();
+        assert_eq!(session.get_fill_model(), "QueuePosition");
+        assert!(session._set_fill_model("Unknown").is_err());
+
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 100.0, size: 5.0, id: "".to_string()}, &mut tick_result);
+
+        // 直近の100.0での約定(1.0 + 5.0)が前に並んでいると推定される。
+        let _r = session._make_order(OrderSide::Buy, 100.0, 10.0, 100, "".to_string(), true);
+        assert_eq!(session.long_orders.get_q()[0].queue_ahead, 6.0);
+
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Sell, price: 100.0, size: 4.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 0.0);
+        assert_eq!(session.long_orders.get_q()[0].queue_ahead, 2.0);
+
+        // 前に並んでいる分が消化されたあとに約定する（TradeThroughでは同じ価格では約定しない）
+        session.process_trade(&Trade{time: SEC(5), order_side: OrderSide::Sell, price: 100.0, size: 12.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 10.0);
+        assert_eq!(session.get_long_order_size(), 0.0);
+    }
+
+    #[test]
+    fn test_funding() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        session.set_funding_rates(vec![(SEC(6), -0.0002), (SEC(1), 0.0005), (SEC(4), 0.0001)]);
+        // 過去のFundingRateは無視
+        assert_eq!(session.funding_rates.len(), 2);
+
+        let _r = session._make_order(OrderSide::Buy, 0.0, 10.0, 0, "".to_string(), false);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Buy, price: 101.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 10.0);
+
+        // longはFundingRateが正の場合に支払う
+        let wallet = session.wallet_balance;
+        session.process_trade(&Trade{time: SEC(5), order_side: OrderSide::Buy, price: 101.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        let funding = tick_result.last().unwrap();
+        assert_eq!(funding.status, OrderStatus::Funding);
+        assert_eq!(funding.fee, 0.0);
+        assert_eq!(funding.total_profit, -0.0001 * 10.0 / 100.5);
+        assert_eq!(session.wallet_balance, wallet + funding.total_profit);
+
+        // 負のFundingRateでは受け取り
+        session.process_trade(&Trade{time: SEC(7), order_side: OrderSide::Buy, price: 101.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        let funding = tick_result.last().unwrap();
+        assert_eq!(funding.status, OrderStatus::Funding);
+        assert_eq!(funding.total_profit, 0.0002 * 10.0 / 100.5);
+        assert_eq!(session.funding_rates.len(), 0);
+    }
+
+    #[test]
+    fn test_book() {
+        let mut session = DummySession::new("BN", "BTCUSDT").unwrap();
+        let mut tick_result = make_log_buffer();
+
+        // 板の数量はBTC、オーダーサイズはUSDT
+        session.set_book_updates(vec![
+            BookUpdate::new(SEC(1), true, OrderSide::Buy, 20_000.0, 0.5),
+            BookUpdate::new(SEC(1), true, OrderSide::Buy, 19_999.0, 0.5),
+            BookUpdate::new(SEC(1), true, OrderSide::Sell, 20_001.0, 0.1),
+            BookUpdate::new(SEC(1), true, OrderSide::Sell, 20_002.0, 1.0),
+        ]);
+        session.push_book_update(BookUpdate::new(SEC(5), false, OrderSide::Sell, 20_001.0, 0.0));
+
+        // 約定ログではなく板の先頭をエッジの価格とする
+        session.process_trade(&Trade{time: SEC(1), order_side: OrderSide::Buy, price: 20_000.5, size: 0.01, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.buy_board_edge_price, 20_000.0);
+        assert_eq!(session.sell_board_edge_price, 20_001.0);
+        assert_eq!(session.book(1), (vec![(20_000.0, 0.5)], vec![(20_001.0, 0.1)]));
+
+        // 3000USDTの成行は、20001の0.1BTC(2000.1USDT)と20002の残り899.9USDT分を消化した平均価格で約定する
+        let _r = session._make_order(OrderSide::Buy, 0.0, 3_000.0, 0, "".to_string(), false);
+        session.process_trade(&Trade{time: SEC(3), order_side: OrderSide::Sell, price: 20_000.0, size: 0.01, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 3_000.0);
+        let expected = 3_000.0 / (0.1 + (3_000.0 - 2_000.1) / 20_002.0);
+        assert!((session.get_long_position_price() - expected).abs() < 1e-6);
+        assert!(20_001.0 < expected && expected < 20_002.0);
+
+        // Makerオーダーは同じ価格の板の後ろに並ぶ（約定ログと同じBTC単位）
+        let _r = session._make_order(OrderSide::Sell, 20_002.0, 100.0, 600, "".to_string(), true);
+        assert_eq!(session.get_short_orders()[0].queue_ahead, 1.0);
+
+        // 時刻をすぎた差分が適用される
+        session.process_trade(&Trade{time: SEC(6), order_side: OrderSide::Buy, price: 20_001.0, size: 0.01, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.sell_board_edge_price, 20_002.0);
+        assert_eq!(session.book_updates.len(), 0);
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        assert_eq!(session.get_latency_model(), "Fixed");
+        session.set_latency(SEC(1), SEC(2), SEC(3), 0, 0).unwrap();
+        assert!(session.set_latency(-1, 0, 0, 0, 0).is_err());
+
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        // 判断遅延の間はオーダーが出ていない
+        let r = session._make_order(OrderSide::Buy, 99.0, 10.0, 100, "".to_string(), true);
+        assert_eq!(r.unwrap(), OrderStatus::Wait);
+        assert_eq!(session.get_long_order_size(), 0.0);
+
+        // 判断遅延の間に板が動いた場合は、経過した時点の板で処理する（PostOnlyError）
+        session.process_trade(&Trade{time: SEC(4), order_side: OrderSide::Sell, price: 98.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_order_size(), 0.0);
+        session.process_trade(&Trade{time: SEC(5), order_side: OrderSide::Buy, price: 99.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(tick_result.last().unwrap().status, OrderStatus::PostOnlyError);
+        assert_eq!(tick_result.last().unwrap().create_time, SEC(5));
+
+        // サーバへ届く前の約定ログでは約定しない
+        session.set_latency(SEC(1), SEC(2), 0, 0, 0).unwrap();
+        let _r = session._make_order(OrderSide::Buy, 97.0, 10.0, 100, "".to_string(), true);
+        assert_eq!(session.get_long_orders()[0].arrival_time, SEC(6));
+        session.process_trade(&Trade{time: SEC(5) + 10, order_side: OrderSide::Sell, price: 96.0, size: 100.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 0.0);
+        session.process_trade(&Trade{time: SEC(6), order_side: OrderSide::Sell, price: 96.0, size: 100.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_position_size(), 10.0);
+
+        // キャンセルはキャンセルの遅延の後に反映される
+        let _r = session._make_order(OrderSide::Buy, 95.0, 10.0, 100, "".to_string(), true);
+        let order_id = session.get_long_orders()[0].order_id.clone();
+        assert_eq!(session.cancel_order(&order_id), OrderStatus::Wait);
+        session.process_trade(&Trade{time: SEC(7), order_side: OrderSide::Sell, price: 96.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_order_size(), 10.0);
+        session.process_trade(&Trade{time: SEC(8), order_side: OrderSide::Sell, price: 96.0, size: 1.0, id: "".to_string()}, &mut tick_result);
+        assert_eq!(session.get_long_order_size(), 0.0);
+
+        // ジッタはseedで再現できる
+        session.set_latency(SEC(1), SEC(1), 0, 100_000, 7).unwrap();
+        assert_eq!(session.get_latency_model(), "Jitter");
+        let _r = session._make_order(OrderSide::Buy, 95.0, 10.0, 100, "".to_string(), true);
+        let arrival_time = session.get_long_orders()[0].arrival_time;
+        assert!(SEC(9) <= arrival_time);
+
+        assert_eq!(session.get_server_delay(), arrival_time - SEC(8));
+
+        let mut other = DummySession::new("BN", "BTCBUSD").unwrap();
+        other.set_latency(SEC(1), SEC(1), 0, 100_000, 7).unwrap();
+        assert_eq!(other.get_server_delay(), SEC(1));
+        assert_eq!(other.latency.order_latency(), arrival_time - SEC(8));
+    }
+
+    #[test]
+    fn test_server_delay() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        assert_eq!(session.get_server_delay(), 100_000);
+
+        session.set_server_delay(SEC(1)).unwrap();
+        assert_eq!(session.get_latency_model(), "Fixed");
+        assert_eq!(session.get_server_delay(), SEC(1));
+        assert_eq!(session.latency.cancel_latency(), SEC(1));
+        assert!(session.set_server_delay(-1).is_err());
+    }
+
+    #[test]
+    fn test_py_latency_model() {
+        let mut session = DummySession::new("BN", "BTCBUSD").unwrap();
+        let mut tick_result = make_log_buffer();
+        warm_up_session(&mut session, &mut tick_result);
+
+        Python::with_gil(|py| {
+            let latency = PyModule::from_code(
+                py,
+                r#"
+class Latency:
+    def order_latency(self):
+        return 2_000_000
+
+    def cancel_latency(self):
+        return 0
+
+    def decision_latency(self):
+        return 0
+"#,
+                "latency.py",
+                "latency",
+            )
+            .unwrap()
+            .getattr("Latency")
+            .unwrap()
+            .call0()
+            .unwrap();
+
+            session.set_latency_model(latency).unwrap();
+            assert!(session.set_latency_model(py.None().as_ref(py)).is_err());
+        });
+
+        assert_eq!(session.get_latency_model(), "Python");
+        assert_eq!(session.get_server_delay(), 0);
+
+        let _r = session._make_order(OrderSide::Buy, 97.0, 10.0, 100, "".to_string(), true);
+        assert_eq!(session.get_long_orders()[0].arrival_time, session.current_timestamp + SEC(2));
+        assert_eq!(session.get_server_delay(), SEC(2));
+    }
 }